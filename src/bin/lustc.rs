@@ -0,0 +1,153 @@
+// A compiler in the style of luac: compiles a Lua file to a binary
+// chunk that lust runs without parsing it again.
+
+use lust::Lua;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+const VERSION: &str = concat!("lustc ", env!("CARGO_PKG_VERSION"));
+
+// luac's default
+const OUTPUT: &str = "luac.out";
+
+fn usage(progname: &str) -> String {
+    format!(
+        "usage: {} [options] [filename]
+Available options are:
+  -l       list (use -l -l for full listing)
+  -o name  output to file 'name' (default is \"{}\")
+  -p       parse only
+  -s       strip debug information
+  -v       show version information
+  --       stop handling options
+  -        stop handling options and process stdin",
+        progname, OUTPUT
+    )
+}
+
+#[derive(Default)]
+struct Options {
+    // None writes to stdout, with `-o -`
+    output: Option<String>,
+    // How many times -l was given
+    list: usize,
+    parse_only: bool,
+    strip: bool,
+    version: bool,
+    input: Option<String>,
+}
+
+// Options come before the file, as with luac.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        output: Some(OUTPUT.to_string()),
+        ..Options::default()
+    };
+    let mut inputs = vec![];
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if !arg.starts_with('-') || arg == "-" {
+            inputs.extend_from_slice(&args[i..]);
+            break;
+        }
+
+        match arg.as_str() {
+            "--" => {
+                inputs.extend_from_slice(&args[i + 1..]);
+                break;
+            }
+            "-o" => {
+                i += 1;
+                options.output = match args.get(i).map(String::as_str) {
+                    Some("-") => None,
+                    Some(name) if !name.starts_with('-') => Some(name.to_string()),
+                    _ => return Err("'-o' needs argument".to_string()),
+                };
+            }
+            "-l" => options.list += 1,
+            "-p" => options.parse_only = true,
+            "-s" => options.strip = true,
+            "-v" => options.version = true,
+            _ => return Err(format!("unrecognized option '{}'", arg)),
+        }
+        i += 1;
+    }
+
+    // Unlike luac, files are not combined into one chunk
+    match inputs.len() {
+        0 if options.version => {}
+        0 => return Err("no input files given".to_string()),
+        1 => options.input = inputs.pop(),
+        _ => return Err("only one input file can be compiled".to_string()),
+    }
+    Ok(options)
+}
+
+fn compile(options: &Options, input: &str) -> Result<(), String> {
+    let (name, chunk) = if input == "-" {
+        let mut chunk = vec![];
+        io::stdin()
+            .read_to_end(&mut chunk)
+            .map_err(|e| format!("cannot read stdin: {}", e))?;
+        ("stdin", chunk)
+    } else {
+        let chunk = fs::read(input).map_err(|e| format!("cannot open {}: {}", input, e))?;
+        (input, chunk)
+    };
+
+    // Binary chunks are accepted too, say to strip them
+    let mut lua = Lua::new();
+    let function = lua
+        .load(chunk, name)
+        .map_err(|e| e.to_string())?
+        .into_function();
+    if options.list > 0 {
+        let listing = lua
+            .listing(&function, options.list > 1)
+            .map_err(|e| e.to_string())?;
+        print!("{}", listing);
+    }
+    if options.parse_only {
+        return Ok(());
+    }
+    let bytes = lua
+        .dump(&function, options.strip)
+        .map_err(|e| e.to_string())?;
+
+    match &options.output {
+        Some(output) => {
+            fs::write(output, bytes).map_err(|e| format!("cannot write {}: {}", output, e))
+        }
+        None => io::stdout()
+            .write_all(&bytes)
+            .map_err(|e| format!("cannot write stdout: {}", e)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let progname = args.first().map_or("lustc", String::as_str).to_string();
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}: {}", progname, msg);
+            eprintln!("{}", usage(&progname));
+            process::exit(1);
+        }
+    };
+    if options.version {
+        println!("{}", VERSION);
+    }
+
+    if let Some(input) = &options.input {
+        if let Err(msg) = compile(&options, input) {
+            eprintln!("{}: {}", progname, msg);
+            process::exit(1);
+        }
+    }
+}
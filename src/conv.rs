@@ -0,0 +1,295 @@
+use crate::string::LuaString;
+use crate::value::{Error, Table, Value};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+// Rust types a Lua value can be read back as.
+pub trait FromLua: Sized {
+    fn from_lua(value: Value) -> Result<Self, Error>;
+}
+
+// Rust types that can be passed to Lua as a value.
+pub trait IntoLua {
+    fn into_lua(self) -> Result<Value, Error>;
+}
+
+// Conversions from all the values a call or chunk returned. Single
+// types take the first value; tuples take one value per element.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(values: Vec<Value>) -> Result<Self, Error>;
+}
+
+// Conversions into argument or return lists.
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self) -> Result<Vec<Value>, Error>;
+}
+
+fn expected(what: &str, value: &Value) -> Error {
+    Error::message(format!("{} expected, got {}", what, value.type_name()))
+}
+
+impl FromLua for Value {
+    fn from_lua(value: Value) -> Result<Value, Error> {
+        Ok(value)
+    }
+}
+
+impl IntoLua for Value {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(self)
+    }
+}
+
+impl FromLua for bool {
+    fn from_lua(value: Value) -> Result<bool, Error> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            _ => Err(expected("boolean", &value)),
+        }
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(Value::Boolean(self))
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {$(
+        impl FromLua for $t {
+            fn from_lua(value: Value) -> Result<$t, Error> {
+                let i = match value.to_integer() {
+                    Some(i) => i,
+                    None if value.to_number().is_some() => {
+                        return Err(Error::message("number has no integer representation"))
+                    }
+                    None => return Err(expected("number", &value)),
+                };
+                <$t>::try_from(i).map_err(|_| {
+                    Error::message(format!("{} is out of range for {}", i, stringify!($t)))
+                })
+            }
+        }
+
+        // Integers beyond i64 become floats, as in Lua source
+        impl IntoLua for $t {
+            fn into_lua(self) -> Result<Value, Error> {
+                Ok(match i64::try_from(self) {
+                    Ok(i) => Value::Integer(i),
+                    Err(_) => Value::Number(self as f64),
+                })
+            }
+        }
+    )*};
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! float_conversions {
+    ($($t:ty),*) => {$(
+        impl FromLua for $t {
+            fn from_lua(value: Value) -> Result<$t, Error> {
+                match value.to_float() {
+                    Some(f) => Ok(f as $t),
+                    None => Err(expected("number", &value)),
+                }
+            }
+        }
+
+        impl IntoLua for $t {
+            fn into_lua(self) -> Result<Value, Error> {
+                Ok(Value::Number(self as f64))
+            }
+        }
+    )*};
+}
+
+float_conversions!(f32, f64);
+
+// Strings, and numbers converted to strings.
+impl FromLua for LuaString {
+    fn from_lua(value: Value) -> Result<LuaString, Error> {
+        match value {
+            Value::String(s) => Ok(s),
+            Value::Integer(_) | Value::Number(_) => Ok(LuaString::from(value.to_string())),
+            _ => Err(expected("string", &value)),
+        }
+    }
+}
+
+// Lua strings are bytes; those that aren't UTF-8 only convert to
+// LuaString.
+impl FromLua for String {
+    fn from_lua(value: Value) -> Result<String, Error> {
+        let s = LuaString::from_lua(value)?;
+        match s.to_str() {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err(Error::message("string is not valid UTF-8")),
+        }
+    }
+}
+
+impl IntoLua for LuaString {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(Value::String(self))
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(Value::String(LuaString::from(self)))
+    }
+}
+
+impl IntoLua for &[u8] {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(Value::String(LuaString::from(self)))
+    }
+}
+
+impl IntoLua for &str {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(Value::from(self))
+    }
+}
+
+impl FromLua for Rc<RefCell<Table>> {
+    fn from_lua(value: Value) -> Result<Rc<RefCell<Table>>, Error> {
+        match value {
+            Value::Table(t) => Ok(t),
+            _ => Err(expected("table", &value)),
+        }
+    }
+}
+
+impl IntoLua for Rc<RefCell<Table>> {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(Value::Table(self))
+    }
+}
+
+impl IntoLua for Table {
+    fn into_lua(self) -> Result<Value, Error> {
+        Ok(Value::from(self))
+    }
+}
+
+// nil is None
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: Value) -> Result<Option<T>, Error> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_lua(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self) -> Result<Value, Error> {
+        match self {
+            Some(v) => v.into_lua(),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+// Sequences are the elements 1..n of a table, nil in any holes.
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: Value) -> Result<Vec<T>, Error> {
+        let t = Rc::<RefCell<Table>>::from_lua(value)?;
+        let t = t.borrow();
+        (1..=t.sequence_len())
+            .map(|i| T::from_lua(t.get(&Value::Integer(i as i64))))
+            .collect()
+    }
+}
+
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self) -> Result<Value, Error> {
+        // Indexed rather than pushed, so a nil element leaves a hole
+        let mut t = Table::new();
+        for (i, v) in self.into_iter().enumerate() {
+            t.set(Value::Integer(i as i64 + 1), v.into_lua()?).unwrap();
+        }
+        Ok(Value::from(t))
+    }
+}
+
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(value: Value) -> Result<HashMap<K, V>, Error> {
+        let t = Rc::<RefCell<Table>>::from_lua(value)?;
+        let pairs = t.borrow().pairs().collect::<Vec<_>>();
+        pairs
+            .into_iter()
+            .map(|(k, v)| Ok((K::from_lua(k)?, V::from_lua(v)?)))
+            .collect()
+    }
+}
+
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self) -> Result<Value, Error> {
+        let mut t = Table::new();
+        for (k, v) in self {
+            t.set(k.into_lua()?, v.into_lua()?)
+                .map_err(Error::message)?;
+        }
+        Ok(Value::from(t))
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(values: Vec<Value>) -> Result<T, Error> {
+        T::from_lua(values.into_iter().next().unwrap_or(Value::Nil))
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self) -> Result<Vec<Value>, Error> {
+        Ok(vec![self.into_lua()?])
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_multi(_: Vec<Value>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl IntoLuaMulti for () {
+    fn into_lua_multi(self) -> Result<Vec<Value>, Error> {
+        Ok(vec![])
+    }
+}
+
+// Missing values read as nil, and extra values are dropped.
+macro_rules! tuple_conversions {
+    ($($name:ident),+) => {
+        impl<$($name: FromLua),+> FromLuaMulti for ($($name,)+) {
+            fn from_lua_multi(values: Vec<Value>) -> Result<Self, Error> {
+                let mut values = values.into_iter();
+                Ok(($($name::from_lua(values.next().unwrap_or(Value::Nil))?,)+))
+            }
+        }
+
+        impl<$($name: IntoLua),+> IntoLuaMulti for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_lua_multi(self) -> Result<Vec<Value>, Error> {
+                let ($($name,)+) = self;
+                Ok(vec![$($name.into_lua()?),+])
+            }
+        }
+    };
+}
+
+tuple_conversions!(A);
+tuple_conversions!(A, B);
+tuple_conversions!(A, B, C);
+tuple_conversions!(A, B, C, D);
+tuple_conversions!(A, B, C, D, E);
+tuple_conversions!(A, B, C, D, E, F);
+tuple_conversions!(A, B, C, D, E, F, G);
+tuple_conversions!(A, B, C, D, E, F, G, H);
@@ -0,0 +1,525 @@
+// Precompiled chunks: prototypes saved as bytes, the way luac and
+// string.dump write them, so loading them skips lexing, parsing and
+// compiling.
+//
+// The layout follows Lua's: a header that rejects chunks written for
+// another version or machine, then the main function's prototype with
+// its constants and nested prototypes. Line numbers, the names in
+// error messages and the names of locals and upvalues are debug info,
+// which stripping leaves out.
+
+use crate::eval::{InlineCache, Instruction, LocVar, Proto, UpvalueDesc};
+use crate::lex::{FileId, SourceMap, Span};
+use crate::opcodes;
+use crate::string::LuaString;
+use crate::value::Value;
+use std::rc::Rc;
+
+// LUA_SIGNATURE, which tells binary chunks from source
+pub(crate) const SIGNATURE: &[u8] = b"\x1bLua";
+const VERSION: u8 = 0x54;
+// Official Lua chunks are format 0, which this can't read. Bump it
+// whenever the instruction set or the layout changes.
+const FORMAT: u8 = 0x86;
+// Catches chunks mangled by newline conversion
+const DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+// Read back to check the byte order and representation of numbers
+const CHECK_INTEGER: i64 = 0x5678;
+const CHECK_NUMBER: f64 = 370.5;
+// Loaded chunks get a stand-in source with as many lines as the last
+// one named, so a corrupted line number mustn't run to billions
+const MAX_LINE: usize = 1 << 24;
+
+struct Writer<'a> {
+    sm: &'a SourceMap,
+    strip: bool,
+    out: Vec<u8>,
+}
+
+impl Writer<'_> {
+    fn byte(&mut self, b: u8) {
+        self.out.push(b);
+    }
+
+    // Sizes and indices, seven bits at a time, lowest first
+    fn size(&mut self, mut n: usize) {
+        while n >= 0x80 {
+            self.out.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.out.push(n as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.size(bytes.len());
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn header(&mut self) {
+        self.out.extend_from_slice(SIGNATURE);
+        self.byte(VERSION);
+        self.byte(FORMAT);
+        self.out.extend_from_slice(DATA);
+        self.byte(std::mem::size_of::<i64>() as u8);
+        self.byte(std::mem::size_of::<f64>() as u8);
+        self.out.extend_from_slice(&CHECK_INTEGER.to_ne_bytes());
+        self.out.extend_from_slice(&CHECK_NUMBER.to_ne_bytes());
+    }
+
+    fn constant(&mut self, v: &Value) {
+        match v {
+            Value::Nil => self.byte(0),
+            Value::Boolean(false) => self.byte(1),
+            Value::Boolean(true) => self.byte(2),
+            Value::Integer(i) => {
+                self.byte(3);
+                self.out.extend_from_slice(&i.to_ne_bytes());
+            }
+            Value::Number(n) => {
+                self.byte(4);
+                self.out.extend_from_slice(&n.to_ne_bytes());
+            }
+            Value::String(s) => {
+                self.byte(5);
+                self.bytes(s.as_bytes());
+            }
+            v => unreachable!("{} constant", v.type_name()),
+        }
+    }
+
+    fn proto(&mut self, proto: &Proto) {
+        self.size(proto.nparameters);
+        self.byte(proto.vararg as u8);
+        self.size(proto.max_stack);
+        self.size(proto.line_defined);
+        self.size(proto.last_line_defined);
+
+        self.size(proto.code.len());
+        for &instruction in &proto.code {
+            self.out.extend_from_slice(&instruction.to_ne_bytes());
+        }
+        self.size(proto.constants.len());
+        for k in &proto.constants {
+            self.constant(k);
+        }
+        self.size(proto.upvalues.len());
+        for desc in &proto.upvalues {
+            match *desc {
+                UpvalueDesc::Local(slot) => {
+                    self.byte(1);
+                    self.size(slot);
+                }
+                UpvalueDesc::Upvalue(i) => {
+                    self.byte(0);
+                    self.size(i);
+                }
+            }
+        }
+        self.size(proto.protos.len());
+        for child in &proto.protos {
+            self.proto(child);
+        }
+
+        // Debug info. Functions loaded from stripped chunks have no
+        // lines to save.
+        let lines: Option<Vec<usize>> = match self.strip {
+            true => None,
+            false => proto
+                .spans
+                .iter()
+                .map(|&span| self.sm.line_number(span))
+                .collect(),
+        };
+        let lines = lines.unwrap_or_default();
+        self.size(lines.len());
+        for line in lines {
+            self.size(line);
+        }
+        let mut names: Vec<_> = match self.strip {
+            true => vec![],
+            false => proto.names.iter().collect(),
+        };
+        names.sort();
+        self.size(names.len());
+        for (pc, name) in names {
+            self.size(*pc);
+            self.bytes(name.as_bytes());
+        }
+        let locvars = if self.strip {
+            &[][..]
+        } else {
+            &proto.locvars[..]
+        };
+        self.size(locvars.len());
+        for local in locvars {
+            self.bytes(&local.name);
+            self.size(local.start_pc);
+            self.size(local.end_pc);
+        }
+        let upvalue_names = match self.strip {
+            true => &[][..],
+            false => &proto.upvalue_names[..],
+        };
+        self.size(upvalue_names.len());
+        for name in upvalue_names {
+            self.bytes(name);
+        }
+    }
+}
+
+// Saves the prototype of a main function or of any function in a
+// chunk, named for runtime errors as in the chunk it came from.
+pub(crate) fn dump(sm: &SourceMap, proto: &Proto, strip: bool) -> Vec<u8> {
+    let mut w = Writer {
+        sm,
+        strip,
+        out: vec![],
+    };
+    w.header();
+    let source = match proto.spans.first() {
+        Some(&span) if !strip && sm.line_number(span).is_some() => Some(sm.name(span.file)),
+        _ => None,
+    };
+    match source {
+        Some(name) => {
+            w.byte(1);
+            w.bytes(name.as_bytes());
+        }
+        None => w.byte(0),
+    }
+    w.proto(proto);
+    w.out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // The last line any instruction is on
+    max_line: usize,
+}
+
+// Why a chunk was rejected, for "bad binary format (...)"
+type Invalid = &'static str;
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, Invalid> {
+        let b = *self.bytes.get(self.pos).ok_or("truncated chunk")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], Invalid> {
+        let end = self.pos.checked_add(n).ok_or("truncated chunk")?;
+        let bytes = self.bytes.get(self.pos..end).ok_or("truncated chunk")?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn size(&mut self) -> Result<usize, Invalid> {
+        let mut n: usize = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= usize::BITS || (b as usize & 0x7f) > usize::MAX >> shift {
+                return Err("integer overflow");
+            }
+            n |= (b as usize & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&[u8], Invalid> {
+        let n = self.size()?;
+        self.take(n)
+    }
+
+    fn literal(&mut self, expected: &[u8], why: Invalid) -> Result<(), Invalid> {
+        match self.take(expected.len())? == expected {
+            true => Ok(()),
+            false => Err(why),
+        }
+    }
+
+    fn header(&mut self) -> Result<(), Invalid> {
+        self.literal(SIGNATURE, "not a binary chunk")?;
+        if self.byte()? != VERSION {
+            return Err("version mismatch");
+        }
+        if self.byte()? != FORMAT {
+            return Err("format mismatch");
+        }
+        self.literal(DATA, "corrupted chunk")?;
+        if self.byte()? as usize != std::mem::size_of::<i64>() {
+            return Err("lua_Integer size mismatch");
+        }
+        if self.byte()? as usize != std::mem::size_of::<f64>() {
+            return Err("lua_Number size mismatch");
+        }
+        self.literal(&CHECK_INTEGER.to_ne_bytes(), "integer format mismatch")?;
+        self.literal(&CHECK_NUMBER.to_ne_bytes(), "float format mismatch")
+    }
+
+    fn integer(&mut self) -> Result<[u8; 8], Invalid> {
+        Ok(self.take(8)?.try_into().unwrap())
+    }
+
+    fn constant(&mut self) -> Result<Value, Invalid> {
+        Ok(match self.byte()? {
+            0 => Value::Nil,
+            1 => Value::Boolean(false),
+            2 => Value::Boolean(true),
+            3 => Value::Integer(i64::from_ne_bytes(self.integer()?)),
+            4 => Value::Number(f64::from_ne_bytes(self.integer()?)),
+            5 => Value::String(LuaString::from(self.bytes()?)),
+            _ => return Err("corrupted chunk"),
+        })
+    }
+
+    fn proto(&mut self) -> Result<Proto, Invalid> {
+        let nparameters = self.size()?;
+        let mut proto = Proto::new(self.byte()? != 0);
+        proto.nparameters = nparameters;
+        proto.max_stack = self.size()?;
+        if proto.max_stack > u8::MAX as usize {
+            return Err("corrupted chunk");
+        }
+        proto.line_defined = self.size()?;
+        proto.last_line_defined = self.size()?;
+
+        let n = self.size()?;
+        for _ in 0..n {
+            let word = self.take(4)?.try_into().unwrap();
+            proto.code.push(u32::from_ne_bytes(word));
+        }
+        proto.caches = vec![InlineCache::default(); n];
+        let n = self.size()?;
+        for _ in 0..n {
+            let k = self.constant()?;
+            proto.constants.push(k);
+        }
+        let n = self.size()?;
+        for _ in 0..n {
+            let desc = match self.byte()? {
+                0 => UpvalueDesc::Upvalue(self.size()?),
+                1 => UpvalueDesc::Local(self.size()?),
+                _ => return Err("corrupted chunk"),
+            };
+            proto.upvalues.push(desc);
+        }
+        let n = self.size()?;
+        for _ in 0..n {
+            let child = self.proto()?;
+            proto.protos.push(Rc::new(child));
+        }
+
+        // Until the file standing in for the source is added, spans
+        // only hold the line, as its offset.
+        let n = self.size()?;
+        if n != 0 && n != proto.code.len() {
+            return Err("corrupted chunk");
+        }
+        for _ in 0..n {
+            let line = self.size()?;
+            if line == 0 || line > MAX_LINE {
+                return Err("corrupted chunk");
+            }
+            self.max_line = self.max_line.max(line);
+            proto
+                .spans
+                .push(Span::new(FileId::default(), line - 1, line - 1));
+        }
+        proto.spans.resize(proto.code.len(), Span::default());
+        let n = self.size()?;
+        for _ in 0..n {
+            let pc = self.size()?;
+            let name = String::from_utf8(self.bytes()?.to_vec()).map_err(|_| "corrupted chunk")?;
+            proto.names.insert(pc, name);
+        }
+        let n = self.size()?;
+        for _ in 0..n {
+            let name = LuaString::from(self.bytes()?);
+            let (start_pc, end_pc) = (self.size()?, self.size()?);
+            proto.locvars.push(LocVar {
+                name,
+                start_pc,
+                end_pc,
+            });
+        }
+        let n = self.size()?;
+        if n != 0 && n != proto.upvalues.len() {
+            return Err("corrupted chunk");
+        }
+        for _ in 0..n {
+            let name = LuaString::from(self.bytes()?);
+            proto.upvalue_names.push(name);
+        }
+
+        verify(&proto)?;
+        Ok(proto)
+    }
+}
+
+// Points the spans of a freshly loaded prototype at its file
+fn place(proto: &mut Proto, file: FileId) {
+    for span in &mut proto.spans {
+        span.file = file;
+    }
+    for child in &mut proto.protos {
+        place(Rc::get_mut(child).unwrap(), file);
+    }
+}
+
+// Checks what the interpreter indexes without checking: registers,
+// constants, upvalues, nested prototypes and jump targets, where
+// closures find their upvalues, and the debug info naming them. Like
+// in Lua, a chunk that passes can still misbehave, but this catches
+// corruption.
+fn verify(proto: &Proto) -> Result<(), Invalid> {
+    use Instruction::*;
+    let code = opcodes::unpack(&proto.code).ok_or("corrupted chunk")?;
+    let code_ok = |t: u32| (t as usize) < proto.code.len();
+    let k_ok = |k: u32| (k as usize) < proto.constants.len();
+    let upvalue_ok = |i: u8| (i as usize) < proto.upvalues.len();
+    // Registers a..a+n, which must all be in the frame
+    let regs_ok = |a: u8, n: usize| a as usize + n <= proto.max_stack;
+    let reg_ok = |a: u8| regs_ok(a, 1);
+    let count = |n: Option<u8>| n.map_or(0, |n| n as usize);
+    for &instruction in &code {
+        let ok = match instruction {
+            Move(a, b) | Unary(_, a, b) => reg_ok(a) && reg_ok(b),
+            GetUpvalue(a, i) | SetUpvalue(a, i) => reg_ok(a) && upvalue_ok(i),
+            LoadK(a, k) | GetGlobal(a, k) | SetGlobal(a, k) => reg_ok(a) && k_ok(k),
+            LoadNil(a, n) => regs_ok(a, n as usize),
+            GetTable(a, b, c) | SetTable(a, b, c) | Binary(_, a, b, c) => {
+                reg_ok(a) && reg_ok(b) && reg_ok(c)
+            }
+            GetField(a, b, k) | EqK(a, b, k, _) => reg_ok(a) && reg_ok(b) && k_ok(k),
+            SetField(a, k, c) => reg_ok(a) && reg_ok(c) && k_ok(k),
+            SelfIndex(a, b, k) => regs_ok(a, 2) && reg_ok(b) && k_ok(k),
+            ArithI(_, a, b, _) | CompareI(_, a, b, _) => reg_ok(a) && reg_ok(b),
+            NewTable(a) | ToBeClosed(a) => reg_ok(a),
+            Close(a) => regs_ok(a, 0),
+            SetList(a, n, _) => regs_ok(a, 1 + count(n)),
+            Closure(a, i) => reg_ok(a) && (i as usize) < proto.protos.len(),
+            Jump(t) => code_ok(t),
+            JumpIfFalse(a, t) | JumpIfTrue(a, t) => reg_ok(a) && code_ok(t),
+            Call(a, nargs, nresults) => regs_ok(a, 1 + count(nargs)) && regs_ok(a, count(nresults)),
+            TailCall(a, nargs) => regs_ok(a, 1 + count(nargs)),
+            Return(a, n) | Vararg(a, n) => regs_ok(a, count(n)),
+            ForPrep(a, t) | ForLoop(a, t) | ForInLoop(a, t) => regs_ok(a, 4) && code_ok(t),
+            ForInCall(a, n) => regs_ok(a, 3 + n as usize),
+            ExtraArg(_) => true,
+        };
+        if !ok {
+            return Err("corrupted chunk");
+        }
+    }
+
+    // Instructions taking all the values up to the top of the stack
+    // must directly follow the one leaving them there, starting no
+    // lower than they read from
+    let open_from = |instruction| match instruction {
+        Call(a, _, None) | TailCall(a, _) | Vararg(a, None) => Some(a),
+        _ => None,
+    };
+    let mut targets = vec![false; proto.code.len()];
+    for &instruction in &code {
+        if let Jump(t)
+        | JumpIfFalse(_, t)
+        | JumpIfTrue(_, t)
+        | ForPrep(_, t)
+        | ForLoop(_, t)
+        | ForInLoop(_, t) = instruction
+        {
+            targets[t as usize] = true;
+        }
+    }
+    for (pc, &instruction) in code.iter().enumerate() {
+        let reads_from = match instruction {
+            Call(a, None, _) | TailCall(a, None) | SetList(a, None, _) => Some(a as usize + 1),
+            Return(a, None) => Some(a as usize),
+            _ => None,
+        };
+        let Some(reads_from) = reads_from else {
+            continue;
+        };
+        let previous = pc.checked_sub(1).and_then(|pc| open_from(code[pc]));
+        match previous {
+            Some(a) if a as usize >= reads_from && !targets[pc] => {}
+            _ => return Err("corrupted chunk"),
+        }
+    }
+    for (pc, &instruction) in code.iter().enumerate() {
+        if open_from(instruction).is_some() {
+            match code.get(pc + 1) {
+                Some(
+                    Call(_, None, _) | TailCall(_, None) | SetList(_, None, _) | Return(_, None),
+                ) => {}
+                _ => return Err("corrupted chunk"),
+            }
+        }
+    }
+
+    for child in &proto.protos {
+        for desc in &child.upvalues {
+            let ok = match *desc {
+                UpvalueDesc::Local(slot) => slot < proto.max_stack,
+                UpvalueDesc::Upvalue(i) => i < proto.upvalues.len(),
+            };
+            if !ok {
+                return Err("corrupted chunk");
+            }
+        }
+    }
+    let pc_ok = |pc: usize| pc <= proto.code.len();
+    if proto.names.keys().any(|&pc| !pc_ok(pc + 1))
+        || proto
+            .locvars
+            .iter()
+            .any(|l| l.start_pc > l.end_pc || !pc_ok(l.end_pc))
+    {
+        return Err("corrupted chunk");
+    }
+    // Running off the end of the code is never right
+    match code.last() {
+        Some(Return(..)) | Some(Jump(_)) => Ok(()),
+        _ => Err("corrupted chunk"),
+    }
+}
+
+// Loads a chunk written by dump. chunkname names it in load errors;
+// runtime errors name the source the chunk was compiled from.
+pub(crate) fn undump(sm: &mut SourceMap, bytes: &[u8], chunkname: &str) -> Result<Proto, String> {
+    let mut r = Reader {
+        bytes,
+        pos: 0,
+        max_line: 0,
+    };
+    let read = |r: &mut Reader| -> Result<(Option<String>, Proto), Invalid> {
+        r.header()?;
+        let source = match r.byte()? {
+            0 => None,
+            1 => Some(String::from_utf8_lossy(r.bytes()?).into_owned()),
+            _ => return Err("corrupted chunk"),
+        };
+        let proto = r.proto()?;
+        if r.pos != r.bytes.len() {
+            return Err("corrupted chunk");
+        }
+        Ok((source, proto))
+    };
+    let (source, mut proto) =
+        read(&mut r).map_err(|why| format!("{}: bad binary format ({})", chunkname, why))?;
+
+    let file = match source {
+        Some(name) => sm.add_lines(name, Some(r.max_line)),
+        None => sm.add_lines("?", None),
+    };
+    place(&mut proto, file);
+    proto.hold_source(&sm.users(file));
+    sm.release(file);
+    Ok(proto)
+}
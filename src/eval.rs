@@ -1,333 +1,3380 @@
+use crate::dump;
+use crate::gc::Heap;
+use crate::lex::{str_to_number, FileId, Lexer, SourceMap, Span, Token};
+use crate::native::Args;
+use crate::opcodes::{self, MAX_AX, MAX_BX, MAX_C};
+use crate::optimize;
 use crate::parse::*;
-use std::collections::HashMap;
+use crate::string::LuaString;
+use crate::tvalue::TValue;
+use crate::value::{self, Error, Table, Value, TWO_POW_63};
+use std::any::TypeId;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-#[derive(Debug)]
-enum Instruction {
-    DupPlusFP(i32),
-    MoveMinusFP(usize, i32),
-    MovePlusFP(usize),
-    Store(i32),
-    Return,
-    JumpIfNotZero(String),
-    Jump(String),
-    Call(String, usize),
+// Like Lua's MAXREGS, so registers fit in a byte
+const MAX_REGISTERS: usize = 255;
+
+// How many Lua calls can be in progress before deeper ones fail with
+// "stack overflow", unless the host sets another limit
+const MAX_CALL_DEPTH: usize = 200_000;
+
+// Like Lua's LUAI_MAXCCALLS: native code calling back into Lua, as
+// metamethods and functions like require do, nests on the Rust stack.
+const MAX_NATIVE_CALLS: usize = 200;
+
+// Rust stack those nested calls may take below the outermost one. A
+// level takes about 16 KB in debug builds and 2 KB in release ones,
+// so this fails before the 2 MB of a spawned thread runs out.
+const MAX_NATIVE_STACK: usize = 1 << 20;
+
+// How many levels of a traceback are shown from the innermost and the
+// outermost, as in Lua's luaL_traceback
+const TRACEBACK_FIRST: usize = 10;
+const TRACEBACK_LAST: usize = 11;
+
+// Like Lua's LFIELDS_PER_FLUSH: positional values of a table
+// constructor are stored in batches this size.
+const FIELDS_PER_FLUSH: usize = 50;
+
+// Registers are slots relative to the base of the running function's
+// frame, where its parameters and then its locals live, with
+// temporaries above them. K is the function's constants and U the
+// closure's upvalues. Counts of None run up to the top of the stack,
+// as left by the call or vararg just before. Code is stored packed
+// into 32-bit words (see opcodes.rs), which limits constants in
+// fields of 8 bits to the first 256. Those of 17 bits saturate, and
+// the rest of the index follows in an ExtraArg.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Instruction {
+    // R[a] = R[b]
+    Move(u8, u8),
+    // R[a] = K[k]
+    LoadK(u8, u32),
+    // R[a..a+n] = nil
+    LoadNil(u8, u8),
+    // R[a] = U[i]
+    GetUpvalue(u8, u8),
+    // U[i] = R[a]
+    SetUpvalue(u8, u8),
+    // R[a] = globals[K[k]]
+    GetGlobal(u8, u32),
+    // globals[K[k]] = R[a]
+    SetGlobal(u8, u32),
+    // R[a] = R[b][R[c]]
+    GetTable(u8, u8, u8),
+    // R[a] = R[b][K[k]]
+    GetField(u8, u8, u32),
+    // R[a][R[b]] = R[c]
+    SetTable(u8, u8, u8),
+    // R[a][K[k]] = R[c]
+    SetField(u8, u32, u8),
+    NewTable(u8),
+    // R[a][c+i] = R[a+i] for i in 1..=n, where c counts the
+    // positional values stored by earlier batches, and saturates
+    SetList(u8, Option<u8>, u32),
+    // R[a+1] = R[b]; R[a] = R[b][K[k]], for method calls
+    SelfIndex(u8, u8, u32),
+    // R[a] = R[b] op R[c]
+    Binary(BinaryOp, u8, u8, u8),
+    // R[a] = R[b] op i, for addition and subtraction
+    ArithI(BinaryOp, u8, u8, i8),
+    // R[a] = R[b] op i, for the order comparisons
+    CompareI(BinaryOp, u8, u8, i8),
+    // R[a] = (R[b] == K[k]) == equal, as constants have no __eq
+    EqK(u8, u8, u32, bool),
+    // R[a] = op R[b]
+    Unary(UnaryOp, u8, u8),
+    // Jump targets are label numbers until link replaces them with
+    // instruction offsets.
+    Jump(u32),
+    JumpIfFalse(u8, u32),
+    JumpIfTrue(u8, u32),
+    // Calls R[a] with the n arguments above it. The results replace
+    // the function, adjusted to a count if given.
+    Call(u8, Option<u8>, Option<u8>),
+    // Like Call with all results, but a Lua function called replaces
+    // the running one, whose results become its own
+    TailCall(u8, Option<u8>),
+    // Returns R[a..a+n]
+    Return(u8, Option<u8>),
+    // R[a..a+n] = ...
+    Vararg(u8, Option<u8>),
+    // R[a] = a closure of function i
+    Closure(u8, u32),
+    // Closes upvalues and to-be-closed variables from R[a] up, as
+    // their scope ends.
+    Close(u8),
+    // Marks the local in R[a] to have its __close metamethod called
+    // when it goes out of scope
+    ToBeClosed(u8),
+    // Numeric for loops keep the index, limit and step in R[a..a+3]
+    // and the loop variable in R[a+3].
+    ForPrep(u8, u32),
+    ForLoop(u8, u32),
+    // Generic for loops keep the iterator, state and control in
+    // R[a..a+3]. ForInCall puts n results of calling the iterator
+    // from R[a+3], and ForInLoop jumps back to the body unless the
+    // first is nil.
+    ForInCall(u8, u8),
+    ForInLoop(u8, u32),
+    // The rest of a saturated index or count of the instruction before
+    ExtraArg(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BinaryOp {
     Add,
     Subtract,
+    Multiply,
+    Divide,
+    FloorDivide,
+    Modulo,
+    Power,
+    Concat,
+    Equal,
+    NotEqual,
     LessThan,
+    LessEqual,
+    GreaterThan,
+    GreaterEqual,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
 }
 
-#[derive(Debug)]
-struct Symbol {
-    location: i32,
-    narguments: usize,
-    nlocals: usize,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UnaryOp {
+    Negate,
+    Not,
+    Length,
+    BitNot,
+}
+
+// Where a closure finds an upvalue when it is created: a local of the
+// enclosing function, or one of the enclosing function's upvalues.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum UpvalueDesc {
+    Local(usize),
+    Upvalue(usize),
 }
 
+// A compiled function, which closures are made from: its code and
+// constants, and the prototypes of the functions defined in it. A
+// chunk compiles to the prototype of a vararg main function.
 #[derive(Debug)]
-pub struct Program {
-    syms: HashMap<String, Symbol>,
-    instructions: Vec<Instruction>,
+pub struct Proto {
+    pub(crate) nparameters: usize,
+    pub(crate) vararg: bool,
+    // Registers the function uses, which its frame is sized to
+    pub(crate) max_stack: usize,
+    pub(crate) upvalues: Vec<UpvalueDesc>,
+    pub(crate) constants: Vec<Value>,
+    pub(crate) protos: Vec<Rc<Proto>>,
+    // Packed instructions
+    pub(crate) code: Vec<u32>,
+    // Source span of the node each instruction was compiled from,
+    // parallel to code.
+    pub(crate) spans: Vec<Span>,
+    // How the value called or indexed by an instruction was named,
+    // like "global 'f'", for error messages.
+    pub(crate) names: HashMap<usize, String>,
+    // Where the function's source starts and ends, 0 for main chunks
+    pub(crate) line_defined: usize,
+    pub(crate) last_line_defined: usize,
+    // Names of locals and the code they are in scope over, and names
+    // of upvalues, for listings.
+    pub(crate) locvars: Vec<LocVar>,
+    pub(crate) upvalue_names: Vec<LuaString>,
+    // Keeps the file spans refer to in the source map
+    source: Option<Rc<()>>,
+    // What each instruction's table lookups last found, parallel to
+    // code once it is final.
+    pub(crate) caches: Vec<InlineCache>,
+    // Instructions while compiling, which finish packs into code
+    pub(crate) ops: Vec<Instruction>,
+    // Offsets of the labels jumps refer to, while compiling
+    labels: Vec<usize>,
+    // Where each constant is in constants, while compiling
+    constant_index: HashMap<ConstantKey, u32>,
 }
 
-fn compile_binary_operation(
-    pgrm: &mut Program,
-    raw: &[char],
-    locals: &mut HashMap<String, i32>,
-    bop: BinaryOperation,
-) {
-    compile_expression(pgrm, raw, locals, *bop.left);
-    compile_expression(pgrm, raw, locals, *bop.right);
-    match bop.operator.value.as_str() {
-        "+" => {
-            pgrm.instructions.push(Instruction::Add);
+// How deep into __index chains of tables lookups are cached, which
+// covers methods of a class and of the class it inherits from.
+const CACHED_LEVELS: usize = 3;
+
+// Slots where a field lookup found things last time: the key in the
+// table indexed and each table its __index chain leads to, and
+// __index in each metatable on the way. Tables check a slot still
+// holds the key before using it (see Table::get_hinted).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InlineCache {
+    keys: [Cell<u32>; CACHED_LEVELS],
+    indexes: [Cell<u32>; CACHED_LEVELS],
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LocVar {
+    pub(crate) name: LuaString,
+    // Active from start_pc up to but not including end_pc
+    pub(crate) start_pc: usize,
+    pub(crate) end_pc: usize,
+}
+
+// Constants are only shared with values of the same type, so 1 and
+// 1.0 (or 0.0 and -0.0) stay apart.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Boolean(bool),
+    Integer(i64),
+    Number(u64),
+    String(LuaString),
+}
+
+impl ConstantKey {
+    fn of(v: &Value) -> Option<ConstantKey> {
+        match v {
+            Value::Boolean(b) => Some(ConstantKey::Boolean(*b)),
+            Value::Integer(i) => Some(ConstantKey::Integer(*i)),
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::String(s) => Some(ConstantKey::String(s.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl Proto {
+    pub(crate) fn new(vararg: bool) -> Proto {
+        Proto {
+            nparameters: 0,
+            vararg,
+            max_stack: 0,
+            upvalues: vec![],
+            constants: vec![],
+            protos: vec![],
+            code: vec![],
+            spans: vec![],
+            names: HashMap::new(),
+            line_defined: 0,
+            last_line_defined: 0,
+            locvars: vec![],
+            upvalue_names: vec![],
+            source: None,
+            caches: vec![],
+            ops: vec![],
+            labels: vec![],
+            constant_index: HashMap::new(),
+        }
+    }
+
+    // Holds the file the function and those nested in it came from,
+    // which the source map keeps as long as any of them is alive.
+    pub(crate) fn hold_source(&mut self, users: &Rc<()>) {
+        self.source = Some(users.clone());
+        for child in &mut self.protos {
+            Rc::get_mut(child).unwrap().hold_source(users);
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction, span: Span) {
+        use Instruction::*;
+        let (instruction, extra) = match instruction {
+            LoadK(a, k) if k >= MAX_BX => (LoadK(a, MAX_BX), Some(k)),
+            GetGlobal(a, k) if k >= MAX_BX => (GetGlobal(a, MAX_BX), Some(k)),
+            SetGlobal(a, k) if k >= MAX_BX => (SetGlobal(a, MAX_BX), Some(k)),
+            Closure(a, i) if i >= MAX_BX => (Closure(a, MAX_BX), Some(i)),
+            SetList(a, n, c) if c >= MAX_C => (SetList(a, n, MAX_C), Some(c)),
+            instruction => (instruction, None),
+        };
+        self.ops.push(instruction);
+        self.spans.push(span);
+        if let Some(x) = extra {
+            self.ops.push(ExtraArg(x));
+            self.spans.push(span);
+        }
+    }
+
+    // Names the next instruction emitted in error messages
+    fn name(&mut self, name: Option<String>) {
+        if let Some(name) = name {
+            self.names.insert(self.ops.len(), name);
+        }
+    }
+
+    fn constant(&mut self, v: Value) -> u32 {
+        let key = ConstantKey::of(&v);
+        if let Some(&k) = key.as_ref().and_then(|key| self.constant_index.get(key)) {
+            return k;
+        }
+        let k = self.constants.len() as u32;
+        self.constants.push(v);
+        if let Some(key) = key {
+            self.constant_index.insert(key, k);
+        }
+        k
+    }
+
+    fn label(&mut self) -> u32 {
+        self.labels.push(usize::MAX);
+        (self.labels.len() - 1) as u32
+    }
+
+    // Points a label at the next instruction emitted
+    fn mark(&mut self, label: u32) {
+        self.labels[label as usize] = self.ops.len();
+    }
+
+    // Takes what the function's scope learned while compiling it,
+    // and packs its code.
+    fn finish(&mut self, sm: &SourceMap, fs: FunctionScope) -> Result<(), String> {
+        for (name, desc) in fs.upvalues {
+            self.upvalue_names.push(name);
+            self.upvalues.push(desc);
+        }
+        self.locvars = fs.locvars;
+        self.max_stack = fs.max_depth;
+        self.link();
+        optimize::peephole(self);
+        if let Some(pc) = (0..self.ops.len()).find(|&pc| !opcodes::jump_fits(self.ops[pc], pc)) {
+            return Err(self.spans[pc].debug(sm, "Control structure too long:"));
         }
-        "-" => {
-            pgrm.instructions.push(Instruction::Subtract);
+        if self.constants.len() > MAX_AX as usize {
+            return Err(self.spans[0].debug(sm, "Function or expression needs too many constants:"));
         }
+        let ops = std::mem::take(&mut self.ops);
+        self.code = ops
+            .into_iter()
+            .enumerate()
+            .map(|(pc, i)| opcodes::encode(i, pc))
+            .collect();
+        self.caches = vec![InlineCache::default(); self.code.len()];
+        self.constant_index = HashMap::new();
+        Ok(())
+    }
 
-        "<" => {
-            pgrm.instructions.push(Instruction::LessThan);
+    // Rewrites the labels of jumps to the offsets they point at, so
+    // running a jump doesn't look anything up.
+    fn link(&mut self) {
+        for instruction in &mut self.ops {
+            match instruction {
+                Instruction::Jump(target)
+                | Instruction::JumpIfFalse(_, target)
+                | Instruction::JumpIfTrue(_, target)
+                | Instruction::ForPrep(_, target)
+                | Instruction::ForLoop(_, target)
+                | Instruction::ForInLoop(_, target) => {
+                    *target = self.labels[*target as usize] as u32
+                }
+                _ => {}
+            }
         }
-        _ => panic!(
-            "{}",
-            bop.operator
-                .loc
-                .debug(raw, "Unable to compile binary operation:")
-        ),
+        self.labels = vec![];
     }
 }
 
-fn compile_function_call(
-    pgrm: &mut Program,
-    raw: &[char],
-    locals: &mut HashMap<String, i32>,
-    fc: FunctionCall,
-) {
-    let len = fc.arguments.len();
-    for arg in fc.arguments {
-        compile_expression(pgrm, raw, locals, arg);
+struct Label {
+    name: LuaString,
+    key: u32,
+    nactive: usize,
+}
+
+// A goto waiting for its label, which must come later in the
+// enclosing blocks of the same function.
+struct Goto {
+    name: Token,
+    // The Close and Jump instructions to patch
+    index: usize,
+    nactive: usize,
+    block: usize,
+}
+
+struct Block {
+    nactive: usize,
+    labels: Vec<Label>,
+    // Set for loop bodies
+    break_label: Option<u32>,
+}
+
+struct LocalVar {
+    name: LuaString,
+    // <const> and <close> locals can't be assigned to
+    readonly: bool,
+    // Captured by a closure, or <close>: the end of its scope must
+    // close it.
+    needs_close: bool,
+    // <close>, which rules out tail calls in its scope
+    to_be_closed: bool,
+    // Index in locvars
+    debug: usize,
+}
+
+struct FunctionScope {
+    // Active locals by register, innermost last
+    locals: Vec<LocalVar>,
+    // Every local the function declares, in order
+    locvars: Vec<LocVar>,
+    upvalues: Vec<(LuaString, UpvalueDesc)>,
+    // The first free register, above the locals and temporaries
+    depth: usize,
+    max_depth: usize,
+    blocks: Vec<Block>,
+    gotos: Vec<Goto>,
+    vararg: bool,
+}
+
+impl FunctionScope {
+    fn new(vararg: bool) -> FunctionScope {
+        FunctionScope {
+            locals: vec![],
+            locvars: vec![],
+            upvalues: vec![],
+            depth: 0,
+            max_depth: 0,
+            blocks: vec![],
+            gotos: vec![],
+            vararg,
+        }
     }
+}
 
-    pgrm.instructions
-        .push(Instruction::Call(fc.name.value, len));
+// Compile-time view of the functions being compiled, innermost last.
+struct Locals {
+    functions: Vec<FunctionScope>,
 }
 
-fn compile_literal(
-    pgrm: &mut Program,
-    _: &[char],
-    locals: &mut HashMap<String, i32>,
-    lit: Literal,
-) {
-    match lit {
-        Literal::Number(i) => {
-            let n = i.value.parse::<i32>().unwrap();
-            pgrm.instructions.push(Instruction::Store(n));
+enum Variable {
+    Local(usize),
+    Upvalue(usize),
+    Global(LuaString),
+}
+
+impl Locals {
+    fn current(&mut self) -> &mut FunctionScope {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn depth(&self) -> usize {
+        self.functions.last().unwrap().depth
+    }
+
+    fn nactive(&self) -> usize {
+        self.functions.last().unwrap().locals.len()
+    }
+
+    // Takes the next n free registers, returning the first
+    fn reserve(&mut self, n: usize) -> usize {
+        let fs = self.current();
+        let first = fs.depth;
+        fs.depth += n;
+        fs.max_depth = fs.max_depth.max(fs.depth);
+        first
+    }
+
+    // Frees the registers from depth up
+    fn free_to(&mut self, depth: usize) {
+        self.current().depth = depth;
+    }
+
+    // Names the next register, which must have been reserved. The
+    // local is in scope from the instruction at pc.
+    fn declare(&mut self, name: LuaString, pc: usize) -> usize {
+        self.declare_local(name, false, pc)
+    }
+
+    fn declare_local(&mut self, name: LuaString, readonly: bool, pc: usize) -> usize {
+        let fs = self.current();
+        fs.locvars.push(LocVar {
+            name: name.clone(),
+            start_pc: pc,
+            end_pc: pc,
+        });
+        fs.locals.push(LocalVar {
+            name,
+            readonly,
+            needs_close: false,
+            to_be_closed: false,
+            debug: fs.locvars.len() - 1,
+        });
+        fs.locals.len() - 1
+    }
+
+    // Ends the scope of the locals from nactive up at pc
+    fn remove_locals(&mut self, nactive: usize, pc: usize) {
+        let fs = self.current();
+        for local in &fs.locals[nactive.min(fs.locals.len())..] {
+            fs.locvars[local.debug].end_pc = pc;
+        }
+        fs.locals.truncate(nactive);
+    }
+
+    fn resolve(&mut self, name: &LuaString) -> Variable {
+        let fs = self.functions.last().unwrap();
+        if let Some(slot) = fs.locals.iter().rposition(|l| l.name == *name) {
+            return Variable::Local(slot);
         }
-        Literal::Identifier(ident) => {
-            pgrm.instructions
-                .push(Instruction::DupPlusFP(locals[&ident.value]));
+
+        match resolve_upvalue(&mut self.functions, name) {
+            Some(i) => Variable::Upvalue(i),
+            None => Variable::Global(name.clone()),
         }
     }
+
+    // Whether the local or upvalue a name refers to is read-only
+    fn readonly(&self, name: &LuaString) -> bool {
+        self.functions
+            .iter()
+            .rev()
+            .find_map(|fs| fs.locals.iter().rev().find(|l| l.name == *name))
+            .is_some_and(|l| l.readonly)
+    }
 }
 
-fn compile_expression(
-    pgrm: &mut Program,
-    raw: &[char],
-    locals: &mut HashMap<String, i32>,
-    exp: Expression,
-) {
-    match exp {
-        Expression::BinaryOperation(bop) => {
-            compile_binary_operation(pgrm, raw, locals, bop);
+fn resolve_upvalue(functions: &mut [FunctionScope], name: &LuaString) -> Option<usize> {
+    let (current, enclosing) = functions.split_last_mut()?;
+    if let Some(i) = current.upvalues.iter().position(|(n, _)| n == name) {
+        return Some(i);
+    }
+
+    let parent = enclosing.last_mut()?;
+    let desc = match parent.locals.iter().rposition(|l| l.name == *name) {
+        Some(slot) => {
+            parent.locals[slot].needs_close = true;
+            UpvalueDesc::Local(slot)
         }
-        Expression::FunctionCall(fc) => {
-            compile_function_call(pgrm, raw, locals, fc);
+        None => UpvalueDesc::Upvalue(resolve_upvalue(enclosing, name)?),
+    };
+    current.upvalues.push((name.clone(), desc));
+    Some(current.upvalues.len() - 1)
+}
+
+// Calls and `...`, which can produce any number of values
+fn is_multiple(exp: &Expression) -> bool {
+    matches!(
+        exp,
+        Expression::FunctionCall(_) | Expression::Literal(Literal::Vararg(_))
+    )
+}
+
+pub(crate) fn binary_op(operator: &str) -> BinaryOp {
+    match operator {
+        "+" => BinaryOp::Add,
+        "-" => BinaryOp::Subtract,
+        "*" => BinaryOp::Multiply,
+        "/" => BinaryOp::Divide,
+        "//" => BinaryOp::FloorDivide,
+        "%" => BinaryOp::Modulo,
+        "^" => BinaryOp::Power,
+        ".." => BinaryOp::Concat,
+        "==" => BinaryOp::Equal,
+        "~=" => BinaryOp::NotEqual,
+        "<" => BinaryOp::LessThan,
+        "<=" => BinaryOp::LessEqual,
+        ">" => BinaryOp::GreaterThan,
+        ">=" => BinaryOp::GreaterEqual,
+        "&" => BinaryOp::BitAnd,
+        "|" => BinaryOp::BitOr,
+        "~" => BinaryOp::BitXor,
+        "<<" => BinaryOp::ShiftLeft,
+        ">>" => BinaryOp::ShiftRight,
+        _ => unreachable!("binary operator {}", operator),
+    }
+}
+
+// The left operand of an operation in a chain like `a + b + c`:
+// either an expression still to compile or the register holding the
+// result of the operation before it.
+enum Operand {
+    Expression(Expression),
+    Register(usize),
+}
+
+impl Operand {
+    fn constant(&self) -> Option<Value> {
+        match self {
+            Operand::Expression(exp) => optimize::constant(exp),
+            Operand::Register(_) => None,
         }
-        Expression::Literal(lit) => {
-            compile_literal(pgrm, raw, locals, lit);
+    }
+
+    fn compile(
+        self,
+        proto: &mut Proto,
+        sm: &SourceMap,
+        locals: &mut Locals,
+    ) -> Result<usize, String> {
+        match self {
+            Operand::Expression(exp) => compile_operand(proto, sm, locals, exp),
+            Operand::Register(reg) => Ok(reg),
         }
     }
 }
 
-fn compile_declaration(
-    pgrm: &mut Program,
-    raw: &[char],
-    _: &mut HashMap<String, i32>,
-    fd: FunctionDeclaration,
-) {
-    // Jump to end of function to guard top-level
-    let done_label = format!("function_done_{}", pgrm.instructions.len());
-    pgrm.instructions
-        .push(Instruction::Jump(done_label.clone()));
-
-    let mut new_locals = HashMap::<String, i32>::new();
-
-    let function_index = pgrm.instructions.len() as i32;
-    let narguments = fd.parameters.len();
-    for (i, param) in fd.parameters.iter().enumerate() {
-        pgrm.instructions.push(Instruction::MoveMinusFP(
-            i,
-            narguments as i32 - (i as i32 + 1),
-        ));
-        new_locals.insert(param.value.clone(), i as i32);
+fn compile_binary_operation(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    bop: BinaryOperation,
+    dest: usize,
+) -> Result<(), String> {
+    // Chains nest to the left as deeply as they are long, so they are
+    // compiled in a loop from the innermost operation out. Results
+    // before the last are kept in one register, which is dest unless
+    // that is a local or holds something else.
+    let mut chain = vec![];
+    let mut exp = Expression::BinaryOperation(bop);
+    while let Expression::BinaryOperation(bop) = exp {
+        chain.push((bop.operator, *bop.right, bop.span));
+        exp = *bop.left;
+    }
+    let at_top = dest >= locals.nactive() && dest + 1 == locals.depth();
+    let partial = if at_top || chain.len() == 1 {
+        dest
+    } else {
+        locals.reserve(1)
+    };
+    let top = locals.depth();
+    let mut left = Operand::Expression(exp);
+    while let Some((operator, right, span)) = chain.pop() {
+        let dest = if chain.is_empty() { dest } else { partial };
+        compile_binary_step(proto, sm, locals, left, (operator, right, span), dest)?;
+        locals.free_to(top);
+        left = Operand::Register(partial);
     }
+    Ok(())
+}
 
-    for stmt in fd.body {
-        compile_statement(pgrm, raw, &mut new_locals, stmt);
+fn compile_binary_step(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    left: Operand,
+    (operator, right, span): (Token, Expression, Span),
+    dest: usize,
+) -> Result<(), String> {
+    // Short-circuit, leaving the deciding operand as the result
+    if let "and" | "or" = operator.text() {
+        let done_label = proto.label();
+        match left {
+            Operand::Expression(exp) => compile_into(proto, sm, locals, exp, dest)?,
+            Operand::Register(reg) if reg != dest => {
+                proto.emit(Instruction::Move(dest as u8, reg as u8), span)
+            }
+            Operand::Register(_) => {}
+        }
+        let jump = if operator.text() == "and" {
+            Instruction::JumpIfFalse(dest as u8, done_label)
+        } else {
+            Instruction::JumpIfTrue(dest as u8, done_label)
+        };
+        proto.emit(jump, span);
+        compile_into(proto, sm, locals, right, dest)?;
+        proto.mark(done_label);
+        return Ok(());
     }
 
-    // Overwrite function lookup with total number of locals
-    pgrm.syms.insert(
-        fd.name.value,
-        Symbol {
-            location: function_index as i32,
-            narguments,
-            nlocals: new_locals.keys().len(),
-        },
-    );
-
-    pgrm.syms.insert(
-        done_label,
-        Symbol {
-            location: pgrm.instructions.len() as i32,
-            narguments: 0,
-            nlocals: 0,
+    let op = binary_op(operator.text());
+    let immediate = |k: Option<Value>| match k {
+        Some(Value::Integer(i)) => i8::try_from(i).ok(),
+        _ => None,
+    };
+
+    // A constant compared for equality, and whether it is the right
+    // operand, if its index fits in EqK
+    let equal_k = match op {
+        BinaryOp::Equal | BinaryOp::NotEqual => {
+            match (optimize::constant(&right), left.constant()) {
+                (Some(k), _) => Some((k, true)),
+                (None, Some(k)) => Some((k, false)),
+                (None, None) => None,
+            }
+        }
+        _ => None,
+    };
+    let equal_k = equal_k
+        .map(|(k, right)| (proto.constant(k), right))
+        .filter(|&(k, _)| k <= MAX_C);
+
+    // Small integers added, subtracted or compared, and constants
+    // compared for equality, go in the instruction rather than a
+    // register. Only x + k is done, as k + x must call __add as it is
+    // written.
+    let dest = dest as u8;
+    let instruction = match (
+        op,
+        immediate(left.constant()),
+        immediate(optimize::constant(&right)),
+    ) {
+        (BinaryOp::Add | BinaryOp::Subtract, _, Some(i)) => {
+            let b = left.compile(proto, sm, locals)?;
+            Instruction::ArithI(op, dest, b as u8, i)
+        }
+        (
+            BinaryOp::LessThan
+            | BinaryOp::LessEqual
+            | BinaryOp::GreaterThan
+            | BinaryOp::GreaterEqual,
+            left_i,
+            right_i,
+        ) if left_i.is_some() || right_i.is_some() => match right_i {
+            Some(i) => {
+                let b = left.compile(proto, sm, locals)?;
+                Instruction::CompareI(op, dest, b as u8, i)
+            }
+            None => {
+                // k < x is x > k
+                let flipped = match op {
+                    BinaryOp::LessThan => BinaryOp::GreaterThan,
+                    BinaryOp::LessEqual => BinaryOp::GreaterEqual,
+                    BinaryOp::GreaterThan => BinaryOp::LessThan,
+                    _ => BinaryOp::LessEqual,
+                };
+                let b = compile_operand(proto, sm, locals, right)?;
+                Instruction::CompareI(flipped, dest, b as u8, left_i.unwrap())
+            }
         },
-    );
+        (BinaryOp::Equal | BinaryOp::NotEqual, ..) if equal_k.is_some() => {
+            let (k, b) = match equal_k.unwrap() {
+                (k, true) => (k, left.compile(proto, sm, locals)?),
+                (k, false) => (k, compile_operand(proto, sm, locals, right)?),
+            };
+            Instruction::EqK(dest, b as u8, k, op == BinaryOp::Equal)
+        }
+        _ => {
+            let left = left.compile(proto, sm, locals)?;
+            let right = compile_operand(proto, sm, locals, right)?;
+            Instruction::Binary(op, dest, left as u8, right as u8)
+        }
+    };
+    proto.emit(instruction, span);
+    Ok(())
 }
 
-fn compile_return(
-    pgrm: &mut Program,
-    raw: &[char],
-    locals: &mut HashMap<String, i32>,
-    ret: Return,
-) {
-    compile_expression(pgrm, raw, locals, ret.expression);
-    pgrm.instructions.push(Instruction::Return);
-}
-
-fn compile_if(pgrm: &mut Program, raw: &[char], locals: &mut HashMap<String, i32>, if_: If) {
-    compile_expression(pgrm, raw, locals, if_.test);
-    let done_label = format!("if_else_{}", pgrm.instructions.len());
-    pgrm.instructions
-        .push(Instruction::JumpIfNotZero(done_label.clone()));
-    for stmt in if_.body {
-        compile_statement(pgrm, raw, locals, stmt);
-    }
-    pgrm.syms.insert(
-        done_label,
-        Symbol {
-            location: pgrm.instructions.len() as i32 - 1,
-            nlocals: 0,
-            narguments: 0,
-        },
-    );
+pub(crate) fn unary_op(operator: &str) -> UnaryOp {
+    match operator {
+        "-" => UnaryOp::Negate,
+        "not" => UnaryOp::Not,
+        "#" => UnaryOp::Length,
+        "~" => UnaryOp::BitNot,
+        _ => unreachable!("unary operator {}", operator),
+    }
 }
 
-fn compile_local(
-    pgrm: &mut Program,
-    raw: &[char],
-    locals: &mut HashMap<String, i32>,
-    local: Local,
-) {
-    let index = locals.keys().len();
-    locals.insert(local.name.value, index as i32);
-    compile_expression(pgrm, raw, locals, local.expression);
-    pgrm.instructions.push(Instruction::MovePlusFP(index));
+fn compile_unary_operation(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    uop: UnaryOperation,
+    dest: usize,
+) -> Result<(), String> {
+    let op = unary_op(uop.operator.text());
+
+    let operand = compile_operand(proto, sm, locals, *uop.operand)?;
+    let instruction = Instruction::Unary(op, dest as u8, operand as u8);
+    proto.emit(instruction, uop.span);
+    Ok(())
 }
 
-fn compile_statement(
-    pgrm: &mut Program,
-    raw: &[char],
-    locals: &mut HashMap<String, i32>,
-    stmt: Statement,
-) {
-    match stmt {
-        Statement::FunctionDeclaration(fd) => compile_declaration(pgrm, raw, locals, fd),
-        Statement::Return(r) => compile_return(pgrm, raw, locals, r),
-        Statement::If(if_) => compile_if(pgrm, raw, locals, if_),
-        Statement::Local(loc) => compile_local(pgrm, raw, locals, loc),
-        Statement::Expression(e) => compile_expression(pgrm, raw, locals, e),
+// How an expression is named in error messages, as lua does for
+// variables and constant fields.
+fn describe(locals: &mut Locals, exp: &Expression) -> Option<String> {
+    match exp {
+        Expression::Literal(Literal::Identifier(t)) => Some(match locals.resolve(t.value()) {
+            Variable::Local(_) => format!("local '{}'", t.value()),
+            Variable::Upvalue(_) => format!("upvalue '{}'", t.value()),
+            Variable::Global(_) => format!("global '{}'", t.value()),
+        }),
+        Expression::Index(Index { key, .. }) => match &**key {
+            Expression::Literal(Literal::String(t)) => Some(format!("field '{}'", t.value())),
+            _ => None,
+        },
+        _ => None,
     }
 }
 
-pub fn compile(raw: &[char], ast: Ast) -> Program {
-    let mut locals: HashMap<String, i32> = HashMap::new();
-    let mut pgrm = Program {
-        syms: HashMap::new(),
-        instructions: Vec::new(),
-    };
-    for stmt in ast {
-        compile_statement(&mut pgrm, raw, &mut locals, stmt);
+// The constant for a key like the `k` of `t.k`, if it is a string
+// whose index fits in the field instructions
+fn string_key(proto: &mut Proto, key: &Expression) -> Option<u32> {
+    match key {
+        Expression::Literal(Literal::String(t)) => {
+            let k = proto.constant(Value::String(t.value().clone()));
+            (k <= MAX_C).then_some(k)
+        }
+        _ => None,
+    }
+}
+
+// A key for a field instruction if string_key finds one, or else the
+// register the key is put in
+fn compile_key(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    key: Expression,
+) -> Result<Result<u32, usize>, String> {
+    match string_key(proto, &key) {
+        Some(k) => Ok(Ok(k)),
+        None => Ok(Err(compile_operand(proto, sm, locals, key)?)),
     }
+}
 
-    pgrm
+fn compile_index(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    idx: Index,
+    dest: usize,
+) -> Result<(), String> {
+    let name = describe(locals, &idx.object);
+    let object = compile_operand(proto, sm, locals, *idx.object)?;
+    let instruction = match compile_key(proto, sm, locals, *idx.key)? {
+        Ok(k) => Instruction::GetField(dest as u8, object as u8, k),
+        Err(key) => Instruction::GetTable(dest as u8, object as u8, key as u8),
+    };
+    proto.name(name);
+    proto.emit(instruction, idx.span);
+    Ok(())
 }
 
-pub fn eval(pgrm: Program) {
-    let mut pc: i32 = 0;
-    let mut fp: i32 = 0;
-    let mut data: Vec<i32> = vec![];
+// Fills a new table in dest, which must be the top register.
+fn compile_table(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    table: crate::parse::Table,
+    dest: usize,
+) -> Result<(), String> {
+    proto.emit(Instruction::NewTable(dest as u8), table.span);
 
-    while pc < pgrm.instructions.len() as i32 {
-        match &pgrm.instructions[pc as usize] {
-            Instruction::DupPlusFP(i) => {
-                data.push(data[(fp + i) as usize]);
-                pc += 1;
+    // Positional values wait in the registers above the table until
+    // a batch is full. The last field expands if it is positional.
+    let nfields = table.fields.len();
+    let mut pending = 0;
+    let mut stored = 0;
+    for (i, field) in table.fields.into_iter().enumerate() {
+        match field {
+            Field::Positional(exp) if i == nfields - 1 => {
+                let span = exp.span();
+                let n = compile_multiple(proto, sm, locals, exp, None)?;
+                let n = n.map(|n| (pending + n) as u8);
+                proto.emit(Instruction::SetList(dest as u8, n, stored), span);
+                pending = 0;
             }
-            Instruction::MoveMinusFP(local_offset, fp_offset) => {
-                data[fp as usize + local_offset] = data[(fp - (fp_offset + 4)) as usize];
-                pc += 1;
-            }
-            Instruction::MovePlusFP(i) => {
-                let val = data.pop().unwrap();
-                let index = fp as usize + *i;
-                // Accounts for top-level locals
-                while index >= data.len() {
-                    data.push(0);
+            Field::Positional(exp) => {
+                let span = exp.span();
+                compile_expression(proto, sm, locals, exp)?;
+                pending += 1;
+                if pending == FIELDS_PER_FLUSH {
+                    let n = Some(pending as u8);
+                    proto.emit(Instruction::SetList(dest as u8, n, stored), span);
+                    locals.free_to(dest + 1);
+                    stored += pending as u32;
+                    pending = 0;
                 }
-                data[index] = val;
-                pc += 1;
             }
-            Instruction::JumpIfNotZero(label) => {
-                let top = data.pop().unwrap();
-                if top == 0 {
-                    pc = pgrm.syms[label].location;
-                }
-                pc += 1;
+            Field::Named(name, exp) => {
+                let span = name.span;
+                let top = locals.depth();
+                let key = Expression::Literal(Literal::String(name));
+                let key = compile_key(proto, sm, locals, key)?;
+                let value = compile_operand(proto, sm, locals, exp)?;
+                let instruction = match key {
+                    Ok(k) => Instruction::SetField(dest as u8, k, value as u8),
+                    Err(key) => Instruction::SetTable(dest as u8, key as u8, value as u8),
+                };
+                proto.emit(instruction, span);
+                locals.free_to(top);
             }
-            Instruction::Jump(label) => {
-                pc = pgrm.syms[label].location;
+            Field::Keyed(key, exp) => {
+                let span = key.span();
+                let top = locals.depth();
+                let key = compile_operand(proto, sm, locals, key)?;
+                let value = compile_operand(proto, sm, locals, exp)?;
+                let instruction = Instruction::SetTable(dest as u8, key as u8, value as u8);
+                proto.emit(instruction, span);
+                locals.free_to(top);
             }
-            Instruction::Return => {
-                let ret = data.pop().unwrap();
+        }
+    }
+
+    if pending > 0 {
+        let n = Some(pending as u8);
+        proto.emit(Instruction::SetList(dest as u8, n, stored), table.span);
+    }
+    Ok(())
+}
 
-                // Clean up the local stack
-                while fp < data.len() as i32 {
-                    data.pop();
+// Calls with the function in the next free register, returning it.
+// The results are left from there.
+fn compile_function_call(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    fc: FunctionCall,
+    nresults: Option<usize>,
+) -> Result<usize, String> {
+    let func = locals.depth();
+    let (name, nself) = match &fc.method {
+        Some(method) => {
+            let object_name = describe(locals, &fc.function);
+            let object = compile_operand(proto, sm, locals, *fc.function)?;
+            locals.free_to(func);
+            locals.reserve(2);
+            let key = Expression::Literal(Literal::String(method.clone()));
+            match string_key(proto, &key) {
+                Some(k) => {
+                    proto.name(object_name);
+                    let instruction = Instruction::SelfIndex(func as u8, object as u8, k);
+                    proto.emit(instruction, method.span);
+                }
+                // The name's constant doesn't fit in SelfIndex
+                None => {
+                    let this = func as u8 + 1;
+                    proto.emit(Instruction::Move(this, object as u8), method.span);
+                    let key = compile_operand(proto, sm, locals, key)?;
+                    proto.name(object_name);
+                    let instruction = Instruction::GetTable(func as u8, this, key as u8);
+                    proto.emit(instruction, method.span);
+                    locals.free_to(func + 2);
                 }
+            }
+            (Some(format!("method '{}'", method.value())), 1)
+        }
+        None => {
+            let name = describe(locals, &fc.function);
+            compile_expression(proto, sm, locals, *fc.function)?;
+            (name, 0)
+        }
+    };
+    let nargs = compile_expression_list(proto, sm, locals, fc.arguments, None)?;
 
-                // Restore pc and fp
-                let mut narguments = data.pop().unwrap();
-                pc = data.pop().unwrap();
-                fp = data.pop().unwrap();
+    proto.name(name);
+    let nargs = nargs.map(|n| (n + nself) as u8);
+    let instruction = Instruction::Call(func as u8, nargs, nresults.map(|n| n as u8));
+    proto.emit(instruction, fc.span);
+    locals.free_to(func);
+    locals.reserve(nresults.unwrap_or(0));
+    Ok(func)
+}
 
-                // Clean up arguments
-                while narguments > 0 {
-                    data.pop();
-                    narguments -= 1;
-                }
+// Puts `...` in the next free registers, returning the first.
+fn compile_vararg(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    t: Token,
+    nresults: Option<usize>,
+) -> Result<usize, String> {
+    if !locals.current().vararg {
+        return Err(t
+            .span
+            .debug(sm, "Cannot use '...' outside a vararg function:"));
+    }
+
+    let first = locals.reserve(nresults.unwrap_or(0));
+    let instruction = Instruction::Vararg(first as u8, nresults.map(|n| n as u8));
+    proto.emit(instruction, t.span);
+    Ok(first)
+}
 
-                // Add back return value
-                data.push(ret);
+fn compile_literal(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    lit: Literal,
+    dest: usize,
+) -> Result<(), String> {
+    let dest = dest as u8;
+    let (instruction, span) = match lit {
+        Literal::Number(t) => match str_to_number(t.text()) {
+            Some(n) => (
+                Instruction::LoadK(dest, proto.constant(Value::from(n))),
+                t.span,
+            ),
+            None => return Err(t.span.debug(sm, "Malformed number:")),
+        },
+        Literal::String(t) => {
+            let k = proto.constant(Value::String(t.value().clone()));
+            (Instruction::LoadK(dest, k), t.span)
+        }
+        Literal::Nil(t) => (Instruction::LoadNil(dest, 1), t.span),
+        Literal::Boolean(t) => {
+            let k = proto.constant(Value::Boolean(t.text() == "true"));
+            (Instruction::LoadK(dest, k), t.span)
+        }
+        Literal::Identifier(t) => match locals.resolve(t.value()) {
+            Variable::Local(slot) => (Instruction::Move(dest, slot as u8), t.span),
+            Variable::Upvalue(i) => (Instruction::GetUpvalue(dest, i as u8), t.span),
+            Variable::Global(name) => {
+                let k = proto.constant(Value::String(name));
+                (Instruction::GetGlobal(dest, k), t.span)
             }
-            Instruction::Call(label, narguments) => {
-                // Handle builtin functions
-                if label == "print" {
-                    for _ in 0..*narguments {
-                        print!("{}", data.pop().unwrap());
-                        print!(" ");
-                    }
-                    println!();
-                    pc += 1;
-                    continue;
-                }
+        },
+        Literal::Vararg(_) => unreachable!("vararg compiled into a register"),
+    };
+
+    proto.emit(instruction, span);
+    Ok(())
+}
+
+// Compiles an expression to exactly one value in the next free
+// register, returning it.
+fn compile_expression(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    exp: Expression,
+) -> Result<usize, String> {
+    match exp {
+        Expression::FunctionCall(fc) => compile_function_call(proto, sm, locals, fc, Some(1)),
+        Expression::Literal(Literal::Vararg(t)) => compile_vararg(proto, sm, locals, t, Some(1)),
+        Expression::Parenthesized(e, _) => compile_expression(proto, sm, locals, *e),
+        exp => {
+            let dest = locals.reserve(1);
+            compile_into(proto, sm, locals, exp, dest)?;
+            Ok(dest)
+        }
+    }
+}
+
+// Like compile_expression, but locals are read from their own
+// registers rather than copied.
+fn compile_operand(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    exp: Expression,
+) -> Result<usize, String> {
+    if let Expression::Literal(Literal::Identifier(t)) = &exp {
+        if let Variable::Local(slot) = locals.resolve(t.value()) {
+            return Ok(slot);
+        }
+    }
+    compile_expression(proto, sm, locals, exp)
+}
+
+// Compiles an expression to exactly one value in a register, which
+// may be a local. Locals are only written once the value is complete,
+// so `x = {x}` and `x = y and x` see the old value of x.
+fn compile_into(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    exp: Expression,
+    dest: usize,
+) -> Result<(), String> {
+    let top = locals.depth();
+    let at_top = dest >= locals.nactive() && dest + 1 == top;
+    let early = match &exp {
+        Expression::Table(_) => true,
+        Expression::BinaryOperation(bop) => matches!(bop.operator.text(), "and" | "or"),
+        _ => false,
+    };
+    if is_multiple(&exp) || (early && !at_top) {
+        let span = exp.span();
+        let reg = compile_expression(proto, sm, locals, exp)?;
+        proto.emit(Instruction::Move(dest as u8, reg as u8), span);
+        locals.free_to(top);
+        return Ok(());
+    }
 
-                data.push(fp);
-                data.push(pc + 1);
-                data.push(pgrm.syms[label].narguments as i32);
-                pc = pgrm.syms[label].location;
-                fp = data.len() as i32;
+    match exp {
+        Expression::BinaryOperation(bop) => compile_binary_operation(proto, sm, locals, bop, dest)?,
+        Expression::UnaryOperation(uop) => compile_unary_operation(proto, sm, locals, uop, dest)?,
+        Expression::Literal(lit) => compile_literal(proto, sm, locals, lit, dest)?,
+        Expression::Parenthesized(e, _) => compile_into(proto, sm, locals, *e, dest)?,
+        Expression::Constant(Value::Nil, span) => {
+            proto.emit(Instruction::LoadNil(dest as u8, 1), span)
+        }
+        Expression::Constant(v, span) => {
+            let k = proto.constant(v);
+            proto.emit(Instruction::LoadK(dest as u8, k), span);
+        }
+        Expression::Function(f) => {
+            let span = f.span;
+            let index = compile_function(proto, sm, locals, f, None)?;
+            proto.emit(Instruction::Closure(dest as u8, index as u32), span);
+        }
+        Expression::Index(idx) => compile_index(proto, sm, locals, idx, dest)?,
+        Expression::Table(t) => compile_table(proto, sm, locals, t, dest)?,
+        Expression::FunctionCall(_) => unreachable!("call compiled into a register"),
+    }
+    locals.free_to(top);
+    Ok(())
+}
 
-                // Set up space for all arguments/locals
-                let mut nlocals = pgrm.syms[label].nlocals;
-                while nlocals > 0 {
-                    data.push(0);
-                    nlocals -= 1;
+// Compiles an expression to nresults values in the next free
+// registers, or to all of the values of a call or `...` if nresults
+// is None. Returns how many values it left, or None for all.
+fn compile_multiple(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    exp: Expression,
+    nresults: Option<usize>,
+) -> Result<Option<usize>, String> {
+    match exp {
+        Expression::FunctionCall(fc) => {
+            compile_function_call(proto, sm, locals, fc, nresults)?;
+            Ok(nresults)
+        }
+        Expression::Literal(Literal::Vararg(t)) => {
+            compile_vararg(proto, sm, locals, t, nresults)?;
+            Ok(nresults)
+        }
+        exp => {
+            let span = exp.span();
+            let reg = compile_expression(proto, sm, locals, exp)?;
+            match nresults {
+                Some(0) => locals.free_to(reg),
+                Some(n) if n > 1 => {
+                    let first = locals.reserve(n - 1);
+                    proto.emit(Instruction::LoadNil(first as u8, (n - 1) as u8), span);
                 }
+                _ => {}
             }
-            Instruction::Add => {
-                let right = data.pop().unwrap();
-                let left = data.pop().unwrap();
-                data.push(left + right);
-                pc += 1;
-            }
-            Instruction::Subtract => {
-                let right = data.pop().unwrap();
-                let left = data.pop().unwrap();
-                data.push(left - right);
-                pc += 1;
-            }
-            Instruction::LessThan => {
-                let right = data.pop().unwrap();
-                let left = data.pop().unwrap();
-                data.push(if left < right { 1 } else { 0 });
-                pc += 1;
-            }
-            Instruction::Store(n) => {
-                data.push(*n);
-                pc += 1;
+            Ok(Some(nresults.unwrap_or(1)))
+        }
+    }
+}
+
+// Compiles an expression list to exactly nresults values in the next
+// free registers, or to all of its values with the last expression
+// expanded if nresults is None. Every expression is evaluated even if
+// its values are dropped. Returns how many values it left, or None
+// for all of them up to the top of the stack.
+fn compile_expression_list(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    exps: Vec<Expression>,
+    nresults: Option<usize>,
+) -> Result<Option<usize>, String> {
+    let start = locals.depth();
+    let len = exps.len();
+    let mut span = Span::default();
+    for (i, exp) in exps.into_iter().enumerate() {
+        span = exp.span();
+        if i == len - 1 {
+            let wanted = nresults.map(|n| n.saturating_sub(i));
+            if compile_multiple(proto, sm, locals, exp, wanted)?.is_none() {
+                return Ok(None);
             }
+        } else {
+            compile_expression(proto, sm, locals, exp)?;
+        }
+    }
+
+    let depth = locals.depth();
+    match nresults {
+        Some(n) if depth > start + n => locals.free_to(start + n),
+        Some(n) if depth < start + n => {
+            let first = locals.reserve(start + n - depth);
+            let count = (start + n - depth) as u8;
+            proto.emit(Instruction::LoadNil(first as u8, count), span);
+        }
+        _ => {}
+    }
+    Ok(Some(locals.depth() - start))
+}
+
+// Compiles a function into a prototype nested in proto, and returns
+// its index in proto.protos.
+fn compile_function(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    f: Function,
+    self_parameter: Option<LuaString>,
+) -> Result<usize, String> {
+    let mut child = Proto::new(f.vararg);
+
+    locals.functions.push(FunctionScope::new(f.vararg));
+    let parameters = self_parameter
+        .into_iter()
+        .chain(f.parameters.iter().map(|p| p.value().clone()));
+    for name in parameters {
+        locals.reserve(1);
+        locals.declare(name, 0);
+    }
+    child.nparameters = locals.depth();
+    child.line_defined = sm.lookup(f.span.file, f.span.start).0 + 1;
+    child.last_line_defined = sm.lookup(f.span.file, f.span.end.saturating_sub(1)).0 + 1;
+
+    compile_body(&mut child, sm, locals, f.body, f.span)?;
+
+    locals.remove_locals(0, child.ops.len());
+    let fs = locals.functions.pop().unwrap();
+    child.finish(sm, fs)?;
+
+    proto.protos.push(Rc::new(child));
+    Ok(proto.protos.len() - 1)
+}
+
+// The outermost block of a function, which returns nothing if it
+// falls off its end.
+fn compile_body(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    body: Vec<Statement>,
+    span: Span,
+) -> Result<(), String> {
+    compile_block(proto, sm, locals, body, None)?;
+
+    if let Some(goto) = locals.current().gotos.first() {
+        return Err(goto.name.span.debug(
+            sm,
+            format!("No visible label '{}' for goto:", goto.name.value()),
+        ));
+    }
+    if locals.current().max_depth > MAX_REGISTERS {
+        return Err(span.debug(sm, "Function or expression needs too many registers:"));
+    }
+
+    proto.emit(Instruction::Return(0, Some(0)), span);
+    Ok(())
+}
+
+fn check_assignable(sm: &SourceMap, locals: &Locals, name: &Token) -> Result<(), String> {
+    if locals.readonly(name.value()) {
+        return Err(name.span.debug(
+            sm,
+            format!("Attempt to assign to const variable '{}':", name.value()),
+        ));
+    }
+    Ok(())
+}
+
+// Stores the value in a register into a variable.
+fn compile_assign(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    name: &Token,
+    reg: usize,
+) -> Result<(), String> {
+    check_assignable(sm, locals, name)?;
+
+    let reg = reg as u8;
+    let instruction = match locals.resolve(name.value()) {
+        Variable::Local(slot) if slot == reg as usize => return Ok(()),
+        Variable::Local(slot) => Instruction::Move(slot as u8, reg),
+        Variable::Upvalue(i) => Instruction::SetUpvalue(reg, i as u8),
+        Variable::Global(name) => Instruction::SetGlobal(reg, proto.constant(Value::String(name))),
+    };
+    proto.emit(instruction, name.span);
+    Ok(())
+}
+
+fn compile_declaration(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    fd: FunctionDeclaration,
+) -> Result<(), String> {
+    let top = locals.depth();
+
+    // The local is in scope in its own body, so it can recurse
+    if fd.local {
+        let slot = locals.reserve(1);
+        locals.declare(fd.name.value().clone(), proto.ops.len());
+        let index = compile_function(proto, sm, locals, fd.function, None)?;
+        proto.emit(Instruction::Closure(slot as u8, index as u32), fd.span);
+        return Ok(());
+    }
+
+    // `function a.b:c()` stores into the table a.b under c
+    let mut keys = fd.fields.iter().chain(fd.method.iter());
+    let Some(last) = keys.next_back() else {
+        let reg = locals.reserve(1);
+        let index = compile_function(proto, sm, locals, fd.function, None)?;
+        proto.emit(Instruction::Closure(reg as u8, index as u32), fd.span);
+        compile_assign(proto, sm, locals, &fd.name, reg)?;
+        locals.free_to(top);
+        return Ok(());
+    };
+
+    let name = Expression::Literal(Literal::Identifier(fd.name.clone()));
+    let mut object = compile_operand(proto, sm, locals, name)?;
+    for key in keys {
+        let reg = locals.reserve(1);
+        let key = Expression::Literal(Literal::String(key.clone()));
+        let instruction = match compile_key(proto, sm, locals, key)? {
+            Ok(k) => Instruction::GetField(reg as u8, object as u8, k),
+            Err(key) => Instruction::GetTable(reg as u8, object as u8, key as u8),
+        };
+        proto.emit(instruction, fd.span);
+        locals.free_to(reg + 1);
+        object = reg;
+    }
+
+    let self_parameter = fd.method.as_ref().map(|_| LuaString::from("self"));
+    let reg = locals.reserve(1);
+    let index = compile_function(proto, sm, locals, fd.function, self_parameter)?;
+    proto.emit(Instruction::Closure(reg as u8, index as u32), fd.span);
+    let key = Expression::Literal(Literal::String(last.clone()));
+    let instruction = match compile_key(proto, sm, locals, key)? {
+        Ok(k) => Instruction::SetField(object as u8, k, reg as u8),
+        Err(key) => Instruction::SetTable(object as u8, key as u8, reg as u8),
+    };
+    proto.emit(instruction, fd.span);
+    locals.free_to(top);
+    Ok(())
+}
+
+fn compile_return(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    mut ret: Return,
+) -> Result<(), String> {
+    // `return f(x)` reuses the frame, unless a to-be-closed variable
+    // has to be closed after f returns. The Return after it is for
+    // functions that aren't Lua's, which are called as usual.
+    let tail = matches!(&ret.expressions[..], [Expression::FunctionCall(_)])
+        && !locals.current().locals.iter().any(|l| l.to_be_closed);
+    if tail {
+        let Some(Expression::FunctionCall(fc)) = ret.expressions.pop() else {
+            unreachable!("tail call of a non-call")
+        };
+        let func = compile_function_call(proto, sm, locals, fc, None)?;
+        let last = proto.ops.last_mut().unwrap();
+        if let Instruction::Call(a, nargs, None) = *last {
+            *last = Instruction::TailCall(a, nargs);
+        }
+        proto.emit(Instruction::Return(func as u8, None), ret.span);
+        return Ok(());
+    }
+
+    // A single value can be returned from wherever it is
+    let (first, n) = match ret.expressions.len() {
+        1 if !is_multiple(&ret.expressions[0]) => {
+            let exp = ret.expressions.pop().unwrap();
+            (compile_operand(proto, sm, locals, exp)?, Some(1))
         }
+        _ => {
+            let first = locals.depth();
+            let n = compile_expression_list(proto, sm, locals, ret.expressions, None)?;
+            (first, n)
+        }
+    };
+    let instruction = Instruction::Return(first as u8, n.map(|n| n as u8));
+    proto.emit(instruction, ret.span);
+    Ok(())
+}
+
+// Compiles a condition, jumping to a label if it is false.
+fn compile_test(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    test: Expression,
+    label: u32,
+) -> Result<(), String> {
+    // Constant conditions always or never jump
+    if let Some(v) = optimize::constant(&test) {
+        if !v.truthy() {
+            proto.emit(Instruction::Jump(label), test.span());
+        }
+        return Ok(());
+    }
+
+    let top = locals.depth();
+    let span = test.span();
+    let reg = compile_operand(proto, sm, locals, test)?;
+    proto.emit(Instruction::JumpIfFalse(reg as u8, label), span);
+    locals.free_to(top);
+    Ok(())
+}
+
+fn compile_if(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    if_: If,
+) -> Result<(), String> {
+    let end_label = proto.label();
+    let branches = std::iter::once((if_.test, if_.body, if_.span))
+        .chain(if_.elseifs.into_iter().map(|e| (e.test, e.body, e.span)));
+    for (test, body, span) in branches {
+        let else_label = proto.label();
+        compile_test(proto, sm, locals, test, else_label)?;
+        compile_block(proto, sm, locals, body, None)?;
+        proto.emit(Instruction::Jump(end_label), span);
+        proto.mark(else_label);
+    }
+
+    if let Some(body) = if_.else_body {
+        compile_block(proto, sm, locals, body, None)?;
+    }
+
+    proto.mark(end_label);
+    Ok(())
+}
+
+fn compile_local(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    local: Local,
+) -> Result<(), String> {
+    let mut close = None;
+    for (i, name) in local.names.iter().enumerate() {
+        match &name.attribute {
+            Some(attrib) if attrib.text() == "close" => {
+                if close.is_some() {
+                    return Err(attrib
+                        .span
+                        .debug(sm, "Multiple to-be-closed variables in local list:"));
+                }
+                close = Some((i, name.name.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    let base = locals.depth();
+    let n = local.names.len();
+    compile_expression_list(proto, sm, locals, local.expressions, Some(n))?;
+
+    // Declared only after the expressions, which still see any
+    // shadowed variables.
+    for name in local.names {
+        locals.declare_local(
+            name.name.value().clone(),
+            name.attribute.is_some(),
+            proto.ops.len(),
+        );
+    }
+
+    if let Some((i, name)) = close {
+        let local = &mut locals.current().locals[base + i];
+        local.needs_close = true;
+        local.to_be_closed = true;
+        proto.name(Some(name.value().to_string()));
+        proto.emit(Instruction::ToBeClosed((base + i) as u8), name.span);
+    }
+    Ok(())
+}
+
+fn compile_assignment(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    mut assignment: Assignment,
+) -> Result<(), String> {
+    let top = locals.depth();
+
+    // A single value goes straight to its target
+    if assignment.targets.len() == 1 && assignment.expressions.len() == 1 {
+        let exp = assignment.expressions.pop().unwrap();
+        match assignment.targets.pop().unwrap() {
+            Expression::Literal(Literal::Identifier(name)) => {
+                check_assignable(sm, locals, &name)?;
+                match locals.resolve(name.value()) {
+                    Variable::Local(slot) => compile_into(proto, sm, locals, exp, slot)?,
+                    _ => {
+                        let reg = compile_operand(proto, sm, locals, exp)?;
+                        compile_assign(proto, sm, locals, &name, reg)?;
+                    }
+                }
+            }
+            Expression::Index(idx) => {
+                let object = compile_operand(proto, sm, locals, *idx.object)?;
+                let key = match string_key(proto, &idx.key) {
+                    Some(k) => Ok(k),
+                    None => Err(compile_operand(proto, sm, locals, *idx.key)?),
+                };
+                let value = compile_operand(proto, sm, locals, exp)?;
+                let instruction = match key {
+                    Ok(k) => Instruction::SetField(object as u8, k, value as u8),
+                    Err(key) => Instruction::SetTable(object as u8, key as u8, value as u8),
+                };
+                proto.emit(instruction, idx.span);
+            }
+            target => unreachable!("assignment to {:?}", target),
+        }
+        locals.free_to(top);
+        return Ok(());
+    }
+
+    // Objects and keys of table targets are evaluated first and wait
+    // in registers of their own for the values, so assigning to a
+    // local doesn't change them.
+    let mut targets = vec![];
+    for target in assignment.targets {
+        match target {
+            Expression::Literal(Literal::Identifier(name)) => targets.push(Ok(name)),
+            Expression::Index(idx) => {
+                let object = compile_expression(proto, sm, locals, *idx.object)?;
+                let key = match string_key(proto, &idx.key) {
+                    Some(k) => Ok(k),
+                    None => Err(compile_expression(proto, sm, locals, *idx.key)?),
+                };
+                targets.push(Err((object, key, idx.span)));
+            }
+            target => unreachable!("assignment to {:?}", target),
+        }
+    }
+
+    let first = locals.depth();
+    let n = targets.len();
+    compile_expression_list(proto, sm, locals, assignment.expressions, Some(n))?;
+    for (i, target) in targets.iter().enumerate().rev() {
+        let value = (first + i) as u8;
+        match target {
+            Ok(name) => compile_assign(proto, sm, locals, name, first + i)?,
+            Err((object, Ok(k), span)) => {
+                let instruction = Instruction::SetField(*object as u8, *k, value);
+                proto.emit(instruction, *span);
+            }
+            Err((object, Err(key), span)) => {
+                let instruction = Instruction::SetTable(*object as u8, *key as u8, value);
+                proto.emit(instruction, *span);
+            }
+        }
+    }
+
+    locals.free_to(top);
+    Ok(())
+}
+
+fn compile_while(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    while_: While,
+) -> Result<(), String> {
+    let top_label = proto.label();
+    let exit_label = proto.label();
+
+    proto.mark(top_label);
+    compile_test(proto, sm, locals, while_.test, exit_label)?;
+    compile_block(proto, sm, locals, while_.body, Some(exit_label))?;
+    proto.emit(Instruction::Jump(top_label), while_.span);
+
+    proto.mark(exit_label);
+    Ok(())
+}
+
+fn compile_repeat(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    repeat: Repeat,
+) -> Result<(), String> {
+    let start = locals.depth();
+    let top_label = proto.label();
+    let exit_label = proto.label();
+
+    // The test can see the body's locals, so it is compiled inside
+    // the body's block.
+    proto.mark(top_label);
+    enter_block(locals, Some(exit_label));
+    compile_statements(proto, sm, locals, repeat.body)?;
+    if block_needs_close(locals) {
+        // The body's locals are closed whichever way the test goes
+        let continue_label = proto.label();
+        compile_test(proto, sm, locals, repeat.test, continue_label)?;
+        proto.emit(Instruction::Close(start as u8), repeat.span);
+        proto.emit(Instruction::Jump(exit_label), repeat.span);
+        proto.mark(continue_label);
+        proto.emit(Instruction::Close(start as u8), repeat.span);
+        proto.emit(Instruction::Jump(top_label), repeat.span);
+    } else {
+        compile_test(proto, sm, locals, repeat.test, top_label)?;
+    }
+    end_block(locals, proto.ops.len());
+
+    proto.mark(exit_label);
+    Ok(())
+}
+
+fn compile_numeric_for(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    for_: NumericFor,
+) -> Result<(), String> {
+    let base = locals.depth();
+    let body_label = proto.label();
+    let exit_label = proto.label();
+
+    compile_expression(proto, sm, locals, for_.start)?;
+    compile_expression(proto, sm, locals, for_.limit)?;
+    match for_.step {
+        Some(step) => {
+            compile_expression(proto, sm, locals, step)?;
+        }
+        None => {
+            let reg = locals.reserve(1);
+            let k = proto.constant(Value::Integer(1));
+            proto.emit(Instruction::LoadK(reg as u8, k), for_.span);
+        }
+    }
+    for _ in 0..3 {
+        locals.declare(LuaString::from("(for state)"), proto.ops.len());
+    }
+
+    // The loop variable is closed at the end of each iteration, so
+    // closures capture the value of their own iteration.
+    proto.emit(Instruction::ForPrep(base as u8, exit_label), for_.span);
+    proto.mark(body_label);
+    enter_block(locals, Some(exit_label));
+    locals.reserve(1);
+    locals.declare(for_.name.value().clone(), proto.ops.len());
+    compile_block(proto, sm, locals, for_.body, None)?;
+    leave_block(proto, locals, for_.span);
+    proto.emit(Instruction::ForLoop(base as u8, body_label), for_.span);
+
+    proto.mark(exit_label);
+    locals.remove_locals(base, proto.ops.len());
+    locals.free_to(base);
+    Ok(())
+}
+
+fn compile_generic_for(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    for_: GenericFor,
+) -> Result<(), String> {
+    let base = locals.depth();
+    let body_label = proto.label();
+    let call_label = proto.label();
+    let exit_label = proto.label();
+
+    compile_expression_list(proto, sm, locals, for_.expressions, Some(3))?;
+    for _ in 0..3 {
+        locals.declare(LuaString::from("(for state)"), proto.ops.len());
+    }
+
+    proto.emit(Instruction::Jump(call_label), for_.span);
+    proto.mark(body_label);
+    enter_block(locals, Some(exit_label));
+    let n = for_.names.len();
+    locals.reserve(n);
+    for name in &for_.names {
+        locals.declare(name.value().clone(), proto.ops.len());
+    }
+    compile_block(proto, sm, locals, for_.body, None)?;
+    leave_block(proto, locals, for_.span);
+
+    proto.mark(call_label);
+    // The call needs room for the iterator and its arguments
+    locals.reserve(3);
+    proto.name(Some("for iterator 'for iterator'".to_string()));
+    proto.emit(Instruction::ForInCall(base as u8, n as u8), for_.span);
+    proto.emit(Instruction::ForInLoop(base as u8, body_label), for_.span);
+
+    proto.mark(exit_label);
+    locals.remove_locals(base, proto.ops.len());
+    locals.free_to(base);
+    Ok(())
+}
+
+fn compile_break(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    t: Token,
+) -> Result<(), String> {
+    let fs = locals.current();
+    let loop_block = fs.blocks.iter().rev().find(|b| b.break_label.is_some());
+    match loop_block {
+        Some(block) => {
+            let (label, nactive) = (block.break_label.unwrap(), block.nactive);
+            if fs.locals.len() > nactive {
+                proto.emit(Instruction::Close(nactive as u8), t.span);
+            }
+            proto.emit(Instruction::Jump(label), t.span);
+            Ok(())
+        }
+        None => Err(t.span.debug(sm, "Break outside a loop:")),
+    }
+}
+
+fn compile_goto(proto: &mut Proto, locals: &mut Locals, name: Token) {
+    let fs = locals.current();
+    let nactive = fs.locals.len();
+
+    let visible = fs
+        .blocks
+        .iter()
+        .flat_map(|b| b.labels.iter())
+        .find(|l| &l.name == name.value());
+    match visible {
+        Some(label) => {
+            let (key, nactive) = (label.key, label.nactive);
+            proto.emit(Instruction::Close(nactive as u8), name.span);
+            proto.emit(Instruction::Jump(key), name.span);
+        }
+        None => {
+            fs.gotos.push(Goto {
+                name: name.clone(),
+                index: proto.ops.len(),
+                nactive,
+                block: fs.blocks.len() - 1,
+            });
+            proto.emit(Instruction::Close(nactive as u8), name.span);
+            proto.emit(Instruction::Jump(0), name.span);
+        }
+    }
+}
+
+fn compile_label(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    name: Token,
+    at_block_end: bool,
+) -> Result<(), String> {
+    let fs = locals.current();
+    if fs
+        .blocks
+        .iter()
+        .flat_map(|b| b.labels.iter())
+        .any(|l| &l.name == name.value())
+    {
+        return Err(name
+            .span
+            .debug(sm, format!("Label '{}' already defined:", name.value())));
+    }
+
+    // Jumping to a label at the end of a block skips nothing, so the
+    // block's own locals are not considered in scope there.
+    let block = fs.blocks.len() - 1;
+    let nactive = if at_block_end {
+        fs.blocks[block].nactive
+    } else {
+        fs.locals.len()
+    };
+    let key = proto.label();
+    proto.mark(key);
+
+    let mut i = 0;
+    while i < fs.gotos.len() {
+        if fs.gotos[i].block != block || fs.gotos[i].name.value() != name.value() {
+            i += 1;
+            continue;
+        }
+
+        let goto = fs.gotos.remove(i);
+        if goto.nactive < nactive {
+            return Err(goto.name.span.debug(
+                sm,
+                format!(
+                    "Goto jumps into the scope of local '{}':",
+                    fs.locals[goto.nactive].name
+                ),
+            ));
+        }
+        proto.ops[goto.index] = Instruction::Close(nactive as u8);
+        proto.ops[goto.index + 1] = Instruction::Jump(key);
+    }
+
+    fs.blocks[block].labels.push(Label {
+        name: name.value().clone(),
+        key,
+        nactive,
+    });
+    Ok(())
+}
+
+fn compile_statement(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    stmt: Statement,
+    at_block_end: bool,
+) -> Result<(), String> {
+    match stmt {
+        Statement::FunctionDeclaration(fd) => compile_declaration(proto, sm, locals, fd),
+        Statement::Return(r) => compile_return(proto, sm, locals, r),
+        Statement::If(if_) => compile_if(proto, sm, locals, if_),
+        Statement::Local(loc) => compile_local(proto, sm, locals, loc),
+        Statement::Assignment(a) => compile_assignment(proto, sm, locals, a),
+        Statement::Expression(e) => compile_multiple(proto, sm, locals, e, Some(0)).map(|_| ()),
+        Statement::While(w) => compile_while(proto, sm, locals, w),
+        Statement::Repeat(r) => compile_repeat(proto, sm, locals, r),
+        Statement::NumericFor(f) => compile_numeric_for(proto, sm, locals, f),
+        Statement::GenericFor(f) => compile_generic_for(proto, sm, locals, f),
+        Statement::Do(d) => compile_block(proto, sm, locals, d.body, None),
+        Statement::Break(t) => compile_break(proto, sm, locals, t),
+        Statement::Goto(t) => {
+            compile_goto(proto, locals, t);
+            Ok(())
+        }
+        Statement::Label(t) => compile_label(proto, sm, locals, t, at_block_end),
+        Statement::Empty(_) => Ok(()),
+    }
+}
+
+fn enter_block(locals: &mut Locals, break_label: Option<u32>) {
+    let fs = locals.current();
+    let nactive = fs.locals.len();
+    fs.blocks.push(Block {
+        nactive,
+        labels: vec![],
+        break_label,
+    });
+}
+
+// Whether leaving the innermost block has upvalues or to-be-closed
+// variables to close.
+fn block_needs_close(locals: &Locals) -> bool {
+    let fs = locals.functions.last().unwrap();
+    let block = fs.blocks.last().unwrap();
+    fs.locals[block.nactive..].iter().any(|l| l.needs_close)
+}
+
+// Ends the scope of the block's locals. Gotos still waiting for a
+// label move out to the enclosing block.
+fn end_block(locals: &mut Locals, pc: usize) -> usize {
+    let nactive = locals.current().blocks.last().unwrap().nactive;
+    locals.remove_locals(nactive, pc);
+    let fs = locals.current();
+    let block = fs.blocks.pop().unwrap();
+    let level = fs.blocks.len();
+    // Gotos left in the function's outermost block are reported by
+    // compile_body
+    for goto in fs
+        .gotos
+        .iter_mut()
+        .filter(|g| level > 0 && g.block >= level)
+    {
+        goto.block = level - 1;
+        goto.nactive = goto.nactive.min(block.nactive);
+    }
+
+    fs.depth = block.nactive;
+    block.nactive
+}
+
+fn leave_block(proto: &mut Proto, locals: &mut Locals, span: Span) {
+    let close = block_needs_close(locals);
+    let nactive = end_block(locals, proto.ops.len());
+    if close {
+        proto.emit(Instruction::Close(nactive as u8), span);
+    }
+}
+
+fn compile_statements(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    block: Vec<Statement>,
+) -> Result<(), String> {
+    // Labels followed only by other labels and empty statements are
+    // at the end of their block.
+    let void = |s: &Statement| matches!(s, Statement::Label(_) | Statement::Empty(_));
+    let ends: Vec<bool> = (0..block.len())
+        .map(|i| block[i + 1..].iter().all(void))
+        .collect();
+
+    for (stmt, at_block_end) in block.into_iter().zip(ends) {
+        compile_statement(proto, sm, locals, stmt, at_block_end)?;
+        // Temporaries don't outlive their statement
+        let nactive = locals.nactive();
+        locals.free_to(nactive);
+    }
+
+    Ok(())
+}
+
+fn compile_block(
+    proto: &mut Proto,
+    sm: &SourceMap,
+    locals: &mut Locals,
+    block: Vec<Statement>,
+    break_label: Option<u32>,
+) -> Result<(), String> {
+    let span = match (block.first(), block.last()) {
+        (Some(first), Some(last)) => statement_span(first).to(statement_span(last)),
+        _ => return Ok(()),
+    };
+
+    enter_block(locals, break_label);
+    compile_statements(proto, sm, locals, block)?;
+    leave_block(proto, locals, span);
+    Ok(())
+}
+
+fn statement_span(stmt: &Statement) -> Span {
+    match stmt {
+        Statement::Expression(e) => e.span(),
+        Statement::If(s) => s.span,
+        Statement::FunctionDeclaration(s) => s.span,
+        Statement::Return(s) => s.span,
+        Statement::Local(s) => s.span,
+        Statement::Assignment(s) => s.span,
+        Statement::While(s) => s.span,
+        Statement::Repeat(s) => s.span,
+        Statement::NumericFor(s) => s.span,
+        Statement::GenericFor(s) => s.span,
+        Statement::Do(s) => s.span,
+        Statement::Break(t) | Statement::Goto(t) | Statement::Label(t) | Statement::Empty(t) => {
+            t.span
+        }
+    }
+}
+
+// Compiles a chunk as the body of a vararg main function.
+pub fn compile(sm: &SourceMap, mut ast: Ast) -> Result<Proto, String> {
+    optimize::fold_block(&mut ast);
+    let mut proto = Proto::new(true);
+    let span = match (ast.first(), ast.last()) {
+        (Some(first), Some(last)) => statement_span(first).to(statement_span(last)),
+        _ => {
+            proto.emit(Instruction::Return(0, Some(0)), Span::default());
+            proto.finish(sm, FunctionScope::new(true))?;
+            return Ok(proto);
+        }
+    };
+
+    let mut locals = Locals {
+        functions: vec![FunctionScope::new(true)],
+    };
+    compile_body(&mut proto, sm, &mut locals, ast, span)?;
+    locals.remove_locals(0, proto.ops.len());
+    proto.finish(sm, locals.functions.pop().unwrap())?;
+
+    Ok(proto)
+}
+
+#[derive(Debug)]
+pub enum Upvalue {
+    // Still a live stack slot of the function that declared it
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Closure {
+    pub(crate) proto: Rc<Proto>,
+    pub(crate) upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+struct Frame {
+    // The function being run, kept alive by slot func of the stack
+    closure: *const Closure,
+    // Saved at calls, where execution resumes after returning
+    pc: usize,
+    base: usize,
+    // Slot holding the function being run, where results go
+    func: usize,
+    nresults: Option<usize>,
+    varargs: Vec<Value>,
+    // Called by a tail call, so the frames of its callers are gone
+    tail: bool,
+    // Called by native code, through Vm::call
+    from_native: bool,
+}
+
+impl Frame {
+    // The frame's function. Nothing writes to slot func while the
+    // frame is there, so the reference is good until the frame is
+    // popped; callers mustn't keep it past that.
+    fn closure<'a>(&self) -> &'a Closure {
+        unsafe { &*self.closure }
+    }
+}
+
+pub struct Vm {
+    pub(crate) sm: SourceMap,
+    pub(crate) globals: Rc<RefCell<Table>>,
+    // Slots below top are in use. Those above hold what finished calls
+    // left, until they are reused or a collection drops them, so calls
+    // don't have to resize the stack.
+    stack: Vec<TValue>,
+    top: usize,
+    frames: Vec<Frame>,
+    // Sorted by stack slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    // Slots of to-be-closed variables, innermost last
+    tbc: Vec<usize>,
+    // Toggled by warn("@on") and warn("@off")
+    pub(crate) warnings: bool,
+    // Lua frames allowed, and calls into Lua from native code in
+    // progress
+    pub(crate) max_call_depth: usize,
+    native_calls: usize,
+    // Address on the Rust stack of the outermost native call
+    native_stack: usize,
+    // Where the last error to escape every Lua function was raised.
+    // Code that catches errors clears it.
+    pub(crate) traceback: Option<String>,
+    // "__index", interned once for cached lookups
+    index_key: Value,
+    // One metatable per UserData type
+    pub(crate) metatables: HashMap<TypeId, Rc<RefCell<Table>>>,
+    pub(crate) heap: Heap,
+}
+
+impl Vm {
+    // A state without any globals; Lua::new opens the standard library.
+    pub(crate) fn empty() -> Vm {
+        Vm {
+            sm: SourceMap::new(),
+            globals: Rc::new(RefCell::new(Table::new())),
+            stack: vec![],
+            top: 0,
+            frames: vec![],
+            open_upvalues: vec![],
+            tbc: vec![],
+            warnings: false,
+            max_call_depth: MAX_CALL_DEPTH,
+            native_calls: 0,
+            native_stack: 0,
+            traceback: None,
+            index_key: Value::from("__index"),
+            metatables: HashMap::new(),
+            heap: Heap::new(),
+        }
+    }
+
+    // Grows the stack to at least size slots
+    fn reserve_stack(&mut self, size: usize) {
+        if self.stack.len() < size {
+            self.stack.resize_with(size, TValue::default);
+        }
+    }
+
+    // Takes the values from a slot of the stack up to the top
+    fn split_values(&mut self, at: usize) -> Vec<Value> {
+        let values = self.stack[at..self.top]
+            .iter_mut()
+            .map(|v| std::mem::take(v).into_value())
+            .collect();
+        self.top = at;
+        values
+    }
+
+    fn push_values(&mut self, values: Vec<Value>) {
+        self.reserve_stack(self.top + values.len());
+        for v in values {
+            self.stack[self.top].set(v);
+            self.top += 1;
+        }
+    }
+
+    // Drops what finished calls left above the top of the stack, which
+    // would keep it alive through a collection.
+    pub(crate) fn drop_stale_slots(&mut self) {
+        for v in &mut self.stack[self.top..] {
+            v.set(Value::Nil);
+        }
+    }
+
+    // Lets go of everything the state keeps alive, once it is closing.
+    pub(crate) fn drop_roots(&mut self) {
+        self.stack.clear();
+        self.top = 0;
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.tbc.clear();
+        self.metatables.clear();
+        self.globals = Rc::new(RefCell::new(Table::new()));
+    }
+
+    // Lexes, parses and compiles a chunk into a function value, or
+    // loads a precompiled one. mode is "t" for source only, "b" for
+    // binary chunks only or "bt" for both, as in load.
+    pub(crate) fn load_function(
+        &mut self,
+        chunk: &[u8],
+        chunkname: &str,
+        mode: &str,
+    ) -> Result<Value, Error> {
+        let binary = chunk.starts_with(dump::SIGNATURE);
+        let (kind, allowed) = match binary {
+            true => ("binary", mode.contains('b')),
+            false => ("text", mode.contains('t')),
+        };
+        if !allowed {
+            return Err(Error::Syntax(format!(
+                "attempt to load a {} chunk (mode is '{}')",
+                kind, mode
+            )));
+        }
+
+        let proto = if binary {
+            dump::undump(&mut self.sm, chunk, chunkname).map_err(Error::Syntax)?
+        } else {
+            let file = self.add_source(chunk, chunkname);
+            let proto = self
+                .parse_file(file)
+                .and_then(|ast| self.compile_file(file, ast));
+            self.sm.release(file);
+            let proto = proto?;
+            if self.warnings {
+                self.warn_undefined_calls(&proto);
+            }
+            proto
+        };
+        Ok(self.closure(proto))
+    }
+
+    // Warns about calls to globals that are neither defined yet nor
+    // assigned anywhere in the chunk, which will fail unless something
+    // else defines them first.
+    fn warn_undefined_calls(&self, proto: &Proto) {
+        fn walk<'p>(
+            proto: &'p Proto,
+            calls: &mut Vec<(&'p str, Span)>,
+            assigned: &mut HashSet<&'p [u8]>,
+        ) {
+            let code = opcodes::unpack(&proto.code).unwrap_or_default();
+            for (pc, instruction) in code.into_iter().enumerate() {
+                match instruction {
+                    Instruction::Call(..) | Instruction::TailCall(..) => {
+                        let global = proto.names.get(&pc).and_then(|n| n.strip_prefix("global "));
+                        if let Some(name) = global {
+                            calls.push((name, proto.spans[pc]));
+                        }
+                    }
+                    Instruction::SetGlobal(_, k) => {
+                        if let Value::String(s) = &proto.constants[k as usize] {
+                            assigned.insert(s);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            for child in &proto.protos {
+                walk(child, calls, assigned);
+            }
+        }
+
+        let (mut calls, mut assigned) = (vec![], HashSet::new());
+        walk(proto, &mut calls, &mut assigned);
+        calls.sort_by_key(|&(_, span)| span.start);
+        let globals = self.globals.borrow();
+        for (name, span) in calls {
+            let name = name.trim_matches('\'');
+            let defined = !matches!(globals.get(&Value::from(name)), Value::Nil);
+            if !assigned.contains(name.as_bytes()) && !defined {
+                eprintln!(
+                    "Lua warning: {}: call to undefined global '{}'",
+                    self.sm.position(span),
+                    name
+                );
+            }
+        }
+    }
+
+    pub(crate) fn add_source(&mut self, source: &[u8], chunkname: &str) -> FileId {
+        self.sm.add(chunkname, source)
+    }
+
+    pub(crate) fn parse_file(&self, file: FileId) -> Result<Ast, Error> {
+        let lexer = Lexer::new(file, self.sm.source(file));
+        parse(&self.sm, lexer).map_err(|e| Error::Syntax(e.msg))
+    }
+
+    // Compiles a file's syntax tree into a prototype that holds the
+    // file, so it outlives the file being released
+    pub(crate) fn compile_file(&self, file: FileId, ast: Ast) -> Result<Proto, Error> {
+        let mut proto = compile(&self.sm, ast).map_err(Error::Syntax)?;
+        proto.hold_source(&self.sm.users(file));
+        Ok(proto)
+    }
+
+    // The binary chunk of a Lua function, which load turns back into
+    // a function. strip leaves out line numbers and names.
+    pub(crate) fn dump_function(&self, f: &Value, strip: bool) -> Option<Vec<u8>> {
+        match f {
+            Value::Function(value::Function::Lua(c)) => Some(dump::dump(&self.sm, &c.proto, strip)),
+            _ => None,
+        }
+    }
+
+    // Functions that had upvalues when dumped get fresh ones, all nil
+    fn closure(&mut self, proto: Proto) -> Value {
+        let upvalues = proto
+            .upvalues
+            .iter()
+            .map(|_| Rc::new(RefCell::new(Upvalue::Closed(Value::Nil))))
+            .collect();
+        self.new_closure(Closure {
+            proto: Rc::new(proto),
+            upvalues,
+        })
+    }
+
+    pub(crate) fn eval(&mut self, proto: Proto) -> Result<Vec<Value>, Error> {
+        let f = self.closure(proto);
+        self.call(f, vec![])
+    }
+
+    pub fn call(&mut self, f: Value, args: Vec<Value>) -> Result<Vec<Value>, Error> {
+        let func = self.top;
+        let depth = self.frames.len();
+        if depth == 0 {
+            self.traceback = None;
+        }
+        // The Rust stack grows down on the platforms lust runs on. Were
+        // it to grow up, only the count would apply.
+        let here = &func as *const usize as usize;
+        if self.native_calls == 0 {
+            self.native_stack = here;
+        }
+        if self.native_calls >= MAX_NATIVE_CALLS
+            || self.native_stack.saturating_sub(here) > MAX_NATIVE_STACK
+        {
+            return Err(Error::runtime(format!(
+                "{}C stack overflow",
+                self.location()
+            )));
+        }
+        self.push_values(vec![f]);
+        self.push_values(args);
+
+        self.native_calls += 1;
+        let result = match self.precall(func, None) {
+            Ok(true) => {
+                self.frames.last_mut().unwrap().from_native = true;
+                self.execute(depth)
+            }
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.native_calls -= 1;
+        match result {
+            Ok(()) => Ok(self.split_values(func)),
+            Err(e) => {
+                // Taken where the error is raised, while the frames
+                // of the functions it escaped are still there
+                if self.traceback.is_none() && self.frames.len() > depth {
+                    self.traceback = Some(self.stack_traceback());
+                }
+                let e = self.close_on_error(func, e);
+                self.close_upvalues(func);
+                self.top = func;
+                self.frames.truncate(depth);
+                Err(e)
+            }
+        }
+    }
+
+    // Lua's listing of the running functions, innermost first. A
+    // function that made a tail call has no frame left to list.
+    fn stack_traceback(&self) -> String {
+        let mut levels = vec![];
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            let proto = &frame.closure().proto;
+            let span = proto.spans[frame.pc];
+            // How the caller's call named the function, if it is at one
+            let call = i.checked_sub(1).and_then(|c| {
+                let caller = &self.frames[c];
+                let caller_proto = &caller.closure().proto;
+                match opcodes::decode(caller_proto.code[caller.pc], caller.pc) {
+                    Some(Instruction::Call(..) | Instruction::TailCall(..)) => {
+                        Some(match caller_proto.names.get(&caller.pc) {
+                            Some(name) => match name.strip_prefix("global ") {
+                                Some(name) => format!("function {}", name),
+                                None => name.clone(),
+                            },
+                            None => "?".to_string(),
+                        })
+                    }
+                    _ => None,
+                }
+            });
+            let what = match &call {
+                _ if proto.line_defined == 0 => "main chunk".to_string(),
+                Some(name) if !frame.tail && !frame.from_native && name != "?" => name.clone(),
+                _ => {
+                    let chunk = match self.sm.line_number(span) {
+                        Some(_) => self.sm.name(span.file),
+                        None => "?",
+                    };
+                    format!("function <{}:{}>", chunk, proto.line_defined)
+                }
+            };
+            let mut level = format!("\n\t{}: in {}", self.sm.position(span), what);
+            if frame.tail {
+                level += "\n\t(...tail calls...)";
+            }
+            // A native function the caller called called this one
+            if let (true, Some(name)) = (frame.from_native, call) {
+                level += &format!("\n\t[C]: in {}", name);
+            }
+            levels.push(level);
+        }
+
+        // Like Lua, deep stacks only show their ends
+        let mut out = String::from("stack traceback:");
+        if levels.len() > TRACEBACK_FIRST + TRACEBACK_LAST {
+            let skipped = levels.len() - TRACEBACK_FIRST - TRACEBACK_LAST;
+            levels.splice(
+                TRACEBACK_FIRST..TRACEBACK_FIRST + skipped,
+                [format!("\n\t...\t(skipping {} levels)", skipped)],
+            );
+        }
+        out.extend(levels);
+        out + "\n\t[C]: in ?"
+    }
+
+    // The `chunk:line: ` position of the running Lua function, which
+    // errors raised by native functions are reported at.
+    pub(crate) fn location(&self) -> String {
+        self.location_at(1)
+    }
+
+    // Like location, for the function level calls up: 1 is the running
+    // one and 2 its caller, as for error.
+    pub(crate) fn location_at(&self, level: usize) -> String {
+        let frame = level
+            .checked_sub(1)
+            .and_then(|up| self.frames.len().checked_sub(up + 1))
+            .map(|i| &self.frames[i]);
+        match frame {
+            Some(frame) => {
+                let span = frame.closure().proto.spans[frame.pc];
+                format!("{}: ", self.sm.position(span))
+            }
+            None => String::new(),
+        }
+    }
+
+    // Positions errors of the instruction at pc. Errors already raised
+    // elsewhere, such as in metamethods, keep their position.
+    fn located(&self, e: Error, proto: &Proto, pc: usize) -> Error {
+        match e {
+            Error::Message(msg) => self.index_error(proto, pc, msg),
+            e => e,
+        }
+    }
+
+    // Operands that binary can't handle, for instructions whose fast
+    // paths only cover numbers
+    fn binary_slow(
+        &mut self,
+        op: BinaryOp,
+        left: Value,
+        right: Value,
+        proto: &Proto,
+        pc: usize,
+    ) -> Result<Value, Error> {
+        match binary(op, &left, &right) {
+            Ok(v) => Ok(v),
+            Err(msg) => {
+                self.frames.last_mut().unwrap().pc = pc;
+                self.binary_metamethod(op, left, right, msg)
+                    .map_err(|e| self.located(e, proto, pc))
+            }
+        }
+    }
+
+    fn index_error(&self, proto: &Proto, pc: usize, mut msg: String) -> Error {
+        if let Some(name) = proto.names.get(&pc) {
+            msg = format!("{} ({})", msg, name);
+        }
+        self.runtime_error(proto, pc, msg)
+    }
+
+    fn runtime_error(&self, proto: &Proto, pc: usize, msg: String) -> Error {
+        let span = proto.spans[pc];
+        Error::runtime(format!("{}: {}", self.sm.position(span), msg))
+    }
+
+    // Starts a call to the function in a slot, with the arguments
+    // above it. Native functions run to completion; Lua functions
+    // get a frame and return true.
+    fn precall(&mut self, func: usize, nresults: Option<usize>) -> Result<bool, Error> {
+        let lua = match &*self.stack[func].value() {
+            Value::Function(value::Function::Lua(closure)) => Some(Rc::as_ptr(closure)),
+            _ => None,
+        };
+        if let Some(closure) = lua {
+            if self.frames.len() >= self.max_call_depth {
+                return Err(Error::message("stack overflow"));
+            }
+            // Slot func holds the closure, as Frame::closure needs
+            let proto = &unsafe { &*closure }.proto;
+            let nargs = self.top - func - 1;
+            let varargs = if proto.vararg && nargs > proto.nparameters {
+                self.split_values(func + 1 + proto.nparameters)
+            } else {
+                vec![]
+            };
+            // Missing parameters start nil. The compiler writes every
+            // other register before reading it.
+            let params = func + 1 + proto.nparameters;
+            let size = func + 1 + proto.max_stack.max(proto.nparameters);
+            self.reserve_stack(size);
+            for v in &mut self.stack[func + 1 + nargs.min(proto.nparameters)..params] {
+                v.set(Value::Nil);
+            }
+            self.top = size;
+
+            self.frames.push(Frame {
+                closure,
+                pc: 0,
+                base: func + 1,
+                func,
+                nresults,
+                varargs,
+                tail: false,
+                from_native: false,
+            });
+            return Ok(true);
+        }
+
+        match self.stack[func].get() {
+            Value::Function(value::Function::Native(n)) => {
+                let args = Args::new(n.name.clone(), self.split_values(func + 1));
+                self.top = func;
+                let mut results = (n.call)(self, args).map_err(|e| match e {
+                    Error::Message(msg) => Error::runtime(format!("{}{}", self.location(), msg)),
+                    e => e,
+                })?;
+                if let Some(n) = nresults {
+                    results.resize(n, Value::Nil);
+                }
+                self.push_values(results);
+                Ok(false)
+            }
+            v => {
+                let h = self.metamethod(&v, "__call");
+                if let Value::Nil = h {
+                    return Err(Error::message(format!(
+                        "attempt to call a {} value",
+                        v.type_name()
+                    )));
+                }
+                // The handler gets the called value as its first argument
+                self.reserve_stack(self.top + 1);
+                self.stack[func..=self.top].rotate_right(1);
+                self.stack[func].set(h);
+                self.top += 1;
+                self.precall(func, nresults)
+            }
+        }
+    }
+
+    fn find_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let mut i = self.open_upvalues.len();
+        while i > 0 {
+            let existing = match &*self.open_upvalues[i - 1].borrow() {
+                Upvalue::Open(s) => *s,
+                Upvalue::Closed(_) => unreachable!("closed upvalue in open list"),
+            };
+            if existing == slot {
+                return self.open_upvalues[i - 1].clone();
+            }
+            if existing < slot {
+                break;
+            }
+            i -= 1;
+        }
+
+        let upvalue = self.new_upvalue(slot);
+        self.open_upvalues.insert(i, upvalue.clone());
+        upvalue
+    }
+
+    // Moves the values of upvalues at or above a slot off the stack.
+    fn close_upvalues(&mut self, level: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(s) => *s,
+                Upvalue::Closed(_) => unreachable!("closed upvalue in open list"),
+            };
+            if slot < level {
+                break;
+            }
+
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].get());
+            self.open_upvalues.pop();
+        }
+    }
+
+    // Calls __close for the to-be-closed variables at or above a slot,
+    // innermost first.
+    fn close_variables(&mut self, level: usize) -> Result<(), Error> {
+        while let Some(&slot) = self.tbc.last().filter(|&&slot| slot >= level) {
+            self.tbc.pop();
+            let v = self.stack[slot].get();
+            let h = self.metamethod(&v, "__close");
+            self.call(h, vec![v, Value::Nil])?;
+        }
+        Ok(())
+    }
+
+    // Like close_variables while an error unwinds the stack. Closing
+    // methods get the error, and an error in one replaces it.
+    fn close_on_error(&mut self, level: usize, mut e: Error) -> Error {
+        while let Some(&slot) = self.tbc.last().filter(|&&slot| slot >= level) {
+            self.tbc.pop();
+            let v = self.stack[slot].get();
+            let h = self.metamethod(&v, "__close");
+            if let Err(err) = self.call(h, vec![v, e.value()]) {
+                e = err;
+            }
+        }
+        e
+    }
+
+    // Runs Lua frames until the frame count drops back to entry.
+    fn execute(&mut self, entry: usize) -> Result<(), Error> {
+        let mut pc = self.frames.last().unwrap().pc;
+        let result = self.run(entry, &mut pc);
+        // The failing frame is left at the instruction that raised
+        // the error, for tracebacks
+        if result.is_err() {
+            self.frames.last_mut().unwrap().pc = pc;
+        }
+        result
+    }
+
+    // The loop of execute. pc is the running frame's, which is only
+    // saved in the frame at calls and where errors may be raised.
+    fn run(&mut self, entry: usize, pc: &mut usize) -> Result<(), Error> {
+        let frame = self.frames.last().unwrap();
+        let mut closure = frame.closure();
+        let mut proto = &*closure.proto;
+        let mut base = frame.base;
+        let mut top = base + proto.max_stack;
+
+        // Picks up the frame now running, after a call or a return
+        macro_rules! enter {
+            () => {{
+                let frame = self.frames.last().unwrap();
+                closure = frame.closure();
+                proto = &*closure.proto;
+                *pc = frame.pc;
+                base = frame.base;
+                top = base + proto.max_stack;
+            }};
+        }
+        // A field's value, or the ExtraArg's after it if it is saturated
+        macro_rules! extra {
+            ($field:expr, $max:ident) => {
+                match $field {
+                    $max => opcodes::extra_arg(&proto.code, *pc),
+                    field => field,
+                }
+            };
+        }
+
+        macro_rules! reg {
+            ($r:expr) => {
+                *self.stack[base + $r as usize].value()
+            };
+        }
+        macro_rules! set {
+            ($r:expr, $v:expr) => {{
+                let v = $v;
+                self.stack[base + $r as usize].set(v)
+            }};
+        }
+
+        loop {
+            let Some(instruction) = opcodes::decode(proto.code[*pc], *pc) else {
+                unreachable!("unknown opcode");
+            };
+            match instruction {
+                Instruction::Move(a, b) => {
+                    let v = reg!(b).clone();
+                    set!(a, v);
+                }
+                Instruction::LoadK(a, k) => {
+                    let k = extra!(k, MAX_BX);
+                    set!(a, proto.constants[k as usize].clone());
+                }
+                Instruction::LoadNil(a, n) => {
+                    let first = base + a as usize;
+                    for v in &mut self.stack[first..first + n as usize] {
+                        v.set(Value::Nil);
+                    }
+                }
+                Instruction::GetUpvalue(a, i) => {
+                    let v = match &*closure.upvalues[i as usize].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].get(),
+                        Upvalue::Closed(v) => v.clone(),
+                    };
+                    set!(a, v);
+                }
+                Instruction::SetUpvalue(a, i) => {
+                    let v = reg!(a).clone();
+                    match &mut *closure.upvalues[i as usize].borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot].set(v),
+                        Upvalue::Closed(c) => *c = v,
+                    }
+                }
+                Instruction::GetGlobal(a, k) => {
+                    let name = &proto.constants[extra!(k, MAX_BX) as usize];
+                    let slot = &proto.caches[*pc].keys[0];
+                    // Absent globals go through __index if _G has a
+                    // metatable, like any other table
+                    let v = {
+                        let globals = self.globals.borrow();
+                        match globals.get_hinted(name, slot) {
+                            Value::Nil if globals.has_metatable() => None,
+                            v => Some(v),
+                        }
+                    };
+                    let v = match v {
+                        Some(v) => v,
+                        None => {
+                            let globals = Value::Table(self.globals.clone());
+                            self.frames.last_mut().unwrap().pc = *pc;
+                            self.index(globals, name.clone())
+                                .map_err(|e| self.located(e, proto, *pc))?
+                        }
+                    };
+                    set!(a, v);
+                }
+                Instruction::SetGlobal(a, k) => {
+                    let name = &proto.constants[extra!(k, MAX_BX) as usize];
+                    let slot = &proto.caches[*pc].keys[0];
+                    let v = reg!(a).clone();
+                    let v = {
+                        let mut globals = self.globals.borrow_mut();
+                        match globals.replace_hinted(name, v, slot) {
+                            Ok(()) => None,
+                            Err(v) if globals.has_metatable() => Some(v),
+                            Err(v) => {
+                                globals.set(name.clone(), v).unwrap();
+                                None
+                            }
+                        }
+                    };
+                    if let Some(v) = v {
+                        let globals = Value::Table(self.globals.clone());
+                        self.frames.last_mut().unwrap().pc = *pc;
+                        self.set_index(globals, name.clone(), v)
+                            .map_err(|e| self.located(e, proto, *pc))?;
+                    }
+                }
+                Instruction::GetTable(a, b, c) => {
+                    let v = match raw_index(&reg!(b), &reg!(c)) {
+                        Some(v) => v,
+                        None => {
+                            let (object, key) = (reg!(b).clone(), reg!(c).clone());
+                            self.frames.last_mut().unwrap().pc = *pc;
+                            self.index(object, key)
+                                .map_err(|e| self.located(e, proto, *pc))?
+                        }
+                    };
+                    set!(a, v);
+                }
+                Instruction::GetField(a, b, k) => {
+                    let key = &proto.constants[k as usize];
+                    let v = match self.cached_index(&reg!(b), key, &proto.caches[*pc]) {
+                        Some(v) => v,
+                        None => {
+                            let object = reg!(b).clone();
+                            self.frames.last_mut().unwrap().pc = *pc;
+                            self.index(object, key.clone())
+                                .map_err(|e| self.located(e, proto, *pc))?
+                        }
+                    };
+                    set!(a, v);
+                }
+                Instruction::SetTable(a, b, c) => {
+                    let (object, key, value) = (reg!(a).clone(), reg!(b).clone(), reg!(c).clone());
+                    self.frames.last_mut().unwrap().pc = *pc;
+                    self.set_index(object, key, value)
+                        .map_err(|e| self.located(e, proto, *pc))?;
+                }
+                Instruction::SetField(a, k, c) => {
+                    let key = &proto.constants[k as usize];
+                    let (object, value) = (reg!(a).clone(), reg!(c).clone());
+                    let value = match &object {
+                        Value::Table(t) => {
+                            let slot = &proto.caches[*pc].keys[0];
+                            t.borrow_mut().replace_hinted(key, value, slot).err()
+                        }
+                        _ => Some(value),
+                    };
+                    if let Some(value) = value {
+                        self.frames.last_mut().unwrap().pc = *pc;
+                        self.set_index(object, key.clone(), value)
+                            .map_err(|e| self.located(e, proto, *pc))?;
+                    }
+                }
+                Instruction::NewTable(a) => {
+                    set!(a, self.new_table(Table::new()));
+                    self.frames.last_mut().unwrap().pc = *pc;
+                    self.check_gc();
+                }
+                Instruction::SetList(a, n, c) => {
+                    let c = extra!(c, MAX_C);
+                    let first = base + a as usize + 1;
+                    let last = match n {
+                        Some(n) => first + n as usize,
+                        None => self.top,
+                    };
+                    if let Value::Table(t) = &*self.stack[first - 1].value() {
+                        let mut t = t.borrow_mut();
+                        for (i, v) in self.stack[first..last].iter().enumerate() {
+                            let key = Value::Integer(c as i64 + i as i64 + 1);
+                            t.set(key, v.get()).unwrap();
+                        }
+                    }
+                    self.top = top;
+                }
+                Instruction::SelfIndex(a, b, k) => {
+                    let object = reg!(b).clone();
+                    let key = &proto.constants[k as usize];
+                    let v = match self.cached_index(&object, key, &proto.caches[*pc]) {
+                        Some(v) => v,
+                        None => {
+                            self.frames.last_mut().unwrap().pc = *pc;
+                            self.index(object.clone(), key.clone())
+                                .map_err(|e| self.located(e, proto, *pc))?
+                        }
+                    };
+                    set!(a + 1, object);
+                    set!(a, v);
+                }
+                Instruction::Binary(op, a, b, c) => {
+                    let v = match (op, &reg!(b), &reg!(c)) {
+                        (BinaryOp::Add, Value::Integer(x), Value::Integer(y)) => {
+                            Ok(Value::Integer(x.wrapping_add(*y)))
+                        }
+                        (BinaryOp::Subtract, Value::Integer(x), Value::Integer(y)) => {
+                            Ok(Value::Integer(x.wrapping_sub(*y)))
+                        }
+                        (op, x, y) => binary(op, x, y),
+                    };
+                    let v = match v {
+                        Ok(v) => v,
+                        Err(msg) => {
+                            let (left, right) = (reg!(b).clone(), reg!(c).clone());
+                            self.frames.last_mut().unwrap().pc = *pc;
+                            self.binary_metamethod(op, left, right, msg)
+                                .map_err(|e| self.located(e, proto, *pc))?
+                        }
+                    };
+                    set!(a, v);
+                }
+                Instruction::ArithI(op, a, b, i) => {
+                    let add = op == BinaryOp::Add;
+                    let v = match &reg!(b) {
+                        Value::Integer(n) if add => Value::Integer(n.wrapping_add(i as i64)),
+                        Value::Integer(n) => Value::Integer(n.wrapping_sub(i as i64)),
+                        Value::Number(n) if add => Value::Number(n + i as f64),
+                        Value::Number(n) => Value::Number(n - i as f64),
+                        v => {
+                            let v = v.clone();
+                            self.binary_slow(op, v, Value::Integer(i as i64), proto, *pc)?
+                        }
+                    };
+                    set!(a, v);
+                }
+                Instruction::CompareI(op, a, b, i) => {
+                    let ordering = match &reg!(b) {
+                        Value::Integer(n) => Some(Some(n.cmp(&(i as i64)))),
+                        Value::Number(n) => Some(n.partial_cmp(&(i as f64))),
+                        _ => None,
+                    };
+                    let v = match ordering {
+                        Some(o) => Value::Boolean(o.is_some_and(|o| match op {
+                            BinaryOp::LessThan => o.is_lt(),
+                            BinaryOp::LessEqual => o.is_le(),
+                            BinaryOp::GreaterThan => o.is_gt(),
+                            _ => o.is_ge(),
+                        })),
+                        None => {
+                            let v = reg!(b).clone();
+                            self.binary_slow(op, v, Value::Integer(i as i64), proto, *pc)?
+                        }
+                    };
+                    set!(a, v);
+                }
+                Instruction::EqK(a, b, k, equal) => {
+                    let v = (reg!(b) == proto.constants[k as usize]) == equal;
+                    set!(a, Value::Boolean(v));
+                }
+                Instruction::Unary(op, a, b) => {
+                    let v = match unary(op, &reg!(b)) {
+                        Ok(v) => v,
+                        Err(msg) => {
+                            let v = reg!(b).clone();
+                            self.frames.last_mut().unwrap().pc = *pc;
+                            self.unary_metamethod(op, v, msg)
+                                .map_err(|e| self.located(e, proto, *pc))?
+                        }
+                    };
+                    set!(a, v);
+                }
+                Instruction::Jump(target) => {
+                    *pc = target as usize;
+                    continue;
+                }
+                Instruction::JumpIfFalse(a, target) => {
+                    if !reg!(a).truthy() {
+                        *pc = target as usize;
+                        continue;
+                    }
+                }
+                Instruction::JumpIfTrue(a, target) => {
+                    if reg!(a).truthy() {
+                        *pc = target as usize;
+                        continue;
+                    }
+                }
+                Instruction::Call(a, nargs, nresults) => {
+                    let func = base + a as usize;
+                    if let Some(n) = nargs {
+                        self.top = func + 1 + n as usize;
+                    }
+                    let nresults = nresults.map(|n| n as usize);
+                    if self.call_lua(proto, *pc, func, nresults)? {
+                        enter!();
+                        continue;
+                    }
+                    if nresults.is_some() {
+                        self.top = top;
+                    }
+                }
+                Instruction::TailCall(a, nargs) => {
+                    let func = base + a as usize;
+                    if let Some(n) = nargs {
+                        self.top = func + 1 + n as usize;
+                    }
+                    if let Value::Function(value::Function::Lua(_)) = *self.stack[func].value() {
+                        // The callee and its arguments move down to
+                        // where the running function is, which goes
+                        // with its frame
+                        self.close_upvalues(base);
+                        let frame = self.frames.pop().unwrap();
+                        for i in 0..self.top - func {
+                            let v = std::mem::take(&mut self.stack[func + i]);
+                            self.stack[frame.func + i] = v;
+                        }
+                        self.top -= func - frame.func;
+                        self.precall(frame.func, frame.nresults)?;
+                        let callee = self.frames.last_mut().unwrap();
+                        callee.tail = true;
+                        callee.from_native = frame.from_native;
+                        enter!();
+                        continue;
+                    }
+                    if self.call_lua(proto, *pc, func, None)? {
+                        enter!();
+                        continue;
+                    }
+                }
+                Instruction::Return(a, n) => {
+                    if self.tbc.last().is_some_and(|&slot| slot >= base) {
+                        self.frames.last_mut().unwrap().pc = *pc;
+                        self.close_variables(base)?;
+                    }
+                    self.close_upvalues(base);
+                    // The results move down to where the function was,
+                    // which may drop it, so nothing of it is used after
+                    let frame = self.frames.pop().unwrap();
+                    let first = base + a as usize;
+                    let n = n.map_or(self.top - first, |n| n as usize);
+                    let wanted = frame.nresults.unwrap_or(n);
+                    for i in 0..n.min(wanted) {
+                        let v = std::mem::take(&mut self.stack[first + i]);
+                        self.stack[frame.func + i] = v;
+                    }
+                    for v in &mut self.stack[frame.func + n.min(wanted)..frame.func + wanted] {
+                        v.set(Value::Nil);
+                    }
+                    self.top = frame.func + wanted;
+
+                    if self.frames.len() == entry {
+                        return Ok(());
+                    }
+                    enter!();
+                    if frame.nresults.is_some() {
+                        self.top = top;
+                    }
+                }
+                Instruction::Vararg(a, n) => {
+                    let first = base + a as usize;
+                    let n = match n {
+                        Some(n) => n as usize,
+                        None => {
+                            let n = self.frames.last().unwrap().varargs.len();
+                            self.reserve_stack(first + n);
+                            self.top = first + n;
+                            n
+                        }
+                    };
+                    let varargs = &self.frames.last().unwrap().varargs;
+                    for (i, v) in self.stack[first..first + n].iter_mut().enumerate() {
+                        v.set(varargs.get(i).cloned().unwrap_or(Value::Nil));
+                    }
+                }
+                Instruction::Closure(a, index) => {
+                    let child = proto.protos[extra!(index, MAX_BX) as usize].clone();
+                    let mut upvalues = Vec::with_capacity(child.upvalues.len());
+                    for desc in &child.upvalues {
+                        upvalues.push(match desc {
+                            UpvalueDesc::Local(slot) => self.find_upvalue(base + slot),
+                            UpvalueDesc::Upvalue(i) => closure.upvalues[*i].clone(),
+                        });
+                    }
+                    let f = self.new_closure(Closure {
+                        proto: child,
+                        upvalues,
+                    });
+                    set!(a, f);
+                    self.frames.last_mut().unwrap().pc = *pc;
+                    self.check_gc();
+                }
+                Instruction::Close(a) => {
+                    let level = base + a as usize;
+                    if self.tbc.last().is_some_and(|&slot| slot >= level) {
+                        self.frames.last_mut().unwrap().pc = *pc;
+                        self.close_variables(level)?;
+                    }
+                    self.close_upvalues(level);
+                }
+                Instruction::ToBeClosed(a) => {
+                    // nil and false are allowed, and ignored
+                    let v = &reg!(a);
+                    if v.truthy() {
+                        if let Value::Nil = self.metamethod(v, "__close") {
+                            // Stripped chunks have no names, as in Lua
+                            let name = proto.names.get(&*pc).map_or("?", |n| n.as_str());
+                            let msg = format!("variable '{}' got a non-closable value", name);
+                            return Err(self.runtime_error(proto, *pc, msg));
+                        }
+                        self.tbc.push(base + a as usize);
+                    }
+                }
+                Instruction::ForPrep(a, target) => {
+                    let first = base + a as usize;
+                    match for_prep(&mut self.stack[first..first + 3]) {
+                        Ok(Some(v)) => self.stack[first + 3].set(v),
+                        Ok(None) => {
+                            *pc = target as usize;
+                            continue;
+                        }
+                        Err(msg) => return Err(self.runtime_error(proto, *pc, msg)),
+                    }
+                }
+                Instruction::ForLoop(a, target) => {
+                    let first = base + a as usize;
+                    match for_loop(&mut self.stack[first..first + 3]) {
+                        Ok(Some(v)) => {
+                            self.stack[first + 3].set(v);
+                            *pc = target as usize;
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(msg) => return Err(self.runtime_error(proto, *pc, msg)),
+                    }
+                }
+                Instruction::ForInCall(a, n) => {
+                    let func = base + a as usize + 3;
+                    self.reserve_stack(func + 3);
+                    for i in func..func + 3 {
+                        let v = self.stack[i - 3].get();
+                        self.stack[i].set(v);
+                    }
+                    self.top = func + 3;
+                    if self.call_lua(proto, *pc, func, Some(n as usize))? {
+                        enter!();
+                        continue;
+                    }
+                    self.top = top;
+                }
+                Instruction::ExtraArg(_) => {}
+                Instruction::ForInLoop(a, target) => {
+                    let control = reg!(a + 3).clone();
+                    if !matches!(control, Value::Nil) {
+                        set!(a + 2, control);
+                        *pc = target as usize;
+                        continue;
+                    }
+                }
+            }
+
+            *pc += 1;
+        }
+    }
+
+    // Starts a call from the instruction at pc, naming the called
+    // value in the error if it can't be called.
+    fn call_lua(
+        &mut self,
+        proto: &Proto,
+        pc: usize,
+        func: usize,
+        nresults: Option<usize>,
+    ) -> Result<bool, Error> {
+        let f = self.stack[func].value();
+        if !matches!(*f, Value::Function(_)) && matches!(self.metamethod(&f, "__call"), Value::Nil)
+        {
+            let msg = format!("attempt to call a {} value", f.type_name());
+            return Err(self.index_error(proto, pc, msg));
+        }
+
+        self.frames.last_mut().unwrap().pc = pc;
+        self.precall(func, nresults).map_err(|e| match e {
+            Error::Message(msg) => self.runtime_error(proto, pc, msg),
+            e => e,
+        })
+    }
+
+    // raw_index for the field lookups of the instruction with cache,
+    // which also follows __index through tables, as looking up a
+    // method in an object's class does.
+    fn cached_index(&self, object: &Value, key: &Value, cache: &InlineCache) -> Option<Value> {
+        let Value::Table(t) = object else {
+            return None;
+        };
+        let mut t = t.clone();
+        for level in 0..CACHED_LEVELS {
+            let h = {
+                let table = t.borrow();
+                let v = table.get_hinted(key, &cache.keys[level]);
+                match (v, table.metatable()) {
+                    (Value::Nil, Some(mt)) => mt
+                        .borrow()
+                        .get_hinted(&self.index_key, &cache.indexes[level]),
+                    (v, _) => return Some(v),
+                }
+            };
+            match h {
+                Value::Nil => return Some(Value::Nil),
+                Value::Table(h) => t = h,
+                _ => return None,
+            }
+        }
+        None
+    }
+}
+
+// Indexing that needs no metamethod: present keys, and absent keys
+// of tables without a metatable.
+fn raw_index(object: &Value, key: &Value) -> Option<Value> {
+    match object {
+        Value::Table(t) => {
+            let t = t.borrow();
+            match t.get(key) {
+                Value::Nil if t.metatable().is_some() => None,
+                v => Some(v),
+            }
+        }
+        _ => None,
+    }
+}
+
+// Validates a numeric for loop's index, limit and step, leaving the
+// state for_loop expects. Integer loops precompute an iteration count
+// in the limit slot so they cannot overflow. Returns the first value
+// of the loop variable, or None to skip the loop.
+fn for_prep(state: &mut [TValue]) -> Result<Option<Value>, String> {
+    let (init, limit, step) = (state[0].get(), state[1].get(), state[2].get());
+    if let (Value::Integer(init), Value::Integer(step)) = (&init, &step) {
+        let (init, step) = (*init, *step);
+        if step == 0 {
+            return Err("'for' step is zero".to_string());
+        }
+
+        let limit = match &limit {
+            Value::Integer(i) => *i,
+            Value::Number(f) if f.is_nan() => return Ok(None),
+            // Clip float limits to the integer range
+            Value::Number(f) => {
+                let f = if step > 0 { f.floor() } else { f.ceil() };
+                if f >= TWO_POW_63 {
+                    i64::MAX
+                } else if f < -TWO_POW_63 {
+                    i64::MIN
+                } else {
+                    f as i64
+                }
+            }
+            _ => return Err("'for' limit must be a number".to_string()),
+        };
+        if (step > 0 && init > limit) || (step < 0 && init < limit) {
+            return Ok(None);
+        }
+
+        let count = if step > 0 {
+            (limit as u64).wrapping_sub(init as u64) / step as u64
+        } else {
+            (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+        };
+        state[1].set(Value::Integer(count as i64));
+        return Ok(Some(Value::Integer(init)));
+    }
+
+    let number = |v: &Value, what: &str| match v {
+        Value::Integer(i) => Ok(*i as f64),
+        Value::Number(f) => Ok(*f),
+        _ => Err(format!("'for' {} must be a number", what)),
+    };
+    let init = number(&init, "initial value")?;
+    let limit = number(&limit, "limit")?;
+    let step = number(&step, "step")?;
+    if step == 0.0 {
+        return Err("'for' step is zero".to_string());
+    }
+    if (step > 0.0 && limit < init) || (step < 0.0 && init < limit) {
+        return Ok(None);
+    }
+
+    state[0].set(Value::Number(init));
+    state[1].set(Value::Number(limit));
+    state[2].set(Value::Number(step));
+    Ok(Some(Value::Number(init)))
+}
+
+// Steps a numeric for loop, returning the next value of the loop
+// variable or None when it is done. Only corrupted binary chunks reach
+// it with a state for_prep didn't leave.
+fn for_loop(state: &mut [TValue]) -> Result<Option<Value>, String> {
+    // Read in place, as cloning the state costs more than the step
+    let (next, count) = match (&*state[0].value(), &*state[1].value(), &*state[2].value()) {
+        (&Value::Integer(i), &Value::Integer(count), &Value::Integer(step)) => {
+            if count == 0 {
+                return Ok(None);
+            }
+            let count = Value::Integer((count as u64 - 1) as i64);
+            (Value::Integer(i.wrapping_add(step)), Some(count))
+        }
+        (&Value::Number(i), &Value::Number(limit), &Value::Number(step)) => {
+            let next = i + step;
+            let more = if step > 0.0 {
+                next <= limit
+            } else {
+                limit <= next
+            };
+            if !more {
+                return Ok(None);
+            }
+            (Value::Number(next), None)
+        }
+        _ => return Err("'for' loop state is corrupted".to_string()),
+    };
+    state[0].set(next.clone());
+    if let Some(count) = count {
+        state[1].set(count);
+    }
+    Ok(Some(next))
+}
+
+fn arithmetic(op: BinaryOp, left: &Value, right: &Value) -> Result<Value, String> {
+    let (a, b) = match (left.to_number(), right.to_number()) {
+        (Some(a), Some(b)) => (a, b),
+        (None, _) => return Err(arithmetic_error(left)),
+        (_, None) => return Err(arithmetic_error(right)),
+    };
+
+    if let (Value::Integer(a), Value::Integer(b)) = (&a, &b) {
+        let (a, b) = (*a, *b);
+        let result = match op {
+            BinaryOp::Add => Some(a.wrapping_add(b)),
+            BinaryOp::Subtract => Some(a.wrapping_sub(b)),
+            BinaryOp::Multiply => Some(a.wrapping_mul(b)),
+            BinaryOp::FloorDivide => {
+                if b == 0 {
+                    return Err("attempt to perform 'n//0'".to_string());
+                }
+                let q = a.wrapping_div(b);
+                Some(if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+                    q - 1
+                } else {
+                    q
+                })
+            }
+            BinaryOp::Modulo => {
+                if b == 0 {
+                    return Err("attempt to perform 'n%%0'".to_string());
+                }
+                let r = a.wrapping_rem(b);
+                Some(if r != 0 && (r ^ b) < 0 { r + b } else { r })
+            }
+            _ => None,
+        };
+        if let Some(i) = result {
+            return Ok(Value::Integer(i));
+        }
+    }
+
+    let (a, b) = (a.to_float().unwrap(), b.to_float().unwrap());
+    Ok(Value::Number(match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide => a / b,
+        BinaryOp::FloorDivide => (a / b).floor(),
+        BinaryOp::Modulo => {
+            let m = a % b;
+            if m != 0.0 && (m < 0.0) != (b < 0.0) {
+                m + b
+            } else {
+                m
+            }
+        }
+        BinaryOp::Power => a.powf(b),
+        _ => unreachable!("not arithmetic: {:?}", op),
+    }))
+}
+
+fn arithmetic_error(v: &Value) -> String {
+    format!("attempt to perform arithmetic on a {} value", v.type_name())
+}
+
+fn to_bitwise(v: &Value) -> Result<i64, String> {
+    match v.to_number() {
+        None => Err(format!(
+            "attempt to perform bitwise operation on a {} value",
+            v.type_name()
+        )),
+        Some(n) => n
+            .to_integer()
+            .ok_or_else(|| "number has no integer representation".to_string()),
+    }
+}
+
+fn shift_left(a: i64, b: i64) -> i64 {
+    if b <= -64 || b >= 64 {
+        0
+    } else if b < 0 {
+        ((a as u64) >> -b) as i64
+    } else {
+        ((a as u64) << b) as i64
+    }
+}
+
+fn concat_operand(v: &Value) -> Result<Vec<u8>, String> {
+    match v {
+        Value::String(s) => Ok(s.to_vec()),
+        Value::Integer(_) | Value::Number(_) => Ok(v.to_string().into_bytes()),
+        _ => Err(format!("attempt to concatenate a {} value", v.type_name())),
+    }
+}
+
+fn compare_error(a: &Value, b: &Value) -> String {
+    let (a, b) = (a.type_name(), b.type_name());
+    if a == b {
+        format!("attempt to compare two {} values", a)
+    } else {
+        format!("attempt to compare {} with {}", a, b)
+    }
+}
+
+// Integer and float comparisons are exact, even where converting the
+// integer to a float would round.
+fn less_than(a: &Value, b: &Value, or_equal: bool) -> Result<bool, String> {
+    Ok(match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => {
+            if or_equal {
+                a <= b
+            } else {
+                a < b
+            }
+        }
+        (Value::Number(a), Value::Number(b)) => {
+            if or_equal {
+                a <= b
+            } else {
+                a < b
+            }
+        }
+        (Value::Integer(i), Value::Number(f)) => {
+            if f.is_nan() || *f < -TWO_POW_63 {
+                false
+            } else if *f >= TWO_POW_63 {
+                true
+            } else if or_equal {
+                *i <= f.floor() as i64
+            } else {
+                *i < f.ceil() as i64
+            }
+        }
+        (Value::Number(f), Value::Integer(i)) => {
+            if f.is_nan() || *f >= TWO_POW_63 {
+                false
+            } else if *f < -TWO_POW_63 {
+                true
+            } else if or_equal {
+                (f.ceil() as i64) <= *i
+            } else {
+                (f.floor() as i64) < *i
+            }
+        }
+        (Value::String(a), Value::String(b)) => {
+            if or_equal {
+                a.as_bytes() <= b.as_bytes()
+            } else {
+                a.as_bytes() < b.as_bytes()
+            }
+        }
+        _ => return Err(compare_error(a, b)),
+    })
+}
+
+// Operations on values of the right types. Errors, and equality of
+// tables and userdata, fall back to metamethods.
+pub(crate) fn binary(op: BinaryOp, left: &Value, right: &Value) -> Result<Value, String> {
+    let bitwise = |f: fn(i64, i64) -> i64| -> Result<Value, String> {
+        Ok(Value::Integer(f(to_bitwise(left)?, to_bitwise(right)?)))
+    };
+
+    match op {
+        BinaryOp::Add
+        | BinaryOp::Subtract
+        | BinaryOp::Multiply
+        | BinaryOp::Divide
+        | BinaryOp::FloorDivide
+        | BinaryOp::Modulo
+        | BinaryOp::Power => arithmetic(op, left, right),
+        BinaryOp::Concat => {
+            let mut s = concat_operand(left)?;
+            s.extend(concat_operand(right)?);
+            Ok(Value::from(LuaString::from(s)))
+        }
+        BinaryOp::Equal | BinaryOp::NotEqual => {
+            let equal = left == right;
+            match (left, right) {
+                (Value::Table(_), Value::Table(_)) | (Value::UserData(_), Value::UserData(_))
+                    if !equal =>
+                {
+                    Err(String::new())
+                }
+                _ => Ok(Value::Boolean(equal == (op == BinaryOp::Equal))),
+            }
+        }
+        BinaryOp::LessThan => Ok(Value::Boolean(less_than(left, right, false)?)),
+        BinaryOp::LessEqual => Ok(Value::Boolean(less_than(left, right, true)?)),
+        BinaryOp::GreaterThan => Ok(Value::Boolean(less_than(right, left, false)?)),
+        BinaryOp::GreaterEqual => Ok(Value::Boolean(less_than(right, left, true)?)),
+        BinaryOp::BitAnd => bitwise(|a, b| a & b),
+        BinaryOp::BitOr => bitwise(|a, b| a | b),
+        BinaryOp::BitXor => bitwise(|a, b| a ^ b),
+        BinaryOp::ShiftLeft => bitwise(shift_left),
+        BinaryOp::ShiftRight => bitwise(|a, b| shift_left(a, b.wrapping_neg())),
+    }
+}
+
+// Like binary; the length of tables with a __len metamethod comes
+// from the metamethod.
+pub(crate) fn unary(op: UnaryOp, v: &Value) -> Result<Value, String> {
+    match op {
+        UnaryOp::Not => Ok(Value::Boolean(!v.truthy())),
+        UnaryOp::Negate => match v.to_number() {
+            Some(Value::Integer(i)) => Ok(Value::Integer(i.wrapping_neg())),
+            Some(Value::Number(f)) => Ok(Value::Number(-f)),
+            _ => Err(arithmetic_error(v)),
+        },
+        UnaryOp::Length => match v {
+            Value::String(s) => Ok(Value::Integer(s.len() as i64)),
+            Value::Table(t) if t.borrow().metatable().is_none() => {
+                Ok(Value::Integer(t.borrow().len() as i64))
+            }
+            v => Err(format!(
+                "attempt to get length of a {} value",
+                v.type_name()
+            )),
+        },
+        UnaryOp::BitNot => Ok(Value::Integer(!to_bitwise(v)?)),
     }
 }
@@ -0,0 +1,594 @@
+// The cycle collector. Values are reference counted, which frees
+// everything but cycles; this finds cycles nothing outside them refers
+// to and clears them so the counts can drop to zero.
+//
+// Only tables, closures and upvalues can be part of a cycle, so only
+// they are tracked, along with userdata so weak tables can drop them.
+// Strings and other leaf values go with their Rc.
+
+use crate::eval::{Closure, Upvalue, Vm};
+use crate::userdata::AnyUserData;
+use crate::value::{Function, Table, Value};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+// Lua 5.4's defaults, in percent except for the step size, which is
+// the log2 of a number of bytes
+const PAUSE: i64 = 200;
+const STEPSIZE: i64 = 13;
+const MINORMUL: i64 = 20;
+const MAJORMUL: i64 = 100;
+
+// Every collection runs to completion; none is split into steps.
+// Full collections look at every object and minor ones at the objects
+// created since the last collection.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Mode {
+    Full,
+    Generational,
+}
+
+impl Mode {
+    // What collectgarbage calls the mode. Full collections go by Lua's
+    // name for its default mode, so scripts tuning the collector run
+    // unchanged, though they aren't split into steps.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Mode::Full => "incremental",
+            Mode::Generational => "generational",
+        }
+    }
+}
+
+enum Object {
+    Table(Weak<RefCell<Table>>),
+    Closure(Weak<Closure>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+    UserData(Weak<AnyUserData>),
+}
+
+// A tracked object kept alive for the length of a collection.
+enum Node {
+    Table(Rc<RefCell<Table>>),
+    Closure(Rc<Closure>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+    UserData(Rc<AnyUserData>),
+}
+
+fn value_ptr(v: &Value) -> Option<*const ()> {
+    match v {
+        Value::Table(t) => Some(Rc::as_ptr(t) as *const ()),
+        Value::Function(Function::Lua(c)) => Some(Rc::as_ptr(c) as *const ()),
+        Value::UserData(ud) => Some(Rc::as_ptr(ud) as *const ()),
+        _ => None,
+    }
+}
+
+// Whether a table's keys and values are weak, from its metatable's
+// __mode
+fn weakness(t: &Table) -> Option<(bool, bool)> {
+    let mode = match t.metatable()?.borrow().get_str("__mode") {
+        Value::String(mode) => mode,
+        _ => return None,
+    };
+    let (keys, values) = (mode.contains(&b'k'), mode.contains(&b'v'));
+    (keys || values).then_some((keys, values))
+}
+
+impl Object {
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Object::Table(t) => t.upgrade().map(Node::Table),
+            Object::Closure(c) => c.upgrade().map(Node::Closure),
+            Object::Upvalue(u) => u.upgrade().map(Node::Upvalue),
+            Object::UserData(ud) => ud.upgrade().map(Node::UserData),
+        }
+    }
+}
+
+impl Node {
+    fn ptr(&self) -> *const () {
+        match self {
+            Node::Table(t) => Rc::as_ptr(t) as *const (),
+            Node::Closure(c) => Rc::as_ptr(c) as *const (),
+            Node::Upvalue(u) => Rc::as_ptr(u) as *const (),
+            Node::UserData(ud) => Rc::as_ptr(ud) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Table(t) => Rc::strong_count(t),
+            Node::Closure(c) => Rc::strong_count(c),
+            Node::Upvalue(u) => Rc::strong_count(u),
+            Node::UserData(ud) => Rc::strong_count(ud),
+        }
+    }
+
+    fn downgrade(&self) -> Object {
+        match self {
+            Node::Table(t) => Object::Table(Rc::downgrade(t)),
+            Node::Closure(c) => Object::Closure(Rc::downgrade(c)),
+            Node::Upvalue(u) => Object::Upvalue(Rc::downgrade(u)),
+            Node::UserData(ud) => Object::UserData(Rc::downgrade(ud)),
+        }
+    }
+
+    // Borrowed by code running further up the Rust stack, so its
+    // contents can't be looked at
+    fn busy(&self) -> bool {
+        match self {
+            Node::Table(t) => t.try_borrow_mut().is_err(),
+            Node::Upvalue(u) => u.try_borrow_mut().is_err(),
+            Node::Closure(_) | Node::UserData(_) => false,
+        }
+    }
+
+    // Calls f once per strong reference this object holds.
+    fn references<F: FnMut(*const ())>(&self, mut f: F) {
+        match self {
+            Node::Table(t) => {
+                let Ok(t) = t.try_borrow() else { return };
+                t.for_each_value(|v| value_ptr(v).into_iter().for_each(&mut f));
+                if let Some(mt) = t.metatable() {
+                    f(Rc::as_ptr(&mt) as *const ());
+                }
+            }
+            Node::Closure(c) => {
+                for u in &c.upvalues {
+                    f(Rc::as_ptr(u) as *const ());
+                }
+            }
+            Node::Upvalue(u) => {
+                let Ok(u) = u.try_borrow() else { return };
+                if let Upvalue::Closed(v) = &*u {
+                    value_ptr(v).into_iter().for_each(f);
+                }
+            }
+            Node::UserData(ud) => f(Rc::as_ptr(&ud.metatable) as *const ()),
+        }
+    }
+
+    // The weakness of a table that has weak keys or values
+    fn weakness(&self) -> Option<(bool, bool)> {
+        match self {
+            Node::Table(t) => weakness(&*t.try_borrow().ok()?),
+            _ => None,
+        }
+    }
+
+    fn clear(&self) {
+        match self {
+            Node::Table(t) => t.borrow_mut().clear(),
+            // Its upvalues are garbage too, or still in use elsewhere.
+            // The data of a userdata is opaque.
+            Node::Closure(_) | Node::UserData(_) => {}
+            Node::Upvalue(u) => {
+                if let Upvalue::Closed(v) = &mut *u.borrow_mut() {
+                    *v = Value::Nil;
+                }
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Node::Table(t) => t.try_borrow().map_or(mem::size_of::<Table>(), |t| t.size()),
+            Node::Closure(c) => {
+                mem::size_of::<Closure>() + c.upvalues.len() * mem::size_of::<Rc<()>>()
+            }
+            Node::Upvalue(_) => mem::size_of::<RefCell<Upvalue>>(),
+            Node::UserData(_) => mem::size_of::<AnyUserData>(),
+        }
+    }
+}
+
+pub(crate) struct Heap {
+    // Objects created since the last collection, and the ones that
+    // survived one. Minor collections only look at the young.
+    young: Vec<Object>,
+    old: Vec<Object>,
+    // Bytes allocated since the last collection
+    debt: usize,
+    // Bytes in use after the last collection, and after the last
+    // major one
+    estimate: usize,
+    major_estimate: usize,
+    pub(crate) running: bool,
+    pub(crate) mode: Mode,
+    pub(crate) pause: i64,
+    pub(crate) stepsize: i64,
+    pub(crate) minormul: i64,
+    pub(crate) majormul: i64,
+    // Objects with a __gc metamethod, in the order they were marked
+    // for finalization
+    finalizers: Vec<Value>,
+    finalizable: HashSet<*const ()>,
+    // Unreachable objects whose finalizers are still to run
+    pending: Vec<Value>,
+    finalizing: bool,
+    // Set while the state closes, when objects are no longer marked
+    closing: bool,
+}
+
+impl Heap {
+    pub(crate) fn new() -> Heap {
+        Heap {
+            young: vec![],
+            old: vec![],
+            debt: 0,
+            estimate: 0,
+            major_estimate: 0,
+            running: true,
+            mode: Mode::Full,
+            pause: PAUSE,
+            stepsize: STEPSIZE,
+            minormul: MINORMUL,
+            majormul: MAJORMUL,
+            finalizers: vec![],
+            finalizable: HashSet::new(),
+            pending: vec![],
+            finalizing: false,
+            closing: false,
+        }
+    }
+
+    // Like Lua, only objects whose metatable has __gc when it is set
+    // get finalized, and each one only once.
+    pub(crate) fn mark_for_finalization(&mut self, v: Value) {
+        let Some(p) = value_ptr(&v) else { return };
+        if !self.closing && self.finalizable.insert(p) {
+            self.finalizers.push(v);
+        }
+    }
+
+    fn track(&mut self, object: Object, size: usize) {
+        self.young.push(object);
+        self.debt += size;
+    }
+
+    // Bytes allocated between collections. Never less than one step,
+    // so that small heaps aren't collected after every allocation.
+    fn threshold(&self) -> usize {
+        let percent = match self.mode {
+            Mode::Full => self.pause - 100,
+            Mode::Generational => self.minormul,
+        };
+        let step = 1usize << self.stepsize.clamp(0, 40);
+        (self.estimate as i64 * percent / 100).max(step as i64) as usize
+    }
+
+    fn due(&self) -> bool {
+        self.debt >= self.threshold()
+    }
+
+    // In generational mode, whether the old objects grew enough since
+    // the last major collection to be worth looking at again
+    fn major_due(&self) -> bool {
+        let limit = self.major_estimate as i64 * (100 + self.majormul) / 100;
+        self.estimate as i64 > limit
+    }
+
+    // Bytes in use by tracked objects
+    pub(crate) fn count(&self) -> usize {
+        self.young
+            .iter()
+            .chain(&self.old)
+            .filter_map(Object::upgrade)
+            .map(|n| n.size())
+            .sum()
+    }
+
+    // Clears the unreachable tracked objects; the young ones only
+    // unless full.
+    //
+    // The roots are whatever refers to an object from outside the set
+    // being collected: the VM stack and call frames, the globals, the
+    // userdata metatables, open upvalues, older objects in a minor
+    // collection, and values the host holds on to. They are found by
+    // subtracting the references objects in the set hold to each other
+    // from their reference counts; any count left over comes from a
+    // root. Marking then traces everything reachable from those, and
+    // what isn't marked is garbage.
+    //
+    // Unreachable objects marked for finalization are resurrected,
+    // along with everything they refer to, and left in pending for
+    // their finalizers to run. They are freed by a later collection
+    // if the finalizer doesn't store them somewhere.
+    fn collect(&mut self, full: bool) {
+        let mut objects = mem::take(&mut self.young);
+        if full {
+            objects.append(&mut self.old);
+        }
+        let nodes: Vec<Node> = objects.iter().filter_map(Object::upgrade).collect();
+        drop(objects);
+
+        let index: HashMap<*const (), usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.ptr(), i))
+            .collect();
+
+        // Less one for the Rc in nodes
+        let mut external: Vec<isize> = nodes
+            .iter()
+            .map(|n| n.strong_count() as isize - 1)
+            .collect();
+        for node in &nodes {
+            node.references(|p| {
+                if let Some(&i) = index.get(&p) {
+                    external[i] -= 1;
+                }
+            });
+        }
+        // Being marked for finalization doesn't keep an object alive
+        for v in &self.finalizers {
+            if let Some(&i) = value_ptr(v).and_then(|p| index.get(&p)) {
+                external[i] -= 1;
+            }
+        }
+
+        let weak: HashMap<usize, (bool, bool)> = nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, n)| Some((i, n.weakness()?)))
+            .collect();
+        let mut marker = Marker {
+            index: &index,
+            marked: nodes
+                .iter()
+                .zip(&external)
+                .map(|(n, &refs)| refs > 0 || n.busy())
+                .collect(),
+            gray: vec![],
+        };
+        marker.gray = (0..nodes.len()).filter(|&i| marker.marked[i]).collect();
+        marker.propagate(&nodes, &weak);
+
+        // Weak values referring to objects about to be finalized are
+        // cleared before they are resurrected; weak keys only once the
+        // objects are really gone.
+        marker.clear_weak(&nodes, &weak, false);
+        let mut separated = vec![];
+        let finalizable = &mut self.finalizable;
+        self.finalizers.retain(|v| {
+            if marker.alive(v) {
+                return true;
+            }
+            finalizable.remove(&value_ptr(v).unwrap());
+            separated.push(v.clone());
+            false
+        });
+        for v in &separated {
+            marker.mark_value(v);
+        }
+        marker.propagate(&nodes, &weak);
+        marker.clear_weak(&nodes, &weak, true);
+        self.pending.extend(separated);
+
+        let mut live = 0;
+        let marked = marker.marked;
+        for (node, marked) in nodes.iter().zip(marked) {
+            if marked {
+                live += node.size();
+                self.old.push(node.downgrade());
+            } else {
+                node.clear();
+            }
+        }
+        drop(nodes);
+
+        self.debt = 0;
+        if full {
+            self.estimate = live;
+            self.major_estimate = live;
+        } else {
+            self.estimate += live;
+        }
+    }
+}
+
+struct Marker<'a> {
+    index: &'a HashMap<*const (), usize>,
+    marked: Vec<bool>,
+    gray: Vec<usize>,
+}
+
+impl Marker<'_> {
+    fn mark(&mut self, p: *const ()) {
+        if let Some(&i) = self.index.get(&p) {
+            if !self.marked[i] {
+                self.marked[i] = true;
+                self.gray.push(i);
+            }
+        }
+    }
+
+    fn mark_value(&mut self, v: &Value) {
+        if let Some(p) = value_ptr(v) {
+            self.mark(p);
+        }
+    }
+
+    fn propagate(&mut self, nodes: &[Node], weak: &HashMap<usize, (bool, bool)>) {
+        loop {
+            while let Some(i) = self.gray.pop() {
+                match (&nodes[i], weak.get(&i)) {
+                    (Node::Table(t), Some(&(keys, values))) => {
+                        self.trace_weak(&t.borrow(), keys, values)
+                    }
+                    (node, _) => node.references(|p| self.mark(p)),
+                }
+            }
+
+            // An ephemeron's value stays as long as its key does, so
+            // marking values can make more keys reachable
+            for (&i, &(keys, values)) in weak {
+                if let (Node::Table(t), true) = (&nodes[i], self.marked[i]) {
+                    if keys && !values {
+                        self.trace_weak(&t.borrow(), keys, values);
+                    }
+                }
+            }
+            if self.gray.is_empty() {
+                break;
+            }
+        }
+    }
+
+    // Removes the entries of reachable weak tables that refer to
+    // garbage, by value only or by key too.
+    fn clear_weak(&self, nodes: &[Node], weak: &HashMap<usize, (bool, bool)>, by_key: bool) {
+        for (&i, &(keys, values)) in weak {
+            if let (Node::Table(t), true) = (&nodes[i], self.marked[i]) {
+                let dead: Vec<Value> = t
+                    .borrow()
+                    .pairs()
+                    .filter(|(k, v)| by_key && keys && !self.alive(k) || values && !self.alive(v))
+                    .map(|(k, _)| k)
+                    .collect();
+                let mut t = t.borrow_mut();
+                for k in dead {
+                    t.set(k, Value::Nil).unwrap();
+                }
+            }
+        }
+    }
+
+    // Values that aren't objects being collected are never garbage
+    fn alive(&self, v: &Value) -> bool {
+        match value_ptr(v).and_then(|p| self.index.get(&p)) {
+            Some(&i) => self.marked[i],
+            None => true,
+        }
+    }
+
+    // Marks what a weak table keeps alive: its metatable, its strong
+    // keys or values, and the values of weak keys already reachable.
+    fn trace_weak(&mut self, t: &Table, keys: bool, values: bool) {
+        if let Some(mt) = t.metatable() {
+            self.mark(Rc::as_ptr(&mt) as *const ());
+        }
+        for (k, v) in t.pairs() {
+            if !keys {
+                self.mark_value(&k);
+            }
+            if !values && self.alive(&k) {
+                self.mark_value(&v);
+            }
+        }
+    }
+}
+
+impl Vm {
+    pub(crate) fn new_table(&mut self, t: Table) -> Value {
+        let size = t.size();
+        let t = Rc::new(RefCell::new(t));
+        self.heap.track(Object::Table(Rc::downgrade(&t)), size);
+        Value::Table(t)
+    }
+
+    pub(crate) fn new_closure(&mut self, closure: Closure) -> Value {
+        let c = Rc::new(closure);
+        let size = Node::Closure(c.clone()).size();
+        self.heap.track(Object::Closure(Rc::downgrade(&c)), size);
+        Value::Function(Function::Lua(c))
+    }
+
+    pub(crate) fn track_userdata(&mut self, ud: &Rc<AnyUserData>) {
+        let size = mem::size_of::<AnyUserData>();
+        self.heap.track(Object::UserData(Rc::downgrade(ud)), size);
+    }
+
+    pub(crate) fn new_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let u = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        let size = mem::size_of::<RefCell<Upvalue>>();
+        self.heap.track(Object::Upvalue(Rc::downgrade(&u)), size);
+        u
+    }
+
+    // Runs a collection if enough was allocated since the last one.
+    // Called between instructions, where every live value is on the
+    // stack or in a frame.
+    pub(crate) fn check_gc(&mut self) {
+        if self.heap.running && self.heap.due() {
+            self.gc_cycle();
+        }
+    }
+
+    fn gc_cycle(&mut self) {
+        let full = self.heap.mode == Mode::Full || self.heap.major_due();
+        self.drop_stale_slots();
+        self.heap.collect(full);
+        self.run_finalizers();
+    }
+
+    // Calls __gc for the objects collections found unreachable, most
+    // recently marked first. Errors become warnings, as they have no
+    // caller to go to. Collections started by a finalizer leave the
+    // objects they find to the loop already running.
+    fn run_finalizers(&mut self) {
+        if self.heap.finalizing {
+            return;
+        }
+        self.heap.finalizing = true;
+        while let Some(v) = self.heap.pending.pop() {
+            let h = self.metamethod(&v, "__gc");
+            if !matches!(h, Value::Function(_)) {
+                continue;
+            }
+            if let Err(e) = self.call(h, vec![v]) {
+                self.traceback = None;
+                if self.warnings {
+                    eprintln!("Lua warning: error in __gc metamethod ({})", e);
+                }
+            }
+        }
+        self.heap.finalizing = false;
+    }
+
+    // collectgarbage("step", kb): pays kb kilobytes of debt and runs a
+    // collection if that makes one due, or runs one right away when kb
+    // is 0. Tells whether a collection ran, as there are no smaller
+    // steps to take.
+    pub(crate) fn gc_step(&mut self, kb: i64) -> bool {
+        if kb <= 0 {
+            self.collect_garbage();
+            return true;
+        }
+        self.heap.debt = self.heap.debt.saturating_add(kb as usize * 1024);
+        if self.heap.due() {
+            self.gc_cycle();
+            return true;
+        }
+        false
+    }
+
+    // Frees unreachable cycles and finalizes userdata scripts no
+    // longer refer to.
+    pub fn collect_garbage(&mut self) {
+        self.drop_stale_slots();
+        self.heap.collect(true);
+        self.run_finalizers();
+    }
+}
+
+// Closing the state finalizes every object still marked, reachable or
+// not, in the reverse order they were marked, then frees whatever the
+// host doesn't hold on to.
+impl Drop for Vm {
+    fn drop(&mut self) {
+        self.run_finalizers();
+        self.heap.closing = true;
+        let marked = mem::take(&mut self.heap.finalizers);
+        self.heap.pending.extend(marked);
+        self.run_finalizers();
+
+        // Cycles only the state referred to would outlive it otherwise
+        self.drop_roots();
+        self.heap.collect(true);
+    }
+}
@@ -1,50 +1,228 @@
-#[derive(Copy, Clone, Debug)]
-pub struct Location {
-    col: i32,
-    line: i32,
-    index: usize,
+use std::borrow::Cow;
+use std::rc::{Rc, Weak};
+
+use crate::string::LuaString;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FileId(u32);
+
+// A half-open range of byte offsets into a single source file.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
 }
 
-impl Location {
-    fn increment(&self, newline: bool) -> Location {
-        if newline {
-            Location {
-                index: self.index + 1,
-                col: 0,
-                line: self.line + 1,
+impl Span {
+    pub fn new(file: FileId, start: usize, end: usize) -> Span {
+        Span { file, start, end }
+    }
+
+    // Smallest span covering both self and other.
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            file: self.file,
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    pub fn debug<S: Into<String>>(&self, sm: &SourceMap, msg: S) -> String {
+        sm.debug(*self, msg)
+    }
+}
+
+#[derive(Debug)]
+struct SourceFile {
+    name: String,
+    // Lua source is bytes: strings and comments need not be UTF-8
+    src: Vec<u8>,
+    // Offset of the first byte of every line, always starting with
+    // 0, so lookups are a binary search.
+    line_starts: Vec<usize>,
+    // Held by the prototypes compiled from the file, and by the map
+    // until the file is released once compiled. When nothing holds
+    // it, no span refers to the file and its slot is reused.
+    users: Weak<()>,
+    pinned: Option<Rc<()>>,
+}
+
+impl SourceFile {
+    fn new(name: String, src: Vec<u8>, line_starts: Vec<usize>) -> SourceFile {
+        let pinned = Rc::new(());
+        SourceFile {
+            name,
+            src,
+            line_starts,
+            users: Rc::downgrade(&pinned),
+            pinned: Some(pinned),
+        }
+    }
+
+    fn unused(&self) -> bool {
+        self.pinned.is_none() && self.users.strong_count() == 0
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: vec![] }
+    }
+
+    pub fn add<S: Into<String>, B: Into<Vec<u8>>>(&mut self, name: S, src: B) -> FileId {
+        let src = src.into();
+        let mut line_starts = vec![0];
+        for (i, &c) in src.iter().enumerate() {
+            if c == b'\n' {
+                line_starts.push(i + 1);
             }
-        } else {
-            Location {
-                index: self.index + 1,
-                col: self.col + 1,
-                line: self.line,
+        }
+
+        self.insert(SourceFile::new(name.into(), src, line_starts))
+    }
+
+    // Takes the slot of a file nothing uses any more, if there is one,
+    // freeing the source of all such files.
+    fn insert(&mut self, file: SourceFile) -> FileId {
+        let mut free = None;
+        for (i, f) in self.files.iter_mut().enumerate() {
+            if f.unused() {
+                free = free.or(Some(i));
+                f.src = vec![];
+                f.line_starts = vec![];
+            }
+        }
+        match free {
+            Some(i) => {
+                self.files[i] = file;
+                FileId(i as u32)
+            }
+            None => {
+                self.files.push(file);
+                FileId(self.files.len() as u32 - 1)
             }
         }
     }
 
-    pub fn debug<S: Into<String>>(&self, raw: &[char], msg: S) -> String {
-        let mut line = 0;
-        let mut line_str = String::new();
-        // Find the whole line of original source
-        for c in raw {
-            if *c == '\n' {
-                line += 1;
+    // What prototypes compiled from a file hold to keep it, which it
+    // must not have been released yet to give.
+    pub fn users(&self, file: FileId) -> Rc<()> {
+        let f = &self.files[file.0 as usize];
+        f.users.upgrade().expect("source file already released")
+    }
 
-                // Done discovering line in question
-                if !line_str.is_empty() {
-                    break;
-                }
+    // Lets a file go once nothing compiled from it is left, as with
+    // chunks that fail to compile or are done with.
+    pub fn release(&mut self, file: FileId) {
+        self.files[file.0 as usize].pinned = None;
+    }
 
-                continue;
+    // Extends a file with more source, as the REPL does with each
+    // line typed.
+    pub fn append(&mut self, file: FileId, src: &str) {
+        let f = &mut self.files[file.0 as usize];
+        for (i, c) in src.bytes().enumerate() {
+            if c == b'\n' {
+                f.line_starts.push(f.src.len() + i + 1);
             }
+        }
+        f.src.extend_from_slice(src.as_bytes());
+    }
 
-            if self.line == line {
-                line_str.push_str(&c.to_string());
-            }
+    // Stands in for the source of a precompiled chunk, which only
+    // keeps line numbers: a file of empty lines, where line n starts
+    // at offset n - 1. Stripped chunks keep no lines at all.
+    pub fn add_lines<S: Into<String>>(&mut self, name: S, lines: Option<usize>) -> FileId {
+        let line_starts = match lines {
+            Some(n) => (0..n.max(1)).collect(),
+            None => vec![],
+        };
+        let src = vec![b'\n'; lines.unwrap_or(0).saturating_sub(1)];
+        self.insert(SourceFile::new(name.into(), src, line_starts))
+    }
+
+    pub fn source(&self, file: FileId) -> &[u8] {
+        &self.files[file.0 as usize].src
+    }
+
+    pub fn name(&self, file: FileId) -> &str {
+        &self.files[file.0 as usize].name
+    }
+
+    // Zero-based line and byte column of an offset.
+    pub fn lookup(&self, file: FileId, offset: usize) -> (usize, usize) {
+        let starts = &self.files[file.0 as usize].line_starts;
+        let line = match starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        (line, offset - starts[line])
+    }
+
+    // One-based line of a span, unless it is in a stripped chunk.
+    pub fn line_number(&self, span: Span) -> Option<usize> {
+        if self.files[span.file.0 as usize].line_starts.is_empty() {
+            return None;
+        }
+        Some(self.lookup(span.file, span.start).0 + 1)
+    }
+
+    // `chunk:line` for runtime errors. Like Lua, stripped chunks are
+    // at `?:-1`.
+    pub fn position(&self, span: Span) -> String {
+        match self.line_number(span) {
+            Some(line) => format!("{}:{}", self.name(span.file), line),
+            None => "?:-1".to_string(),
         }
+    }
 
-        let space = " ".repeat(self.col as usize);
-        format!("{}\n\n{}\n{}^ Near here", msg.into(), line_str, space)
+    fn line(&self, file: FileId, line: usize) -> &[u8] {
+        let f = &self.files[file.0 as usize];
+        let start = f.line_starts[line];
+        let end = match f.line_starts.get(line + 1) {
+            Some(next) => next - 1,
+            None => f.src.len(),
+        };
+        &f.src[start..end]
+    }
+
+    // Formats msg as `file.lua:12:4: msg` followed by the offending
+    // line with the span underlined. Spans covering several lines
+    // are underlined up to the end of their first line.
+    pub fn debug<S: Into<String>>(&self, span: Span, msg: S) -> String {
+        let (line, col) = self.lookup(span.file, span.start);
+        let line_bytes = self.line(span.file, line);
+        let (end_line, end_col) = self.lookup(span.file, span.end.max(span.start + 1));
+        let end_col = if end_line == line {
+            end_col.min(line_bytes.len())
+        } else {
+            line_bytes.len()
+        };
+
+        // Columns are reported in characters, not bytes, with bytes
+        // that are not UTF-8 shown as one replacement character each
+        let chars = |bytes: &[u8]| String::from_utf8_lossy(bytes).chars().count();
+        let col_bytes = col.min(line_bytes.len());
+        let col = chars(&line_bytes[..col_bytes]) + (col - col_bytes);
+        let width = chars(&line_bytes[..end_col]).saturating_sub(col);
+        let line_str = String::from_utf8_lossy(line_bytes);
+
+        format!(
+            "{}:{}:{}: {}\n\n{}\n{}{}",
+            self.name(span.file),
+            line + 1,
+            col + 1,
+            msg.into(),
+            line_str,
+            " ".repeat(col),
+            "^".repeat(width.max(1))
+        )
     }
 }
 
@@ -54,210 +232,757 @@ pub enum TokenKind {
     Syntax,
     Keyword,
     Number,
+    String,
+    Nil,
+    Boolean,
     Operator,
+    // Only produced when trivia is kept
+    Comment,
+}
+
+#[derive(Debug, Clone)]
+enum Text {
+    // Keywords, symbols, nil and booleans, which are never interned
+    Fixed(&'static str),
+    // Numerals as written
+    Numeral(Box<str>),
+    // Names, string literals and comments share the interned strings
+    // values use
+    Value(LuaString),
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
-    pub value: String,
     pub kind: TokenKind,
-    pub loc: Location,
+    pub span: Span,
+    text: Text,
 }
 
-fn lex_operator(raw: &[char], initial_loc: Location) -> Option<(Token, Location)> {
-    let operators = ["+", "-", "<"];
-
-    for possible_syntax in operators {
-        let c = raw[initial_loc.index];
-        let next_loc = initial_loc.increment(false);
-        // TODO: this won't work with multiple-character operators like >= or ==
-        if possible_syntax == c.to_string() {
-            return Some((
-                Token {
-                    value: possible_syntax.to_string(),
-                    loc: initial_loc,
-                    kind: TokenKind::Operator,
-                },
-                next_loc,
-            ));
+impl Token {
+    // The source text of keywords, names, numerals and symbols, which
+    // is always UTF-8. String literals are only looked at through
+    // value().
+    pub fn text(&self) -> &str {
+        match &self.text {
+            Text::Fixed(s) => s,
+            Text::Numeral(s) => s,
+            Text::Value(s) => s.to_str().unwrap_or_default(),
         }
     }
 
-    None
+    // The string of a name, string literal or comment.
+    pub fn value(&self) -> &LuaString {
+        match &self.text {
+            Text::Value(s) => s,
+            _ => panic!("{:?} token has no string value", self.kind),
+        }
+    }
 }
 
-fn lex_syntax(raw: &[char], initial_loc: Location) -> Option<(Token, Location)> {
-    let syntax = [";", "=", "(", ")", ","];
-
-    for possible_syntax in syntax {
-        let c = raw[initial_loc.index];
-        let next_loc = initial_loc.increment(false);
-        // TODO: this won't work with multiple-character syntax bits like >= or ==
-        if possible_syntax == c.to_string() {
-            return Some((
-                Token {
-                    value: possible_syntax.to_string(),
-                    loc: initial_loc,
-                    kind: TokenKind::Syntax,
-                },
-                next_loc,
-            ));
-        }
+#[derive(Debug, Clone)]
+pub struct LexError {
+    pub span: Span,
+    pub msg: &'static str,
+    // True if more input could have completed the token, used by the
+    // REPL to decide whether to prompt for another line.
+    pub incomplete: bool,
+}
+
+impl LexError {
+    pub fn debug(&self, sm: &SourceMap) -> String {
+        self.span.debug(sm, self.msg)
     }
+}
 
-    None
+// Lua 5.4 reserved words. nil, true and false are lexed as literal
+// tokens rather than keywords.
+const KEYWORDS: [&str; 19] = [
+    "and", "break", "do", "else", "elseif", "end", "for", "function", "goto", "if", "in", "local",
+    "not", "or", "repeat", "return", "then", "until", "while",
+];
+
+const OPERATORS: [&str; 20] = [
+    "+", "-", "*", "/", "//", "%", "^", "#", "&", "~", "|", "<<", ">>", "==", "~=", "<=", ">=",
+    "<", ">", "..",
+];
+
+const SYNTAX: [&str; 13] = [
+    "...", "::", "=", "(", ")", "{", "}", "[", "]", ";", ":", ",", ".",
+];
+
+fn is_identifier_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
 }
 
-fn lex_keyword(raw: &[char], initial_loc: Location) -> Option<(Token, Location)> {
-    let syntax = ["function", "end", "if", "then", "local", "return"];
-
-    let mut next_loc = initial_loc;
-    let mut value = String::new();
-    'outer: for possible_syntax in syntax {
-        let mut c = raw[initial_loc.index];
-        next_loc = initial_loc;
-        while c.is_alphanumeric() || c == '_' {
-            value.push_str(&c.to_string());
-            next_loc = next_loc.increment(false);
-            c = raw[next_loc.index];
-
-            let n = next_loc.index - initial_loc.index;
-            if value != possible_syntax[..n] {
-                value = String::new();
-                continue 'outer;
-            }
-        }
+enum Step {
+    Match(Token, usize),
+    NoMatch,
+    // The token may continue past the end of the input fed so far
+    NeedMore,
+}
 
-        // Not a complete match
-        if value.len() < possible_syntax.len() {
-            value = String::new();
-            continue;
+// Lexes a source buffer into tokens on demand. A Lexer built with
+// new() borrows a complete source. One built with incremental() owns
+// its buffer and is fed with feed(): iteration stops (returns None)
+// whenever the rest of the buffer might be the start of a longer
+// token, and resumes after the next feed(). There is no end of input,
+// so the last token must be followed by whitespace, as the newline
+// ending each line the REPL feeds is.
+pub struct Lexer<'a> {
+    file: FileId,
+    src: Cow<'a, [u8]>,
+    index: usize,
+    eof: bool,
+    failed: bool,
+    trivia: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(file: FileId, src: &'a [u8]) -> Lexer<'a> {
+        Lexer {
+            file,
+            src: Cow::Borrowed(src),
+            index: 0,
+            eof: true,
+            failed: false,
+            trivia: false,
         }
+    }
 
-        // If it got to this point it found a match, so exit early.
-        // We don't need a longest match.
-        break;
+    // Keep comments as Comment tokens, for tooling that needs to see
+    // them.
+    pub fn with_trivia(mut self) -> Lexer<'a> {
+        self.trivia = true;
+        self
     }
 
-    if value.is_empty() {
-        return None;
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span::new(self.file, start, end)
     }
 
-    // If the next character would be part of a valid identifier, then
-    // this is not a keyword.
-    if next_loc.index < raw.len() - 1 {
-        let next_c = raw[next_loc.index];
-        if next_c.is_alphanumeric() || next_c == '_' {
-            return None;
+    fn token_with_value(&self, kind: TokenKind, start: usize, end: usize, value: &[u8]) -> Token {
+        Token {
+            kind,
+            span: self.span(start, end),
+            text: Text::Value(LuaString::from(value)),
         }
     }
 
-    Some((
+    fn fixed_token(&self, kind: TokenKind, start: usize, text: &'static str) -> Token {
         Token {
-            value,
-            loc: initial_loc,
-            kind: TokenKind::Keyword,
-        },
-        next_loc,
-    ))
-}
+            kind,
+            span: self.span(start, start + text.len()),
+            text: Text::Fixed(text),
+        }
+    }
 
-fn lex_identifier(raw: &[char], initial_loc: Location) -> Option<(Token, Location)> {
-    let mut ident = String::new();
-    let mut next_loc = initial_loc;
-    let mut c = raw[initial_loc.index];
-    while c.is_alphanumeric() || c == '_' {
-        ident.push_str(&c.to_string());
-        next_loc = next_loc.increment(false);
-        c = raw[next_loc.index];
+    fn lex_symbol(&mut self, start: usize) -> Result<Step, LexError> {
+        let raw = &self.src[start..];
+        // Longest match first, so `...` wins over `..` and `.`
+        for len in [3, 2, 1] {
+            if raw.len() < len {
+                continue;
+            }
+
+            let candidate = &raw[..len];
+            let operator = OPERATORS.iter().find(|op| op.as_bytes() == candidate);
+            let syntax = SYNTAX.iter().find(|op| op.as_bytes() == candidate);
+            let (kind, text) = match (operator, syntax) {
+                (Some(op), _) => (TokenKind::Operator, *op),
+                (_, Some(op)) => (TokenKind::Syntax, *op),
+                _ => continue,
+            };
+
+            // A longer symbol might start with this one
+            if len == raw.len() && len < 3 && !self.eof {
+                return Ok(Step::NeedMore);
+            }
+
+            return Ok(Step::Match(
+                self.fixed_token(kind, start, text),
+                start + len,
+            ));
+        }
+
+        Ok(Step::NoMatch)
+    }
+
+    fn lex_name(&mut self, start: usize) -> Result<Step, LexError> {
+        let raw = &self.src[..];
+        // First character must not be a digit
+        if !is_identifier_byte(raw[start]) || raw[start].is_ascii_digit() {
+            return Ok(Step::NoMatch);
+        }
+
+        let mut next = start;
+        while next < raw.len() && is_identifier_byte(raw[next]) {
+            next += 1;
+        }
+
+        if next == raw.len() && !self.eof {
+            return Ok(Step::NeedMore);
+        }
+
+        let token = match &self.src[start..next] {
+            b"nil" => self.fixed_token(TokenKind::Nil, start, "nil"),
+            b"true" => self.fixed_token(TokenKind::Boolean, start, "true"),
+            b"false" => self.fixed_token(TokenKind::Boolean, start, "false"),
+            v => match KEYWORDS.iter().find(|k| k.as_bytes() == v) {
+                Some(k) => self.fixed_token(TokenKind::Keyword, start, k),
+                None => self.token_with_value(
+                    TokenKind::Identifier,
+                    start,
+                    next,
+                    &self.src[start..next],
+                ),
+            },
+        };
+
+        Ok(Step::Match(token, next))
     }
 
-    // First character must not be a digit
-    if !ident.is_empty() && !ident.chars().next().unwrap().is_digit(10) {
-        Some((
+    fn lex_number(&mut self, start: usize) -> Result<Step, LexError> {
+        let raw = &self.src[..];
+        let starts_number = raw[start].is_ascii_digit()
+            || (raw[start] == b'.' && raw.get(start + 1).is_some_and(u8::is_ascii_digit));
+        if !starts_number {
+            return Ok(Step::NoMatch);
+        }
+
+        // Like Lua, greedily take anything that could be part of a
+        // numeral and validate the whole thing afterwards.
+        let exponent = if raw[start..].starts_with(b"0x") || raw[start..].starts_with(b"0X") {
+            [b'p', b'P']
+        } else {
+            [b'e', b'E']
+        };
+        let mut next = start;
+        while next < raw.len() {
+            let c = raw[next];
+            if exponent.contains(&c) && matches!(raw.get(next + 1), Some(b'+') | Some(b'-')) {
+                next += 2;
+            } else if is_identifier_byte(c) || c == b'.' {
+                next += 1;
+            } else {
+                break;
+            }
+        }
+
+        if next >= raw.len() && !self.eof {
+            return Ok(Step::NeedMore);
+        }
+
+        // Only ASCII bytes can have been taken
+        let numeral = match std::str::from_utf8(&self.src[start..next]) {
+            Ok(numeral) if str_to_number(numeral).is_some() => numeral.into(),
+            _ => {
+                return Err(LexError {
+                    span: self.span(start, next),
+                    msg: "Malformed number:",
+                    incomplete: false,
+                })
+            }
+        };
+
+        Ok(Step::Match(
             Token {
-                value: ident,
-                loc: initial_loc,
-                kind: TokenKind::Identifier,
+                kind: TokenKind::Number,
+                span: self.span(start, next),
+                text: Text::Numeral(numeral),
             },
-            next_loc,
+            next,
         ))
-    } else {
-        None
     }
-}
 
-fn lex_number(raw: &[char], initial_loc: Location) -> Option<(Token, Location)> {
-    let mut ident = String::new();
-    let mut next_loc = initial_loc;
-    let mut c = raw[initial_loc.index];
-    while c.is_digit(10) {
-        ident.push_str(&c.to_string());
-        next_loc = next_loc.increment(false);
-        c = raw[next_loc.index];
+    fn lex_string(&mut self, start: usize) -> Result<Step, LexError> {
+        let raw = &self.src[..];
+        if let Some((level, body)) = long_bracket_open(raw, start) {
+            let next = match long_bracket_close(raw, body, level) {
+                Some(next) => next,
+                None if !self.eof => return Ok(Step::NeedMore),
+                None => {
+                    return Err(LexError {
+                        span: self.span(start, raw.len()),
+                        msg: "Unfinished long string:",
+                        incomplete: true,
+                    });
+                }
+            };
+
+            // A newline directly after the opening bracket is skipped
+            let mut contents = &raw[body..next - level - 2];
+            for newline in [&b"\r\n"[..], b"\n\r", b"\n", b"\r"] {
+                if let Some(rest) = contents.strip_prefix(newline) {
+                    contents = rest;
+                    break;
+                }
+            }
+            let mut value = Vec::with_capacity(contents.len());
+            for (i, &c) in contents.iter().enumerate() {
+                if !(c == b'\r' && contents.get(i + 1) == Some(&b'\n')) {
+                    value.push(c);
+                }
+            }
+            return Ok(Step::Match(
+                self.token_with_value(TokenKind::String, start, next, &value),
+                next,
+            ));
+        }
+
+        let quote = raw[start];
+        if quote != b'"' && quote != b'\'' {
+            return Ok(Step::NoMatch);
+        }
+
+        let mut value: Vec<u8> = vec![];
+        let mut next = start + 1;
+        loop {
+            let c = match raw.get(next) {
+                Some(c) => *c,
+                None if !self.eof => return Ok(Step::NeedMore),
+                None => {
+                    return Err(LexError {
+                        span: self.span(start, raw.len()),
+                        msg: "Unfinished string:",
+                        incomplete: true,
+                    });
+                }
+            };
+
+            if c == quote {
+                next += 1;
+                break;
+            }
+
+            if c == b'\n' || c == b'\r' {
+                return Err(LexError {
+                    span: self.span(start, next),
+                    msg: "Unfinished string:",
+                    incomplete: false,
+                });
+            }
+
+            if c != b'\\' {
+                value.push(c);
+                next += 1;
+                continue;
+            }
+
+            let escape_start = next;
+            next += 1;
+            let e = match raw.get(next) {
+                Some(e) => *e,
+                None if !self.eof => return Ok(Step::NeedMore),
+                None => {
+                    return Err(LexError {
+                        span: self.span(start, raw.len()),
+                        msg: "Unfinished string:",
+                        incomplete: true,
+                    });
+                }
+            };
+            next += 1;
+            match e {
+                b'a' => value.push(7),
+                b'b' => value.push(8),
+                b'f' => value.push(12),
+                b'n' => value.push(b'\n'),
+                b'r' => value.push(b'\r'),
+                b't' => value.push(b'\t'),
+                b'v' => value.push(11),
+                b'\\' | b'"' | b'\'' => value.push(e),
+                b'\n' | b'\r' => {
+                    // An escaped line break is kept as a newline
+                    let other = if e == b'\n' { b'\r' } else { b'\n' };
+                    if raw.get(next) == Some(&other) {
+                        next += 1;
+                    }
+                    value.push(b'\n');
+                }
+                b'z' => {
+                    while next < raw.len() && raw[next].is_ascii_whitespace() {
+                        next += 1;
+                    }
+                }
+                b'x' => {
+                    let digits = raw.get(next..next + 2).and_then(|d| {
+                        std::str::from_utf8(d)
+                            .ok()
+                            .and_then(|d| u8::from_str_radix(d, 16).ok())
+                    });
+                    match digits {
+                        Some(byte) => {
+                            value.push(byte);
+                            next += 2;
+                        }
+                        None => {
+                            return Err(LexError {
+                                span: self.span(escape_start, next),
+                                msg: "Hexadecimal digit expected in escape sequence:",
+                                incomplete: false,
+                            });
+                        }
+                    }
+                }
+                b'u' => {
+                    let close = raw[next..].iter().position(|c| *c == b'}');
+                    let code = match (raw.get(next), close) {
+                        (Some(b'{'), Some(close)) => {
+                            std::str::from_utf8(&raw[next + 1..next + close])
+                                .ok()
+                                .and_then(|d| u32::from_str_radix(d, 16).ok())
+                                .and_then(char::from_u32)
+                                .map(|c| (c, close))
+                        }
+                        _ => None,
+                    };
+                    match code {
+                        Some((c, close)) => {
+                            let mut buf = [0; 4];
+                            value.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                            next += close + 1;
+                        }
+                        None => {
+                            return Err(LexError {
+                                span: self.span(escape_start, next),
+                                msg: "Invalid UTF-8 escape sequence:",
+                                incomplete: false,
+                            });
+                        }
+                    }
+                }
+                b'0'..=b'9' => {
+                    let mut n = (e - b'0') as u32;
+                    for _ in 0..2 {
+                        match raw.get(next) {
+                            Some(d) if d.is_ascii_digit() => {
+                                n = n * 10 + (d - b'0') as u32;
+                                next += 1;
+                            }
+                            _ => break,
+                        }
+                    }
+                    if n > 255 {
+                        return Err(LexError {
+                            span: self.span(escape_start, next),
+                            msg: "Decimal escape too large:",
+                            incomplete: false,
+                        });
+                    }
+                    value.push(n as u8);
+                }
+                _ => {
+                    return Err(LexError {
+                        span: self.span(escape_start, next),
+                        msg: "Invalid escape sequence:",
+                        incomplete: false,
+                    });
+                }
+            }
+        }
+
+        Ok(Step::Match(
+            self.token_with_value(TokenKind::String, start, next, &value),
+            next,
+        ))
     }
 
-    if !ident.is_empty() {
-        Some((
-            Token {
-                value: ident,
-                loc: initial_loc,
-                kind: TokenKind::Number,
+    fn lex_comment(&mut self, start: usize) -> Result<Step, LexError> {
+        let raw = &self.src[..];
+        if raw[start] != b'-' {
+            return Ok(Step::NoMatch);
+        }
+
+        match raw.get(start + 1) {
+            Some(b'-') => {}
+            None if !self.eof => return Ok(Step::NeedMore),
+            _ => return Ok(Step::NoMatch),
+        }
+
+        let next = match long_bracket_open(raw, start + 2) {
+            Some((level, body)) => match long_bracket_close(raw, body, level) {
+                Some(next) => next,
+                None => {
+                    if !self.eof {
+                        return Ok(Step::NeedMore);
+                    }
+
+                    return Err(LexError {
+                        span: self.span(start, raw.len()),
+                        msg: "Unfinished long comment:",
+                        incomplete: true,
+                    });
+                }
             },
-            next_loc,
+            // `--[` or `--[==` might still become a long bracket
+            None if !self.eof && raw[start + 2..].iter().all(|c| *c == b'[' || *c == b'=') => {
+                return Ok(Step::NeedMore);
+            }
+            None => match raw[start..].iter().position(|c| *c == b'\n') {
+                Some(n) => start + n,
+                None if !self.eof => return Ok(Step::NeedMore),
+                None => raw.len(),
+            },
+        };
+
+        Ok(Step::Match(
+            self.token_with_value(TokenKind::Comment, start, next, &raw[start..next]),
+            next,
         ))
-    } else {
-        None
+    }
+
+    // A `#` on the first line is a Unix shebang, which Lua skips up
+    // to (but not including) the newline.
+    fn eat_shebang(&mut self) -> bool {
+        let raw = &self.src[..];
+        if self.index != 0 || raw.first() != Some(&b'#') {
+            return true;
+        }
+
+        match raw.iter().position(|c| *c == b'\n') {
+            Some(n) => self.index = n,
+            None if !self.eof => return false,
+            None => self.index = raw.len(),
+        }
+
+        true
+    }
+
+    fn eat_whitespace(&mut self) {
+        let raw = &self.src[..];
+        while self.index < raw.len() && [b' ', b'\n', b'\r', b'\t'].contains(&raw[self.index]) {
+            self.index += 1;
+        }
+    }
+}
+
+// Used by the REPL, which feeds lines as they are typed.
+impl Lexer<'static> {
+    pub fn incremental(file: FileId) -> Lexer<'static> {
+        Lexer {
+            file,
+            src: Cow::Owned(vec![]),
+            index: 0,
+            eof: false,
+            failed: false,
+            trivia: false,
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &str) {
+        self.src.to_mut().extend_from_slice(chunk.as_bytes());
+    }
+
+    // True if buffered input has not been turned into tokens yet.
+    pub fn pending(&self) -> bool {
+        self.index < self.src.len()
     }
 }
 
-fn eat_whitespace(raw: &[char], initial_loc: Location) -> Location {
-    let mut c = raw[initial_loc.index];
-    let mut next_loc = initial_loc;
-    while [' ', '\n', '\r', '\t'].contains(&c) {
-        next_loc = next_loc.increment(c == '\n');
-        if next_loc.index == raw.len() {
-            break;
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Result<Token, LexError>> {
+        if self.failed || !self.eat_shebang() {
+            return None;
         }
-        c = raw[next_loc.index];
+
+        loop {
+            self.eat_whitespace();
+            if self.index == self.src.len() {
+                return None;
+            }
+
+            let start = self.index;
+            let step = match self.lex_comment(start) {
+                Ok(step) => step,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+            match step {
+                Step::Match(t, next) => {
+                    self.index = next;
+                    if self.trivia {
+                        return Some(Ok(t));
+                    }
+                    continue;
+                }
+                Step::NeedMore => return None,
+                Step::NoMatch => {}
+            }
+
+            let lexers = [
+                Lexer::lex_name,
+                Lexer::lex_number,
+                Lexer::lex_string,
+                Lexer::lex_symbol,
+            ];
+            for lexer in lexers {
+                let step = match lexer(self, start) {
+                    Ok(step) => step,
+                    Err(e) => {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                };
+                match step {
+                    Step::Match(t, next) => {
+                        self.index = next;
+                        return Some(Ok(t));
+                    }
+                    Step::NeedMore => return None,
+                    Step::NoMatch => {}
+                }
+            }
+
+            self.failed = true;
+            let first = self.src[start..].utf8_chunks().next();
+            let len = first
+                .and_then(|c| c.valid().chars().next())
+                .map_or(1, char::len_utf8);
+            let end = start + len;
+            return Some(Err(LexError {
+                span: self.span(start, end),
+                msg: "Unrecognized character while lexing:",
+                incomplete: false,
+            }));
+        }
+    }
+}
+
+// Matches the opening of a long bracket, `[`, any number of `=`,
+// then `[`, returning the level (number of `=`) and the index after
+// the opening.
+fn long_bracket_open(raw: &[u8], start: usize) -> Option<(usize, usize)> {
+    if raw.get(start) != Some(&b'[') {
+        return None;
+    }
+
+    let mut next = start + 1;
+    while raw.get(next) == Some(&b'=') {
+        next += 1;
     }
 
-    next_loc
+    if raw.get(next) != Some(&b'[') {
+        return None;
+    }
+
+    Some((next - start - 1, next + 1))
 }
 
-pub fn lex(s: &[char]) -> Result<Vec<Token>, String> {
-    let mut loc = Location {
-        col: 0,
-        index: 0,
-        line: 0,
+// Finds the closing long bracket of the same level, returning the
+// index after it.
+fn long_bracket_close(raw: &[u8], start: usize, level: usize) -> Option<usize> {
+    let mut next = start;
+    while next < raw.len() {
+        if raw[next] == b']' {
+            let mut end = next + 1;
+            while raw.get(end) == Some(&b'=') {
+                end += 1;
+            }
+
+            if end - next - 1 == level && raw.get(end) == Some(&b']') {
+                return Some(end + 1);
+            }
+        }
+
+        next += 1;
+    }
+
+    None
+}
+
+// The tokens of a file, keeping comments as Comment tokens.
+pub fn lex_with_trivia(sm: &SourceMap, file: FileId) -> Result<Vec<Token>, String> {
+    Lexer::new(file, sm.source(file))
+        .with_trivia()
+        .collect::<Result<Vec<Token>, LexError>>()
+        .map_err(|e| e.debug(sm))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Numeral {
+    Integer(i64),
+    Float(f64),
+}
+
+// Converts a Lua numeral, allowing surrounding whitespace and a sign
+// like tonumber does. Decimal integers that overflow become floats;
+// hexadecimal integers wrap around.
+pub fn str_to_number(s: &str) -> Option<Numeral> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, body) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
     };
-    let size = s.len();
-    let mut tokens: Vec<Token> = vec![];
-
-    let lexers = [
-        lex_keyword,
-        lex_identifier,
-        lex_number,
-        lex_syntax,
-        lex_operator,
-    ];
-    'outer: while loc.index < size {
-        loc = eat_whitespace(s, loc);
-        if loc.index == size {
-            break;
-        }
-
-        for lexer in lexers {
-            let res = lexer(s, loc);
-            if let Some((t, next_loc)) = res {
-                loc = next_loc;
-                tokens.push(t);
-                continue 'outer;
+
+    if let Some(hex) = body.strip_prefix("0x").or_else(|| body.strip_prefix("0X")) {
+        if !hex.is_empty() && hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            let mut n: i64 = 0;
+            for c in hex.chars() {
+                n = n
+                    .wrapping_mul(16)
+                    .wrapping_add(c.to_digit(16).unwrap() as i64);
             }
+            return Some(Numeral::Integer(if negative {
+                n.wrapping_neg()
+            } else {
+                n
+            }));
+        }
+
+        let f = hex_to_float(hex)?;
+        return Some(Numeral::Float(if negative { -f } else { f }));
+    }
+
+    if !body.is_empty() && body.bytes().all(|c| c.is_ascii_digit()) {
+        if let Ok(n) = s.parse::<i64>() {
+            return Some(Numeral::Integer(n));
         }
+    }
+
+    // Rust would also accept inf and nan, Lua does not
+    let valid = body
+        .bytes()
+        .next()
+        .is_some_and(|c| c.is_ascii_digit() || c == b'.')
+        && body
+            .bytes()
+            .all(|c| c.is_ascii_digit() || b".eE+-".contains(&c));
+    if !valid {
+        return None;
+    }
+
+    let f = body.parse::<f64>().ok()?;
+    Some(Numeral::Float(if negative { -f } else { f }))
+}
 
-        return Err(loc.debug(s, "Unrecognized character while lexing:"));
+fn hex_to_float(s: &str) -> Option<f64> {
+    let (mantissa, exponent) = match s.find(['p', 'P']) {
+        Some(i) => (&s[..i], s[i + 1..].parse::<i32>().ok()?),
+        None => (s, 0),
+    };
+
+    let (whole, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, ""),
+    };
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+
+    let mut f = 0.0;
+    for c in whole.chars() {
+        f = f * 16.0 + c.to_digit(16)? as f64;
+    }
+    let mut scale = 1.0 / 16.0;
+    for c in fraction.chars() {
+        f += c.to_digit(16)? as f64 * scale;
+        scale /= 16.0;
     }
 
-    Ok(tokens)
+    Some(f * 2f64.powi(exponent))
 }
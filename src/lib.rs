@@ -0,0 +1,35 @@
+// lust can be embedded: create a Lua state, load chunks into it and
+// exchange values with them.
+//
+//     let mut lua = lust::Lua::new();
+//     lua.load("x = 6 * 7", "config")?.exec()?;
+//     let x = lua.get_global("x");
+
+mod conv;
+mod dump;
+mod eval;
+mod gc;
+mod lex;
+mod listing;
+mod lua;
+mod meta;
+mod native;
+mod opcodes;
+mod optimize;
+mod parse;
+pub mod repl;
+pub mod serialize;
+mod stdlib;
+mod string;
+mod tvalue;
+mod userdata;
+mod value;
+
+pub use conv::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti};
+pub use eval::Vm as Lua;
+pub use lua::Chunk;
+pub use native::{Args, NativeCall, NativeFunction};
+pub use serialize::{from_value, to_value};
+pub use string::LuaString;
+pub use userdata::{AnyUserData, UserData, UserDataMethods};
+pub use value::{Error, Function, Table, Value};
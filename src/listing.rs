@@ -0,0 +1,521 @@
+// Human-readable dumps of what the compiler works with: luac -l style
+// listings of bytecode, and syntax trees.
+
+use crate::eval::{BinaryOp, Instruction, Proto, UnaryOp, UpvalueDesc};
+use crate::lex::{SourceMap, Span, Token};
+use crate::opcodes;
+use crate::parse::*;
+use crate::value::Value;
+use std::fmt::Write;
+
+fn binary_name(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "ADD",
+        BinaryOp::Subtract => "SUB",
+        BinaryOp::Multiply => "MUL",
+        BinaryOp::Divide => "DIV",
+        BinaryOp::FloorDivide => "IDIV",
+        BinaryOp::Modulo => "MOD",
+        BinaryOp::Power => "POW",
+        BinaryOp::Concat => "CONCAT",
+        BinaryOp::Equal => "EQ",
+        BinaryOp::NotEqual => "NE",
+        BinaryOp::LessThan => "LT",
+        BinaryOp::LessEqual => "LE",
+        BinaryOp::GreaterThan => "GT",
+        BinaryOp::GreaterEqual => "GE",
+        BinaryOp::BitAnd => "BAND",
+        BinaryOp::BitOr => "BOR",
+        BinaryOp::BitXor => "BXOR",
+        BinaryOp::ShiftLeft => "SHL",
+        BinaryOp::ShiftRight => "SHR",
+    }
+}
+
+fn unary_name(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Negate => "UNM",
+        UnaryOp::Not => "NOT",
+        UnaryOp::Length => "LEN",
+        UnaryOp::BitNot => "BNOT",
+    }
+}
+
+// Strings as a Lua literal, with the bytes that aren't printable
+// escaped.
+fn quote(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            b'\n' => s.push_str("\\n"),
+            b'\r' => s.push_str("\\r"),
+            b'\t' => s.push_str("\\t"),
+            b' '..=b'~' => s.push(b as char),
+            _ => write!(s, "\\{}", b).unwrap(),
+        }
+    }
+    s.push('"');
+    s
+}
+
+fn constant(v: &Value) -> String {
+    match v {
+        Value::String(s) => quote(s),
+        v => v.to_string(),
+    }
+}
+
+// "all" for counts running up to the top of the stack
+fn count(n: Option<u8>) -> String {
+    n.map_or("all".to_string(), |n| n.to_string())
+}
+
+fn operand(n: Option<u8>) -> String {
+    n.map_or("*".to_string(), |n| n.to_string())
+}
+
+fn plural(n: usize, what: &str) -> String {
+    format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+}
+
+struct Listing<'a> {
+    sm: &'a SourceMap,
+    // Also list constants, locals and upvalues, like luac -l -l
+    full: bool,
+    out: String,
+}
+
+impl Listing<'_> {
+    // `<chunk:first,last>`, where main functions are at lines 0,0
+    fn source(&self, proto: &Proto) -> String {
+        let name = match proto.spans.first() {
+            Some(&span) if self.sm.line_number(span).is_some() => self.sm.name(span.file),
+            _ => "?",
+        };
+        format!(
+            "<{}:{},{}>",
+            name, proto.line_defined, proto.last_line_defined
+        )
+    }
+
+    fn upvalue(&self, proto: &Proto, i: u8) -> String {
+        match proto.upvalue_names.get(i as usize) {
+            Some(name) => name.to_string(),
+            None => "-".to_string(),
+        }
+    }
+
+    fn instruction(&self, proto: &Proto, instruction: Instruction) -> (String, String, String) {
+        use Instruction::*;
+        let k = |k: u32| constant(&proto.constants[k as usize]);
+        let to = |t: u32| format!("to {}", t + 1);
+        let (name, operands, comment) = match instruction {
+            Move(a, b) => ("MOVE", format!("{} {}", a, b), String::new()),
+            LoadK(a, i) => ("LOADK", format!("{} {}", a, i), k(i)),
+            LoadNil(a, n) => ("LOADNIL", format!("{} {}", a, n), String::new()),
+            GetUpvalue(a, i) => ("GETUPVAL", format!("{} {}", a, i), self.upvalue(proto, i)),
+            SetUpvalue(a, i) => ("SETUPVAL", format!("{} {}", a, i), self.upvalue(proto, i)),
+            GetGlobal(a, i) => ("GETGLOBAL", format!("{} {}", a, i), k(i)),
+            SetGlobal(a, i) => ("SETGLOBAL", format!("{} {}", a, i), k(i)),
+            GetTable(a, b, c) => ("GETTABLE", format!("{} {} {}", a, b, c), String::new()),
+            GetField(a, b, i) => ("GETFIELD", format!("{} {} {}", a, b, i), k(i)),
+            SetTable(a, b, c) => ("SETTABLE", format!("{} {} {}", a, b, c), String::new()),
+            SetField(a, i, c) => ("SETFIELD", format!("{} {} {}", a, i, c), k(i)),
+            NewTable(a) => ("NEWTABLE", a.to_string(), String::new()),
+            SetList(a, n, c) => ("SETLIST", format!("{} {} {}", a, operand(n), c), count(n)),
+            SelfIndex(a, b, i) => ("SELF", format!("{} {} {}", a, b, i), k(i)),
+            Binary(op, a, b, c) => (binary_name(op), format!("{} {} {}", a, b, c), String::new()),
+            ArithI(op, a, b, i) => {
+                let name = if op == BinaryOp::Add { "ADDI" } else { "SUBI" };
+                (name, format!("{} {} {}", a, b, i), String::new())
+            }
+            CompareI(op, a, b, i) => {
+                let name = match op {
+                    BinaryOp::LessThan => "LTI",
+                    BinaryOp::LessEqual => "LEI",
+                    BinaryOp::GreaterThan => "GTI",
+                    _ => "GEI",
+                };
+                (name, format!("{} {} {}", a, b, i), String::new())
+            }
+            EqK(a, b, i, equal) => {
+                let name = if equal { "EQK" } else { "NEK" };
+                (name, format!("{} {} {}", a, b, i), k(i))
+            }
+            Unary(op, a, b) => (unary_name(op), format!("{} {}", a, b), String::new()),
+            Jump(t) => ("JMP", (t + 1).to_string(), to(t)),
+            JumpIfFalse(a, t) => ("JMPIFNOT", format!("{} {}", a, t + 1), to(t)),
+            JumpIfTrue(a, t) => ("JMPIF", format!("{} {}", a, t + 1), to(t)),
+            Call(a, nargs, nresults) => (
+                "CALL",
+                format!("{} {} {}", a, operand(nargs), operand(nresults)),
+                format!("{} in {} out", count(nargs), count(nresults)),
+            ),
+            TailCall(a, nargs) => (
+                "TAILCALL",
+                format!("{} {}", a, operand(nargs)),
+                format!("{} in", count(nargs)),
+            ),
+            Return(a, n) => (
+                "RETURN",
+                format!("{} {}", a, operand(n)),
+                format!("{} out", count(n)),
+            ),
+            Vararg(a, n) => (
+                "VARARG",
+                format!("{} {}", a, operand(n)),
+                format!("{} out", count(n)),
+            ),
+            Closure(a, i) => (
+                "CLOSURE",
+                format!("{} {}", a, i),
+                self.source(&proto.protos[i as usize]),
+            ),
+            Close(a) => ("CLOSE", a.to_string(), String::new()),
+            ToBeClosed(a) => ("TBC", a.to_string(), String::new()),
+            ForPrep(a, t) => (
+                "FORPREP",
+                format!("{} {}", a, t + 1),
+                format!("exit {}", to(t)),
+            ),
+            ForLoop(a, t) => ("FORLOOP", format!("{} {}", a, t + 1), to(t)),
+            ForInCall(a, n) => ("TFORCALL", format!("{} {}", a, n), String::new()),
+            ForInLoop(a, t) => ("TFORLOOP", format!("{} {}", a, t + 1), to(t)),
+            ExtraArg(x) => ("EXTRAARG", x.to_string(), String::new()),
+        };
+        (name.to_string(), operands, comment)
+    }
+
+    fn function(&mut self, proto: &Proto, main: bool) {
+        let kind = if main { "main" } else { "function" };
+        writeln!(
+            self.out,
+            "\n{} {} ({})",
+            kind,
+            self.source(proto),
+            plural(proto.code.len(), "instruction")
+        )
+        .unwrap();
+        writeln!(
+            self.out,
+            "{}{} param{}, {}, {}, {}, {}, {}",
+            proto.nparameters,
+            if proto.vararg { "+" } else { "" },
+            if proto.nparameters == 1 { "" } else { "s" },
+            plural(proto.max_stack, "slot"),
+            plural(proto.upvalues.len(), "upvalue"),
+            plural(proto.locvars.len(), "local"),
+            plural(proto.constants.len(), "constant"),
+            plural(proto.protos.len(), "function")
+        )
+        .unwrap();
+
+        // Code that was compiled or verified always unpacks
+        let code = opcodes::unpack(&proto.code).unwrap_or_default();
+        for (pc, instruction) in code.into_iter().enumerate() {
+            let line = match self.sm.line_number(proto.spans[pc]) {
+                Some(line) => line.to_string(),
+                None => "-".to_string(),
+            };
+            let (name, operands, comment) = self.instruction(proto, instruction);
+            write!(
+                self.out,
+                "\t{}\t[{}]\t{:<9}\t{}",
+                pc + 1,
+                line,
+                name,
+                operands
+            )
+            .unwrap();
+            if !comment.is_empty() {
+                write!(self.out, "\t; {}", comment).unwrap();
+            }
+            self.out.push('\n');
+        }
+
+        if self.full {
+            self.details(proto);
+        }
+        for child in &proto.protos {
+            self.function(child, false);
+        }
+    }
+
+    fn details(&mut self, proto: &Proto) {
+        writeln!(self.out, "constants ({}):", proto.constants.len()).unwrap();
+        for (i, k) in proto.constants.iter().enumerate() {
+            let kind = match k {
+                Value::Nil => "N",
+                Value::Boolean(_) => "B",
+                Value::Integer(_) => "I",
+                Value::Number(_) => "F",
+                _ => "S",
+            };
+            writeln!(self.out, "\t{}\t{}\t{}", i, kind, constant(k)).unwrap();
+        }
+        writeln!(self.out, "locals ({}):", proto.locvars.len()).unwrap();
+        for (i, local) in proto.locvars.iter().enumerate() {
+            writeln!(
+                self.out,
+                "\t{}\t{}\t{}\t{}",
+                i,
+                local.name,
+                local.start_pc + 1,
+                local.end_pc + 1
+            )
+            .unwrap();
+        }
+        writeln!(self.out, "upvalues ({}):", proto.upvalues.len()).unwrap();
+        for (i, desc) in proto.upvalues.iter().enumerate() {
+            // Like luac, whether it is a local of the enclosing
+            // function, and its index there
+            let (instack, index) = match *desc {
+                UpvalueDesc::Local(slot) => (1, slot),
+                UpvalueDesc::Upvalue(i) => (0, i),
+            };
+            let name = self.upvalue(proto, i as u8);
+            writeln!(self.out, "\t{}\t{}\t{}\t{}", i, name, instack, index).unwrap();
+        }
+    }
+}
+
+// Lists a function and the functions defined in it.
+pub(crate) fn list(sm: &SourceMap, proto: &Proto, main: bool, full: bool) -> String {
+    let mut listing = Listing {
+        sm,
+        full,
+        out: String::new(),
+    };
+    listing.function(proto, main);
+    listing.out
+}
+
+// Prints a syntax tree one node per line, children indented under
+// their parent, with the line each node starts on.
+struct Tree<'a> {
+    sm: &'a SourceMap,
+    depth: usize,
+    out: String,
+}
+
+impl Tree<'_> {
+    fn node<S: AsRef<str>>(&mut self, label: S, span: Option<Span>) {
+        write!(self.out, "{}{}", "  ".repeat(self.depth), label.as_ref()).unwrap();
+        if let Some(span) = span {
+            let (line, _) = self.sm.lookup(span.file, span.start);
+            write!(self.out, "  [{}]", line + 1).unwrap();
+        }
+        self.out.push('\n');
+    }
+
+    // A node whose children are printed by f
+    fn branch<S: AsRef<str>>(&mut self, label: S, span: Option<Span>, f: impl FnOnce(&mut Self)) {
+        self.node(label, span);
+        self.depth += 1;
+        f(self);
+        self.depth -= 1;
+    }
+
+    fn block(&mut self, label: &str, block: &[Statement]) {
+        self.branch(label, None, |t| {
+            for stmt in block {
+                t.statement(stmt);
+            }
+        });
+    }
+
+    fn expressions(&mut self, label: &str, exps: &[Expression]) {
+        self.branch(label, None, |t| {
+            for exp in exps {
+                t.expression(exp);
+            }
+        });
+    }
+
+    fn function(&mut self, label: String, f: &Function) {
+        let mut parameters: Vec<&str> = f.parameters.iter().map(|p| p.text()).collect();
+        if f.vararg {
+            parameters.push("...");
+        }
+        let label = format!("{} ({})", label, parameters.join(", "));
+        self.branch(label, Some(f.span), |t| t.block("Body", &f.body));
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Expression(e) => self.expression(e),
+            Statement::If(if_) => self.branch("If", Some(if_.span), |t| {
+                t.expression(&if_.test);
+                t.block("Then", &if_.body);
+                for elseif in &if_.elseifs {
+                    t.branch("ElseIf", Some(elseif.span), |t| {
+                        t.expression(&elseif.test);
+                        t.block("Then", &elseif.body);
+                    });
+                }
+                if let Some(body) = &if_.else_body {
+                    t.block("Else", body);
+                }
+            }),
+            Statement::FunctionDeclaration(fd) => {
+                let mut name = fd.name.text().to_string();
+                for field in &fd.fields {
+                    write!(name, ".{}", field.text()).unwrap();
+                }
+                if let Some(method) = &fd.method {
+                    write!(name, ":{}", method.text()).unwrap();
+                }
+                let kind = if fd.local {
+                    "LocalFunction"
+                } else {
+                    "Function"
+                };
+                self.function(format!("{} {}", kind, name), &fd.function);
+            }
+            Statement::Return(ret) => self.branch("Return", Some(ret.span), |t| {
+                for exp in &ret.expressions {
+                    t.expression(exp);
+                }
+            }),
+            Statement::Local(local) => {
+                let names: Vec<String> = local
+                    .names
+                    .iter()
+                    .map(|n| match &n.attribute {
+                        Some(attrib) => format!("{} <{}>", n.name.text(), attrib.text()),
+                        None => n.name.text().to_string(),
+                    })
+                    .collect();
+                let label = format!("Local {}", names.join(", "));
+                self.branch(label, Some(local.span), |t| {
+                    for exp in &local.expressions {
+                        t.expression(exp);
+                    }
+                });
+            }
+            Statement::Assignment(a) => self.branch("Assignment", Some(a.span), |t| {
+                t.expressions("Targets", &a.targets);
+                t.expressions("Values", &a.expressions);
+            }),
+            Statement::While(w) => self.branch("While", Some(w.span), |t| {
+                t.expression(&w.test);
+                t.block("Body", &w.body);
+            }),
+            Statement::Repeat(r) => self.branch("Repeat", Some(r.span), |t| {
+                t.block("Body", &r.body);
+                t.expression(&r.test);
+            }),
+            Statement::NumericFor(f) => {
+                let label = format!("NumericFor {}", f.name.text());
+                self.branch(label, Some(f.span), |t| {
+                    t.expression(&f.start);
+                    t.expression(&f.limit);
+                    if let Some(step) = &f.step {
+                        t.expression(step);
+                    }
+                    t.block("Body", &f.body);
+                });
+            }
+            Statement::GenericFor(f) => {
+                let names: Vec<&str> = f.names.iter().map(|n| n.text()).collect();
+                let label = format!("GenericFor {}", names.join(", "));
+                self.branch(label, Some(f.span), |t| {
+                    t.expressions("In", &f.expressions);
+                    t.block("Body", &f.body);
+                });
+            }
+            Statement::Do(d) => self.branch("Do", Some(d.span), |t| {
+                for stmt in &d.body {
+                    t.statement(stmt);
+                }
+            }),
+            Statement::Break(t) => self.node("Break", Some(t.span)),
+            Statement::Goto(t) => self.node(format!("Goto {}", t.text()), Some(t.span)),
+            Statement::Label(t) => self.node(format!("Label {}", t.text()), Some(t.span)),
+            Statement::Empty(t) => self.node("Empty", Some(t.span)),
+        }
+    }
+
+    fn expression(&mut self, exp: &Expression) {
+        match exp {
+            Expression::FunctionCall(fc) => {
+                let label = match &fc.method {
+                    Some(method) => format!("MethodCall {}", method.text()),
+                    None => "Call".to_string(),
+                };
+                self.branch(label, Some(fc.span), |t| {
+                    t.expression(&fc.function);
+                    t.expressions("Arguments", &fc.arguments);
+                });
+            }
+            Expression::BinaryOperation(bop) => {
+                let label = format!("BinaryOperation {}", bop.operator.text());
+                self.branch(label, Some(bop.span), |t| {
+                    t.expression(&bop.left);
+                    t.expression(&bop.right);
+                });
+            }
+            Expression::UnaryOperation(uop) => {
+                let label = format!("UnaryOperation {}", uop.operator.text());
+                self.branch(label, Some(uop.span), |t| t.expression(&uop.operand));
+            }
+            Expression::Literal(lit) => {
+                let label = match lit {
+                    Literal::Identifier(t) => format!("Identifier {}", t.text()),
+                    Literal::Number(t) => format!("Number {}", t.text()),
+                    Literal::String(t) => format!("String {}", quote(t.value())),
+                    Literal::Nil(_) => "Nil".to_string(),
+                    Literal::Boolean(t) => format!("Boolean {}", t.text()),
+                    Literal::Vararg(_) => "Vararg".to_string(),
+                };
+                self.node(label, Some(lit.span()));
+            }
+            Expression::Constant(v, span) => {
+                self.node(format!("Constant {}", constant(v)), Some(*span))
+            }
+            Expression::Index(idx) => self.branch("Index", Some(idx.span), |t| {
+                t.expression(&idx.object);
+                t.expression(&idx.key);
+            }),
+            Expression::Function(f) => self.function("Function".to_string(), f),
+            Expression::Table(table) => self.branch("Table", Some(table.span), |t| {
+                for field in &table.fields {
+                    match field {
+                        Field::Positional(exp) => t.expression(exp),
+                        Field::Named(name, exp) => {
+                            let label = format!("Field {}", name.text());
+                            t.branch(label, Some(name.span), |t| t.expression(exp));
+                        }
+                        Field::Keyed(key, exp) => t.branch("Field", Some(key.span()), |t| {
+                            t.expression(key);
+                            t.expression(exp);
+                        }),
+                    }
+                }
+            }),
+            Expression::Parenthesized(e, span) => {
+                self.branch("Parenthesized", Some(*span), |t| t.expression(e))
+            }
+        }
+    }
+}
+
+// The comments, which the tree leaves out, follow it.
+pub(crate) fn syntax_tree(sm: &SourceMap, ast: &Ast, comments: &[Token]) -> String {
+    let mut tree = Tree {
+        sm,
+        depth: 0,
+        out: String::new(),
+    };
+    tree.block("Chunk", ast);
+    if !comments.is_empty() {
+        tree.branch("Comments", None, |t| {
+            for c in comments {
+                t.node(format!("Comment {}", quote(c.value())), Some(c.span));
+            }
+        });
+    }
+    tree.out
+}
@@ -0,0 +1,210 @@
+use crate::conv::FromLuaMulti;
+use crate::dump;
+use crate::eval::Vm;
+use crate::lex::{lex_with_trivia, TokenKind};
+use crate::listing;
+use crate::native::{self, Args};
+use crate::serialize;
+use crate::stdlib;
+use crate::value::{Error, Function, Table, Value};
+
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// A compiled chunk, ready to run in the state that loaded it.
+pub struct Chunk<'lua> {
+    lua: &'lua mut Vm,
+    function: Value,
+}
+
+impl Chunk<'_> {
+    // Runs the chunk for its side effects.
+    pub fn exec(self) -> Result<(), Error> {
+        self.call(vec![])?;
+        Ok(())
+    }
+
+    // Runs the chunk and converts its results.
+    pub fn eval<T: FromLuaMulti>(self) -> Result<T, Error> {
+        T::from_lua_multi(self.call(vec![])?)
+    }
+
+    // Runs the chunk and deserializes its first result, for types
+    // that implement serde's Deserialize rather than FromLua.
+    pub fn eval_as<T: DeserializeOwned>(self) -> Result<T, Error> {
+        let value = self.call(vec![])?.into_iter().next().unwrap_or(Value::Nil);
+        serialize::from_value(value)
+    }
+
+    // Runs the chunk with args as its `...`.
+    pub fn call(self, args: Vec<Value>) -> Result<Vec<Value>, Error> {
+        self.lua.call(self.function, args)
+    }
+
+    pub fn into_function(self) -> Value {
+        self.function
+    }
+}
+
+// The embedding API. The state is the VM itself, so that native
+// functions get the same handle the host does.
+impl Vm {
+    // A state with the standard library opened.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Vm {
+        Vm::with_env(true)
+    }
+
+    // Like new, but use_env false ignores variables such as LUA_PATH,
+    // as `lua -E` does.
+    pub fn with_env(use_env: bool) -> Vm {
+        let mut vm = Vm::empty();
+        stdlib::open(&mut vm, use_env);
+        vm
+    }
+
+    // Compiles source, naming it chunkname in error messages. Binary
+    // chunks, as written by dump, are loaded as they are.
+    pub fn load<S: AsRef<[u8]>>(&mut self, chunk: S, chunkname: &str) -> Result<Chunk<'_>, Error> {
+        self.load_mode(chunk, chunkname, "bt")
+    }
+
+    // Like load, with mode "t" to only accept source, "b" to only
+    // accept binary chunks or "bt" for both.
+    pub fn load_mode<S: AsRef<[u8]>>(
+        &mut self,
+        chunk: S,
+        chunkname: &str,
+        mode: &str,
+    ) -> Result<Chunk<'_>, Error> {
+        let function = self.load_function(chunk.as_ref(), chunkname, mode)?;
+        Ok(Chunk {
+            lua: self,
+            function,
+        })
+    }
+
+    // The binary chunk of a Lua function, like string.dump. strip
+    // leaves out the debug info: line numbers and variable names.
+    pub fn dump(&self, function: &Value, strip: bool) -> Result<Vec<u8>, Error> {
+        self.dump_function(function, strip)
+            .ok_or_else(|| Error::runtime("unable to dump given function"))
+    }
+
+    // A luac -l style listing of a Lua function's bytecode and of the
+    // functions defined in it. full adds their constants, locals and
+    // upvalues, like luac -l -l.
+    pub fn listing(&self, function: &Value, full: bool) -> Result<String, Error> {
+        match function {
+            Value::Function(Function::Lua(c)) => {
+                let main = c.proto.line_defined == 0;
+                Ok(listing::list(&self.sm, &c.proto, main, full))
+            }
+            _ => Err(Error::runtime("unable to list given function")),
+        }
+    }
+
+    // The syntax tree of a chunk of source, as an indented outline.
+    pub fn syntax_tree<S: AsRef<[u8]>>(
+        &mut self,
+        source: S,
+        chunkname: &str,
+    ) -> Result<String, Error> {
+        let source = source.as_ref();
+        if source.starts_with(dump::SIGNATURE) {
+            return Err(Error::Syntax(format!(
+                "{}: binary chunks have no syntax tree",
+                chunkname
+            )));
+        }
+        let file = self.add_source(source, chunkname);
+        let tree = self.parse_file(file).and_then(|ast| {
+            let comments = lex_with_trivia(&self.sm, file)
+                .map_err(Error::Syntax)?
+                .into_iter()
+                .filter(|t| t.kind == TokenKind::Comment)
+                .collect::<Vec<_>>();
+            Ok(listing::syntax_tree(&self.sm, &ast, &comments))
+        });
+        self.sm.release(file);
+        tree
+    }
+
+    pub fn globals(&self) -> Rc<RefCell<Table>> {
+        self.globals.clone()
+    }
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.globals.borrow().get_str(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().set_str(name, value);
+    }
+
+    // Wraps a Rust closure as a Lua function value. name is what
+    // argument errors call it.
+    pub fn create_function<F>(&self, name: &str, f: F) -> Value
+    where
+        F: Fn(&mut Vm, Args) -> Result<Vec<Value>, Error> + 'static,
+    {
+        native::function(name, f)
+    }
+
+    // Registers f as the global function name.
+    pub fn register<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Vm, Args) -> Result<Vec<Value>, Error> + 'static,
+    {
+        let f = self.create_function(name, f);
+        self.set_global(name, f);
+    }
+
+    // Registers f as lib.name, creating the global table lib if
+    // needed, the way libraries such as string and math are laid out.
+    pub fn register_in<F>(&mut self, lib: &str, name: &str, f: F)
+    where
+        F: Fn(&mut Vm, Args) -> Result<Vec<Value>, Error> + 'static,
+    {
+        let table = match self.get_global(lib) {
+            Value::Table(t) => t,
+            _ => {
+                let t = Rc::new(RefCell::new(Table::new()));
+                self.set_global(lib, Value::Table(t.clone()));
+                t
+            }
+        };
+        let f = self.create_function(name, f);
+        table.borrow_mut().set_str(name, f);
+    }
+
+    // Calls the function stored in a global.
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Vec<Value>, Error> {
+        match self.get_global(name) {
+            f @ Value::Function(_) => self.call(f, args),
+            f => Err(Error::runtime(format!(
+                "attempt to call a {} value (global '{}')",
+                f.type_name(),
+                name
+            ))),
+        }
+    }
+
+    // Limits how many Lua calls can be in progress. Deeper calls fail
+    // with a "stack overflow" error that can be handled like any other.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    // The stack traceback of the last runtime error to escape a call
+    // from the host, as the lust command prints it.
+    pub fn traceback(&self) -> Option<&str> {
+        self.traceback.as_deref()
+    }
+
+    // Whether warn() prints, which scripts toggle with "@on" and "@off".
+    pub fn set_warnings(&mut self, on: bool) {
+        self.warnings = on;
+    }
+}
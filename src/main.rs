@@ -1,27 +1,281 @@
-mod eval;
-mod lex;
-mod parse;
+use lust::{repl, Error, Lua, Table, Value};
 
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::process;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let contents = fs::read_to_string(&args[1]).expect("Could not read file");
+const VERSION: &str = concat!("lust ", env!("CARGO_PKG_VERSION"));
+
+fn usage(progname: &str) -> String {
+    format!(
+        "usage: {} [options] [script [args]]
+Available options are:
+  -e stat   execute string 'stat'
+  -i        enter interactive mode after executing 'script'
+  -l mod    require library 'mod' into global 'mod'
+  -l g=mod  require library 'mod' into global 'g'
+  -v        show version information
+  -E        ignore environment variables
+  -W        turn warnings on
+  --dump-ast       print the syntax tree of 'script' instead of running it
+  --dump-bytecode  print the bytecode of 'script' instead of running it
+  --        stop handling options
+  -         stop handling options and execute stdin",
+        progname
+    )
+}
+
+// Options that run in command line order before the script.
+enum Action {
+    Execute(String),
+    Require(String),
+}
+
+// What --dump-ast and --dump-bytecode print
+#[derive(Clone, Copy)]
+enum Dump {
+    Ast,
+    Bytecode,
+}
+
+#[derive(Default)]
+struct Options {
+    interactive: bool,
+    version: bool,
+    ignore_env: bool,
+    warnings: bool,
+    dump: Option<Dump>,
+    actions: Vec<Action>,
+    // Index of the script in the arguments, if any
+    script: Option<usize>,
+}
+
+// Parses the interpreter options like lua.c does: they end at the
+// first argument not starting with '-', at "--" or at "-".
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if !arg.starts_with('-') || arg == "-" {
+            options.script = Some(i);
+            break;
+        }
+
+        match arg.as_str() {
+            "--" => {
+                if i + 1 < args.len() {
+                    options.script = Some(i + 1);
+                }
+                break;
+            }
+            "-i" => {
+                options.interactive = true;
+                options.version = true;
+            }
+            "-v" => options.version = true,
+            "-E" => options.ignore_env = true,
+            "-W" => options.warnings = true,
+            "--dump-ast" => options.dump = Some(Dump::Ast),
+            "--dump-bytecode" => options.dump = Some(Dump::Bytecode),
+            _ if arg.starts_with("-e") || arg.starts_with("-l") => {
+                let value = if arg.len() > 2 {
+                    arg[2..].to_string()
+                } else {
+                    i += 1;
+                    match args.get(i) {
+                        Some(value) if !value.starts_with('-') => value.clone(),
+                        _ => return Err(format!("'{}' needs argument", arg)),
+                    }
+                };
+                options.actions.push(if arg.starts_with("-e") {
+                    Action::Execute(value)
+                } else {
+                    Action::Require(value)
+                });
+            }
+            _ => return Err(format!("unrecognized option '{}'", arg)),
+        }
+        i += 1;
+    }
+
+    Ok(options)
+}
+
+// The global `arg` table: the script name at 0, its arguments at
+// positive indices and the interpreter and its options at negative
+// ones.
+fn arg_table(args: &[String], script: Option<usize>) -> Table {
+    let script = script.unwrap_or(0) as i64;
+    let mut t = Table::new();
+    for (i, arg) in args.iter().enumerate() {
+        t.set(Value::Integer(i as i64 - script), Value::from(arg.as_str()))
+            .unwrap();
+    }
+    t
+}
+
+fn run_chunk<S: AsRef<[u8]>>(
+    lua: &mut Lua,
+    name: &str,
+    chunk: S,
+    args: Vec<Value>,
+) -> Result<(), Error> {
+    lua.load(chunk, name)?.call(args)?;
+    Ok(())
+}
+
+// Scripts may be source or precompiled by lustc.
+fn run_file(lua: &mut Lua, path: &str, args: Vec<Value>) -> Result<(), Error> {
+    let (name, chunk) = read_file(path)?;
+
+    // Like lua, a first line starting with '#' is skipped by the lexer
+    run_chunk(lua, name, chunk, args)
+}
+
+// `-l g=mod` stores require("mod") in the global g; a plain `-l mod`
+// uses the module name up to any '-' version suffix.
+fn require(lua: &mut Lua, spec: &str) -> Result<(), Error> {
+    let (global, module) = match spec.split_once('=') {
+        Some((global, module)) => (global, module),
+        None => (spec.split('-').next().unwrap_or(spec), spec),
+    };
+
+    let result = lua.call_function("require", vec![Value::from(module)])?;
+    let value = result.into_iter().next().unwrap_or(Value::Nil);
+    lua.set_global(global, value);
+    Ok(())
+}
 
-    let raw: Vec<char> = contents.chars().collect();
+// LUA_INIT_5_4 or LUA_INIT holds either code or `@filename`.
+fn run_init(lua: &mut Lua) -> Result<(), Error> {
+    let init = ["LUA_INIT_5_4", "LUA_INIT"]
+        .iter()
+        .find_map(|var| env::var(var).ok().map(|value| (*var, value)));
 
-    let tokens = match lex::lex(&raw) {
-        Ok(tokens) => tokens,
-        Err(msg) => panic!("{}", msg),
+    match init {
+        Some((_, value)) if value.starts_with('@') => run_file(lua, &value[1..], vec![]),
+        Some((name, value)) => run_chunk(lua, name, value, vec![]),
+        None => Ok(()),
+    }
+}
+
+fn read_file(path: &str) -> Result<(&str, Vec<u8>), Error> {
+    if path == "-" {
+        let mut chunk = vec![];
+        io::stdin()
+            .read_to_end(&mut chunk)
+            .map_err(|e| Error::Syntax(format!("cannot read stdin: {}", e)))?;
+        Ok(("stdin", chunk))
+    } else {
+        let chunk =
+            fs::read(path).map_err(|e| Error::Syntax(format!("cannot open {}: {}", path, e)))?;
+        Ok((path, chunk))
+    }
+}
+
+fn dump_chunk(lua: &mut Lua, name: &str, chunk: &[u8], dump: Dump) -> Result<(), Error> {
+    let text = match dump {
+        Dump::Ast => lua.syntax_tree(chunk, name)?,
+        Dump::Bytecode => {
+            let function = lua.load(chunk, name)?.into_function();
+            lua.listing(&function, true)?
+        }
     };
+    print!("{}", text);
+    Ok(())
+}
+
+// With --dump-ast or --dump-bytecode, the chunks given by -e and the
+// script are printed and nothing is run.
+fn dump(lua: &mut Lua, args: &[String], options: &Options, dump: Dump) -> Result<(), Error> {
+    for action in &options.actions {
+        if let Action::Execute(stat) = action {
+            dump_chunk(lua, "(command line)", stat.as_bytes(), dump)?;
+        }
+    }
+    let script = match options.script {
+        Some(script) => args[script].as_str(),
+        None if options.actions.is_empty() => "-",
+        None => return Ok(()),
+    };
+    let (name, chunk) = read_file(script)?;
+    dump_chunk(lua, name, &chunk, dump)
+}
 
-    let ast = match parse::parse(&raw, tokens) {
-        Ok(ast) => ast,
-        Err(msg) => panic!("{}", msg),
+fn run(lua: &mut Lua, args: &[String], options: &Options) -> Result<(), Error> {
+    if !options.ignore_env {
+        run_init(lua)?;
+    }
+
+    for action in &options.actions {
+        match action {
+            Action::Execute(stat) => run_chunk(lua, "(command line)", stat.clone(), vec![])?,
+            Action::Require(spec) => require(lua, spec)?,
+        }
+    }
+
+    if let Some(script) = options.script {
+        let script_args = args[script + 1..]
+            .iter()
+            .map(|arg| Value::from(arg.as_str()))
+            .collect();
+        run_file(lua, &args[script], script_args)?;
+    }
+
+    Ok(())
+}
+
+// Reports an error that ends the program, with where it was raised
+// if it was raised while running
+fn fail(lua: &Lua, progname: &str, e: Error) -> ! {
+    eprintln!("{}: {}", progname, e);
+    if let (Error::Runtime(_), Some(traceback)) = (&e, lua.traceback()) {
+        eprintln!("{}", traceback);
+    }
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let progname = args.first().map_or("lust", String::as_str).to_string();
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}: {}", progname, msg);
+            eprintln!("{}", usage(&progname));
+            process::exit(1);
+        }
     };
+    if options.version {
+        println!("{}", VERSION);
+    }
+
+    let mut lua = Lua::with_env(!options.ignore_env);
+    if let Some(mode) = options.dump {
+        if let Err(e) = dump(&mut lua, &args, &options, mode) {
+            eprintln!("{}: {}", progname, e);
+            process::exit(1);
+        }
+        return;
+    }
+    lua.set_global("arg", Value::from(arg_table(&args, options.script)));
+    lua.set_warnings(options.warnings);
 
-    let pgrm = eval::compile(&raw, ast);
+    if let Err(e) = run(&mut lua, &args, &options) {
+        fail(&lua, &progname, e);
+    }
 
-    eval::eval(pgrm);
+    if options.interactive {
+        repl::repl(&mut lua);
+    } else if options.script.is_none() && options.actions.is_empty() && !options.version {
+        if io::stdin().is_terminal() {
+            println!("{}", VERSION);
+            repl::repl(&mut lua);
+        } else if let Err(e) = run_file(&mut lua, "-", vec![]) {
+            fail(&lua, &progname, e);
+        }
+    }
 }
@@ -0,0 +1,233 @@
+// Metatables and the metamethods operators fall back to.
+
+use crate::eval::{BinaryOp, UnaryOp, Vm};
+use crate::string::LuaString;
+use crate::value::{Error, Table, Value};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Like Lua, give up on __index and __newindex chains this long, which
+// are most likely loops.
+const MAX_META_CHAIN: usize = 2000;
+
+fn binary_event(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "__add",
+        BinaryOp::Subtract => "__sub",
+        BinaryOp::Multiply => "__mul",
+        BinaryOp::Divide => "__div",
+        BinaryOp::Modulo => "__mod",
+        BinaryOp::Power => "__pow",
+        BinaryOp::FloorDivide => "__idiv",
+        BinaryOp::BitAnd => "__band",
+        BinaryOp::BitOr => "__bor",
+        BinaryOp::BitXor => "__bxor",
+        BinaryOp::ShiftLeft => "__shl",
+        BinaryOp::ShiftRight => "__shr",
+        BinaryOp::Concat => "__concat",
+        BinaryOp::Equal | BinaryOp::NotEqual => "__eq",
+        BinaryOp::LessThan | BinaryOp::GreaterThan => "__lt",
+        BinaryOp::LessEqual | BinaryOp::GreaterEqual => "__le",
+    }
+}
+
+fn unary_event(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Negate => "__unm",
+        UnaryOp::BitNot => "__bnot",
+        UnaryOp::Length => "__len",
+        UnaryOp::Not => unreachable!("no metamethod for not"),
+    }
+}
+
+impl Vm {
+    pub(crate) fn metatable(&self, v: &Value) -> Option<Rc<RefCell<Table>>> {
+        match v {
+            Value::Table(t) => t.borrow().metatable(),
+            Value::UserData(ud) => Some(ud.metatable.clone()),
+            _ => None,
+        }
+    }
+
+    // The metamethod for event, or nil.
+    pub(crate) fn metamethod(&self, v: &Value, event: &str) -> Value {
+        match self.metatable(v) {
+            Some(mt) => mt.borrow().get_str(event),
+            None => Value::Nil,
+        }
+    }
+
+    fn call_metamethod(&mut self, h: Value, args: Vec<Value>) -> Result<Value, Error> {
+        let results = self.call(h, args)?;
+        Ok(results.into_iter().next().unwrap_or(Value::Nil))
+    }
+
+    // object[key], following __index.
+    pub(crate) fn index(&mut self, mut object: Value, key: Value) -> Result<Value, Error> {
+        for _ in 0..MAX_META_CHAIN {
+            let h = match &object {
+                Value::Table(t) => {
+                    let t = t.borrow();
+                    let v = t.get(&key);
+                    match (&v, t.metatable()) {
+                        (Value::Nil, Some(mt)) => mt.borrow().get_str("__index"),
+                        _ => return Ok(v),
+                    }
+                }
+                _ => self.metamethod(&object, "__index"),
+            };
+
+            match h {
+                Value::Nil if matches!(object, Value::Table(_)) => return Ok(Value::Nil),
+                Value::Nil => {
+                    return Err(Error::message(format!(
+                        "attempt to index a {} value",
+                        object.type_name()
+                    )))
+                }
+                Value::Function(_) => return self.call_metamethod(h, vec![object, key]),
+                h => object = h,
+            }
+        }
+
+        Err(Error::message("'__index' chain too long; possible loop"))
+    }
+
+    // object[key] = value, following __newindex.
+    pub(crate) fn set_index(
+        &mut self,
+        mut object: Value,
+        key: Value,
+        value: Value,
+    ) -> Result<(), Error> {
+        for _ in 0..MAX_META_CHAIN {
+            let h = match &object {
+                Value::Table(t) => {
+                    let h = match t.borrow().metatable() {
+                        Some(mt) if matches!(t.borrow().get(&key), Value::Nil) => {
+                            mt.borrow().get_str("__newindex")
+                        }
+                        _ => Value::Nil,
+                    };
+                    if let Value::Nil = h {
+                        return t.borrow_mut().set(key, value).map_err(Error::message);
+                    }
+                    h
+                }
+                _ => self.metamethod(&object, "__newindex"),
+            };
+
+            match h {
+                Value::Nil => {
+                    return Err(Error::message(format!(
+                        "attempt to index a {} value",
+                        object.type_name()
+                    )))
+                }
+                Value::Function(_) => {
+                    self.call(h, vec![object, key, value])?;
+                    return Ok(());
+                }
+                h => object = h,
+            }
+        }
+
+        Err(Error::message("'__newindex' chain too long; possible loop"))
+    }
+
+    // Finishes an __index lookup a native __index function could not
+    // answer, with the handler that was set before it.
+    pub(crate) fn fallback_index(
+        &mut self,
+        h: &Value,
+        object: Value,
+        key: Value,
+    ) -> Result<Vec<Value>, Error> {
+        match h {
+            Value::Nil => Ok(vec![Value::Nil]),
+            Value::Function(_) => self.call(h.clone(), vec![object, key]),
+            h => Ok(vec![self.index(h.clone(), key)?]),
+        }
+    }
+
+    // Tries the left operand's metamethod, then the right's, failing
+    // with msg if neither has one.
+    pub(crate) fn binary_metamethod(
+        &mut self,
+        op: BinaryOp,
+        left: Value,
+        right: Value,
+        msg: String,
+    ) -> Result<Value, Error> {
+        let event = binary_event(op);
+        // a > b is b < a
+        let (left, right) = match op {
+            BinaryOp::GreaterThan | BinaryOp::GreaterEqual => (right, left),
+            _ => (left, right),
+        };
+
+        let mut h = self.metamethod(&left, event);
+        if let Value::Nil = h {
+            h = self.metamethod(&right, event);
+        }
+        if let Value::Nil = h {
+            return match op {
+                BinaryOp::Equal => Ok(Value::Boolean(false)),
+                BinaryOp::NotEqual => Ok(Value::Boolean(true)),
+                _ => Err(Error::message(msg)),
+            };
+        }
+
+        let v = self.call_metamethod(h, vec![left, right])?;
+        Ok(match op {
+            BinaryOp::NotEqual => Value::Boolean(!v.truthy()),
+            BinaryOp::Equal
+            | BinaryOp::LessThan
+            | BinaryOp::LessEqual
+            | BinaryOp::GreaterThan
+            | BinaryOp::GreaterEqual => Value::Boolean(v.truthy()),
+            _ => v,
+        })
+    }
+
+    pub(crate) fn unary_metamethod(
+        &mut self,
+        op: UnaryOp,
+        v: Value,
+        msg: String,
+    ) -> Result<Value, Error> {
+        let h = self.metamethod(&v, unary_event(op));
+        match (h, &v) {
+            (Value::Nil, Value::Table(t)) if op == UnaryOp::Length => {
+                Ok(Value::Integer(t.borrow().len() as i64))
+            }
+            (Value::Nil, _) => Err(Error::message(msg)),
+            // Unary metamethods get the operand twice, as in Lua
+            (h, _) => self.call_metamethod(h, vec![v.clone(), v]),
+        }
+    }
+
+    // tostring(v): __tostring, then __name, then the plain form.
+    pub(crate) fn tostring(&mut self, v: &Value) -> Result<LuaString, Error> {
+        let h = self.metamethod(v, "__tostring");
+        if !matches!(h, Value::Nil) {
+            return match self.call_metamethod(h, vec![v.clone()])? {
+                Value::String(s) => Ok(s),
+                _ => Err(Error::message("'__tostring' must return a string")),
+            };
+        }
+
+        if let Value::String(s) = v {
+            return Ok(s.clone());
+        }
+        let s = v.to_string();
+        if let Some(mt) = self.metatable(v) {
+            if let Value::String(name) = mt.borrow().get_str("__name") {
+                let address = s.split_once(": ").map_or("", |(_, a)| a);
+                return Ok(LuaString::from(format!("{}: {}", name, address)));
+            }
+        }
+        Ok(LuaString::from(s))
+    }
+}
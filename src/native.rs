@@ -0,0 +1,168 @@
+use crate::eval::Vm;
+use crate::string::LuaString;
+use crate::value::{Error, Function, Table, Value};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub type NativeCall = dyn Fn(&mut Vm, Args) -> Result<Vec<Value>, Error>;
+
+// A Rust function callable from Lua. The name is the global or field
+// it was registered as, which argument errors refer to.
+pub struct NativeFunction {
+    pub name: Rc<str>,
+    pub call: Box<NativeCall>,
+}
+
+pub(crate) fn function<F>(name: &str, f: F) -> Value
+where
+    F: Fn(&mut Vm, Args) -> Result<Vec<Value>, Error> + 'static,
+{
+    Value::Function(Function::Native(Rc::new(NativeFunction {
+        name: Rc::from(name),
+        call: Box::new(f),
+    })))
+}
+
+// The arguments of a native call. Positions are 1-based like in the
+// Lua C API, and missing arguments read as nil.
+pub struct Args {
+    name: Rc<str>,
+    values: Vec<Value>,
+}
+
+impl Args {
+    pub(crate) fn new(name: Rc<str>, values: Vec<Value>) -> Args {
+        Args { name, values }
+    }
+
+    // The name of the function being called
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The arguments after self, for methods.
+    pub(crate) fn skip_self(mut self) -> Args {
+        if !self.values.is_empty() {
+            self.values.remove(0);
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn get(&self, n: usize) -> Value {
+        self.values.get(n - 1).cloned().unwrap_or(Value::Nil)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter()
+    }
+
+    pub fn into_vec(self) -> Vec<Value> {
+        self.values
+    }
+
+    // "bad argument #1 to 'foo' (msg)"
+    pub fn error<S: AsRef<str>>(&self, n: usize, msg: S) -> Error {
+        Error::message(format!(
+            "bad argument #{} to '{}' ({})",
+            n,
+            self.name,
+            msg.as_ref()
+        ))
+    }
+
+    pub fn type_error(&self, n: usize, expected: &str) -> Error {
+        let got = match self.values.get(n - 1) {
+            Some(v) => v.type_name(),
+            None => "no value",
+        };
+        self.error(n, format!("{} expected, got {}", expected, got))
+    }
+
+    // Any value, including nil, as long as it was passed.
+    pub fn check_any(&self, n: usize) -> Result<Value, Error> {
+        match self.values.get(n - 1) {
+            Some(v) => Ok(v.clone()),
+            None => Err(self.error(n, "value expected")),
+        }
+    }
+
+    // Integers, and floats and strings with an exact integer value.
+    pub fn check_integer(&self, n: usize) -> Result<i64, Error> {
+        let v = self.get(n);
+        match v.to_integer() {
+            Some(i) => Ok(i),
+            None if v.to_number().is_some() => {
+                Err(self.error(n, "number has no integer representation"))
+            }
+            None => Err(self.type_error(n, "number")),
+        }
+    }
+
+    pub fn check_number(&self, n: usize) -> Result<f64, Error> {
+        self.get(n)
+            .to_float()
+            .ok_or_else(|| self.type_error(n, "number"))
+    }
+
+    // Strings, and numbers converted to strings.
+    pub fn check_string(&self, n: usize) -> Result<LuaString, Error> {
+        match self.get(n) {
+            Value::String(s) => Ok(s),
+            v @ (Value::Integer(_) | Value::Number(_)) => Ok(LuaString::from(v.to_string())),
+            _ => Err(self.type_error(n, "string")),
+        }
+    }
+
+    pub fn check_table(&self, n: usize) -> Result<Rc<RefCell<Table>>, Error> {
+        match self.get(n) {
+            Value::Table(t) => Ok(t),
+            _ => Err(self.type_error(n, "table")),
+        }
+    }
+
+    pub fn check_function(&self, n: usize) -> Result<Value, Error> {
+        match self.get(n) {
+            f @ Value::Function(_) => Ok(f),
+            _ => Err(self.type_error(n, "function")),
+        }
+    }
+
+    // The opt_ variants accept nil or a missing argument as None.
+
+    pub fn opt_integer(&self, n: usize) -> Result<Option<i64>, Error> {
+        match self.get(n) {
+            Value::Nil => Ok(None),
+            _ => self.check_integer(n).map(Some),
+        }
+    }
+
+    pub fn opt_number(&self, n: usize) -> Result<Option<f64>, Error> {
+        match self.get(n) {
+            Value::Nil => Ok(None),
+            _ => self.check_number(n).map(Some),
+        }
+    }
+
+    pub fn opt_string(&self, n: usize) -> Result<Option<LuaString>, Error> {
+        match self.get(n) {
+            Value::Nil => Ok(None),
+            _ => self.check_string(n).map(Some),
+        }
+    }
+
+    pub fn opt_table(&self, n: usize) -> Result<Option<Rc<RefCell<Table>>>, Error> {
+        match self.get(n) {
+            Value::Nil => Ok(None),
+            _ => self.check_table(n).map(Some),
+        }
+    }
+}
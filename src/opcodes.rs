@@ -0,0 +1,270 @@
+// How instructions are packed into 32-bit words, after Lua 5.4's
+// lopcodes.h. Every instruction starts with a 7-bit opcode, followed
+// by one of these layouts, lowest bits first:
+//
+//     iABC   op(7) A(8) k(1) B(8) C(8)
+//     iABx   op(7) A(8) Bx(17)
+//     iAsBx  op(7) A(8) sBx(17)
+//     isJ    op(7) sJ(25)
+//     iAx    op(7) Ax(25)
+//
+// Jumps are relative to the instruction after them, and signed fields
+// are stored with an offset added, so they are never negative.
+// Constant indices too big for their field saturate it, and the real
+// index follows in an ExtraArg word.
+
+use crate::eval::{BinaryOp, Instruction, UnaryOp};
+
+pub(crate) const MAX_C: u32 = (1 << 8) - 1;
+pub(crate) const MAX_BX: u32 = (1 << 17) - 1;
+pub(crate) const MAX_AX: u32 = (1 << 25) - 1;
+const OFFSET_SBX: i64 = (MAX_BX >> 1) as i64;
+const OFFSET_SJ: i64 = (MAX_AX >> 1) as i64;
+
+const MOVE: u32 = 0;
+const LOADK: u32 = 1;
+const LOADNIL: u32 = 2;
+const GETUPVAL: u32 = 3;
+const SETUPVAL: u32 = 4;
+const GETGLOBAL: u32 = 5;
+const SETGLOBAL: u32 = 6;
+const GETTABLE: u32 = 7;
+const GETFIELD: u32 = 8;
+const SETTABLE: u32 = 9;
+const SETFIELD: u32 = 10;
+const NEWTABLE: u32 = 11;
+const SETLIST: u32 = 12;
+const SELF: u32 = 13;
+const EQK: u32 = 14;
+const ADDI: u32 = 15;
+const SUBI: u32 = 16;
+const LTI: u32 = 17;
+const LEI: u32 = 18;
+const GTI: u32 = 19;
+const GEI: u32 = 20;
+const JMP: u32 = 21;
+const JMPIFFALSE: u32 = 22;
+const JMPIFTRUE: u32 = 23;
+const CALL: u32 = 24;
+const TAILCALL: u32 = 25;
+const RETURN: u32 = 26;
+const VARARG: u32 = 27;
+const CLOSURE: u32 = 28;
+const CLOSE: u32 = 29;
+const TBC: u32 = 30;
+const FORPREP: u32 = 31;
+const FORLOOP: u32 = 32;
+const TFORCALL: u32 = 33;
+const TFORLOOP: u32 = 34;
+const EXTRAARG: u32 = 35;
+// One opcode per operator, in the order of BINARY_OPS and UNARY_OPS
+const BINARY: u32 = 36;
+const UNARY: u32 = BINARY + BINARY_OPS.len() as u32;
+
+pub(crate) const BINARY_OPS: [BinaryOp; 19] = [
+    BinaryOp::Add,
+    BinaryOp::Subtract,
+    BinaryOp::Multiply,
+    BinaryOp::Divide,
+    BinaryOp::FloorDivide,
+    BinaryOp::Modulo,
+    BinaryOp::Power,
+    BinaryOp::Concat,
+    BinaryOp::Equal,
+    BinaryOp::NotEqual,
+    BinaryOp::LessThan,
+    BinaryOp::LessEqual,
+    BinaryOp::GreaterThan,
+    BinaryOp::GreaterEqual,
+    BinaryOp::BitAnd,
+    BinaryOp::BitOr,
+    BinaryOp::BitXor,
+    BinaryOp::ShiftLeft,
+    BinaryOp::ShiftRight,
+];
+
+pub(crate) const UNARY_OPS: [UnaryOp; 4] = [
+    UnaryOp::Negate,
+    UnaryOp::Not,
+    UnaryOp::Length,
+    UnaryOp::BitNot,
+];
+
+fn abc(op: u32, a: u8, k: bool, b: u8, c: u8) -> u32 {
+    op | (a as u32) << 7 | (k as u32) << 15 | (b as u32) << 16 | (c as u32) << 24
+}
+
+fn abx(op: u32, a: u8, bx: u32) -> u32 {
+    debug_assert!(bx <= MAX_BX);
+    op | (a as u32) << 7 | bx << 15
+}
+
+fn ax(op: u32, ax: u32) -> u32 {
+    debug_assert!(ax <= MAX_AX);
+    op | ax << 7
+}
+
+// The offset of a jump from the instruction at pc to target, if it
+// fits in a field with this offset added
+fn offset(pc: usize, target: u32, excess: i64) -> Option<u32> {
+    let offset = target as i64 - (pc as i64 + 1) + excess;
+    (0..=2 * excess + 1)
+        .contains(&offset)
+        .then_some(offset as u32)
+}
+
+// Whether the jump of the instruction at pc reaches its target
+pub(crate) fn jump_fits(instruction: Instruction, pc: usize) -> bool {
+    use Instruction::*;
+    match instruction {
+        Jump(t) => offset(pc, t, OFFSET_SJ).is_some(),
+        JumpIfFalse(_, t) | JumpIfTrue(_, t) | ForPrep(_, t) | ForLoop(_, t) | ForInLoop(_, t) => {
+            offset(pc, t, OFFSET_SBX).is_some()
+        }
+        _ => true,
+    }
+}
+
+fn count(n: Option<u8>) -> u8 {
+    n.map_or(0, |n| n + 1)
+}
+
+// Packs the instruction at pc, whose jump must fit and whose constant
+// indices must fit their fields, saturated or not.
+pub(crate) fn encode(instruction: Instruction, pc: usize) -> u32 {
+    use Instruction::*;
+    let sbx = |t| offset(pc, t, OFFSET_SBX).expect("jump too long");
+    match instruction {
+        Move(a, b) => abc(MOVE, a, false, b, 0),
+        LoadK(a, k) => abx(LOADK, a, k),
+        LoadNil(a, n) => abc(LOADNIL, a, false, n, 0),
+        GetUpvalue(a, i) => abc(GETUPVAL, a, false, i, 0),
+        SetUpvalue(a, i) => abc(SETUPVAL, a, false, i, 0),
+        GetGlobal(a, k) => abx(GETGLOBAL, a, k),
+        SetGlobal(a, k) => abx(SETGLOBAL, a, k),
+        GetTable(a, b, c) => abc(GETTABLE, a, false, b, c),
+        GetField(a, b, k) => abc(GETFIELD, a, false, b, k as u8),
+        SetTable(a, b, c) => abc(SETTABLE, a, false, b, c),
+        SetField(a, k, c) => abc(SETFIELD, a, false, k as u8, c),
+        NewTable(a) => abc(NEWTABLE, a, false, 0, 0),
+        SetList(a, n, c) => abc(SETLIST, a, false, count(n), c as u8),
+        SelfIndex(a, b, k) => abc(SELF, a, false, b, k as u8),
+        Binary(op, a, b, c) => abc(BINARY + op as u32, a, false, b, c),
+        ArithI(op, a, b, i) => {
+            let op = if op == BinaryOp::Add { ADDI } else { SUBI };
+            abc(op, a, false, b, i as u8)
+        }
+        CompareI(op, a, b, i) => {
+            let op = match op {
+                BinaryOp::LessThan => LTI,
+                BinaryOp::LessEqual => LEI,
+                BinaryOp::GreaterThan => GTI,
+                _ => GEI,
+            };
+            abc(op, a, false, b, i as u8)
+        }
+        EqK(a, b, k, equal) => abc(EQK, a, equal, b, k as u8),
+        Unary(op, a, b) => abc(UNARY + op as u32, a, false, b, 0),
+        Jump(t) => ax(JMP, offset(pc, t, OFFSET_SJ).expect("jump too long")),
+        JumpIfFalse(a, t) => abx(JMPIFFALSE, a, sbx(t)),
+        JumpIfTrue(a, t) => abx(JMPIFTRUE, a, sbx(t)),
+        Call(a, nargs, nresults) => abc(CALL, a, false, count(nargs), count(nresults)),
+        TailCall(a, nargs) => abc(TAILCALL, a, false, count(nargs), 0),
+        Return(a, n) => abc(RETURN, a, false, count(n), 0),
+        Vararg(a, n) => abc(VARARG, a, false, 0, count(n)),
+        Closure(a, i) => abx(CLOSURE, a, i),
+        Close(a) => abc(CLOSE, a, false, 0, 0),
+        ToBeClosed(a) => abc(TBC, a, false, 0, 0),
+        ForPrep(a, t) => abx(FORPREP, a, sbx(t)),
+        ForLoop(a, t) => abx(FORLOOP, a, sbx(t)),
+        ForInCall(a, n) => abc(TFORCALL, a, false, 0, n),
+        ForInLoop(a, t) => abx(TFORLOOP, a, sbx(t)),
+        ExtraArg(x) => ax(EXTRAARG, x),
+    }
+}
+
+// Unpacks the instruction at pc, or None for an unknown opcode.
+#[inline(always)]
+pub(crate) fn decode(i: u32, pc: usize) -> Option<Instruction> {
+    use Instruction::*;
+    let op = i & 0x7f;
+    let a = (i >> 7) as u8;
+    let k = (i >> 15) & 1 != 0;
+    let b = (i >> 16) as u8;
+    let c = (i >> 24) as u8;
+    let bx = i >> 15;
+    let jump = |excess: i64, field: u32| (pc as i64 + 1 + field as i64 - excess) as u32;
+    let sbx = jump(OFFSET_SBX, bx);
+    let count = |n: u8| n.checked_sub(1);
+    Some(match op {
+        MOVE => Move(a, b),
+        LOADK => LoadK(a, bx),
+        LOADNIL => LoadNil(a, b),
+        GETUPVAL => GetUpvalue(a, b),
+        SETUPVAL => SetUpvalue(a, b),
+        GETGLOBAL => GetGlobal(a, bx),
+        SETGLOBAL => SetGlobal(a, bx),
+        GETTABLE => GetTable(a, b, c),
+        GETFIELD => GetField(a, b, c as u32),
+        SETTABLE => SetTable(a, b, c),
+        SETFIELD => SetField(a, b as u32, c),
+        NEWTABLE => NewTable(a),
+        SETLIST => SetList(a, count(b), c as u32),
+        SELF => SelfIndex(a, b, c as u32),
+        EQK => EqK(a, b, c as u32, k),
+        ADDI => ArithI(BinaryOp::Add, a, b, c as i8),
+        SUBI => ArithI(BinaryOp::Subtract, a, b, c as i8),
+        LTI => CompareI(BinaryOp::LessThan, a, b, c as i8),
+        LEI => CompareI(BinaryOp::LessEqual, a, b, c as i8),
+        GTI => CompareI(BinaryOp::GreaterThan, a, b, c as i8),
+        GEI => CompareI(BinaryOp::GreaterEqual, a, b, c as i8),
+        JMP => Jump(jump(OFFSET_SJ, i >> 7)),
+        JMPIFFALSE => JumpIfFalse(a, sbx),
+        JMPIFTRUE => JumpIfTrue(a, sbx),
+        CALL => Call(a, count(b), count(c)),
+        TAILCALL => TailCall(a, count(b)),
+        RETURN => Return(a, count(b)),
+        VARARG => Vararg(a, count(c)),
+        CLOSURE => Closure(a, bx),
+        CLOSE => Close(a),
+        TBC => ToBeClosed(a),
+        FORPREP => ForPrep(a, sbx),
+        FORLOOP => ForLoop(a, sbx),
+        TFORCALL => ForInCall(a, c),
+        TFORLOOP => ForInLoop(a, sbx),
+        EXTRAARG => ExtraArg(i >> 7),
+        op if op < UNARY => Binary(BINARY_OPS[(op - BINARY) as usize], a, b, c),
+        op if op < UNARY + UNARY_OPS.len() as u32 => Unary(UNARY_OPS[(op - UNARY) as usize], a, b),
+        _ => return None,
+    })
+}
+
+// The index or count in the ExtraArg after the instruction at pc,
+// whose own field is saturated
+#[inline(always)]
+pub(crate) fn extra_arg(code: &[u32], pc: usize) -> u32 {
+    code[pc + 1] >> 7
+}
+
+// Unpacks a function's code, with saturated fields filled in from the
+// ExtraArg after them. None if a word doesn't decode or an ExtraArg
+// is missing.
+pub(crate) fn unpack(code: &[u32]) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+    let mut ops = Vec::with_capacity(code.len());
+    for (pc, &word) in code.iter().enumerate() {
+        let extra = || match code.get(pc + 1).map(|&word| decode(word, pc + 1)) {
+            Some(Some(ExtraArg(x))) => Some(x),
+            _ => None,
+        };
+        ops.push(match decode(word, pc)? {
+            LoadK(a, MAX_BX) => LoadK(a, extra()?),
+            GetGlobal(a, MAX_BX) => GetGlobal(a, extra()?),
+            SetGlobal(a, MAX_BX) => SetGlobal(a, extra()?),
+            Closure(a, MAX_BX) => Closure(a, extra()?),
+            SetList(a, n, MAX_C) => SetList(a, n, extra()?),
+            instruction => instruction,
+        });
+    }
+    Some(ops)
+}
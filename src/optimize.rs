@@ -0,0 +1,350 @@
+// Optimizations: folding constant expressions in the syntax tree
+// before it is compiled, and a peephole pass over each function's
+// code once its jumps are linked.
+
+use crate::eval::{binary, binary_op, unary, unary_op, Instruction, Proto};
+use crate::lex::str_to_number;
+use crate::parse::*;
+use crate::value::Value;
+
+// The value of a literal or already folded expression
+pub(crate) fn constant(exp: &Expression) -> Option<Value> {
+    match exp {
+        Expression::Literal(Literal::Number(t)) => str_to_number(t.text()).map(Value::from),
+        Expression::Literal(Literal::String(t)) => Some(Value::String(t.value().clone())),
+        Expression::Literal(Literal::Nil(_)) => Some(Value::Nil),
+        Expression::Literal(Literal::Boolean(t)) => Some(Value::Boolean(t.text() == "true")),
+        Expression::Constant(v, _) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+fn is_number(v: &Value) -> bool {
+    matches!(v, Value::Integer(_) | Value::Number(_))
+}
+
+// Like Lua, results that are NaN or zero floats are left to run
+// time, so constants never need to tell -0.0 from 0.0 or NaN from
+// itself.
+fn folded(v: Value) -> Option<Value> {
+    match v {
+        Value::Number(n) if n.is_nan() || n == 0.0 => None,
+        v => Some(v),
+    }
+}
+
+fn fold_binary(bop: &mut BinaryOperation) -> Option<Expression> {
+    let left = constant(&bop.left)?;
+    let text = bop.operator.text();
+    if let "and" | "or" = text {
+        if left.truthy() == (text == "or") {
+            return Some(Expression::Constant(left, bop.span));
+        }
+        // The right operand decides, still truncated to one value
+        let span = bop.right.span();
+        let right = std::mem::replace(&mut *bop.right, Expression::Constant(Value::Nil, span));
+        return Some(match right {
+            right @ (Expression::FunctionCall(_) | Expression::Literal(Literal::Vararg(_))) => {
+                Expression::Parenthesized(Box::new(right), span)
+            }
+            right => right,
+        });
+    }
+
+    let right = constant(&bop.right)?;
+    let op = binary_op(text);
+    let arithmetic = !matches!(text, ".." | "==" | "~=" | "<" | "<=" | ">" | ">=");
+    // Strings are only converted to numbers at run time, and
+    // division by zero is left to raise its error there.
+    if arithmetic && !(is_number(&left) && is_number(&right)) {
+        return None;
+    }
+    if matches!(text, "/" | "//" | "%") && right.to_float() == Some(0.0) {
+        return None;
+    }
+    let v = folded(binary(op, &left, &right).ok()?)?;
+    Some(Expression::Constant(v, bop.span))
+}
+
+fn fold_unary(uop: &UnaryOperation) -> Option<Expression> {
+    let v = constant(&uop.operand)?;
+    let foldable = match uop.operator.text() {
+        "not" => true,
+        "#" => matches!(v, Value::String(_)),
+        _ => is_number(&v),
+    };
+    if !foldable {
+        return None;
+    }
+    let v = folded(unary(unary_op(uop.operator.text()), &v).ok()?)?;
+    Some(Expression::Constant(v, uop.span))
+}
+
+// Folds an expression bottom up, so each node is looked at once.
+fn fold(exp: &mut Expression) {
+    let replacement = match exp {
+        Expression::FunctionCall(fc) => {
+            fold(&mut fc.function);
+            fc.arguments.iter_mut().for_each(fold);
+            None
+        }
+        Expression::BinaryOperation(bop) => {
+            // Chains like `a + b + c` nest to the left as deeply as
+            // they are long, so they are folded in a loop from the
+            // innermost operation out.
+            let placeholder = Expression::Constant(Value::Nil, bop.span);
+            let mut left = std::mem::replace(exp, placeholder);
+            let mut chain = vec![];
+            while let Expression::BinaryOperation(bop) = left {
+                let BinaryOperation {
+                    operator,
+                    left: inner,
+                    right,
+                    span,
+                } = bop;
+                chain.push((operator, right, span));
+                left = *inner;
+            }
+            fold(&mut left);
+            while let Some((operator, mut right, span)) = chain.pop() {
+                fold(&mut right);
+                let mut bop = BinaryOperation {
+                    operator,
+                    left: Box::new(left),
+                    right,
+                    span,
+                };
+                left = fold_binary(&mut bop).unwrap_or(Expression::BinaryOperation(bop));
+            }
+            Some(left)
+        }
+        Expression::UnaryOperation(uop) => {
+            fold(&mut uop.operand);
+            fold_unary(uop)
+        }
+        Expression::Index(idx) => {
+            fold(&mut idx.object);
+            fold(&mut idx.key);
+            None
+        }
+        Expression::Function(f) => {
+            fold_block(&mut f.body);
+            None
+        }
+        Expression::Table(t) => {
+            for field in &mut t.fields {
+                match field {
+                    Field::Positional(exp) | Field::Named(_, exp) => fold(exp),
+                    Field::Keyed(key, exp) => {
+                        fold(key);
+                        fold(exp);
+                    }
+                }
+            }
+            None
+        }
+        Expression::Parenthesized(e, span) => {
+            fold(e);
+            constant(e).map(|v| Expression::Constant(v, *span))
+        }
+        Expression::Literal(_) | Expression::Constant(..) => None,
+    };
+    if let Some(replacement) = replacement {
+        *exp = replacement;
+    }
+}
+
+fn fold_statement(stmt: &mut Statement) {
+    match stmt {
+        Statement::Expression(e) => fold(e),
+        Statement::If(if_) => {
+            fold(&mut if_.test);
+            fold_block(&mut if_.body);
+            for elseif in &mut if_.elseifs {
+                fold(&mut elseif.test);
+                fold_block(&mut elseif.body);
+            }
+            if let Some(body) = &mut if_.else_body {
+                fold_block(body);
+            }
+        }
+        Statement::FunctionDeclaration(fd) => fold_block(&mut fd.function.body),
+        Statement::Return(ret) => ret.expressions.iter_mut().for_each(fold),
+        Statement::Local(local) => local.expressions.iter_mut().for_each(fold),
+        Statement::Assignment(a) => {
+            a.targets.iter_mut().for_each(fold);
+            a.expressions.iter_mut().for_each(fold);
+        }
+        Statement::While(w) => {
+            fold(&mut w.test);
+            fold_block(&mut w.body);
+        }
+        Statement::Repeat(r) => {
+            fold_block(&mut r.body);
+            fold(&mut r.test);
+        }
+        Statement::NumericFor(f) => {
+            fold(&mut f.start);
+            fold(&mut f.limit);
+            if let Some(step) = &mut f.step {
+                fold(step);
+            }
+            fold_block(&mut f.body);
+        }
+        Statement::GenericFor(f) => {
+            f.expressions.iter_mut().for_each(fold);
+            fold_block(&mut f.body);
+        }
+        Statement::Do(d) => fold_block(&mut d.body),
+        Statement::Break(_) | Statement::Goto(_) | Statement::Label(_) | Statement::Empty(_) => {}
+    }
+}
+
+// Folds arithmetic, comparisons and concatenations of constants, and
+// `and` and `or` whose left operand is constant, throughout a chunk.
+pub(crate) fn fold_block(block: &mut [Statement]) {
+    block.iter_mut().for_each(fold_statement);
+}
+
+fn target(instruction: &mut Instruction) -> Option<&mut u32> {
+    match instruction {
+        Instruction::Jump(target)
+        | Instruction::JumpIfFalse(_, target)
+        | Instruction::JumpIfTrue(_, target)
+        | Instruction::ForPrep(_, target)
+        | Instruction::ForLoop(_, target)
+        | Instruction::ForInLoop(_, target) => Some(target),
+        _ => None,
+    }
+}
+
+// Where control can go after the instruction at pc
+fn successors(instruction: Instruction, pc: usize) -> [Option<usize>; 2] {
+    match instruction {
+        Instruction::Jump(t) => [Some(t as usize), None],
+        Instruction::Return(..) => [None, None],
+        mut instruction => [Some(pc + 1), target(&mut instruction).map(|t| *t as usize)],
+    }
+}
+
+// Points jumps that land on other jumps at where those go. A test of
+// a register that lands on another test of it is decided by the
+// first.
+fn thread_jumps(code: &mut [Instruction]) {
+    use Instruction::*;
+    for pc in 0..code.len() {
+        let mut instruction = code[pc];
+        let Some(&mut start) = target(&mut instruction) else {
+            continue;
+        };
+        let mut t = start as usize;
+        // Bounded, as jumps can go round in circles
+        for _ in 0..code.len() {
+            let next = match (instruction, code[t]) {
+                (_, Jump(next)) => next as usize,
+                (JumpIfFalse(a, _), JumpIfFalse(b, next))
+                | (JumpIfTrue(a, _), JumpIfTrue(b, next))
+                    if a == b =>
+                {
+                    next as usize
+                }
+                (JumpIfFalse(a, _), JumpIfTrue(b, _)) | (JumpIfTrue(a, _), JumpIfFalse(b, _))
+                    if a == b =>
+                {
+                    t + 1
+                }
+                _ => break,
+            };
+            if next == t {
+                break;
+            }
+            t = next;
+        }
+        *target(&mut code[pc]).unwrap() = t as u32;
+    }
+}
+
+// Removes code that can't be reached, and jumps that go where control
+// would go anyway. Returns whether anything was removed.
+fn sweep(proto: &mut Proto) -> bool {
+    let code = &proto.ops;
+    let mut reachable = vec![false; code.len()];
+    let mut work = vec![0];
+    while let Some(pc) = work.pop() {
+        if pc >= code.len() || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        work.extend(successors(code[pc], pc).into_iter().flatten());
+    }
+
+    let mut keep = reachable.clone();
+    for pc in 0..code.len() {
+        let to_next = match code[pc] {
+            Instruction::Jump(t)
+            | Instruction::JumpIfFalse(_, t)
+            | Instruction::JumpIfTrue(_, t) => {
+                let t = t as usize;
+                t > pc && (pc + 1..t).all(|i| !reachable[i])
+            }
+            _ => false,
+        };
+        if to_next {
+            keep[pc] = false;
+        }
+    }
+    if keep.iter().all(|&k| k) {
+        return false;
+    }
+
+    // Where each instruction ends up, or the next one kept if it goes
+    let mut new_pc = Vec::with_capacity(code.len() + 1);
+    let mut n = 0;
+    for &k in &keep {
+        new_pc.push(n);
+        n += k as usize;
+    }
+    new_pc.push(n);
+    // Removed positions take the index of the next kept instruction
+    for pc in (0..code.len()).rev() {
+        if !keep[pc] {
+            new_pc[pc] = new_pc[pc + 1];
+        }
+    }
+
+    let mut pc = 0;
+    proto.ops.retain(|_| {
+        pc += 1;
+        keep[pc - 1]
+    });
+    let mut pc = 0;
+    proto.spans.retain(|_| {
+        pc += 1;
+        keep[pc - 1]
+    });
+    for instruction in &mut proto.ops {
+        if let Some(t) = target(instruction) {
+            *t = new_pc[*t as usize] as u32;
+        }
+    }
+    proto.names = std::mem::take(&mut proto.names)
+        .into_iter()
+        .filter(|(pc, _)| keep[*pc])
+        .map(|(pc, name)| (new_pc[pc], name))
+        .collect();
+    for local in &mut proto.locvars {
+        local.start_pc = new_pc[local.start_pc];
+        local.end_pc = new_pc[local.end_pc];
+    }
+    true
+}
+
+// Runs on linked code, where jump targets are offsets.
+pub(crate) fn peephole(proto: &mut Proto) {
+    loop {
+        thread_jumps(&mut proto.ops);
+        if !sweep(proto) {
+            break;
+        }
+    }
+}
@@ -1,15 +1,25 @@
 use crate::lex::*;
+use crate::value::Value;
+use std::cell::Cell;
+use std::collections::VecDeque;
 
 #[derive(Debug)]
 pub enum Literal {
     Identifier(Token),
     Number(Token),
+    String(Token),
+    Nil(Token),
+    Boolean(Token),
+    Vararg(Token),
 }
 
 #[derive(Debug)]
 pub struct FunctionCall {
-    pub name: Token,
+    pub function: Box<Expression>,
+    // Set for method calls, `object:name(...)`
+    pub method: Option<Token>,
     pub arguments: Vec<Expression>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -17,37 +27,180 @@ pub struct BinaryOperation {
     pub operator: Token,
     pub left: Box<Expression>,
     pub right: Box<Expression>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct UnaryOperation {
+    pub operator: Token,
+    pub operand: Box<Expression>,
+    pub span: Span,
+}
+
+// Both `object[key]` and `object.name`, where the name becomes a
+// String literal key.
+#[derive(Debug)]
+pub struct Index {
+    pub object: Box<Expression>,
+    pub key: Box<Expression>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub parameters: Vec<Token>,
+    pub vararg: bool,
+    pub body: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum Field {
+    Positional(Expression),
+    Named(Token, Expression),
+    Keyed(Expression, Expression),
+}
+
+#[derive(Debug)]
+pub struct Table {
+    pub fields: Vec<Field>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub enum Expression {
     FunctionCall(FunctionCall),
     BinaryOperation(BinaryOperation),
+    UnaryOperation(UnaryOperation),
     Literal(Literal),
+    Index(Index),
+    Function(Function),
+    Table(Table),
+    // Parentheses truncate calls and varargs to a single value
+    Parenthesized(Box<Expression>, Span),
+    // What the compiler folds constant expressions to; never parsed
+    Constant(Value, Span),
+}
+
+impl Literal {
+    pub fn span(&self) -> Span {
+        match self {
+            Literal::Identifier(t)
+            | Literal::Number(t)
+            | Literal::String(t)
+            | Literal::Nil(t)
+            | Literal::Boolean(t)
+            | Literal::Vararg(t) => t.span,
+        }
+    }
+}
+
+impl Expression {
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::FunctionCall(fc) => fc.span,
+            Expression::BinaryOperation(bop) => bop.span,
+            Expression::UnaryOperation(uop) => uop.span,
+            Expression::Literal(lit) => lit.span(),
+            Expression::Index(idx) => idx.span,
+            Expression::Function(f) => f.span,
+            Expression::Table(t) => t.span,
+            Expression::Parenthesized(_, span) | Expression::Constant(_, span) => *span,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct FunctionDeclaration {
     pub name: Token,
-    pub parameters: Vec<Token>,
+    // `a.b.c` in `function a.b.c()`
+    pub fields: Vec<Token>,
+    // `m` in `function a:m()`, which adds an implicit self parameter
+    pub method: Option<Token>,
+    pub local: bool,
+    pub function: Function,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct ElseIf {
+    pub test: Expression,
     pub body: Vec<Statement>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct If {
     pub test: Expression,
     pub body: Vec<Statement>,
+    pub elseifs: Vec<ElseIf>,
+    pub else_body: Option<Vec<Statement>>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct Local {
+pub struct LocalName {
     pub name: Token,
-    pub expression: Expression,
+    // `const` or `close` in `local x <const>`
+    pub attribute: Option<Token>,
+}
+
+#[derive(Debug)]
+pub struct Local {
+    pub names: Vec<LocalName>,
+    pub expressions: Vec<Expression>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Assignment {
+    pub targets: Vec<Expression>,
+    pub expressions: Vec<Expression>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct Return {
-    pub expression: Expression,
+    pub expressions: Vec<Expression>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct While {
+    pub test: Expression,
+    pub body: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Repeat {
+    pub body: Vec<Statement>,
+    pub test: Expression,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct NumericFor {
+    pub name: Token,
+    pub start: Expression,
+    pub limit: Expression,
+    pub step: Option<Expression>,
+    pub body: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct GenericFor {
+    pub names: Vec<Token>,
+    pub expressions: Vec<Expression>,
+    pub body: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Do {
+    pub body: Vec<Statement>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -57,428 +210,935 @@ pub enum Statement {
     FunctionDeclaration(FunctionDeclaration),
     Return(Return),
     Local(Local),
+    Assignment(Assignment),
+    While(While),
+    Repeat(Repeat),
+    NumericFor(NumericFor),
+    GenericFor(GenericFor),
+    Do(Do),
+    Break(Token),
+    Goto(Token),
+    Label(Token),
+    // A lone `;`
+    Empty(Token),
 }
 
 pub type Ast = Vec<Statement>;
 
-fn expect_keyword(tokens: &[Token], index: usize, value: &str) -> bool {
-    if index >= tokens.len() {
-        return false;
+// The tokens of a chunk, pulled from the lexer as the parser gets to
+// them, so only the few being looked at are held.
+struct Tokens<'a> {
+    lexer: &'a mut dyn Iterator<Item = Result<Token, LexError>>,
+    // Looked at but not taken yet. Table fields look two ahead.
+    ahead: VecDeque<Token>,
+    // Where the last token taken ends whatever it closes
+    last: Option<Span>,
+    // Why the lexer stopped early. The tokens before it are parsed
+    // first, so a syntax error among them is the one reported.
+    error: Option<LexError>,
+}
+
+impl Tokens<'_> {
+    fn peek_nth(&mut self, n: usize) -> Option<&Token> {
+        while self.ahead.len() <= n && self.error.is_none() {
+            match self.lexer.next() {
+                Some(Ok(t)) => self.ahead.push_back(t),
+                Some(Err(e)) => self.error = Some(e),
+                None => break,
+            }
+        }
+
+        self.ahead.get(n)
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.peek_nth(0)
+    }
+
+    // Takes the next token, which the caller has looked at.
+    fn take(&mut self) -> Token {
+        self.peek();
+        let t = self.ahead.pop_front().expect("token taken at end of input");
+        self.last = Some(t.span);
+        t
+    }
+
+    // From start to the end of the last token taken.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.last.unwrap_or(start))
     }
+}
+
+fn expect_keyword(tokens: &mut Tokens, value: &str) -> bool {
+    tokens
+        .peek()
+        .is_some_and(|t| t.kind == TokenKind::Keyword && t.text() == value)
+}
+
+fn expect_syntax(tokens: &mut Tokens, value: &str) -> bool {
+    tokens
+        .peek()
+        .is_some_and(|t| t.kind == TokenKind::Syntax && t.text() == value)
+}
+
+fn expect_operator(tokens: &mut Tokens, value: &str) -> bool {
+    tokens
+        .peek()
+        .is_some_and(|t| t.kind == TokenKind::Operator && t.text() == value)
+}
 
-    let t = tokens[index].clone();
-    t.kind == TokenKind::Keyword && t.value == value
+fn expect_identifier(tokens: &mut Tokens) -> bool {
+    tokens
+        .peek()
+        .is_some_and(|t| t.kind == TokenKind::Identifier)
 }
 
-fn expect_syntax(tokens: &[Token], index: usize, value: &str) -> bool {
-    if index >= tokens.len() {
-        return false;
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub msg: String,
+    // True if the input ended before the error, so more input could
+    // have fixed it. Used by the REPL to prompt for another line.
+    pub incomplete: bool,
+}
+
+impl From<String> for ParseError {
+    fn from(msg: String) -> ParseError {
+        ParseError {
+            msg,
+            incomplete: false,
+        }
     }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+// Like Lua's LUAI_MAXCCALLS: statements and expressions nested deeper
+// than this are an error rather than a Rust stack overflow, here or
+// in the passes over the tree after parsing.
+const MAX_SYNTAX_LEVELS: usize = 200;
 
-    let t = tokens[index].clone();
-    t.kind == TokenKind::Syntax && t.value == value
+thread_local! {
+    static LEVELS: Cell<usize> = const { Cell::new(0) };
 }
 
-fn expect_identifier(tokens: &[Token], index: usize) -> bool {
-    if index >= tokens.len() {
-        return false;
+// A level of nesting, counted while it is alive, as Lua's enterlevel
+// and leavelevel do
+struct Level;
+
+impl Level {
+    fn enter(sm: &SourceMap, tokens: &mut Tokens) -> Result<Level, ParseError> {
+        let levels = LEVELS.with(|l| l.get());
+        if levels >= MAX_SYNTAX_LEVELS {
+            return Err(error_at(sm, tokens, "chunk has too many syntax levels:"));
+        }
+        LEVELS.with(|l| l.set(levels + 1));
+        Ok(Level)
     }
+}
 
-    let t = tokens[index].clone();
-    t.kind == TokenKind::Identifier
+impl Drop for Level {
+    fn drop(&mut self) {
+        LEVELS.with(|l| l.set(l.get() - 1));
+    }
 }
 
-fn parse_expression(raw: &[char], tokens: &[Token], index: usize) -> Option<(Expression, usize)> {
-    if index >= tokens.len() {
-        return None;
+// Formats an error at the next token, or just past the last token if
+// the input ended early. If it ended because the lexer failed, that
+// is the error.
+fn error_at(sm: &SourceMap, tokens: &mut Tokens, msg: &str) -> ParseError {
+    if let Some(t) = tokens.peek() {
+        return t.span.debug(sm, msg).into();
     }
 
-    let t = tokens[index].clone();
-    let left = match t.kind {
-        TokenKind::Number => Expression::Literal(Literal::Number(t)),
-        TokenKind::Identifier => Expression::Literal(Literal::Identifier(t)),
-        _ => {
-            return None;
-        }
+    if let Some(e) = &tokens.error {
+        return ParseError {
+            msg: e.debug(sm),
+            incomplete: e.incomplete,
+        };
+    }
+
+    let msg = match tokens.last {
+        Some(span) => Span::new(span.file, span.end, span.end).debug(sm, msg),
+        None => msg.to_string(),
     };
-    let mut next_index = index + 1;
-    if expect_syntax(tokens, next_index, "(") {
-        next_index += 1; // Skip past open paren
-
-        // Function call
-        let mut arguments: Vec<Expression> = vec![];
-        while !expect_syntax(tokens, next_index, ")") {
-            if !arguments.is_empty() {
-                if !expect_syntax(tokens, next_index, ",") {
-                    println!(
-                        "{}",
-                        tokens[next_index]
-                            .loc
-                            .debug(raw, "Expected comma between function call arguments:")
-                    );
-                    return None;
-                }
-
-                next_index += 1; // Skip past comma
-            }
+    ParseError {
+        msg,
+        incomplete: true,
+    }
+}
 
-            let res = parse_expression(raw, tokens, next_index);
-            if let Some((arg, next_next_index)) = res {
-                next_index = next_next_index;
-                arguments.push(arg);
-            } else {
-                println!(
-                    "{}",
-                    tokens[next_index]
-                        .loc
-                        .debug(raw, "Expected valid expression in function call arguments:")
-                );
-                return None;
-            }
-        }
+fn parse_identifier(sm: &SourceMap, tokens: &mut Tokens, msg: &str) -> Result<Token, ParseError> {
+    if !expect_identifier(tokens) {
+        return Err(error_at(sm, tokens, msg));
+    }
 
-        next_index += 1; // Skip past closing paren
+    Ok(tokens.take())
+}
 
-        return Some((
-            Expression::FunctionCall(FunctionCall {
-                name: tokens[index].clone(),
-                arguments,
-            }),
-            next_index,
-        ));
+// Literals other than names, which start suffixed expressions
+fn is_literal(t: &Token) -> bool {
+    match t.kind {
+        TokenKind::Number | TokenKind::String | TokenKind::Nil | TokenKind::Boolean => true,
+        TokenKind::Syntax => t.text() == "...",
+        _ => false,
     }
+}
 
-    // Might be a literal expression
-    if next_index >= tokens.len() || tokens[next_index].clone().kind != TokenKind::Operator {
-        return Some((left, next_index));
-    }
-
-    // Otherwise is a binary operation
-    let op = tokens[next_index].clone();
-    next_index += 1; // Skip past op
-
-    if next_index >= tokens.len() {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected valid right hand side binary operand:")
-        );
-        return None;
-    }
-
-    let rtoken = tokens[next_index].clone();
-    let right = match rtoken.kind {
-        TokenKind::Number => Expression::Literal(Literal::Number(rtoken)),
-        TokenKind::Identifier => Expression::Literal(Literal::Identifier(rtoken)),
-        _ => {
-            println!(
-                "{}",
-                rtoken
-                    .loc
-                    .debug(raw, "Expected valid right hand side binary operand:")
-            );
-            return None;
-        }
+fn parse_literal(t: Token) -> Literal {
+    match t.kind {
+        TokenKind::Number => Literal::Number(t),
+        TokenKind::String => Literal::String(t),
+        TokenKind::Nil => Literal::Nil(t),
+        TokenKind::Boolean => Literal::Boolean(t),
+        _ => Literal::Vararg(t),
+    }
+}
+
+// Left and right binding power of binary operators, from Lua's
+// lparser.c. Right associative operators bind less tightly on the
+// right.
+fn binary_priority(t: &Token) -> Option<(u8, u8)> {
+    let priority = match (&t.kind, t.text()) {
+        (TokenKind::Keyword, "or") => (1, 1),
+        (TokenKind::Keyword, "and") => (2, 2),
+        (TokenKind::Operator, "<" | ">" | "<=" | ">=" | "~=" | "==") => (3, 3),
+        (TokenKind::Operator, "|") => (4, 4),
+        (TokenKind::Operator, "~") => (5, 5),
+        (TokenKind::Operator, "&") => (6, 6),
+        (TokenKind::Operator, "<<" | ">>") => (7, 7),
+        (TokenKind::Operator, "..") => (9, 8),
+        (TokenKind::Operator, "+" | "-") => (10, 10),
+        (TokenKind::Operator, "*" | "/" | "//" | "%") => (11, 11),
+        (TokenKind::Operator, "^") => (14, 13),
+        _ => return None,
     };
-    next_index += 1; // Skip past right hand operand
 
-    Some((
-        Expression::BinaryOperation(BinaryOperation {
-            left: Box::new(left),
-            right: Box::new(right),
-            operator: op,
-        }),
-        next_index,
-    ))
+    Some(priority)
+}
+
+const UNARY_PRIORITY: u8 = 12;
+
+fn is_unary_operator(t: &Token) -> bool {
+    matches!(
+        (&t.kind, t.text()),
+        (TokenKind::Keyword, "not") | (TokenKind::Operator, "-" | "#" | "~")
+    )
+}
+
+fn parse_expression_list(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+) -> Result<Vec<Expression>, ParseError> {
+    let mut expressions = vec![parse_expression(sm, tokens)?];
+    while expect_syntax(tokens, ",") {
+        tokens.take(); // Skip past comma
+        expressions.push(parse_expression(sm, tokens)?);
+    }
+
+    Ok(expressions)
 }
 
-fn parse_function(raw: &[char], tokens: &[Token], index: usize) -> Option<(Statement, usize)> {
-    if !expect_keyword(tokens, index, "function") {
-        return None;
+fn parse_call_arguments(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+) -> Result<Vec<Expression>, ParseError> {
+    // f"str" and f{...} are calls with a single argument
+    if tokens.peek().is_some_and(|t| t.kind == TokenKind::String) {
+        let arg = Expression::Literal(Literal::String(tokens.take()));
+        return Ok(vec![arg]);
+    }
+
+    if expect_syntax(tokens, "{") {
+        return Ok(vec![parse_table(sm, tokens)?]);
+    }
+
+    if !expect_syntax(tokens, "(") {
+        return Err(error_at(sm, tokens, "Expected function call arguments:"));
+    }
+
+    tokens.take(); // Skip past open paren
+    let mut arguments = vec![];
+    if !expect_syntax(tokens, ")") {
+        arguments = parse_expression_list(sm, tokens)?;
     }
 
-    let mut next_index = index + 1;
-    if !expect_identifier(tokens, next_index) {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected valid identifier for function name:")
-        );
-        return None;
+    if !expect_syntax(tokens, ")") {
+        return Err(error_at(
+            sm,
+            tokens,
+            "Expected comma or close parenthesis in function call arguments:",
+        ));
+    }
+
+    tokens.take(); // Skip past close paren
+    Ok(arguments)
+}
+
+fn parse_table(sm: &SourceMap, tokens: &mut Tokens) -> Result<Expression, ParseError> {
+    let start = tokens.take().span; // Skip past open brace
+    let mut fields = vec![];
+    while !expect_syntax(tokens, "}") {
+        if expect_syntax(tokens, "[") {
+            tokens.take(); // Skip past open bracket
+            let key = parse_expression(sm, tokens)?;
+            if !expect_syntax(tokens, "]") {
+                return Err(error_at(sm, tokens, "Expected ] after table key:"));
+            }
+            tokens.take(); // Skip past close bracket
+            if !expect_syntax(tokens, "=") {
+                return Err(error_at(sm, tokens, "Expected = after table key:"));
+            }
+            tokens.take(); // Skip past =
+
+            let value = parse_expression(sm, tokens)?;
+            fields.push(Field::Keyed(key, value));
+        } else if expect_identifier(tokens)
+            && tokens
+                .peek_nth(1)
+                .is_some_and(|t| t.kind == TokenKind::Syntax && t.text() == "=")
+        {
+            let name = tokens.take();
+            tokens.take(); // Skip past =
+            let value = parse_expression(sm, tokens)?;
+            fields.push(Field::Named(name, value));
+        } else {
+            let value = parse_expression(sm, tokens)?;
+            fields.push(Field::Positional(value));
+        }
+
+        if expect_syntax(tokens, ",") || expect_syntax(tokens, ";") {
+            tokens.take(); // Skip past separator
+        } else if !expect_syntax(tokens, "}") {
+            return Err(error_at(
+                sm,
+                tokens,
+                "Expected comma or close brace in table constructor:",
+            ));
+        }
     }
-    let name = tokens[next_index].clone();
 
-    next_index += 1; // Skip past name
-    if !expect_syntax(tokens, next_index, "(") {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected open parenthesis in function declaration:")
-        );
-        return None;
+    // Data files are mostly table constructors, so they are not kept
+    // with room to grow
+    fields.shrink_to_fit();
+    let span = start.to(tokens.take().span);
+    Ok(Expression::Table(Table { fields, span }))
+}
+
+// Parameters and body of a function, starting at the open paren.
+fn parse_function_body(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+    start: Span,
+) -> Result<Function, ParseError> {
+    if !expect_syntax(tokens, "(") {
+        return Err(error_at(
+            sm,
+            tokens,
+            "Expected open parenthesis in function declaration:",
+        ));
     }
 
-    next_index += 1; // Skip past open paren
+    tokens.take(); // Skip past open paren
     let mut parameters: Vec<Token> = vec![];
-    while !expect_syntax(tokens, next_index, ")") {
-        if !parameters.is_empty() {
-            if !expect_syntax(tokens, next_index, ",") {
-                println!("{}", tokens[next_index].loc.debug(raw, "Expected comma or close parenthesis after parameter in function declaration:"));
-                return None;
+    let mut vararg = false;
+    while !expect_syntax(tokens, ")") {
+        if !parameters.is_empty() || vararg {
+            if vararg || !expect_syntax(tokens, ",") {
+                return Err(error_at(
+                    sm,
+                    tokens,
+                    "Expected comma or close parenthesis after parameter in function declaration:",
+                ));
             }
 
-            next_index += 1; // Skip past comma
+            tokens.take(); // Skip past comma
         }
 
-        parameters.push(tokens[next_index].clone());
-        next_index += 1; // Skip past param
+        if expect_syntax(tokens, "...") {
+            vararg = true;
+            tokens.take(); // Skip past ...
+            continue;
+        }
+
+        let param = parse_identifier(
+            sm,
+            tokens,
+            "Expected valid identifier for function parameter:",
+        )?;
+        parameters.push(param);
     }
 
-    next_index += 1; // Skip past close paren
+    tokens.take(); // Skip past close paren
+
+    let body = parse_block(sm, tokens)?;
+    if !expect_keyword(tokens, "end") {
+        return Err(error_at(
+            sm,
+            tokens,
+            "Expected end to close function declaration:",
+        ));
+    }
 
-    let mut statements: Vec<Statement> = vec![];
-    while !expect_keyword(tokens, next_index, "end") {
-        let res = parse_statement(raw, tokens, next_index);
-        if let Some((stmt, next_next_index)) = res {
-            next_index = next_next_index;
-            statements.push(stmt);
+    let span = start.to(tokens.take().span);
+    Ok(Function {
+        parameters,
+        vararg,
+        body,
+        span,
+    })
+}
+
+// Names, parenthesized expressions and the chain of calls, indexes
+// and method calls following them.
+fn parse_suffixed_expression(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+) -> Result<Expression, ParseError> {
+    let mut expr = if expect_syntax(tokens, "(") {
+        let start = tokens.take().span;
+        let inner = parse_expression(sm, tokens)?;
+        if !expect_syntax(tokens, ")") {
+            return Err(error_at(sm, tokens, "Expected close parenthesis:"));
+        }
+
+        let span = start.to(tokens.take().span);
+        Expression::Parenthesized(Box::new(inner), span)
+    } else if expect_identifier(tokens) {
+        Expression::Literal(Literal::Identifier(tokens.take()))
+    } else {
+        return Err(error_at(sm, tokens, "Expected valid expression:"));
+    };
+
+    loop {
+        if expect_syntax(tokens, ".") {
+            tokens.take(); // Skip past period
+            let mut key = parse_identifier(sm, tokens, "Expected valid identifier after period:")?;
+            key.kind = TokenKind::String;
+            let span = expr.span().to(key.span);
+            expr = Expression::Index(Index {
+                object: Box::new(expr),
+                key: Box::new(Expression::Literal(Literal::String(key))),
+                span,
+            });
+        } else if expect_syntax(tokens, "[") {
+            tokens.take(); // Skip past open bracket
+            let key = parse_expression(sm, tokens)?;
+            if !expect_syntax(tokens, "]") {
+                return Err(error_at(sm, tokens, "Expected ] after index:"));
+            }
+
+            let span = expr.span().to(tokens.take().span);
+            expr = Expression::Index(Index {
+                object: Box::new(expr),
+                key: Box::new(key),
+                span,
+            });
+        } else if expect_syntax(tokens, ":") {
+            tokens.take(); // Skip past colon
+            let method =
+                parse_identifier(sm, tokens, "Expected valid identifier for method name:")?;
+            let arguments = parse_call_arguments(sm, tokens)?;
+            let span = tokens.span_from(expr.span());
+            expr = Expression::FunctionCall(FunctionCall {
+                function: Box::new(expr),
+                method: Some(method),
+                arguments,
+                span,
+            });
+        } else if expect_syntax(tokens, "(")
+            || expect_syntax(tokens, "{")
+            || tokens.peek().is_some_and(|t| t.kind == TokenKind::String)
+        {
+            // Like Lua 5.2 and later, an open paren on the next line
+            // still continues the call: `f\n(g)` is `f(g)`.
+            let arguments = parse_call_arguments(sm, tokens)?;
+            let span = tokens.span_from(expr.span());
+            expr = Expression::FunctionCall(FunctionCall {
+                function: Box::new(expr),
+                method: None,
+                arguments,
+                span,
+            });
         } else {
-            println!(
-                "{}",
-                tokens[next_index]
-                    .loc
-                    .debug(raw, "Expected valid statement in function declaration:")
-            );
-            return None;
+            return Ok(expr);
         }
     }
+}
 
-    next_index += 1; // Skip past end
+fn parse_simple_expression(sm: &SourceMap, tokens: &mut Tokens) -> Result<Expression, ParseError> {
+    if tokens.peek().is_some_and(is_literal) {
+        return Ok(Expression::Literal(parse_literal(tokens.take())));
+    }
 
-    Some((
-        Statement::FunctionDeclaration(FunctionDeclaration {
-            name,
-            parameters,
-            body: statements,
-        }),
-        next_index,
-    ))
+    if expect_syntax(tokens, "{") {
+        return parse_table(sm, tokens);
+    }
+
+    if expect_keyword(tokens, "function") {
+        let start = tokens.take().span;
+        let f = parse_function_body(sm, tokens, start)?;
+        return Ok(Expression::Function(f));
+    }
+
+    parse_suffixed_expression(sm, tokens)
+}
+
+// Precedence climbing over binary operators, as in Lua's subexpr.
+fn parse_subexpression(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+    limit: u8,
+) -> Result<Expression, ParseError> {
+    let _level = Level::enter(sm, tokens)?;
+    let mut left = if tokens.peek().is_some_and(is_unary_operator) {
+        let operator = tokens.take();
+        let operand = parse_subexpression(sm, tokens, UNARY_PRIORITY)?;
+        let span = operator.span.to(operand.span());
+        Expression::UnaryOperation(UnaryOperation {
+            operator,
+            operand: Box::new(operand),
+            span,
+        })
+    } else {
+        parse_simple_expression(sm, tokens)?
+    };
+
+    // Only the right operand recurses, so chains like `a + b + c` take
+    // one level however long they are, while `a .. b .. c` nests.
+    while let Some((left_priority, right_priority)) = tokens.peek().and_then(binary_priority) {
+        if left_priority <= limit {
+            break;
+        }
+        let operator = tokens.take();
+        let right = parse_subexpression(sm, tokens, right_priority)?;
+        let span = left.span().to(right.span());
+        left = Expression::BinaryOperation(BinaryOperation {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+            span,
+        });
+    }
+
+    Ok(left)
+}
+
+fn parse_expression(sm: &SourceMap, tokens: &mut Tokens) -> Result<Expression, ParseError> {
+    parse_subexpression(sm, tokens, 0)
+}
+
+// The statement parsers below start after their keyword, which began
+// at start.
+
+fn parse_function(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+    start: Span,
+) -> Result<Statement, ParseError> {
+    let name = parse_identifier(sm, tokens, "Expected valid identifier for function name:")?;
+
+    let mut fields = vec![];
+    while expect_syntax(tokens, ".") {
+        tokens.take(); // Skip past period
+        let field = parse_identifier(
+            sm,
+            tokens,
+            "Expected valid identifier after period in function name:",
+        )?;
+        fields.push(field);
+    }
+
+    let mut method = None;
+    if expect_syntax(tokens, ":") {
+        tokens.take(); // Skip past colon
+        let m = parse_identifier(sm, tokens, "Expected valid identifier for method name:")?;
+        method = Some(m);
+    }
+
+    let function = parse_function_body(sm, tokens, start)?;
+    Ok(Statement::FunctionDeclaration(FunctionDeclaration {
+        span: function.span,
+        name,
+        fields,
+        method,
+        local: false,
+        function,
+    }))
 }
 
-fn parse_return(raw: &[char], tokens: &[Token], index: usize) -> Option<(Statement, usize)> {
-    if !expect_keyword(tokens, index, "return") {
-        return None;
+fn parse_local(sm: &SourceMap, tokens: &mut Tokens, start: Span) -> Result<Statement, ParseError> {
+    if expect_keyword(tokens, "function") {
+        tokens.take(); // Skip past function
+        let name = parse_identifier(sm, tokens, "Expected valid identifier for function name:")?;
+        let function = parse_function_body(sm, tokens, start)?;
+        return Ok(Statement::FunctionDeclaration(FunctionDeclaration {
+            span: function.span,
+            name,
+            fields: vec![],
+            method: None,
+            local: true,
+            function,
+        }));
     }
 
-    let mut next_index = index + 1; // Skip past return
-    let res = parse_expression(raw, tokens, next_index);
-    if res.is_none() {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected valid expression in return statement:")
-        );
-        return None;
+    let mut names = vec![];
+    loop {
+        let name = parse_identifier(sm, tokens, "Expected valid identifier for local name:")?;
+
+        let mut attribute = None;
+        if expect_operator(tokens, "<") {
+            tokens.take(); // Skip past <
+            let attrib = parse_identifier(sm, tokens, "Expected attribute name after <:")?;
+            if attrib.text() != "const" && attrib.text() != "close" {
+                return Err(attrib
+                    .span
+                    .debug(sm, "Unknown attribute, expected const or close:")
+                    .into());
+            }
+            if !expect_operator(tokens, ">") {
+                return Err(error_at(sm, tokens, "Expected > after attribute:"));
+            }
+            tokens.take(); // Skip past >
+            attribute = Some(attrib);
+        }
+
+        names.push(LocalName { name, attribute });
+        if !expect_syntax(tokens, ",") {
+            break;
+        }
+
+        tokens.take(); // Skip past comma
     }
 
-    let (expr, next_next_index) = res.unwrap();
-    next_index = next_next_index;
-    if !expect_syntax(tokens, next_index, ";") {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected semicolon in return statement:")
-        );
-        return None;
+    let mut expressions = vec![];
+    if expect_syntax(tokens, "=") {
+        tokens.take(); // Skip past =
+        expressions = parse_expression_list(sm, tokens)?;
     }
 
-    next_index += 1; // Skip past semicolon
+    let span = tokens.span_from(start);
+    Ok(Statement::Local(Local {
+        names,
+        expressions,
+        span,
+    }))
+}
+
+// Parses `test then body`, stopping at the token after the body,
+// which is one of elseif, else or end.
+fn parse_condition_and_body(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+) -> Result<(Expression, Vec<Statement>), ParseError> {
+    let test = parse_expression(sm, tokens)?;
+    if !expect_keyword(tokens, "then") {
+        return Err(error_at(sm, tokens, "Expected then after if test:"));
+    }
 
-    Some((Statement::Return(Return { expression: expr }), next_index))
+    tokens.take(); // Skip past then
+    let body = parse_block(sm, tokens)?;
+    Ok((test, body))
 }
 
-fn parse_local(raw: &[char], tokens: &[Token], index: usize) -> Option<(Statement, usize)> {
-    if !expect_keyword(tokens, index, "local") {
-        return None;
+fn parse_if(sm: &SourceMap, tokens: &mut Tokens, start: Span) -> Result<Statement, ParseError> {
+    let (test, body) = parse_condition_and_body(sm, tokens)?;
+
+    let mut elseifs = vec![];
+    while expect_keyword(tokens, "elseif") {
+        let elseif = tokens.take().span;
+        let (test, body) = parse_condition_and_body(sm, tokens)?;
+        elseifs.push(ElseIf {
+            test,
+            body,
+            span: tokens.span_from(elseif),
+        });
     }
 
-    let mut next_index = index + 1; // Skip past local
+    let mut else_body = None;
+    if expect_keyword(tokens, "else") {
+        tokens.take(); // Skip past else
+        else_body = Some(parse_block(sm, tokens)?);
+    }
 
-    if !expect_identifier(tokens, next_index) {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected valid identifier for local name:")
-        );
-        return None;
+    if !expect_keyword(tokens, "end") {
+        return Err(error_at(sm, tokens, "Expected end to close if:"));
     }
 
-    let name = tokens[next_index].clone();
-    next_index += 1; // Skip past name
+    let span = start.to(tokens.take().span);
+    Ok(Statement::If(If {
+        test,
+        body,
+        elseifs,
+        else_body,
+        span,
+    }))
+}
 
-    if !expect_syntax(tokens, next_index, "=") {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected = syntax after local name:")
-        );
-        return None;
+// Parses `body end` after a do.
+fn parse_do_end(sm: &SourceMap, tokens: &mut Tokens) -> Result<Vec<Statement>, ParseError> {
+    let body = parse_block(sm, tokens)?;
+    if !expect_keyword(tokens, "end") {
+        return Err(error_at(sm, tokens, "Expected end to close do block:"));
     }
 
-    next_index += 1; // Skip past =
+    tokens.take(); // Skip past end
+    Ok(body)
+}
 
-    let res = parse_expression(raw, tokens, next_index);
-    if res.is_none() {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected valid expression in local declaration:")
-        );
-        return None;
+// Parses `do body end` after a loop header.
+fn parse_do_body(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+    msg: &str,
+) -> Result<Vec<Statement>, ParseError> {
+    if !expect_keyword(tokens, "do") {
+        return Err(error_at(sm, tokens, msg));
     }
 
-    let (expr, next_next_index) = res.unwrap();
-    next_index = next_next_index;
+    tokens.take(); // Skip past do
+    parse_do_end(sm, tokens)
+}
+
+fn parse_while(sm: &SourceMap, tokens: &mut Tokens, start: Span) -> Result<Statement, ParseError> {
+    let test = parse_expression(sm, tokens)?;
+    let body = parse_do_body(sm, tokens, "Expected do after while test:")?;
+    let span = tokens.span_from(start);
+    Ok(Statement::While(While { test, body, span }))
+}
 
-    if !expect_syntax(tokens, next_index, ";") {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected semicolon in return statement:")
-        );
-        return None;
+fn parse_repeat(sm: &SourceMap, tokens: &mut Tokens, start: Span) -> Result<Statement, ParseError> {
+    let body = parse_block(sm, tokens)?;
+    if !expect_keyword(tokens, "until") {
+        return Err(error_at(sm, tokens, "Expected until to close repeat:"));
     }
 
-    next_index += 1; // Skip past semicolon
+    tokens.take(); // Skip past until
+    let test = parse_expression(sm, tokens)?;
+    let span = start.to(test.span());
+    Ok(Statement::Repeat(Repeat { body, test, span }))
+}
+
+fn parse_for(sm: &SourceMap, tokens: &mut Tokens, start: Span) -> Result<Statement, ParseError> {
+    let name = parse_identifier(
+        sm,
+        tokens,
+        "Expected valid identifier for for loop variable:",
+    )?;
+
+    if expect_syntax(tokens, "=") {
+        tokens.take(); // Skip past =
+        let first = parse_expression(sm, tokens)?;
+        if !expect_syntax(tokens, ",") {
+            return Err(error_at(sm, tokens, "Expected comma after for loop start:"));
+        }
 
-    Some((
-        Statement::Local(Local {
+        tokens.take(); // Skip past comma
+        let limit = parse_expression(sm, tokens)?;
+        let mut step = None;
+        if expect_syntax(tokens, ",") {
+            tokens.take(); // Skip past comma
+            step = Some(parse_expression(sm, tokens)?);
+        }
+
+        let body = parse_do_body(sm, tokens, "Expected do after for loop limits:")?;
+        let span = tokens.span_from(start);
+        return Ok(Statement::NumericFor(NumericFor {
             name,
-            expression: expr,
-        }),
-        next_index,
-    ))
-}
+            start: first,
+            limit,
+            step,
+            body,
+            span,
+        }));
+    }
 
-fn parse_if(raw: &[char], tokens: &[Token], index: usize) -> Option<(Statement, usize)> {
-    if !expect_keyword(tokens, index, "if") {
-        return None;
+    let mut names = vec![name];
+    while expect_syntax(tokens, ",") {
+        tokens.take(); // Skip past comma
+        let name = parse_identifier(
+            sm,
+            tokens,
+            "Expected valid identifier for for loop variable:",
+        )?;
+        names.push(name);
     }
 
-    let mut next_index = index + 1; // Skip past if
-    let res = parse_expression(raw, tokens, next_index);
-    if res.is_none() {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected valid expression for if test:")
-        );
-        return None;
+    if !expect_keyword(tokens, "in") {
+        return Err(error_at(
+            sm,
+            tokens,
+            "Expected = or in after for loop variables:",
+        ));
     }
 
-    let (test, next_next_index) = res.unwrap();
-    next_index = next_next_index;
+    tokens.take(); // Skip past in
+    let expressions = parse_expression_list(sm, tokens)?;
+    let body = parse_do_body(sm, tokens, "Expected do after for loop expressions:")?;
+    let span = tokens.span_from(start);
+    Ok(Statement::GenericFor(GenericFor {
+        names,
+        expressions,
+        body,
+        span,
+    }))
+}
 
-    if !expect_keyword(tokens, next_index, "then") {
-        return None;
+// Calls and assignments, which both start with a suffixed
+// expression. Other expressions are not valid statements.
+fn parse_expression_statement(
+    sm: &SourceMap,
+    tokens: &mut Tokens,
+) -> Result<Statement, ParseError> {
+    let expr = parse_suffixed_expression(sm, tokens)?;
+    if !expect_syntax(tokens, "=") && !expect_syntax(tokens, ",") {
+        if let Expression::FunctionCall(_) = expr {
+            return Ok(Statement::Expression(expr));
+        }
+
+        return Err(expr
+            .span()
+            .debug(sm, "Expected function call or assignment:")
+            .into());
     }
 
-    next_index += 1; // Skip past then
+    let mut targets = vec![expr];
+    while expect_syntax(tokens, ",") {
+        tokens.take(); // Skip past comma
+        targets.push(parse_suffixed_expression(sm, tokens)?);
+    }
 
-    let mut statements: Vec<Statement> = vec![];
-    while !expect_keyword(tokens, next_index, "end") {
-        let res = parse_statement(raw, tokens, next_index);
-        if let Some((stmt, next_next_index)) = res {
-            next_index = next_next_index;
-            statements.push(stmt);
-        } else {
-            println!(
-                "{}",
-                tokens[next_index]
-                    .loc
-                    .debug(raw, "Expected valid statement in if body:")
-            );
-            return None;
+    for target in &targets {
+        if !matches!(
+            target,
+            Expression::Literal(Literal::Identifier(_)) | Expression::Index(_)
+        ) {
+            return Err(target
+                .span()
+                .debug(sm, "Cannot assign to this expression:")
+                .into());
         }
     }
 
-    next_index += 1; // Skip past end
+    if !expect_syntax(tokens, "=") {
+        return Err(error_at(sm, tokens, "Expected = in assignment:"));
+    }
 
-    Some((
-        Statement::If(If {
-            test,
-            body: statements,
-        }),
-        next_index,
-    ))
+    tokens.take(); // Skip past =
+    let expressions = parse_expression_list(sm, tokens)?;
+    let span = tokens.span_from(targets[0].span());
+    Ok(Statement::Assignment(Assignment {
+        targets,
+        expressions,
+        span,
+    }))
 }
 
-fn parse_expression_statement(
-    raw: &[char],
-    tokens: &[Token],
-    index: usize,
-) -> Option<(Statement, usize)> {
-    let mut next_index = index;
-    let res = parse_expression(raw, tokens, next_index)?;
-
-    let (expr, next_next_index) = res;
-    next_index = next_next_index;
-    if !expect_syntax(tokens, next_index, ";") {
-        println!(
-            "{}",
-            tokens[next_index]
-                .loc
-                .debug(raw, "Expected semicolon after expression:")
-        );
-        return None;
-    }
-
-    next_index += 1; // Skip past semicolon
-
-    Some((Statement::Expression(expr), next_index))
-}
-
-fn parse_statement(raw: &[char], tokens: &[Token], index: usize) -> Option<(Statement, usize)> {
-    let parsers = [
-        parse_if,
-        parse_expression_statement,
-        parse_return,
-        parse_function,
-        parse_local,
-    ];
-    for parser in parsers {
-        let res = parser(raw, tokens, index);
-        if res.is_some() {
-            return res;
+fn parse_statement(sm: &SourceMap, tokens: &mut Tokens) -> Result<Statement, ParseError> {
+    let _level = Level::enter(sm, tokens)?;
+    if expect_syntax(tokens, ";") {
+        return Ok(Statement::Empty(tokens.take()));
+    }
+
+    if expect_syntax(tokens, "::") {
+        tokens.take(); // Skip past ::
+        let name = parse_identifier(sm, tokens, "Expected valid identifier for label:")?;
+        if !expect_syntax(tokens, "::") {
+            return Err(error_at(sm, tokens, "Expected :: to close label:"));
         }
+
+        tokens.take(); // Skip past ::
+        return Ok(Statement::Label(name));
+    }
+
+    if !tokens.peek().is_some_and(|t| t.kind == TokenKind::Keyword) {
+        return parse_expression_statement(sm, tokens);
     }
 
-    None
+    let t = tokens.take();
+    match t.text() {
+        "if" => parse_if(sm, tokens, t.span),
+        "while" => parse_while(sm, tokens, t.span),
+        "do" => {
+            let body = parse_do_end(sm, tokens)?;
+            let span = tokens.span_from(t.span);
+            Ok(Statement::Do(Do { body, span }))
+        }
+        "for" => parse_for(sm, tokens, t.span),
+        "repeat" => parse_repeat(sm, tokens, t.span),
+        "function" => parse_function(sm, tokens, t.span),
+        "local" => parse_local(sm, tokens, t.span),
+        "break" => Ok(Statement::Break(t)),
+        "goto" => {
+            let name = parse_identifier(sm, tokens, "Expected valid identifier after goto:")?;
+            Ok(Statement::Goto(name))
+        }
+        _ => Err(t.span.debug(sm, "Invalid token while parsing:").into()),
+    }
 }
 
-pub fn parse(raw: &[char], tokens: Vec<Token>) -> Result<Ast, String> {
-    let mut ast = vec![];
-    let mut index = 0;
-    let ntokens = tokens.len();
-    while index < ntokens {
-        let res = parse_statement(raw, &tokens, index);
-        if let Some((stmt, next_index)) = res {
-            index = next_index;
-            ast.push(stmt);
-            continue;
+fn is_block_end(tokens: &mut Tokens) -> bool {
+    tokens.peek().is_none()
+        || ["end", "else", "elseif", "until"]
+            .iter()
+            .any(|k| expect_keyword(tokens, k))
+}
+
+// Statements up to the end of the enclosing block. A return may only
+// be the last statement.
+fn parse_block(sm: &SourceMap, tokens: &mut Tokens) -> Result<Vec<Statement>, ParseError> {
+    let mut statements = vec![];
+    while !is_block_end(tokens) {
+        if expect_keyword(tokens, "return") {
+            let start = tokens.take().span; // Skip past return
+
+            let mut expressions = vec![];
+            if !is_block_end(tokens) && !expect_syntax(tokens, ";") {
+                expressions = parse_expression_list(sm, tokens)?;
+            }
+
+            if expect_syntax(tokens, ";") {
+                tokens.take(); // Skip past semicolon
+            }
+
+            let span = tokens.span_from(start);
+            statements.push(Statement::Return(Return { expressions, span }));
+
+            if !is_block_end(tokens) {
+                return Err(error_at(
+                    sm,
+                    tokens,
+                    "Expected end of block after return statement:",
+                ));
+            }
+            break;
         }
 
-        return Err(tokens[index].loc.debug(raw, "Invalid token while parsing:"));
+        statements.push(parse_statement(sm, tokens)?);
+    }
+
+    Ok(statements)
+}
+
+// Parses a chunk from its tokens as they are lexed, which a Lexer
+// does on demand. A lexer error ends the input there.
+pub fn parse<I>(sm: &SourceMap, tokens: I) -> Result<Ast, ParseError>
+where
+    I: IntoIterator<Item = Result<Token, LexError>>,
+{
+    let mut lexer = tokens.into_iter();
+    let mut tokens = Tokens {
+        lexer: &mut lexer,
+        ahead: VecDeque::new(),
+        last: None,
+        error: None,
+    };
+    let ast = parse_block(sm, &mut tokens)?;
+    if tokens.peek().is_some() || tokens.error.is_some() {
+        return Err(error_at(sm, &mut tokens, "Invalid token while parsing:"));
     }
 
     Ok(ast)
@@ -0,0 +1,164 @@
+use crate::eval::Vm;
+use crate::lex::{FileId, Lexer, Token};
+use crate::parse::{parse, Ast};
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::path::PathBuf;
+
+const PROMPT: &str = "> ";
+const CONTINUE_PROMPT: &str = ">> ";
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".lust_history"))
+}
+
+// What reading one more line of a chunk led to.
+enum Chunk {
+    Complete(Ast),
+    Incomplete,
+    Failed(String),
+}
+
+// Lexes the new line into tokens and tries to parse everything typed
+// so far. Unfinished long strings and comments leave the lexer with
+// pending input, and unfinished blocks fail to parse at the end of
+// the input; both need another line.
+fn read_line(
+    vm: &mut Vm,
+    file: FileId,
+    lexer: &mut Lexer<'static>,
+    tokens: &mut Vec<Token>,
+    line: &str,
+) -> Chunk {
+    let line = format!("{}\n", line);
+    vm.sm.append(file, &line);
+    lexer.feed(&line);
+    for t in &mut *lexer {
+        match t {
+            Ok(t) => tokens.push(t),
+            Err(e) if e.incomplete => return Chunk::Incomplete,
+            Err(e) => return Chunk::Failed(e.debug(&vm.sm)),
+        }
+    }
+
+    if lexer.pending() {
+        return Chunk::Incomplete;
+    }
+
+    match parse(&vm.sm, tokens.iter().cloned().map(Ok)) {
+        Ok(ast) => Chunk::Complete(ast),
+        Err(e) if e.incomplete => Chunk::Incomplete,
+        Err(e) => Chunk::Failed(e.msg),
+    }
+}
+
+// Like lua, a line is first tried as an expression whose values are
+// printed. `=expr` is the Lua 5.1 spelling of the same thing.
+fn read_expression(vm: &mut Vm, line: &str) -> Option<(FileId, Ast)> {
+    let line = line.strip_prefix('=').unwrap_or(line);
+    let file = vm.sm.add("stdin", format!("return {}", line));
+    let ast = parse(&vm.sm, Lexer::new(file, vm.sm.source(file))).ok();
+    if ast.is_none() {
+        // The line is read again as a statement
+        vm.sm.release(file);
+    }
+    ast.map(|ast| (file, ast))
+}
+
+fn run(vm: &mut Vm, file: FileId, ast: Ast) {
+    let pgrm = vm.compile_file(file, ast);
+    vm.sm.release(file);
+    let result = pgrm.and_then(|pgrm| vm.eval(pgrm));
+    match result {
+        Ok(values) if values.is_empty() => {}
+        Ok(values) => {
+            let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+            println!("{}", values.join("\t"));
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+// Reads chunks from the terminal and runs them in vm, whose globals
+// outlive each chunk, until end of input.
+pub fn repl(vm: &mut Vm) {
+    let mut rl = match DefaultEditor::new() {
+        Ok(rl) => rl,
+        Err(e) => {
+            eprintln!("Could not start line editor: {}", e);
+            return;
+        }
+    };
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means a first run
+        let _ = rl.load_history(path);
+    }
+
+    'chunks: loop {
+        let line = match rl.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some((file, ast)) = read_expression(vm, &line) {
+            let _ = rl.add_history_entry(line.as_str());
+            run(vm, file, ast);
+            continue;
+        }
+
+        let file = vm.sm.add("stdin", String::new());
+        let mut lexer = Lexer::incremental(file);
+        let mut tokens = vec![];
+        let mut entry = line.clone();
+        let mut line = line;
+        loop {
+            match read_line(vm, file, &mut lexer, &mut tokens, &line) {
+                Chunk::Complete(ast) => {
+                    let _ = rl.add_history_entry(entry.as_str());
+                    run(vm, file, ast);
+                    continue 'chunks;
+                }
+                Chunk::Failed(msg) => {
+                    vm.sm.release(file);
+                    let _ = rl.add_history_entry(entry.as_str());
+                    eprintln!("{}", msg);
+                    continue 'chunks;
+                }
+                Chunk::Incomplete => {}
+            }
+
+            line = match rl.readline(CONTINUE_PROMPT) {
+                Ok(line) => line,
+                // Ctrl-C abandons the chunk, Ctrl-D the REPL
+                Err(ReadlineError::Interrupted) => {
+                    vm.sm.release(file);
+                    continue 'chunks;
+                }
+                Err(ReadlineError::Eof) => break 'chunks,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    break 'chunks;
+                }
+            };
+            entry.push('\n');
+            entry.push_str(&line);
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = rl.save_history(path) {
+            eprintln!("Could not save history to {}: {}", path.display(), e);
+        }
+    }
+}
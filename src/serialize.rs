@@ -0,0 +1,677 @@
+// A serde bridge between Rust data and Lua values. Structs and maps
+// become tables with string keys, sequences and tuples become arrays,
+// and enum variants carrying data become single-key tables such as
+// `{Move = {x = 1, y = 2}}`. Unit values become empty tables rather
+// than nil, which a table field could not hold.
+
+use crate::string::LuaString;
+use crate::value::{Error, Table, Value};
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use std::fmt::Display;
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::message(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::message(msg.to_string())
+    }
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    value.serialize(Serializer)
+}
+
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(Deserializer::new(value))
+}
+
+fn set(t: &mut Table, key: Value, value: Value) -> Result<(), Error> {
+    t.set(key, value).map_err(Error::message)
+}
+
+fn variant(name: &str, value: Value) -> Value {
+    let mut t = Table::new();
+    t.set_str(name, value);
+    Value::from(t)
+}
+
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeTable;
+    type SerializeStruct = SerializeTable;
+    type SerializeStructVariant = SerializeTable;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    // Integers beyond i64 become floats, as in Lua source
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        Ok(match i64::try_from(v) {
+            Ok(i) => Value::Integer(i),
+            Err(_) => Value::Number(v as f64),
+        })
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(Value::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::from(v.to_string().as_str()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::from(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(Value::String(LuaString::from(v)))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::from(Table::new()))
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(Value::from(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(variant(name, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray::new(None))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray::new(None))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray::new(None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        name: &'static str,
+        _: usize,
+    ) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray::new(Some(name)))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::new(None))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::new(None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        name: &'static str,
+        _: usize,
+    ) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::new(Some(name)))
+    }
+}
+
+// Builds an array; tuple variants wrap it in `{Variant = array}`.
+pub struct SerializeArray {
+    table: Table,
+    len: usize,
+    variant: Option<&'static str>,
+}
+
+impl SerializeArray {
+    fn new(variant: Option<&'static str>) -> SerializeArray {
+        SerializeArray {
+            table: Table::new(),
+            len: 0,
+            variant,
+        }
+    }
+
+    // Counted rather than taken from the table's length, which a nil
+    // element leaves behind
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.len += 1;
+        let key = Value::Integer(self.len as i64);
+        set(&mut self.table, key, value.serialize(Serializer)?)
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let table = Value::from(self.table);
+        Ok(match self.variant {
+            Some(name) => variant(name, table),
+            None => table,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+// Builds a table with keys; struct variants wrap it in
+// `{Variant = table}`.
+pub struct SerializeTable {
+    table: Table,
+    key: Option<Value>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeTable {
+    fn new(variant: Option<&'static str>) -> SerializeTable {
+        SerializeTable {
+            table: Table::new(),
+            key: None,
+            variant,
+        }
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let table = Value::from(self.table);
+        Ok(match self.variant {
+            Some(name) => variant(name, table),
+            None => table,
+        })
+    }
+}
+
+impl ser::SerializeMap for SerializeTable {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().unwrap_or(Value::Nil);
+        set(&mut self.table, key, value.serialize(Serializer)?)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.table.set_str(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.table.set_str(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+pub struct Deserializer {
+    value: Value,
+}
+
+impl Deserializer {
+    pub fn new(value: Value) -> Deserializer {
+        Deserializer { value }
+    }
+
+    fn expected(&self, what: &str) -> Error {
+        Error::message(format!("{} expected, got {}", what, self.value.type_name()))
+    }
+
+    fn integer(&self) -> Result<i64, Error> {
+        match self.value.to_integer() {
+            Some(i) => Ok(i),
+            None if self.value.to_number().is_some() => {
+                Err(Error::message("number has no integer representation"))
+            }
+            None => Err(self.expected("number")),
+        }
+    }
+
+    // The table's entries, or an error naming what was expected
+    fn entries(&self, what: &str) -> Result<Vec<(Value, Value)>, Error> {
+        match &self.value {
+            Value::Table(t) => Ok(t.borrow().pairs().collect()),
+            _ => Err(self.expected(what)),
+        }
+    }
+
+    fn sequence(&self) -> Result<Vec<Value>, Error> {
+        match &self.value {
+            Value::Table(t) => {
+                let t = t.borrow();
+                Ok((1..=t.sequence_len())
+                    .map(|i| t.get(&Value::Integer(i as i64)))
+                    .collect())
+            }
+            _ => Err(self.expected("table")),
+        }
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident => $visit:ident: $t:ty),*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            let i = self.integer()?;
+            let i = <$t>::try_from(i).map_err(|_| {
+                Error::message(format!("{} is out of range for {}", i, stringify!($t)))
+            })?;
+            visitor.$visit(i)
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    // Tables without keys outside 1..#t read as sequences, others as
+    // maps.
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.value {
+            Value::Nil => visitor.visit_unit(),
+            Value::Boolean(b) => visitor.visit_bool(*b),
+            Value::Integer(i) => visitor.visit_i64(*i),
+            Value::Number(f) => visitor.visit_f64(*f),
+            Value::String(s) => match s.to_str() {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(s),
+            },
+            Value::Table(t) => {
+                let len = t.borrow().sequence_len() as u64;
+                let is_sequence = t.borrow().pairs().all(|(k, _)| match k {
+                    Value::Integer(i) => i >= 1 && i as u64 <= len,
+                    _ => false,
+                });
+                if is_sequence && !t.borrow().is_empty() {
+                    self.deserialize_seq(visitor)
+                } else {
+                    self.deserialize_map(visitor)
+                }
+            }
+            Value::Function(_) | Value::UserData(_) => Err(Error::message(format!(
+                "cannot deserialize a {}",
+                self.value.type_name()
+            ))),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Boolean(b) => visitor.visit_bool(b),
+            _ => Err(self.expected("boolean")),
+        }
+    }
+
+    deserialize_integer!(
+        deserialize_i8 => visit_i8: i8,
+        deserialize_i16 => visit_i16: i16,
+        deserialize_i32 => visit_i32: i32,
+        deserialize_i64 => visit_i64: i64,
+        deserialize_u8 => visit_u8: u8,
+        deserialize_u16 => visit_u16: u16,
+        deserialize_u32 => visit_u32: u32,
+        deserialize_u64 => visit_u64: u64
+    );
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value.to_float() {
+            Some(f) => visitor.visit_f64(f),
+            None => Err(self.expected("number")),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    // Numbers convert to strings, as they do for string functions
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.value {
+            Value::String(s) => match s.to_str() {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => Err(Error::message("string is not valid UTF-8")),
+            },
+            Value::Integer(_) | Value::Number(_) => visitor.visit_string(self.value.to_string()),
+            _ => Err(self.expected("string")),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.value {
+            Value::String(s) => visitor.visit_bytes(s.as_bytes()),
+            _ => Err(self.expected("string")),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    // Lua code may also leave a unit value out as nil.
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.value {
+            Value::Nil => visitor.visit_unit(),
+            Value::Table(t) if t.borrow().is_empty() => visitor.visit_unit(),
+            _ => Err(self.expected("empty table")),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let values = self.sequence()?.into_iter().map(Deserializer::new);
+        let mut seq = de::value::SeqDeserializer::new(values);
+        let result = visitor.visit_seq(&mut seq)?;
+        seq.end()?;
+        Ok(result)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let entries = self.entries("table")?;
+        let entries = entries
+            .into_iter()
+            .map(|(k, v)| (Deserializer::new(k), Deserializer::new(v)));
+        let mut map = de::value::MapDeserializer::new(entries);
+        let result = visitor.visit_map(&mut map)?;
+        map.end()?;
+        Ok(result)
+    }
+
+    // Unknown fields are left to the visitor, which ignores them
+    // unless the struct denies them.
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    // Unit variants are strings, and variants with data single-key
+    // tables.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match &self.value {
+            Value::String(s) => visitor.visit_enum(s.to_string().into_deserializer()),
+            Value::Table(_) => {
+                let mut entries = self.entries("table")?;
+                if entries.len() != 1 {
+                    return Err(Error::message(
+                        "enum variant table must have exactly one key",
+                    ));
+                }
+                let (variant, value) = entries.remove(0);
+                let Value::String(variant) = variant else {
+                    return Err(Error::message("enum variant name must be a string"));
+                };
+                visitor.visit_enum(Enum { variant, value })
+            }
+            _ => Err(self.expected("string or table")),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Deserializer {
+    type Deserializer = Deserializer;
+
+    fn into_deserializer(self) -> Deserializer {
+        self
+    }
+}
+
+struct Enum {
+    variant: LuaString,
+    value: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum {
+    type Error = Error;
+    type Variant = Deserializer;
+
+    fn variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Deserializer), Error> {
+        let variant = seed.deserialize(Deserializer::new(Value::String(self.variant)))?;
+        Ok((variant, Deserializer::new(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: de::DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
@@ -0,0 +1,435 @@
+use crate::eval::Vm;
+use crate::gc::Mode;
+use crate::native::Args;
+use crate::string::LuaString;
+use crate::value::{Error, Table, Value};
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+const DEFAULT_PATH: &str = "/usr/local/share/lua/5.4/?.lua;/usr/local/share/lua/5.4/?/init.lua;\
+/usr/local/lib/lua/5.4/?.lua;/usr/local/lib/lua/5.4/?/init.lua;./?.lua;./?/init.lua";
+
+fn print(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    // Strings are written as raw bytes
+    let mut line = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        line.extend_from_slice(&vm.tostring(arg)?);
+    }
+    line.push(b'\n');
+    let mut stdout = std::io::stdout().lock();
+    // Like Lua, a closed stdout is not an error
+    let _ = stdout.write_all(&line);
+    Ok(vec![])
+}
+
+fn tostring(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let v = args.check_any(1)?;
+    Ok(vec![Value::String(vm.tostring(&v)?)])
+}
+
+// A __metatable field protects a metatable from being read or
+// replaced.
+fn getmetatable(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let mt = match vm.metatable(&args.check_any(1)?) {
+        Some(mt) => mt,
+        None => return Ok(vec![Value::Nil]),
+    };
+    let protected = mt.borrow().get_str("__metatable");
+    match protected {
+        Value::Nil => Ok(vec![Value::Table(mt)]),
+        v => Ok(vec![v]),
+    }
+}
+
+fn setmetatable(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let t = args.check_table(1)?;
+    let mt = match args.get(2) {
+        Value::Nil => None,
+        Value::Table(mt) => Some(mt),
+        _ => return Err(args.type_error(2, "nil or table")),
+    };
+
+    let current = t.borrow().metatable();
+    if let Some(current) = current {
+        if !matches!(current.borrow().get_str("__metatable"), Value::Nil) {
+            return Err(Error::message("cannot change a protected metatable"));
+        }
+    }
+    let finalized = mt
+        .as_ref()
+        .is_some_and(|mt| !matches!(mt.borrow().get_str("__gc"), Value::Nil));
+    t.borrow_mut().set_metatable(mt);
+    if finalized {
+        vm.heap.mark_for_finalization(Value::Table(t.clone()));
+    }
+    Ok(vec![Value::Table(t)])
+}
+
+fn rawequal(_: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let (a, b) = (args.check_any(1)?, args.check_any(2)?);
+    Ok(vec![Value::Boolean(a == b)])
+}
+
+fn rawlen(_: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    match args.get(1) {
+        Value::Table(t) => Ok(vec![Value::Integer(t.borrow().len() as i64)]),
+        Value::String(s) => Ok(vec![Value::Integer(s.len() as i64)]),
+        _ => Err(args.error(1, "table or string expected")),
+    }
+}
+
+fn rawget(_: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let t = args.check_table(1)?;
+    let v = t.borrow().get(&args.check_any(2)?);
+    Ok(vec![v])
+}
+
+fn rawset(_: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let t = args.check_table(1)?;
+    let (key, value) = (args.check_any(2)?, args.check_any(3)?);
+    t.borrow_mut().set(key, value).map_err(Error::message)?;
+    Ok(vec![Value::Table(t)])
+}
+
+// Calls a function, catching its errors: true and its results, or
+// false and the error value.
+fn pcall(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let f = args.check_any(1)?;
+    let mut call_args = args.into_vec();
+    call_args.remove(0);
+    match vm.call(f, call_args) {
+        Ok(mut results) => {
+            results.insert(0, Value::Boolean(true));
+            Ok(results)
+        }
+        Err(e) => {
+            // A caught error has no traceback for the host to show
+            vm.traceback = None;
+            Ok(vec![Value::Boolean(false), e.value()])
+        }
+    }
+}
+
+// Raises any value as an error. Strings get the position of the
+// function level calls up, 1 being the one calling error.
+fn error(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let level = args.opt_integer(2)?.unwrap_or(1);
+    match args.get(1) {
+        Value::String(s) if level > 0 => {
+            let mut msg = vm.location_at(level as usize).into_bytes();
+            msg.extend_from_slice(&s);
+            Err(Error::Runtime(Value::String(LuaString::from(msg))))
+        }
+        v => Err(Error::Runtime(v)),
+    }
+}
+
+// Warnings are off until turned on with the control message "@on",
+// and are written to stderr.
+fn warn(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let mut msg = args.check_string(1)?.to_string_lossy().into_owned();
+    for i in 2..=args.len() {
+        msg.push_str(&args.check_string(i)?.to_string_lossy());
+    }
+
+    match msg.as_str() {
+        "@on" if args.len() == 1 => vm.warnings = true,
+        "@off" if args.len() == 1 => vm.warnings = false,
+        // Unknown control messages are ignored
+        _ if args.len() == 1 && msg.starts_with('@') => {}
+        _ if vm.warnings => eprintln!("Lua warning: {}", msg),
+        _ => {}
+    }
+    Ok(vec![])
+}
+
+// Arguments to "incremental" and "generational" left out or 0 keep
+// their current value. "incremental" selects full collections, which
+// run to completion, so its step multiplier is checked but has
+// nothing to set.
+fn collectgarbage(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let option = args
+        .opt_string(1)?
+        .unwrap_or_else(|| LuaString::from("collect"));
+    let param = |n: usize, current: i64| -> Result<i64, Error> {
+        Ok(match args.opt_integer(n)? {
+            None | Some(0) => current,
+            Some(v) => v,
+        })
+    };
+
+    let heap = &mut vm.heap;
+    let result = match option.to_str().unwrap_or_default() {
+        "collect" => {
+            vm.collect_garbage();
+            Value::Integer(0)
+        }
+        "count" => Value::Number(heap.count() as f64 / 1024.0),
+        "step" => Value::Boolean(vm.gc_step(args.opt_integer(2)?.unwrap_or(0))),
+        "stop" => {
+            heap.running = false;
+            Value::Integer(0)
+        }
+        "restart" => {
+            heap.running = true;
+            Value::Integer(0)
+        }
+        "isrunning" => Value::Boolean(heap.running),
+        "incremental" => {
+            heap.pause = param(2, heap.pause)?;
+            args.opt_integer(3)?;
+            heap.stepsize = param(4, heap.stepsize)?;
+            Value::from(std::mem::replace(&mut heap.mode, Mode::Full).name())
+        }
+        "generational" => {
+            heap.minormul = param(2, heap.minormul)?;
+            heap.majormul = param(3, heap.majormul)?;
+            Value::from(std::mem::replace(&mut heap.mode, Mode::Generational).name())
+        }
+        _ => return Err(args.error(1, format!("invalid option '{}'", option))),
+    };
+    Ok(vec![result])
+}
+
+// The name chunks loaded from a string get by default: its first
+// line, shortened, the way Lua shows it.
+fn string_chunkname(chunk: &[u8]) -> String {
+    let source = String::from_utf8_lossy(chunk);
+    let line = source.lines().next().unwrap_or("");
+    let short: String = line.chars().take(45).collect();
+    if short.len() < source.len() {
+        format!("[string \"{}...\"]", short)
+    } else {
+        format!("[string \"{}\"]", short)
+    }
+}
+
+// load(chunk [, chunkname [, mode [, env]]]) compiles a string, or
+// the pieces a function returns until it returns nil or "". Errors
+// are returned as nil and a message rather than raised.
+fn load(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let (chunk, default_name) = match args.get(1) {
+        Value::String(s) => {
+            let name = string_chunkname(&s);
+            (Ok(s.to_vec()), name)
+        }
+        f @ Value::Function(_) => {
+            let mut chunk = vec![];
+            let result = loop {
+                let piece = match vm.call(f.clone(), vec![]) {
+                    Ok(results) => results.into_iter().next().unwrap_or(Value::Nil),
+                    Err(e) => {
+                        vm.traceback = None;
+                        break Err(e.to_string());
+                    }
+                };
+                match piece {
+                    Value::String(s) if !s.is_empty() => chunk.extend_from_slice(&s),
+                    Value::String(_) | Value::Nil => break Ok(chunk),
+                    _ => break Err("reader function must return a string".to_string()),
+                }
+            };
+            (result, "(load)".to_string())
+        }
+        _ => return Err(args.type_error(1, "string")),
+    };
+    let chunkname = match args.opt_string(2)? {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => default_name,
+    };
+    let mode = args.opt_string(3)?.unwrap_or_else(|| LuaString::from("bt"));
+    if !matches!(args.get(4), Value::Nil) {
+        return Err(args.error(4, "custom environments are not supported"));
+    }
+
+    let result = chunk.and_then(|chunk| {
+        vm.load_function(&chunk, &chunkname, &mode.to_string_lossy())
+            .map_err(|e| e.to_string())
+    });
+    match result {
+        Ok(f) => Ok(vec![f]),
+        Err(msg) => Ok(vec![Value::Nil, Value::from(msg.as_str())]),
+    }
+}
+
+fn read_chunk(filename: &str) -> Result<Vec<u8>, String> {
+    fs::read(filename).map_err(|e| format!("cannot open {}: {}", filename, e))
+}
+
+// dofile([filename]) runs a source or binary file, or stdin, and
+// returns what it returns. Unlike load, errors are raised.
+fn dofile(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let (name, chunk) = match args.opt_string(1)? {
+        Some(filename) => {
+            let filename = filename.to_string_lossy().into_owned();
+            let chunk = read_chunk(&filename).map_err(Error::message)?;
+            (filename, chunk)
+        }
+        None => {
+            let mut chunk = vec![];
+            std::io::stdin()
+                .read_to_end(&mut chunk)
+                .map_err(|e| Error::message(format!("cannot read stdin: {}", e)))?;
+            ("stdin".to_string(), chunk)
+        }
+    };
+    let f = vm.load_function(&chunk, &name, "bt")?;
+    vm.call(f, vec![])
+}
+
+// string.dump(f [, strip]) is the binary chunk of a Lua function,
+// which load turns back into a copy of it with fresh upvalues.
+fn dump(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let f = args.check_function(1)?;
+    let strip = args.get(2).truthy();
+    match vm.dump_function(&f, strip) {
+        Some(chunk) => Ok(vec![Value::String(LuaString::from(chunk))]),
+        None => Err(Error::message("unable to dump given function")),
+    }
+}
+
+fn package_table(vm: &Vm, field: &str) -> Result<Rc<RefCell<Table>>, Error> {
+    let package = match vm.get_global("package") {
+        Value::Table(t) => t,
+        _ => return Err(Error::message("'package' must be a table")),
+    };
+    let value = package.borrow().get_str(field);
+    match value {
+        Value::Table(t) => Ok(t),
+        _ => Err(Error::message(format!(
+            "'package.{}' must be a table",
+            field
+        ))),
+    }
+}
+
+// Finds the first file the templates of package.path name, where `?`
+// is replaced by the module name with dots as directory separators.
+fn search_path(name: &str, path: &str) -> Result<String, String> {
+    let name = name.replace('.', "/");
+    let mut tried = String::new();
+    for template in path.split(';').filter(|t| !t.is_empty()) {
+        let filename = template.replace('?', &name);
+        if fs::metadata(&filename).is_ok_and(|m| m.is_file()) {
+            return Ok(filename);
+        }
+        tried.push_str(&format!("\n\tno file '{}'", filename));
+    }
+
+    Err(tried)
+}
+
+fn require(vm: &mut Vm, args: Args) -> Result<Vec<Value>, Error> {
+    let key = Value::String(args.check_string(1)?);
+    let name = key.to_string();
+
+    let loaded = package_table(vm, "loaded")?;
+    let existing = loaded.borrow().get(&key);
+    if existing.truthy() {
+        return Ok(vec![existing]);
+    }
+
+    let preload = package_table(vm, "preload")?.borrow().get(&key);
+    let (loader, extra) = if let Value::Function(_) = preload {
+        (preload, Value::from(":preload:"))
+    } else {
+        let path = match vm.get_global("package") {
+            Value::Table(t) => t.borrow().get_str("path"),
+            _ => Value::Nil,
+        };
+        let Value::String(path) = path else {
+            return Err(Error::message("'package.path' must be a string"));
+        };
+
+        let filename = search_path(&name, &path.to_string_lossy()).map_err(|tried| {
+            Error::message(format!(
+                "module '{}' not found:\n\tno field package.preload['{}']{}",
+                name, name, tried
+            ))
+        })?;
+        let loader = fs::read(&filename)
+            .map_err(|e| e.to_string())
+            .and_then(|chunk| {
+                vm.load_function(&chunk, &filename, "bt")
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(|msg| {
+                Error::message(format!(
+                    "error loading module '{}' from file '{}':\n\t{}",
+                    name, filename, msg
+                ))
+            })?;
+        (loader, Value::from(filename.as_str()))
+    };
+
+    let results = vm.call(loader, vec![key.clone(), extra.clone()])?;
+    let mut module = results.into_iter().next().unwrap_or(Value::Nil);
+    if let Value::Nil = module {
+        // The module may have filled in package.loaded itself
+        module = loaded.borrow().get(&key);
+        if let Value::Nil = module {
+            module = Value::Boolean(true);
+        }
+    }
+
+    loaded
+        .borrow_mut()
+        .set(key, module.clone())
+        .map_err(Error::message)?;
+    Ok(vec![module, extra])
+}
+
+// LUA_PATH_5_4 or LUA_PATH, where `;;` stands for the default path.
+fn default_path(use_env: bool) -> String {
+    let from_env = ["LUA_PATH_5_4", "LUA_PATH"]
+        .iter()
+        .find_map(|var| env::var(var).ok());
+    match from_env {
+        Some(path) if use_env => path.replacen(";;", &format!(";{};", DEFAULT_PATH), 1),
+        _ => DEFAULT_PATH.to_string(),
+    }
+}
+
+// Registers the base library and package library globals. use_env
+// is false when the interpreter runs with -E.
+pub fn open(vm: &mut Vm, use_env: bool) {
+    vm.set_global("_G", Value::Table(vm.globals()));
+    vm.set_global("_VERSION", Value::from("Lua 5.4"));
+    vm.register("print", print);
+    vm.register("tostring", tostring);
+    vm.register("pcall", pcall);
+    vm.register("error", error);
+    vm.register("getmetatable", getmetatable);
+    vm.register("setmetatable", setmetatable);
+    vm.register("rawequal", rawequal);
+    vm.register("rawlen", rawlen);
+    vm.register("rawget", rawget);
+    vm.register("rawset", rawset);
+    vm.register("warn", warn);
+    vm.register("require", require);
+    vm.register("collectgarbage", collectgarbage);
+    vm.register("load", load);
+    vm.register("dofile", dofile);
+    vm.register_in("string", "dump", dump);
+
+    let mut loaded = Table::new();
+    loaded.set_str("_G", Value::Table(vm.globals()));
+
+    let mut package = Table::new();
+    package.set_str("path", Value::from(default_path(use_env).as_str()));
+    package.set_str("config", Value::from("/\n;\n?\n!\n-\n"));
+    package.set_str("preload", Value::from(Table::new()));
+    let loaded = Rc::new(RefCell::new(loaded));
+    package.set_str("loaded", Value::Table(loaded.clone()));
+
+    let package = Value::from(package);
+    loaded.borrow_mut().set_str("package", package.clone());
+    vm.set_global("package", package);
+}
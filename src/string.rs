@@ -0,0 +1,229 @@
+// Lua strings: immutable byte strings, which need not be UTF-8.
+//
+// Like in Lua 5.4, short strings are interned, so there is only ever
+// one copy of each and comparing them compares pointers. Long strings
+// are created as they come and only hashed if used as a table key.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+use std::str::Utf8Error;
+
+// LUAI_MAXSHORTLEN
+const MAX_SHORT_LEN: usize = 40;
+
+const SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+struct StringData {
+    bytes: Box<[u8]>,
+    short: bool,
+    // Computed up front for short strings, on first use for long ones
+    hash: Cell<Option<u64>>,
+}
+
+#[derive(Clone)]
+pub struct LuaString(Rc<StringData>);
+
+thread_local! {
+    // Short strings by hash. An entry goes when the last LuaString
+    // pointing to it is dropped.
+    static STRINGS: RefCell<HashMap<u64, Vec<Rc<StringData>>>> = RefCell::new(HashMap::new());
+}
+
+// luaS_hash
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut h = SEED ^ bytes.len() as u64;
+    for &b in bytes.iter().rev() {
+        h ^= (h << 5).wrapping_add(h >> 2).wrapping_add(b as u64);
+    }
+    h
+}
+
+impl LuaString {
+    pub fn new(bytes: &[u8]) -> LuaString {
+        if bytes.len() > MAX_SHORT_LEN {
+            return LuaString::long(bytes.into());
+        }
+
+        let hash = hash_bytes(bytes);
+        STRINGS.with(|strings| {
+            let mut strings = strings.borrow_mut();
+            let bucket = strings.entry(hash).or_default();
+            if let Some(existing) = bucket.iter().find(|s| &*s.bytes == bytes) {
+                return LuaString(existing.clone());
+            }
+            let data = Rc::new(StringData {
+                bytes: bytes.into(),
+                short: true,
+                hash: Cell::new(Some(hash)),
+            });
+            bucket.push(data.clone());
+            LuaString(data)
+        })
+    }
+
+    fn long(bytes: Box<[u8]>) -> LuaString {
+        LuaString(Rc::new(StringData {
+            bytes,
+            short: false,
+            hash: Cell::new(None),
+        }))
+    }
+
+    // Takes over the buffer of strings too long to intern
+    fn from_vec(bytes: Vec<u8>) -> LuaString {
+        if bytes.len() > MAX_SHORT_LEN {
+            return LuaString::long(bytes.into_boxed_slice());
+        }
+        LuaString::new(&bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0.bytes
+    }
+
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.0.bytes)
+    }
+
+    // Invalid UTF-8 sequences become U+FFFD
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0.bytes)
+    }
+
+    // For nan-boxed TValues, which hold the reference as a pointer
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) fn into_raw(self) -> *const () {
+        let s = std::mem::ManuallyDrop::new(self);
+        Rc::as_ptr(&s.0) as *const ()
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) unsafe fn from_raw(p: *const ()) -> LuaString {
+        LuaString(Rc::from_raw(p as *const StringData))
+    }
+
+    pub(crate) fn hash_code(&self) -> u64 {
+        match self.0.hash.get() {
+            Some(h) => h,
+            None => {
+                let h = hash_bytes(&self.0.bytes);
+                self.0.hash.set(Some(h));
+                h
+            }
+        }
+    }
+}
+
+impl Drop for LuaString {
+    fn drop(&mut self) {
+        // The intern table holds the other reference
+        if !self.0.short || Rc::strong_count(&self.0) != 2 {
+            return;
+        }
+        let hash = self.hash_code();
+        let ptr = Rc::as_ptr(&self.0);
+        // The table may already be gone while the thread exits
+        let _ = STRINGS.try_with(|strings| {
+            let mut strings = strings.borrow_mut();
+            if let Some(bucket) = strings.get_mut(&hash) {
+                bucket.retain(|s| Rc::as_ptr(s) != ptr);
+                if bucket.is_empty() {
+                    strings.remove(&hash);
+                }
+            }
+        });
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0.bytes
+    }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &LuaString) -> bool {
+        if Rc::ptr_eq(&self.0, &other.0) {
+            return true;
+        }
+        // Equal short strings are the same string
+        !self.0.short && !other.0.short && self.0.bytes == other.0.bytes
+    }
+}
+
+impl Eq for LuaString {}
+
+impl PartialEq<str> for LuaString {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0.bytes == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for LuaString {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0.bytes == other.as_bytes()
+    }
+}
+
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash_code());
+    }
+}
+
+// Byte by byte, like strcmp in the C locale
+impl Ord for LuaString {
+    fn cmp(&self, other: &LuaString) -> Ordering {
+        self.0.bytes.cmp(&other.0.bytes)
+    }
+}
+
+impl PartialOrd for LuaString {
+    fn partial_cmp(&self, other: &LuaString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> LuaString {
+        LuaString::new(s.as_bytes())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> LuaString {
+        LuaString::from_vec(s.into_bytes())
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> LuaString {
+        LuaString::new(bytes)
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> LuaString {
+        LuaString::from_vec(bytes)
+    }
+}
+
+impl fmt::Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_lossy())
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_string_lossy())
+    }
+}
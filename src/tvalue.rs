@@ -0,0 +1,228 @@
+// How the VM stack and tables store values, like Lua's TValue. By
+// default a TValue is just a Value, 16 bytes. With the nan-boxing
+// feature it is 8: floats are stored as themselves, and everything
+// else in the payload of a NaN that no float is stored as. Either
+// way, the rest of the VM only sees Values, through these methods.
+
+#[cfg(not(feature = "nan-boxing"))]
+mod repr {
+    use crate::value::Value;
+    use std::ops::Deref;
+
+    #[derive(Clone)]
+    pub(crate) struct TValue(Value);
+
+    // A Value read from a TValue, like the nan-boxed one
+    pub(crate) struct Ref<'a>(&'a Value);
+
+    impl Deref for Ref<'_> {
+        type Target = Value;
+
+        fn deref(&self) -> &Value {
+            self.0
+        }
+    }
+
+    impl TValue {
+        pub(crate) fn new(v: Value) -> TValue {
+            TValue(v)
+        }
+
+        pub(crate) fn value(&self) -> Ref<'_> {
+            Ref(&self.0)
+        }
+
+        pub(crate) fn get(&self) -> Value {
+            self.0.clone()
+        }
+
+        pub(crate) fn set(&mut self, v: Value) {
+            self.0 = v;
+        }
+
+        pub(crate) fn into_value(self) -> Value {
+            self.0
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+mod repr {
+    use crate::eval::Closure;
+    use crate::native::NativeFunction;
+    use crate::string::LuaString;
+    use crate::userdata::AnyUserData;
+    use crate::value::{Function, Table, Value};
+
+    use std::cell::RefCell;
+    use std::marker::PhantomData;
+    use std::mem::ManuallyDrop;
+    use std::ops::Deref;
+    use std::rc::Rc;
+
+    #[cfg(not(target_pointer_width = "64"))]
+    compile_error!("the nan-boxing feature needs 64-bit pointers");
+
+    // The top 16 bits of TValues that aren't floats. Floats with these
+    // are NaNs, which are stored as the NaN with nothing but the
+    // quiet bit and their sign set instead.
+    const NIL: u64 = 0xfff9;
+    const BOOLEAN: u64 = 0xfffa;
+    const INTEGER: u64 = 0xfffb;
+    const STRING: u64 = 0xfffc;
+    const TABLE: u64 = 0xfffd;
+    const CLOSURE: u64 = 0xfffe;
+    const NATIVE: u64 = 0xffff;
+    const USERDATA: u64 = 0x7ff9;
+    // Integers that don't fit in the payload, boxed
+    const BIG_INTEGER: u64 = 0x7ffa;
+
+    const PAYLOAD: u64 = (1 << 48) - 1;
+    const SIGN: u64 = 1 << 63;
+    const NAN: u64 = 0x7ff8 << 48;
+
+    fn tagged(tag: u64, payload: u64) -> u64 {
+        tag << 48 | payload
+    }
+
+    // Pointers of the Rcs a TValue holds a reference of
+    fn pointer<T: ?Sized>(tag: u64, p: *const T) -> u64 {
+        let p = p as *const () as u64;
+        assert!(p <= PAYLOAD, "pointer too wide to nan-box");
+        tagged(tag, p)
+    }
+
+    fn encode(v: Value) -> u64 {
+        match v {
+            Value::Nil => tagged(NIL, 0),
+            Value::Boolean(b) => tagged(BOOLEAN, b as u64),
+            Value::Integer(i) if (i << 16) >> 16 == i => tagged(INTEGER, i as u64 & PAYLOAD),
+            Value::Integer(i) => pointer(BIG_INTEGER, Rc::into_raw(Rc::new(i))),
+            Value::Number(f) if f.is_nan() => f.to_bits() & SIGN | NAN,
+            Value::Number(f) => f.to_bits(),
+            Value::String(s) => pointer(STRING, s.into_raw()),
+            Value::Table(t) => pointer(TABLE, Rc::into_raw(t)),
+            Value::Function(Function::Lua(c)) => pointer(CLOSURE, Rc::into_raw(c)),
+            Value::Function(Function::Native(n)) => pointer(NATIVE, Rc::into_raw(n)),
+            Value::UserData(u) => pointer(USERDATA, Rc::into_raw(u)),
+        }
+    }
+
+    // Whether bits hold a reference, which needs counting
+    fn owns(bits: u64) -> bool {
+        matches!(
+            bits >> 48,
+            STRING | TABLE | CLOSURE | NATIVE | USERDATA | BIG_INTEGER
+        )
+    }
+
+    // The Value in bits, sharing the references the TValue holds, so it
+    // must not be dropped.
+    //
+    // Safety: bits come from encode, and the references they hold are
+    // still alive.
+    unsafe fn decode(bits: u64) -> ManuallyDrop<Value> {
+        let p = bits & PAYLOAD;
+        ManuallyDrop::new(match bits >> 48 {
+            NIL => Value::Nil,
+            BOOLEAN => Value::Boolean(p != 0),
+            INTEGER => Value::Integer(((bits << 16) as i64) >> 16),
+            BIG_INTEGER => Value::Integer(*(p as *const i64)),
+            STRING => Value::String(LuaString::from_raw(p as *const ())),
+            TABLE => Value::Table(Rc::from_raw(p as *const RefCell<Table>)),
+            CLOSURE => Value::Function(Function::Lua(Rc::from_raw(p as *const Closure))),
+            NATIVE => Value::Function(Function::Native(Rc::from_raw(p as *const NativeFunction))),
+            USERDATA => Value::UserData(Rc::from_raw(p as *const AnyUserData)),
+            _ => Value::Number(f64::from_bits(bits)),
+        })
+    }
+
+    // The Value in bits, taking over the references they hold.
+    //
+    // Safety: as for decode, and bits are not used again.
+    unsafe fn take(bits: u64) -> Value {
+        if bits >> 48 == BIG_INTEGER {
+            let i = Rc::from_raw((bits & PAYLOAD) as *const i64);
+            return Value::Integer(*i);
+        }
+        ManuallyDrop::into_inner(decode(bits))
+    }
+
+    // Rc keeps TValues on their thread, like Values
+    pub(crate) struct TValue(u64, PhantomData<Rc<()>>);
+
+    const _: () = assert!(std::mem::size_of::<TValue>() == 8);
+
+    // A Value read from a TValue, valid while that is borrowed
+    pub(crate) struct Ref<'a>(ManuallyDrop<Value>, PhantomData<&'a TValue>);
+
+    impl Deref for Ref<'_> {
+        type Target = Value;
+
+        fn deref(&self) -> &Value {
+            &self.0
+        }
+    }
+
+    impl TValue {
+        pub(crate) fn new(v: Value) -> TValue {
+            TValue(encode(v), PhantomData)
+        }
+
+        pub(crate) fn value(&self) -> Ref<'_> {
+            // self holds on to the references while it is borrowed
+            Ref(unsafe { decode(self.0) }, PhantomData)
+        }
+
+        pub(crate) fn get(&self) -> Value {
+            let v = unsafe { decode(self.0) };
+            if owns(self.0) {
+                return (*v).clone();
+            }
+            // Nothing to count
+            ManuallyDrop::into_inner(v)
+        }
+
+        pub(crate) fn set(&mut self, v: Value) {
+            *self = TValue::new(v);
+        }
+
+        pub(crate) fn into_value(self) -> Value {
+            let v = ManuallyDrop::new(self);
+            unsafe { take(v.0) }
+        }
+    }
+
+    impl Clone for TValue {
+        fn clone(&self) -> TValue {
+            if !owns(self.0) {
+                return TValue(self.0, PhantomData);
+            }
+            TValue::new(self.value().clone())
+        }
+    }
+
+    impl Drop for TValue {
+        fn drop(&mut self) {
+            if owns(self.0) {
+                drop(unsafe { take(self.0) });
+            }
+        }
+    }
+}
+
+pub(crate) use repr::TValue;
+
+use crate::value::Value;
+
+impl Default for TValue {
+    fn default() -> TValue {
+        TValue::new(Value::Nil)
+    }
+}
+
+impl From<Value> for TValue {
+    fn from(v: Value) -> TValue {
+        TValue::new(v)
+    }
+}
@@ -0,0 +1,305 @@
+use crate::conv::{FromLua, IntoLua};
+use crate::eval::Vm;
+use crate::native::{self, Args};
+use crate::value::{Error, Table, Value};
+
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+// A Rust type scripts can hold and call methods on. Each type gets
+// one metatable, built from add_methods the first time a value of it
+// is passed to Lua.
+pub trait UserData: Sized + 'static {
+    // What tostring and type errors call values of this type
+    fn type_name() -> &'static str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+}
+
+type Getter<T> = Box<dyn Fn(&mut Vm, &T) -> Result<Value, Error>>;
+type Setter<T> = Box<dyn Fn(&mut Vm, &mut T, Value) -> Result<(), Error>>;
+
+// Collects the methods, fields and metamethods of a UserData type.
+pub struct UserDataMethods<T> {
+    methods: Vec<(String, Value)>,
+    getters: HashMap<String, Getter<T>>,
+    setters: HashMap<String, Setter<T>>,
+    meta: Vec<(String, Value)>,
+    _marker: PhantomData<T>,
+}
+
+// A Rust value shared with Lua, along with its type's metatable.
+pub struct AnyUserData {
+    data: Rc<dyn Any>,
+    pub(crate) metatable: Rc<RefCell<Table>>,
+}
+
+impl AnyUserData {
+    pub fn is<T: 'static>(&self) -> bool {
+        self.data.is::<RefCell<T>>()
+    }
+
+    // The shared value, if it is a T.
+    pub fn get<T: 'static>(&self) -> Option<Rc<RefCell<T>>> {
+        self.data.clone().downcast::<RefCell<T>>().ok()
+    }
+
+    pub fn borrow<T: 'static>(&self) -> Result<Ref<'_, T>, Error> {
+        let cell = self.cell::<T>()?;
+        cell.try_borrow()
+            .map_err(|_| Error::message("userdata is already mutably borrowed"))
+    }
+
+    pub fn borrow_mut<T: 'static>(&self) -> Result<RefMut<'_, T>, Error> {
+        let cell = self.cell::<T>()?;
+        cell.try_borrow_mut()
+            .map_err(|_| Error::message("userdata is already borrowed"))
+    }
+
+    fn cell<T: 'static>(&self) -> Result<&RefCell<T>, Error> {
+        self.data
+            .downcast_ref::<RefCell<T>>()
+            .ok_or_else(|| Error::message("userdata is not of the expected type"))
+    }
+
+    // The identity of the wrapped value, shared by every userdata
+    // wrapping the same Rc
+    pub(crate) fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.data) as *const ()
+    }
+}
+
+// The self argument of a method, which must be a T.
+fn check_self<T: UserData>(args: &Args) -> Result<Rc<AnyUserData>, Error> {
+    match args.get(1) {
+        Value::UserData(ud) if ud.is::<T>() => Ok(ud),
+        v => Err(Error::message(format!(
+            "calling '{}' on bad self ({} expected, got {})",
+            args.name(),
+            T::type_name(),
+            match args.is_empty() {
+                true => "no value".to_string(),
+                false => value_type_name(&v),
+            }
+        ))),
+    }
+}
+
+// __name of a userdata's metatable, like luaL_typeerror reports
+fn value_type_name(v: &Value) -> String {
+    match v {
+        Value::UserData(ud) => match ud.metatable.borrow().get_str("__name") {
+            Value::String(s) => s.to_string(),
+            _ => "userdata".to_string(),
+        },
+        v => v.type_name().to_string(),
+    }
+}
+
+impl<T: UserData> UserDataMethods<T> {
+    fn new() -> UserDataMethods<T> {
+        UserDataMethods {
+            methods: vec![],
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            meta: vec![],
+            _marker: PhantomData,
+        }
+    }
+
+    // A method called as `obj:name(...)`. args holds the arguments
+    // after self, so argument errors number them the way Lua does.
+    pub fn method<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Vm, &T, Args) -> Result<Vec<Value>, Error> + 'static,
+    {
+        let method = native::function(name, move |vm, args| {
+            let ud = check_self::<T>(&args)?;
+            let this = ud.borrow::<T>()?;
+            f(vm, &this, args.skip_self())
+        });
+        self.methods.push((name.to_string(), method));
+    }
+
+    pub fn method_mut<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Vm, &mut T, Args) -> Result<Vec<Value>, Error> + 'static,
+    {
+        let method = native::function(name, move |vm, args| {
+            let ud = check_self::<T>(&args)?;
+            let mut this = ud.borrow_mut::<T>()?;
+            f(vm, &mut this, args.skip_self())
+        });
+        self.methods.push((name.to_string(), method));
+    }
+
+    // A field read as `obj.name`.
+    pub fn field<R, F>(&mut self, name: &str, get: F)
+    where
+        R: IntoLua,
+        F: Fn(&T) -> R + 'static,
+    {
+        let get: Getter<T> = Box::new(move |_, this| get(this).into_lua());
+        self.getters.insert(name.to_string(), get);
+    }
+
+    // A field assigned as `obj.name = value`.
+    pub fn field_set<A, F>(&mut self, name: &str, set: F)
+    where
+        A: FromLua,
+        F: Fn(&mut T, A) + 'static,
+    {
+        let set: Setter<T> = Box::new(move |_, this, value| {
+            set(this, A::from_lua(value)?);
+            Ok(())
+        });
+        self.setters.insert(name.to_string(), set);
+    }
+
+    // A metamethod such as __tostring or __len that takes self first.
+    pub fn meta_method<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Vm, &T, Args) -> Result<Vec<Value>, Error> + 'static,
+    {
+        let method = native::function(name, move |vm, args| {
+            let ud = check_self::<T>(&args)?;
+            let this = ud.borrow::<T>()?;
+            f(vm, &this, args.skip_self())
+        });
+        self.meta.push((name.to_string(), method));
+    }
+
+    // A metamethod getting its operands as they are, for operators
+    // such as __add where self may be either side.
+    pub fn meta_function<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut Vm, Args) -> Result<Vec<Value>, Error> + 'static,
+    {
+        self.meta
+            .push((name.to_string(), native::function(name, f)));
+    }
+
+    // Lays the collected functions out as a metatable. Methods live in
+    // a table __index points at, unless fields or a custom __index
+    // need a function to look keys up.
+    fn into_metatable(self) -> Table {
+        let mut methods = Table::new();
+        for (name, f) in self.methods {
+            methods.set_str(&name, f);
+        }
+
+        let mut mt = Table::new();
+        mt.set_str("__name", Value::from(T::type_name()));
+        for (name, f) in self.meta {
+            mt.set_str(&name, f);
+        }
+
+        let index = mt.get_str("__index");
+        if self.getters.is_empty() && matches!(index, Value::Nil) {
+            mt.set_str("__index", Value::from(methods));
+        } else {
+            let methods = Rc::new(RefCell::new(methods));
+            let getters = self.getters;
+            let f = native::function("__index", move |vm, args| {
+                let ud = check_self::<T>(&args)?;
+                let key = args.get(2);
+                let method = methods.borrow().get(&key);
+                if !matches!(method, Value::Nil) {
+                    return Ok(vec![method]);
+                }
+                if let Some(get) = key_str(&key).and_then(|k| getters.get(k)) {
+                    let this = ud.borrow::<T>()?;
+                    return Ok(vec![get(vm, &this)?]);
+                }
+                vm.fallback_index(&index, args.get(1), key)
+            });
+            mt.set_str("__index", f);
+        }
+
+        if !self.setters.is_empty() {
+            let setters = self.setters;
+            let newindex = mt.get_str("__newindex");
+            let f = native::function("__newindex", move |vm, args| {
+                let ud = check_self::<T>(&args)?;
+                let key = args.get(2);
+                if let Some(set) = key_str(&key).and_then(|k| setters.get(k)) {
+                    let mut this = ud.borrow_mut::<T>()?;
+                    set(vm, &mut this, args.get(3))?;
+                    return Ok(vec![]);
+                }
+                match newindex {
+                    Value::Nil => Err(Error::message(format!(
+                        "cannot set field '{}' of {}",
+                        key,
+                        T::type_name()
+                    ))),
+                    ref h => vm.call(h.clone(), args.into_vec()).map(|_| vec![]),
+                }
+            });
+            mt.set_str("__newindex", f);
+        }
+
+        mt
+    }
+}
+
+fn key_str(key: &Value) -> Option<&str> {
+    match key {
+        Value::String(s) => s.to_str().ok(),
+        _ => None,
+    }
+}
+
+impl<T: UserData> FromLua for Rc<RefCell<T>> {
+    fn from_lua(value: Value) -> Result<Rc<RefCell<T>>, Error> {
+        match &value {
+            Value::UserData(ud) => ud.get::<T>(),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            Error::message(format!(
+                "{} expected, got {}",
+                T::type_name(),
+                value_type_name(&value)
+            ))
+        })
+    }
+}
+
+impl Vm {
+    // Moves data into Lua as a userdata value.
+    pub fn create_userdata<T: UserData>(&mut self, data: T) -> Value {
+        self.wrap_userdata(Rc::new(RefCell::new(data)))
+    }
+
+    // Shares data with Lua; changes made by scripts are visible
+    // through the host's Rc and the other way around.
+    pub fn wrap_userdata<T: UserData>(&mut self, data: Rc<RefCell<T>>) -> Value {
+        let metatable = self.userdata_metatable::<T>();
+        let ud = Rc::new(AnyUserData { data, metatable });
+        self.track_userdata(&ud);
+        if !matches!(ud.metatable.borrow().get_str("__gc"), Value::Nil) {
+            self.heap.mark_for_finalization(Value::UserData(ud.clone()));
+        }
+        Value::UserData(ud)
+    }
+
+    fn userdata_metatable<T: UserData>(&mut self) -> Rc<RefCell<Table>> {
+        let id = TypeId::of::<T>();
+        if let Some(mt) = self.metatables.get(&id) {
+            return mt.clone();
+        }
+
+        let mut methods = UserDataMethods::<T>::new();
+        T::add_methods(&mut methods);
+        let mt = Rc::new(RefCell::new(methods.into_metatable()));
+        self.metatables.insert(id, mt.clone());
+        mt
+    }
+}
@@ -0,0 +1,529 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::eval::Closure;
+use crate::lex::{str_to_number, Numeral};
+use crate::native::NativeFunction;
+use crate::string::LuaString;
+use crate::tvalue::TValue;
+use crate::userdata::AnyUserData;
+
+#[derive(Clone)]
+pub enum Function {
+    Lua(Rc<Closure>),
+    Native(Rc<NativeFunction>),
+}
+
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(LuaString),
+    Function(Function),
+    Table(Rc<RefCell<Table>>),
+    UserData(Rc<AnyUserData>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) => "function",
+            Value::Table(_) => "table",
+            Value::UserData(_) => "userdata",
+        }
+    }
+
+    // Only nil and false are falsy in Lua
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    // Numbers, and strings that convert to numbers, as arithmetic
+    // operands.
+    pub fn to_number(&self) -> Option<Value> {
+        match self {
+            Value::Integer(_) | Value::Number(_) => Some(self.clone()),
+            Value::String(s) => str_to_number(s.to_str().ok()?).map(Value::from),
+            _ => None,
+        }
+    }
+
+    pub fn to_float(&self) -> Option<f64> {
+        match self.to_number()? {
+            Value::Integer(i) => Some(i as f64),
+            Value::Number(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    // Integers, and floats or strings with an exact integer value.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_number()? {
+            Value::Integer(i) => Some(i),
+            Value::Number(f) => float_to_integer(f),
+            _ => None,
+        }
+    }
+}
+
+impl From<Table> for Value {
+    fn from(t: Table) -> Value {
+        Value::Table(Rc::new(RefCell::new(t)))
+    }
+}
+
+impl From<Numeral> for Value {
+    fn from(n: Numeral) -> Value {
+        match n {
+            Numeral::Integer(i) => Value::Integer(i),
+            Numeral::Float(f) => Value::Number(f),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(LuaString::from(s))
+    }
+}
+
+impl From<LuaString> for Value {
+    fn from(s: LuaString) -> Value {
+        Value::String(s)
+    }
+}
+
+// The bounds of i64 as floats: 2^63 is exactly representable, but
+// i64::MAX is not.
+pub const TWO_POW_63: f64 = 9_223_372_036_854_775_808.0;
+
+pub fn float_to_integer(f: f64) -> Option<i64> {
+    if f.fract() == 0.0 && (-TWO_POW_63..TWO_POW_63).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+// Formats floats like C's "%.14g", which is what Lua uses, adding a
+// trailing ".0" when the result would read as an integer.
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+
+    let precision = 14;
+    let sci = format!("{:.*e}", precision - 1, f);
+    let (mantissa, exponent) = sci.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    let s = if exponent < -4 || exponent >= precision as i32 {
+        let mantissa = strip_zeros(mantissa);
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent).max(0) as usize;
+        strip_zeros(&format!("{:.*}", decimals, f)).to_string()
+    };
+
+    if s.contains(['.', 'e']) {
+        s
+    } else {
+        s + ".0"
+    }
+}
+
+fn strip_zeros(s: &str) -> &str {
+    if !s.contains('.') {
+        return s;
+    }
+
+    s.trim_end_matches('0').trim_end_matches('.')
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Number(n) => write!(f, "{}", format_float(*n)),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(Function::Lua(c)) => write!(f, "function: {:p}", Rc::as_ptr(c)),
+            Value::Function(Function::Native(n)) => {
+                write!(f, "function: builtin: {:p}", Rc::as_ptr(n))
+            }
+            Value::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
+            Value::UserData(u) => write!(f, "userdata: {:p}", u.as_ptr()),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+// Raw equality, without metamethods.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Integer(i), Value::Number(f)) | (Value::Number(f), Value::Integer(i)) => {
+                float_to_integer(*f) == Some(*i)
+            }
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(Function::Lua(a)), Value::Function(Function::Lua(b))) => {
+                Rc::ptr_eq(a, b)
+            }
+            (Value::Function(Function::Native(a)), Value::Function(Function::Native(b))) => {
+                Rc::ptr_eq(a, b)
+            }
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::UserData(a), Value::UserData(b)) => a.as_ptr() == b.as_ptr(),
+            _ => false,
+        }
+    }
+}
+
+// Only sound for table keys, which are never NaN and whose floats
+// with integer values are normalized to integers.
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Nil => {}
+            Value::Boolean(b) => b.hash(state),
+            Value::Integer(i) => i.hash(state),
+            Value::Number(f) => f.to_bits().hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Function(Function::Lua(c)) => Rc::as_ptr(c).hash(state),
+            Value::Function(Function::Native(n)) => Rc::as_ptr(n).hash(state),
+            Value::Table(t) => Rc::as_ptr(t).hash(state),
+            Value::UserData(u) => u.as_ptr().hash(state),
+        }
+    }
+}
+
+// A Lua table. Keys 1..n live in the array part while they are
+// contiguous, everything else in the hash part. Entries of the hash
+// part sit in numbered slots, so code that looked a key up can try
+// its slot again before hashing (see get_hinted).
+#[derive(Default)]
+pub struct Table {
+    array: Vec<TValue>,
+    nodes: Vec<(TValue, TValue)>,
+    slots: HashMap<Value, u32>,
+    metatable: Option<Rc<RefCell<Table>>>,
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table::default()
+    }
+
+    pub fn metatable(&self) -> Option<Rc<RefCell<Table>>> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<Rc<RefCell<Table>>>) {
+        self.metatable = metatable;
+    }
+
+    pub(crate) fn has_metatable(&self) -> bool {
+        self.metatable.is_some()
+    }
+
+    // Floats with an integer value index the same slot as the integer
+    fn normalize(key: &Value) -> Option<Value> {
+        match key {
+            Value::Number(f) => float_to_integer(*f).map(Value::Integer),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        if let Some(key) = Table::normalize(key) {
+            return self.get(&key);
+        }
+
+        if let Value::Integer(i) = key {
+            if *i >= 1 && (*i as u64) <= self.array.len() as u64 {
+                return self.array[*i as usize - 1].get();
+            }
+        }
+
+        match self.slots.get(key) {
+            Some(&slot) => self.nodes[slot as usize].1.get(),
+            None => Value::Nil,
+        }
+    }
+
+    // Like get, trying the slot in hint before hashing the key. The
+    // hint is only trusted if that slot holds this very key, so it can
+    // be stale, or come from another table, and just miss. Removing
+    // keys moves entries between slots and metatables are looked up
+    // afresh, so neither needs to invalidate anything. The hint is
+    // updated to wherever the key is found.
+    pub(crate) fn get_hinted(&self, key: &Value, hint: &Cell<u32>) -> Value {
+        if let Some((k, v)) = self.nodes.get(hint.get() as usize) {
+            if *k.value() == *key {
+                return v.get();
+            }
+        }
+        match self.slots.get(key) {
+            Some(&slot) => {
+                hint.set(slot);
+                self.nodes[slot as usize].1.get()
+            }
+            None => self.get(key),
+        }
+    }
+
+    // Sets a key that is already in the hash part, which never needs
+    // __newindex, trying the slot in hint first like get_hinted.
+    // Gives the value back if the key is absent.
+    pub(crate) fn replace_hinted(
+        &mut self,
+        key: &Value,
+        value: Value,
+        hint: &Cell<u32>,
+    ) -> Result<(), Value> {
+        let slot = match self.nodes.get(hint.get() as usize) {
+            Some((k, _)) if *k.value() == *key => hint.get(),
+            _ => match self.slots.get(key) {
+                Some(&slot) => {
+                    hint.set(slot);
+                    slot
+                }
+                None => return Err(value),
+            },
+        };
+        match value {
+            Value::Nil => {
+                self.remove(key);
+            }
+            value => self.nodes[slot as usize].1.set(value),
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Value) -> Option<Value> {
+        let slot = self.slots.remove(key)? as usize;
+        let (_, v) = self.nodes.swap_remove(slot);
+        // The last entry fills the hole
+        if let Some((moved, _)) = self.nodes.get(slot) {
+            *self.slots.get_mut(&*moved.value()).unwrap() = slot as u32;
+        }
+        Some(v.into_value())
+    }
+
+    fn insert(&mut self, key: Value, value: Value) {
+        match self.slots.get(&key) {
+            Some(&slot) => self.nodes[slot as usize].1.set(value),
+            None => {
+                self.slots.insert(key.clone(), self.nodes.len() as u32);
+                self.nodes.push((key.into(), value.into()));
+            }
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Value {
+        self.get(&Value::from(key))
+    }
+
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), &'static str> {
+        let key = match key {
+            Value::Nil => return Err("index is nil"),
+            Value::Number(f) if f.is_nan() => return Err("index is NaN"),
+            key => Table::normalize(&key).unwrap_or(key),
+        };
+
+        if let Value::Integer(i) = key {
+            let len = self.array.len() as u64;
+            if i >= 1 && (i as u64) <= len {
+                self.array[i as usize - 1].set(value);
+                // Keep the array part ending in a non-nil value
+                while self
+                    .array
+                    .last()
+                    .is_some_and(|v| matches!(*v.value(), Value::Nil))
+                {
+                    self.array.pop();
+                }
+                return Ok(());
+            }
+
+            if i >= 1 && i as u64 == len + 1 {
+                if let Value::Nil = value {
+                    self.remove(&key);
+                    return Ok(());
+                }
+
+                // Appending may make following hash keys contiguous
+                self.array.push(value.into());
+                self.remove(&key);
+                let mut next = Value::Integer(self.array.len() as i64 + 1);
+                while let Some(v) = self.remove(&next) {
+                    self.array.push(v.into());
+                    next = Value::Integer(self.array.len() as i64 + 1);
+                }
+                return Ok(());
+            }
+        }
+
+        match value {
+            Value::Nil => {
+                self.remove(&key);
+            }
+            value => self.insert(key, value),
+        }
+        Ok(())
+    }
+
+    pub fn set_str(&mut self, key: &str, value: Value) {
+        self.set(Value::from(key), value).unwrap();
+    }
+
+    // A border: t[n] is not nil and t[n + 1] is nil
+    pub fn len(&self) -> usize {
+        self.array.len()
+    }
+
+    // How long a sequence read from the table is: up to its last
+    // positive integer key, with holes read as nil, as long as more
+    // than half of the slots before it are used, as Lua sizes array
+    // parts. Sparser tables end at the border len finds.
+    pub(crate) fn sequence_len(&self) -> usize {
+        let mut last = self.array.len() as u64;
+        let mut used = self
+            .array
+            .iter()
+            .filter(|v| !matches!(v.get(), Value::Nil))
+            .count() as u64;
+        for (k, _) in &self.nodes {
+            if let Value::Integer(i) = k.get() {
+                if i >= 1 {
+                    last = last.max(i as u64);
+                    used += 1;
+                }
+            }
+        }
+        if used > last / 2 {
+            last as usize
+        } else {
+            self.len()
+        }
+    }
+
+    // No entries at all, in either part
+    pub fn is_empty(&self) -> bool {
+        self.array.is_empty() && self.nodes.is_empty()
+    }
+
+    // Every key with a non-nil value, array part first.
+    pub fn pairs(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        let array = self.array.iter().enumerate();
+        array
+            .map(|(i, v)| (Value::Integer(i as i64 + 1), v.get()))
+            .filter(|(_, v)| !matches!(v, Value::Nil))
+            .chain(self.nodes.iter().map(|(k, v)| (k.get(), v.get())))
+    }
+
+    pub fn push(&mut self, value: Value) {
+        self.set(Value::Integer(self.array.len() as i64 + 1), value)
+            .unwrap();
+    }
+
+    // Every value the table holds, keys included, without cloning.
+    // Keys come twice, as the slot index holds them too.
+    pub(crate) fn for_each_value<F: FnMut(&Value)>(&self, mut f: F) {
+        self.array.iter().for_each(|v| f(&v.value()));
+        for (k, v) in &self.nodes {
+            f(&k.value());
+            f(&v.value());
+        }
+        self.slots.keys().for_each(f);
+    }
+
+    // Drops the contents, breaking any cycle running through them.
+    pub(crate) fn clear(&mut self) {
+        self.array = vec![];
+        self.nodes = vec![];
+        self.slots = HashMap::new();
+        self.metatable = None;
+    }
+
+    // Roughly the bytes the table occupies
+    pub(crate) fn size(&self) -> usize {
+        let tvalue = std::mem::size_of::<TValue>();
+        std::mem::size_of::<Table>()
+            + self.array.capacity() * tvalue
+            + self.nodes.capacity() * 2 * tvalue
+            + self.slots.capacity() * (std::mem::size_of::<Value>() + 8)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    // Lexing, parsing and compiling errors, already formatted with
+    // their location
+    Syntax(String),
+    // Errors raised while running, with the value passed to error()
+    Runtime(Value),
+    // Errors raised by native functions, which the VM turns into
+    // runtime errors at the position of the calling Lua code
+    Message(String),
+}
+
+impl Error {
+    pub fn runtime<S: Into<String>>(msg: S) -> Error {
+        Error::Runtime(Value::String(LuaString::from(msg.into())))
+    }
+
+    pub fn message<S: Into<String>>(msg: S) -> Error {
+        Error::Message(msg.into())
+    }
+
+    // The error object Lua code sees
+    pub fn value(&self) -> Value {
+        match self {
+            Error::Syntax(msg) | Error::Message(msg) => Value::from(msg.as_str()),
+            Error::Runtime(v) => v.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(msg) | Error::Message(msg) => write!(f, "{}", msg),
+            Error::Runtime(Value::String(s)) => write!(f, "{}", s),
+            Error::Runtime(Value::Nil) => write!(f, "nil"),
+            Error::Runtime(v) => write!(f, "(error object is a {} value)", v.type_name()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
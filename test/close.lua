@@ -0,0 +1,38 @@
+local function resource(name)
+  return setmetatable({name = name}, {
+    __close = function(r, err)
+      print("close", r.name, err)
+    end,
+  })
+end
+
+-- Closed at the end of the block, innermost first
+do
+  local a <close> = resource("a")
+  local b <close> = resource("b")
+  local c <const> = 42
+  local d <close> = nil
+  print("in block", c)
+end
+
+-- and on break and return
+for i = 1, 3 do
+  local r <close> = resource("loop " .. i)
+  if i == 2 then
+    break
+  end
+end
+
+local function f()
+  local r <close> = resource("f")
+  return "returned"
+end
+print(f())
+
+-- Finalizers run once collections find their objects unreachable
+local gc = {__gc = function(o) print("collected", o.name) end}
+do
+  local t = setmetatable({name = "t"}, gc)
+  t.self = t
+end
+collectgarbage()
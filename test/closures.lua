@@ -0,0 +1,27 @@
+function counter()
+   local n = 0;
+   return function()
+      n = n + 1;
+      return n;
+   end
+end
+
+local c1, c2 = counter(), counter();
+print(c1(), c1(), c2());
+
+local saved = nil;
+for i = 1, 3 do
+   if i == 2 then
+      saved = function() return i; end
+   end
+end
+print(saved());
+
+local function sum(...)
+   local total = 0;
+   for _, n in function(s, i) if i < 3 then return i + 1, s end end, ..., 0 do
+      total = total + n;
+   end
+   return total;
+end
+print(sum(10));
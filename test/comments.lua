@@ -0,0 +1,12 @@
+#!/usr/bin/env lua
+-- Line comments run to the end of the line
+function add(a, b) -- even after code
+   --[[ long comments
+        can span lines ]]
+   return a + b;
+end
+
+--[==[ and can contain ]] or ]=] as long
+        as the level does not match ]==]
+print(add(1, 2)); --[[ inline ]] print(add(3, 4));
+-- a comment at the very end without a trailing newline
\ No newline at end of file
@@ -0,0 +1,65 @@
+-- Functions survive string.dump and load
+local function add(a, b)
+  return a + b
+end
+local chunk = string.dump(add)
+print(#chunk > 0, rawequal(chunk, string.dump(add)))
+local add2 = load(chunk)
+print(add2(2, 3), add2(0.5, 1))
+
+-- Upvalues come back fresh and nil
+local n = 10
+local function counter()
+  n = n + 1
+  return n
+end
+print(counter())
+local copy = load(string.dump(counter), "counter", "b")
+print(copy == counter)
+local function get()
+  return n
+end
+print(get(), load(string.dump(get))())
+print(load("return 1 + 1")())
+
+-- The mode argument
+print(load(chunk, "add", "t"))
+print(load("return 1", "one", "b"))
+print(load("return 1", "one", "bt")())
+
+-- Stripping leaves out debug info, and so is smaller
+local function boom()
+  local t = nil
+  return t.x
+end
+print(#string.dump(boom, true) < #string.dump(boom))
+
+-- Corrupted chunks
+print(load("\27Lua", "short"))
+print(load("\27Lua\x53", "old"))
+
+-- A stripped chunk of `local a = 7 return a`, which must only use
+-- the one register it says it needs. Its two instructions are
+-- little-endian words: LOADK, whose first two bytes hold the opcode
+-- and register a, and RETURN, whose third byte is n, one more than the
+-- number of results.
+local function chunk(a, n)
+  return "\27Lua\x54\x86\x19\x93\r\n\x1a\n\8\8\x78\x56\0\0\0\0\0\0"
+    .. "\0\0\0\0\0\x28\x77\x40"
+    .. "\0\0\1\1\0\0\2" .. a .. "\0\0\x1a\0" .. n .. "\0"
+    .. "\1\3\7\0\0\0\0\0\0\0\0\0\0\0\0\0"
+end
+print(load(chunk("\1\0", "\2"), "ok")())
+print(load(chunk("\x81\2", "\2"), "register"))
+print(load(chunk("\1\0", "\4"), "results"))
+
+-- Syntax errors come back as nil and a message
+print(load("x = = 1", "bad"))
+
+-- load takes a function returning pieces
+local pieces = { "return ", "4", "2" }
+local i = 0
+print(load(function()
+  i = i + 1
+  return pieces[i]
+end)())
@@ -0,0 +1,21 @@
+-- Syntax errors name the chunk, line and column, and underline the
+-- whole span on the offending line
+print(load("local x = 12abc + 1", "number"))
+print(load("local t = {1, 2}\nt[1] = 'unfinished\nprint(t)", "string"))
+print(load("for i = 1, 2 do end\ngoto nowhere", "goto"))
+
+-- return ends a block, with or without values and a semicolon
+print(load("return 1 print(2)", "last"))
+print("do", load("do return end print(1)", "do")())
+print("empty", load("return;", "empty")())
+print(load("return 1, 2;", "values")())
+print(load("if true then return 'then' end return 'after'", "if")())
+
+-- Keywords at the very end of a chunk, with no newline after them
+print(load("local function f() return 'eof' end return f()", "eof")())
+print(load("local x = 1 while x < 3 do x = x + 1 end", "end") ~= nil)
+
+-- As does this file
+if true then
+  print("end")
+end
\ No newline at end of file
@@ -0,0 +1,56 @@
+-- Constant expressions are folded when compiled, with the results
+-- they would have at run time
+print(1 + 2, 7 // 2, 7 / 2, 3 * 1.5, 2 ^ 10, 7 % -3, -7.5 % 2)
+print(1 == 1.0, 1 < 2, "a" < "b", "a" .. 1 .. 2.0, 1 << 62, ~0, -(-3))
+print(not nil, #"four", 10 // 3.0, 1e308 * 10)
+print(nil and 1, false or "x", 1 and 2, 1 or error, (nil or nil))
+
+-- Zero floats, NaN and division by zero are left to run time
+print(0.0 * -1, 1 / 0, -1 / 0, 0 / 0 ~= 0 / 0)
+
+-- `and` and `or` still give one value from a call
+local function two()
+  return 1, 2
+end
+print(true and two())
+print(nil or two())
+print((two()))
+
+-- Dead branches
+if false then
+  print("never")
+elseif nil then
+  print("never")
+else
+  print("else")
+end
+local n = 0
+while true do
+  n = n + 1
+  if n > 3 then
+    break
+  end
+end
+print(n)
+
+-- Small integers added and compared, and equality with constants,
+-- with numbers and with metamethods
+local x, y = 5, 2.5
+print(x + 1, y + 1, x + 40000, x < 10, 10 < x, y >= 2, 2 >= y, x == 5, x ~= "5")
+local mt = {
+  __add = function(a, b)
+    return "add " .. tostring(b)
+  end,
+  __lt = function(a, b)
+    return rawequal(a, 1)
+  end,
+  __le = function(a, b)
+    return rawequal(b, 1)
+  end,
+  __eq = function()
+    return true
+  end,
+}
+local t = setmetatable({}, mt)
+print(t + 1, t < 1, 1 < t, t <= 1, 1 <= t, t > 1, t == 1, t ~= nil)
+print("10" + 1, "1" < "2")
@@ -1,7 +1,7 @@
 function i(n)
-   n + 12;
+   local n0 = n + 12;
    local n2 = n + 1;
-   n + 16;
+   local n3 = n + 16;
    return n2;
 end
 
@@ -0,0 +1,46 @@
+-- Cycles are freed by a full collection
+collectgarbage()
+local before = collectgarbage("count")
+
+for i = 1, 10000 do
+  local a, b = {}, {}
+  a.other, b.other = b, a
+  local self = {}
+  self.self = self
+  local function f() return f end
+end
+
+collectgarbage("collect")
+print(collectgarbage("count") < before + 64)
+
+-- Live objects survive, whatever refers to them
+local keep = {}
+keep.me = keep
+local function counter()
+  local n = 0
+  return function() n = n + 1; return n, keep end
+end
+local next_id = counter()
+next_id()
+collectgarbage()
+local n, k = next_id()
+print(keep.me == keep, n, k == keep)
+
+print(collectgarbage("isrunning"))
+collectgarbage("stop")
+print(collectgarbage("isrunning"))
+collectgarbage("restart")
+print(collectgarbage("step", 0))
+print(collectgarbage("generational"))
+for i = 1, 10000 do
+  local t = {}
+  t[1] = t
+end
+print(collectgarbage("incremental", 200, 100))
+print(collectgarbage("incremental"))
+
+-- A step runs a whole collection or none: a kilobyte isn't enough
+-- to make one due right after collecting, many are
+collectgarbage()
+print(collectgarbage("step", 1))
+print(collectgarbage("step", 100000))
@@ -0,0 +1,70 @@
+-- Deep recursion is fine up to the call depth limit
+local function depth(n)
+  if n == 0 then
+    return 0
+  end
+  return 1 + depth(n - 1)
+end
+print(depth(10000))
+
+local function rep(s, n)
+  local out = ""
+  for _ = 1, n do
+    out = out .. s
+  end
+  return out
+end
+
+-- Nesting deeper than the parser allows is a syntax error, which
+-- load returns
+local function try(chunk)
+  local f, msg = load(chunk)
+  return f, msg ~= nil
+end
+print(try("return " .. rep("(", 300) .. "1" .. rep(")", 300)))
+print(try(rep("do ", 300) .. rep("end ", 300)))
+print(try("return " .. rep("not ", 300) .. "1"))
+print(try("return " .. rep("2 ^ ", 300) .. "1"))
+print(load("return " .. rep("(", 100) .. "1" .. rep(")", 100))())
+print(load("return 1" .. rep(" .. 1", 150))() == rep("1", 151))
+
+-- Operators that group to the left only nest their right operands,
+-- so chains of them can be any length
+print(load("return 1" .. rep(" + 1", 10000))())
+print(load("return 1" .. rep(" - 1 < 2 == true and 1", 1000))())
+
+-- Recursing past the limit is an error pcall catches, after which
+-- calls work as before
+local function forever(n)
+  return 1 + forever(n + 1)
+end
+print(pcall(forever, 1))
+print(depth(100))
+
+-- So is native code calling back into Lua too deeply, here through
+-- __index functions indexing the next table
+local t = setmetatable({}, {})
+getmetatable(t).__index = function(_, k)
+  return t[k]
+end
+print(pcall(function()
+  return t.x
+end))
+
+-- pcall returns the function's results, and error raises any value
+print(pcall(depth, 3))
+local e = {}
+local ok, caught = pcall(error, e)
+print(ok, caught == e)
+print(pcall(error, "message", 0))
+print(pcall(function()
+  error("positioned")
+end))
+local function check(x)
+  if not x then
+    error("check failed", 2)
+  end
+end
+print(pcall(function()
+  check(false)
+end))
@@ -0,0 +1,17 @@
+function truthy(x)
+   if x then
+      return 1;
+   end
+   return 0;
+end
+
+print(truthy(true));
+print(truthy(false));
+print(truthy(nil));
+
+local endless = 3;
+print(endless);
+
+function noop()
+   return 0;
+end
\ No newline at end of file
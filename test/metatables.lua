@@ -0,0 +1,39 @@
+local V = {}
+V.__index = V
+V.__add = function(a, b) return setmetatable({x = a.x + b.x}, V) end
+V.__eq = function(a, b) return a.x == b.x end
+V.__lt = function(a, b) return a.x < b.x end
+V.__le = function(a, b) return a.x <= b.x end
+V.__tostring = function(v) return "V(" .. v.x .. ")" end
+V.__len = function(v) return v.x end
+V.__unm = function(v) return setmetatable({x = -v.x}, V) end
+V.__concat = function(a, b) return tostring(a) .. "|" .. tostring(b) end
+V.__call = function(self, y) return self.x * y end
+function V.new(x) return setmetatable({x = x}, V) end
+function V:double() return V.new(self.x * 2) end
+local a, b = V.new(1), V.new(2)
+print(a + b, a == V.new(1), a ~= b, a < b, a <= b, a > b, b >= a, #b, -a, a .. b, a .. "s", a(10))
+print(a:double(), rawequal(a, V.new(1)), rawlen({1,2}), getmetatable(a) == V)
+local p = setmetatable({}, {__newindex = function(t, k, v) rawset(t, k, v * 10) end, __index = function(t, k) return k .. "!" end})
+p.q = 5
+print(p.q, p.zz, rawget(p, "zz"))
+local chain = setmetatable({}, {__index = setmetatable({a = 1}, {__index = {b = 2}})})
+print(chain.a, chain.b, chain.c)
+
+-- Globals are fields of _G, metamethods included
+local declared = {}
+setmetatable(_G, {
+  __index = function(_, name)
+    return "undeclared " .. name
+  end,
+  __newindex = function(t, name, v)
+    declared[#declared + 1] = name
+    rawset(t, name, v)
+  end,
+})
+print(undefined_global)
+new_global = 1
+new_global = 2
+print(new_global, #declared, declared[1])
+setmetatable(_G, nil)
+print(undefined_global)
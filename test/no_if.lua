@@ -3,7 +3,7 @@ if n < 0 then
    print(12);
 end
 
-n + 12;
+local n1 = n + 12;
 local n2 = 1 + n;
 local n3 = n2 + 14;
 print(n3);
@@ -0,0 +1,76 @@
+-- Classes with methods and inheritance through __index: nearly every
+-- step is a field or method lookup
+local Point = {}
+Point.__index = Point
+
+function Point.new(x, y)
+  return setmetatable({x = x, y = y}, Point)
+end
+
+function Point:add(other)
+  return Point.new(self.x + other.x, self.y + other.y)
+end
+
+function Point:dot(other)
+  return self.x * other.x + self.y * other.y
+end
+
+local Particle = setmetatable({}, {__index = Point})
+Particle.__index = Particle
+
+function Particle.new(x, y, vx, vy)
+  local p = setmetatable(Point.new(x, y), Particle)
+  p.velocity = Point.new(vx, vy)
+  return p
+end
+
+function Particle:step()
+  local moved = self:add(self.velocity)
+  self.x, self.y = moved.x, moved.y
+end
+
+function Particle:energy()
+  return self.velocity:dot(self.velocity)
+end
+
+local particles = {}
+for i = 1, 100 do
+  particles[i] = Particle.new(i, -i, i % 7, i % 3)
+end
+
+local total = 0
+for _ = 1, 2000 do
+  for i = 1, #particles do
+    local p = particles[i]
+    p:step()
+    total = total + p:energy() + p:dot(p.velocity)
+  end
+end
+print(total, particles[1].x, particles[100].y)
+
+-- Fields found again after the table changed shape or metatable
+local a = {k = 1}
+local function get(t)
+  return t.k
+end
+print(get(a))
+a.other, a.k = 2, nil
+print(get(a))
+a.k = 3
+print(get(a))
+setmetatable(a, {__index = {k = 4}})
+a.k = nil
+print(get(a))
+getmetatable(a).__index = {k = 5}
+print(get(a), get({k = 6}), get(setmetatable({}, {__index = a})))
+
+-- Globals, assigned and removed
+g = 1
+local function global()
+  return g
+end
+print(global())
+g = nil
+print(global())
+g = 2
+print(global())
@@ -0,0 +1,18 @@
+-- Strings are bytes: escapes needn't be UTF-8, and # counts bytes
+local s = "\xff\0a";
+print(#s);
+print(#"é");
+
+-- Equal strings are the same key, however they were made
+local t = {};
+t["ab" .. "c"] = 1;
+print(t.abc);
+
+local long = "0123456789012345678901234567890123456789012345";
+local built = "01234567890123456789012345678901234567890123" .. "45";
+print(long == built);
+t[long] = 2;
+print(t[built]);
+
+print("\65\066" .. 1 .. 2.5);
+print("a" < "b", "\xff" > "a");
@@ -0,0 +1,12 @@
+local t = {10, 20, 30, n = "three"};
+t[#t + 1] = 40;
+print(#t, t[4], t.n);
+
+local sum = 0;
+for i = 1, #t do
+   sum = sum + t[i];
+end
+print(sum);
+
+-- Run with `lust test/tables.lua a b` to see the script arguments
+print(arg[0], ...);
@@ -0,0 +1,79 @@
+-- Tail calls reuse the caller's frame, so they can go on forever
+local function count(n, acc)
+  if n == 0 then
+    return acc
+  end
+  return count(n - 1, acc + 1)
+end
+print(count(1000000, 0))
+
+-- Mutual recursion, through globals
+function even(n)
+  if n == 0 then
+    return true
+  end
+  return odd(n - 1)
+end
+function odd(n)
+  if n == 0 then
+    return false
+  end
+  return even(n - 1)
+end
+print(even(1000001), odd(1000001))
+
+-- Methods, varargs and all the results of the callee
+local obj = {n = 0}
+function obj:add(k, ...)
+  if k == 0 then
+    return self.n, ...
+  end
+  self.n = self.n + 1
+  return self:add(k - 1, ...)
+end
+print(obj:add(100000, "a", "b"))
+
+local function pass(...)
+  return select_first(...)
+end
+function select_first(x)
+  return x
+end
+print(pass(1, 2, 3))
+
+-- Functions that aren't Lua's are called as usual
+local function show(...)
+  return print(...)
+end
+show("printed", 1)
+local callable = setmetatable({}, {
+  __call = function(self, x)
+    return x * 2
+  end,
+})
+local function call(x)
+  return callable(x)
+end
+print(call(21))
+
+-- Upvalues of the caller are closed before its frame is reused
+local function make(n)
+  local function get()
+    return n
+  end
+  return (function(f)
+    return f
+  end)(get)
+end
+print(make(7)(), make(8)())
+
+-- Not a tail call with a to-be-closed variable in scope
+local function closes()
+  local c <close> = setmetatable({}, {
+    __close = function()
+      print("closed")
+    end,
+  })
+  return count(3, 0)
+end
+print(closes())
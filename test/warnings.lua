@@ -0,0 +1,13 @@
+-- Calls to globals that a chunk doesn't assign, and that aren't
+-- defined when it is loaded, are reported once warnings are on
+local source = "helper(1) defined() print(1)"
+load(source, "off")
+warn("@on")
+load(source, "on")
+load("function defined() end " .. source, "defines")
+helper = print
+load(source, "later")
+
+-- Only when loading: running the chunk fails as usual
+print(pcall(load("local function f() return missing() end return f()", "missing")))
+warn("@off")
@@ -0,0 +1,50 @@
+-- probe holds weakly on to objects, to see which ones were collected
+local probe = setmetatable({}, {__mode = "v"})
+local kept = {}
+
+-- Weak keys: entries go with their keys
+local cache = setmetatable({}, {__mode = "k"})
+cache[kept] = 1
+probe.key = {}
+cache[probe.key] = 2
+cache.name = 3
+collectgarbage()
+print(probe.key, cache[kept], cache.name)
+
+-- Weak values, which strings never are
+local names = setmetatable({}, {__mode = "v"})
+names[1] = kept
+names[2] = {}
+names[3] = "strings stay"
+collectgarbage()
+print(names[1] == kept, names[2], names[3])
+
+-- Both
+local both = setmetatable({}, {__mode = "kv"})
+probe.both = {}
+both[probe.both] = kept
+both.x = kept
+both.y = {}
+collectgarbage()
+print(probe.both, both.x == kept, both.y)
+
+-- Ephemerons: a value referring to its own key doesn't keep it alive,
+-- even through other entries
+local memo = setmetatable({}, {__mode = "k"})
+probe.own = {}
+memo[probe.own] = {owner = probe.own}
+probe.first = {}
+probe.second = {}
+memo[probe.first] = {next = probe.second}
+memo[probe.second] = {back = probe.first}
+memo[kept] = {owner = kept}
+collectgarbage()
+print(probe.own, probe.first, probe.second, memo[kept].owner == kept)
+
+-- while a reachable key keeps its value alive
+local value = {}
+probe.value = value
+memo[kept].value = value
+value = nil
+collectgarbage()
+print(probe.value ~= nil)
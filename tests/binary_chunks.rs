@@ -0,0 +1,158 @@
+// Loading binary chunks written by dump, and rejecting corrupted ones.
+
+use lust::{Lua, Value};
+
+const SOURCE: &str = "
+local t = setmetatable({}, {__index = function(_, k) return k end})
+local function sum(...)
+  local total, values = 0, {...}
+  for i = 1, #values do
+    total = total + values[i]
+  end
+  for i = 1, #values + 1 do
+    total = total + i
+  end
+  local function iter(n, i)
+    if i < n then
+      return i + 1
+    end
+  end
+  for i in iter, 2, 0 do
+    total = total + i
+  end
+  return total, t.x, ...
+end
+local up = 1
+local function inc(n)
+  up = up + n
+  return sum(up, n)
+end
+return inc(41)
+";
+
+fn dumps(lua: &mut Lua) -> Vec<Vec<u8>> {
+    let f = lua.load(SOURCE, "chunk").unwrap().into_function();
+    vec![lua.dump(&f, false).unwrap(), lua.dump(&f, true).unwrap()]
+}
+
+#[test]
+fn round_trip() {
+    let mut lua = Lua::new();
+    for chunk in dumps(&mut lua) {
+        let results = lua.load(&chunk, "chunk").unwrap().call(vec![]).unwrap();
+        assert_eq!(results[0], Value::Integer(92));
+    }
+}
+
+// Changing any one byte, or cutting the chunk short, gets an error or
+// a chunk that loads, but never a panic
+#[test]
+fn corrupted_chunks_are_rejected() {
+    let mut lua = Lua::new();
+    for chunk in dumps(&mut lua) {
+        // A chunk not starting with the signature is loaded as source
+        for i in 4..chunk.len() {
+            let b = chunk[i];
+            for corrupted in [b ^ 1, b ^ 0x80, b.wrapping_add(1), 0, 0xff] {
+                let mut bytes = chunk.clone();
+                bytes[i] = corrupted;
+                if let Err(e) = lua.load(&bytes, "corrupted") {
+                    assert!(e.to_string().contains("bad binary format"), "{}", e);
+                }
+            }
+            let msg = lua.load(&chunk[..i], "short").err().unwrap().to_string();
+            assert!(msg.contains("bad binary format"), "{}", msg);
+        }
+    }
+}
+
+// Opcodes of the 32-bit instruction words in a chunk, in their low 7
+// bits, with register A in the 8 bits above
+const OPCODE: u32 = 0x7f;
+const REGISTER_A: u32 = 0xff << 7;
+const RETURN: u32 = 26;
+const VARARG: u32 = 27;
+const FORPREP: u32 = 31;
+const FORLOOP: u32 = 32;
+
+fn word(chunk: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes(chunk[at..at + 4].try_into().unwrap())
+}
+
+fn set_word(chunk: &mut [u8], at: usize, word: u32) {
+    chunk[at..at + 4].copy_from_slice(&word.to_ne_bytes());
+}
+
+// Where the instructions with these opcodes follow one another
+fn find_code(chunk: &[u8], opcodes: &[u32]) -> usize {
+    (0..chunk.len() - 4 * opcodes.len())
+        .find(|&at| {
+            let op = |i: usize| word(chunk, at + 4 * i) & OPCODE;
+            opcodes
+                .iter()
+                .enumerate()
+                .all(|(i, &expected)| op(i) == expected)
+        })
+        .expect("instructions not in chunk")
+}
+
+fn main_chunk(lua: &mut Lua, source: &str) -> Vec<u8> {
+    let f = lua.load(source, "chunk").unwrap().into_function();
+    lua.dump(&f, false).unwrap()
+}
+
+// A loop whose preparation is gone finds a state it can't step
+#[test]
+fn for_loop_without_prep() {
+    let mut lua = Lua::new();
+    let mut chunk = main_chunk(&mut lua, "for i = 1, 2.5 do end");
+    let at = find_code(&chunk, &[FORPREP, FORLOOP]);
+    // MOVE 0 0
+    set_word(&mut chunk, at, 0);
+    let f = lua.load(&chunk, "corrupted").unwrap();
+    let msg = f.call(vec![]).unwrap_err().to_string();
+    assert_eq!(msg, "chunk:1: 'for' loop state is corrupted");
+}
+
+// Values taken from below a captured local leave the stack no shorter
+// than the slot the upvalue closes over
+#[test]
+fn vararg_below_open_upvalue() {
+    let mut lua = Lua::new();
+    let source = "local x = 1 local function f() return x end return ...";
+    let mut chunk = main_chunk(&mut lua, source);
+    let at = find_code(&chunk, &[VARARG, RETURN]);
+    for at in [at, at + 4] {
+        let w = word(&chunk, at) & !REGISTER_A;
+        set_word(&mut chunk, at, w);
+    }
+    let f = lua.load(&chunk, "corrupted").unwrap();
+    assert_eq!(f.call(vec![]).unwrap(), vec![]);
+}
+
+// Constants past what an instruction's field holds are found through
+// the word after it, or a register, and survive the round trip
+#[test]
+fn many_constants() {
+    let mut source = String::from("local t = {");
+    for i in 0..140_000 {
+        source += &format!("'s{}', ", i);
+    }
+    source += "}\nlocal o = {";
+    for i in 0..300 {
+        source += &format!("k{} = {}, ", i, i);
+    }
+    source += "}\nfunction o.m(self) return self.k299 end\nreturn #t, t[140000], o:m()";
+
+    let mut lua = Lua::new();
+    let f = lua.load(&source, "constants").unwrap().into_function();
+    let chunk = lua.dump(&f, false).unwrap();
+    let expected = vec![
+        Value::Integer(140_000),
+        Value::from("s139999"),
+        Value::Integer(299),
+    ];
+    assert_eq!(lua.call(f, vec![]).unwrap(), expected);
+    let results = lua.load(&chunk, "chunk").unwrap().call(vec![]).unwrap();
+    assert_eq!(results, expected);
+}
@@ -0,0 +1,10 @@
+// Helpers shared by the integration tests.
+
+use lust::Error;
+
+pub fn error_message<T>(result: Result<T, Error>) -> String {
+    match result {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e.to_string(),
+    }
+}
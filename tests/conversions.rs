@@ -0,0 +1,160 @@
+// Converting values between Lua and Rust, with FromLua/IntoLua and
+// through serde.
+
+mod common;
+
+use common::error_message;
+use lust::{from_value, to_value, FromLua, IntoLua, Lua, Value};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Config {
+    name: String,
+    port: u16,
+    debug: bool,
+    tags: Vec<String>,
+    limits: HashMap<String, i64>,
+    owner: Option<String>,
+    origin: (f64, f64),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Marker;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Units {
+    done: (),
+    marker: Marker,
+    found: Option<()>,
+}
+
+fn config() -> Config {
+    Config {
+        name: "web".to_string(),
+        port: 8080,
+        debug: false,
+        tags: vec!["a".to_string(), "b".to_string()],
+        limits: HashMap::from([("cpu".to_string(), 2), ("mem".to_string(), 512)]),
+        owner: None,
+        origin: (1.5, -2.0),
+    }
+}
+
+#[test]
+fn eval_as_struct() {
+    let mut lua = Lua::new();
+    let cfg: Config = lua
+        .load(
+            "return {
+                name = 'web', port = 8080, debug = false, tags = {'a', 'b'},
+                limits = {cpu = 2, mem = 512}, origin = {1.5, -2},
+            }",
+            "config.lua",
+        )
+        .unwrap()
+        .eval_as()
+        .unwrap();
+    assert_eq!(cfg, config());
+}
+
+#[test]
+fn round_trips() {
+    let mut lua = Lua::new();
+    let cfg = config();
+    lua.set_global("cfg", to_value(&cfg).unwrap());
+    let back: Config = lua.load("return cfg", "cfg").unwrap().eval_as().unwrap();
+    assert_eq!(back, cfg);
+
+    let owned = Config {
+        owner: Some("ops".to_string()),
+        ..config()
+    };
+    assert_eq!(
+        from_value::<Config>(to_value(&owned).unwrap()).unwrap(),
+        owned
+    );
+
+    let v = vec![1, 2, 3];
+    assert_eq!(from_value::<Vec<i32>>(to_value(&v).unwrap()).unwrap(), v);
+    // Elements after a nil stay at their index
+    let holes = vec![Some(1), None, Some(3)];
+    assert_eq!(
+        from_value::<Vec<Option<i32>>>(to_value(&holes).unwrap()).unwrap(),
+        holes
+    );
+    assert_eq!(
+        Vec::<Option<i32>>::from_lua(holes.clone().into_lua().unwrap()).unwrap(),
+        holes
+    );
+    lua.set_global("holes", holes.into_lua().unwrap());
+    let third: i64 = lua
+        .load("return holes[3]", "holes")
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(third, 3);
+    let m = HashMap::from([("x".to_string(), true), ("y".to_string(), false)]);
+    assert_eq!(
+        from_value::<HashMap<String, bool>>(to_value(&m).unwrap()).unwrap(),
+        m
+    );
+    let o: Option<String> = None;
+    assert_eq!(
+        from_value::<Option<String>>(to_value(&o).unwrap()).unwrap(),
+        o
+    );
+    // Unit values are kept in tables, where nil would be dropped
+    let units = Units {
+        done: (),
+        marker: Marker,
+        found: Some(()),
+    };
+    assert_eq!(
+        from_value::<Units>(to_value(&units).unwrap()).unwrap(),
+        units
+    );
+    let t = (1u8, "two".to_string(), 3.5f64);
+    assert_eq!(
+        from_value::<(u8, String, f64)>(to_value(&t).unwrap()).unwrap(),
+        t
+    );
+
+    // The same through Lua code
+    let from_lua: (Vec<i64>, HashMap<String, i64>, Option<i64>) = lua
+        .load("return {1, 2}, {k = 3}, nil", "multi")
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(
+        from_lua,
+        (vec![1, 2], HashMap::from([("k".to_string(), 3)]), None)
+    );
+}
+
+#[test]
+fn booleans_are_not_truthiness() {
+    assert!(bool::from_lua(Value::Boolean(true)).unwrap());
+    assert!(!bool::from_lua(Value::Boolean(false)).unwrap());
+    assert_eq!(
+        error_message(bool::from_lua(Value::Integer(1))),
+        "boolean expected, got number"
+    );
+    assert_eq!(
+        error_message(bool::from_lua(Value::Nil)),
+        "boolean expected, got nil"
+    );
+    assert_eq!(
+        error_message(from_value::<bool>(Value::from("yes"))),
+        "boolean expected, got string"
+    );
+
+    let mut lua = Lua::new();
+    let msg = error_message(
+        lua.load("return {name = 'x', port = 1, debug = 1}", "bad")
+            .unwrap()
+            .eval_as::<Config>(),
+    );
+    assert_eq!(msg, "boolean expected, got number");
+}
@@ -0,0 +1,156 @@
+// The embedding API: loading and running chunks, globals and calling
+// Lua functions from Rust.
+
+mod common;
+
+use common::error_message;
+use lust::{Lua, LuaString, Value};
+
+#[test]
+fn load_exec_and_eval() {
+    let mut lua = Lua::new();
+    lua.load("function add(a, b) return a + b end", "init")
+        .unwrap()
+        .exec()
+        .unwrap();
+    let sum: i64 = lua.load("return add(1, 2)", "sum").unwrap().eval().unwrap();
+    assert_eq!(sum, 3);
+
+    let (x, y): (i64, Option<String>) =
+        lua.load("return 1, 'two'", "pair").unwrap().eval().unwrap();
+    assert_eq!((x, y), (1, Some("two".to_string())));
+
+    let results = lua
+        .load("return ...", "args")
+        .unwrap()
+        .call(vec![Value::Integer(7), Value::from("x")])
+        .unwrap();
+    assert_eq!(results, vec![Value::Integer(7), Value::from("x")]);
+}
+
+#[test]
+fn load_errors() {
+    let mut lua = Lua::new();
+    let msg = error_message(lua.load("x = = 1", "bad"));
+    assert!(msg.starts_with("bad:1:"), "{}", msg);
+
+    let msg = error_message(lua.load("return nil + 1", "arith").unwrap().exec());
+    assert_eq!(msg, "arith:1: attempt to perform arithmetic on a nil value");
+    assert!(lua.traceback().unwrap().starts_with("stack traceback:"));
+}
+
+#[test]
+fn source_that_is_not_utf8() {
+    // Latin-1 in a string literal and a comment
+    let mut lua = Lua::new();
+    lua.load(b"s = '\xe9t\xe9' -- \xe9t\xe9\n", "latin1")
+        .unwrap()
+        .exec()
+        .unwrap();
+    assert_eq!(
+        lua.get_global("s"),
+        Value::from(LuaString::from(&b"\xe9t\xe9"[..]))
+    );
+
+    // Errors show such bytes as replacement characters
+    let msg = error_message(lua.load(b"s = '\xe9' +", "latin1"));
+    assert!(msg.starts_with("latin1:1:"), "{}", msg);
+    assert!(msg.contains("s = '\u{fffd}' +"), "{}", msg);
+}
+
+#[test]
+fn sources_live_as_long_as_their_functions() {
+    let mut lua = Lua::new();
+    lua.load("function f()\n  error('kept')\nend", "kept")
+        .unwrap()
+        .exec()
+        .unwrap();
+    // Chunks done with, or that fail to compile, free their source for
+    // later ones, but not the source f still refers to
+    for i in 0..100 {
+        lua.load(format!("return {}", i), "dropped")
+            .unwrap()
+            .exec()
+            .unwrap();
+        error_message(lua.load("x = = 1", "failed"));
+    }
+    let msg = error_message(lua.call_function("f", vec![]));
+    assert_eq!(msg, "kept:2: kept");
+}
+
+#[test]
+fn load_modes_and_dump() {
+    let mut lua = Lua::new();
+    let f = lua.load("return 6 * 7", "answer").unwrap().into_function();
+    let chunk = lua.dump(&f, false).unwrap();
+
+    let answer: i64 = lua.load(&chunk, "answer").unwrap().eval().unwrap();
+    assert_eq!(answer, 42);
+    let msg = error_message(lua.load_mode(&chunk, "answer", "t"));
+    assert!(msg.contains("attempt to load a binary chunk"), "{}", msg);
+    let msg = error_message(lua.load_mode("return 1", "text", "b"));
+    assert!(msg.contains("attempt to load a text chunk"), "{}", msg);
+}
+
+#[test]
+fn globals() {
+    let mut lua = Lua::new();
+    lua.set_global("x", Value::Integer(2));
+    lua.load("y = x * 21", "globals").unwrap().exec().unwrap();
+    assert_eq!(lua.get_global("y"), Value::Integer(42));
+    assert_eq!(lua.globals().borrow().get_str("y"), Value::Integer(42));
+    assert_eq!(lua.get_global("missing"), Value::Nil);
+}
+
+#[test]
+fn call_function() {
+    let mut lua = Lua::new();
+    lua.load(
+        "function greet(name) return 'hi ' .. name, #name end",
+        "greet",
+    )
+    .unwrap()
+    .exec()
+    .unwrap();
+    let results = lua
+        .call_function("greet", vec![Value::from("bob")])
+        .unwrap();
+    assert_eq!(results, vec![Value::from("hi bob"), Value::Integer(3)]);
+
+    let msg = error_message(lua.call_function("nothing", vec![]));
+    assert_eq!(msg, "attempt to call a nil value (global 'nothing')");
+}
+
+#[test]
+fn table_constructor_positions() {
+    let mut lua = Lua::new();
+    let holes: (Option<i64>, i64) = lua
+        .load("local t = {nil, 2} return t[1], t[2]", "holes")
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(holes, (None, 2));
+
+    let varargs: (i64, Option<i64>, i64) = lua
+        .load(
+            "local function f(...) local a = {...} return a[1], a[2], a[3] end return f(1, nil, 3)",
+            "varargs",
+        )
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(varargs, (1, None, 3));
+
+    let keyed: (String, Option<String>) = lua
+        .load("local t = {[1] = 'k', 'p'} return t[1], t[2]", "keyed")
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(keyed, ("p".to_string(), None));
+
+    // Past the first batch of positional values
+    let items = vec!["nil"; 60].join(", ");
+    let src = format!("local t = {{{}, 61}} return t[60], t[61]", items);
+    let batched: (Option<i64>, i64) = lua.load(&src, "batched").unwrap().eval().unwrap();
+    assert_eq!(batched, (None, 61));
+}
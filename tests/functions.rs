@@ -0,0 +1,100 @@
+// Rust functions registered as Lua globals, and their argument checks.
+
+mod common;
+
+use common::error_message;
+use lust::{IntoLua, Lua, Value};
+
+#[test]
+fn register_and_register_in() {
+    let mut lua = Lua::new();
+    lua.register("double", |_, args| {
+        let n = args.check_integer(1)?;
+        Ok(vec![Value::Integer(n * 2)])
+    });
+    lua.register_in("util", "rep", |_, args| {
+        let s = args.check_string(1)?;
+        let n = args.opt_integer(2)?.unwrap_or(2);
+        Ok(vec![s.to_string_lossy().repeat(n as usize).into_lua()?])
+    });
+    let (d, r, r3): (i64, String, String) = lua
+        .load(
+            "return double(21), util.rep('ab'), util.rep('x', 3)",
+            "calls",
+        )
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!((d, r.as_str(), r3.as_str()), (42, "abab", "xxx"));
+
+    // Native functions can call back into Lua
+    lua.register("apply", |lua, args| {
+        let f = args.check_function(1)?;
+        lua.call(f, vec![args.get(2)])
+    });
+    let v: i64 = lua
+        .load("return apply(function(x) return x + 1 end, 41)", "apply")
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(v, 42);
+}
+
+#[test]
+fn argument_errors() {
+    let mut lua = Lua::new();
+    lua.register_in("util", "rep", |_, args| {
+        let s = args.check_string(1)?;
+        let n = args.opt_integer(2)?.unwrap_or(2);
+        Ok(vec![s.to_string_lossy().repeat(n as usize).into_lua()?])
+    });
+    lua.register("needs", |_, args| {
+        args.check_table(1)?;
+        args.check_number(2)?;
+        args.check_any(3)?;
+        Ok(vec![])
+    });
+
+    let cases = [
+        (
+            "util.rep()",
+            "bad argument #1 to 'rep' (string expected, got no value)",
+        ),
+        (
+            "util.rep({})",
+            "bad argument #1 to 'rep' (string expected, got table)",
+        ),
+        (
+            "util.rep('a', 1.5)",
+            "bad argument #2 to 'rep' (number has no integer representation)",
+        ),
+        (
+            "util.rep('a', 'x')",
+            "bad argument #2 to 'rep' (number expected, got string)",
+        ),
+        (
+            "needs(1)",
+            "bad argument #1 to 'needs' (table expected, got number)",
+        ),
+        (
+            "needs({}, nil)",
+            "bad argument #2 to 'needs' (number expected, got nil)",
+        ),
+        (
+            "needs({}, 1)",
+            "bad argument #3 to 'needs' (value expected)",
+        ),
+    ];
+    for (source, expected) in cases {
+        let msg = error_message(lua.load(source, "args").unwrap().exec());
+        assert_eq!(msg, format!("args:1: {}", expected));
+    }
+
+    // Numbers pass as strings and numeric strings as numbers
+    let s: String = lua
+        .load("return util.rep(12, '2')", "coerce")
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(s, "1212");
+}
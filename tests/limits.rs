@@ -0,0 +1,102 @@
+// Limits on the depth of Lua and native calls, and of nesting in
+// source.
+
+mod common;
+
+use common::error_message;
+use lust::{Lua, Value};
+
+#[test]
+fn max_call_depth() {
+    let mut lua = Lua::new();
+    lua.set_max_call_depth(100);
+    lua.load(
+        "function depth(n) if n == 0 then return 0 end return 1 + depth(n - 1) end",
+        "depth",
+    )
+    .unwrap()
+    .exec()
+    .unwrap();
+    let ok = lua
+        .call_function("depth", vec![Value::Integer(50)])
+        .unwrap();
+    assert_eq!(ok, vec![Value::Integer(50)]);
+    let msg = error_message(lua.call_function("depth", vec![Value::Integer(200)]));
+    assert_eq!(msg, "depth:1: stack overflow");
+
+    // The state is still usable afterwards
+    let ok = lua
+        .call_function("depth", vec![Value::Integer(10)])
+        .unwrap();
+    assert_eq!(ok, vec![Value::Integer(10)]);
+}
+
+#[test]
+fn native_recursion_limit() {
+    // Each nested native call holds a run loop on the Rust stack. A
+    // spawned thread's default stack must be enough to reach the limit.
+    let msgs = std::thread::spawn(|| {
+        let mut lua = Lua::new();
+        lua.register("callback", |lua, args| {
+            lua.call(args.check_function(1)?, vec![])
+        });
+        let callback = error_message(
+            lua.load(
+                "local function f() return callback(f) end return f()",
+                "native",
+            )
+            .unwrap()
+            .exec(),
+        );
+        let index = error_message(
+            lua.load(
+                "local t = setmetatable({}, {__index = function(t, k) return t[k] end})
+                return t.x",
+                "index",
+            )
+            .unwrap()
+            .exec(),
+        );
+        [callback, index]
+    })
+    .join()
+    .unwrap();
+    for msg in msgs {
+        assert!(msg.ends_with("C stack overflow"), "{}", msg);
+    }
+}
+
+#[test]
+fn overflow_caught_by_pcall() {
+    let mut lua = Lua::new();
+    lua.set_max_call_depth(50);
+    let (ok, msg): (bool, String) = lua
+        .load(
+            "local function f(n) return 1 + f(n + 1) end
+            return pcall(f, 1)",
+            "caught",
+        )
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert!(!ok);
+    assert_eq!(msg, "caught:1: stack overflow");
+    assert!(lua.traceback().is_none());
+}
+
+#[test]
+fn long_operator_chains() {
+    let mut lua = Lua::new();
+    let chain = format!("x = 1{}", " + 1".repeat(199));
+    lua.load(&chain, "chain").unwrap().exec().unwrap();
+    assert_eq!(lua.get_global("x"), Value::Integer(200));
+
+    let locals = format!("local a = 1 x = a{}", " + a".repeat(5000));
+    lua.load(&locals, "locals").unwrap().exec().unwrap();
+    assert_eq!(lua.get_global("x"), Value::Integer(5001));
+
+    // Right operands nest, as do operators grouping to the right
+    let nested = format!("x = {}1", "2 ^ ".repeat(199));
+    let msg = error_message(lua.load(&nested, "nested"));
+    assert!(msg.contains("chunk has too many syntax levels"), "{}", msg);
+}
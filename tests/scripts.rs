@@ -0,0 +1,62 @@
+// Runs each script in test/ with the lust binary and compares what it
+// prints, standard output then standard error, with the .out file next
+// to it. With LUST_BLESS set, the .out files are written instead.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[test]
+fn scripts_match_their_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+    let mut scripts: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "lua"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts in {}", dir.display());
+
+    // All run at once, and are waited for in turn. They run from test/
+    // so chunk names in errors are just the file name.
+    let children: Vec<_> = scripts
+        .iter()
+        .map(|script| {
+            Command::new(env!("CARGO_BIN_EXE_lust"))
+                .arg(script.file_name().unwrap())
+                .current_dir(&dir)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let bless = env::var_os("LUST_BLESS").is_some();
+    let mut failed = vec![];
+    for (script, child) in scripts.iter().zip(children) {
+        let name = script.file_name().unwrap().to_str().unwrap();
+        let output = child.wait_with_output().unwrap();
+        let mut actual = output.stdout;
+        actual.extend_from_slice(&output.stderr);
+
+        let expected_path = script.with_extension("out");
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = fs::read(&expected_path).unwrap_or_default();
+        if actual != expected || !output.status.success() {
+            eprintln!(
+                "{} ({}) printed:\n{}\nrather than:\n{}",
+                name,
+                output.status,
+                String::from_utf8_lossy(&actual),
+                String::from_utf8_lossy(&expected)
+            );
+            failed.push(name.to_string());
+        }
+    }
+    assert!(failed.is_empty(), "scripts with other output: {:?}", failed);
+}
@@ -0,0 +1,188 @@
+// Rust values shared with Lua as userdata.
+
+mod common;
+
+use common::error_message;
+use lust::{IntoLua, Lua, UserData, UserDataMethods, Value};
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+struct Counter {
+    count: i64,
+    step: i64,
+}
+
+impl UserData for Counter {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.method("get", |_, this, _| Ok(vec![Value::Integer(this.count)]));
+        methods.method_mut("add", |_, this, args| {
+            this.count += args.opt_integer(1)?.unwrap_or(this.step);
+            Ok(vec![])
+        });
+        // Calls back into Lua while self is borrowed
+        methods.method("with", |lua, this, args| {
+            let f = args.check_function(1)?;
+            lua.call(f, vec![Value::Integer(this.count)])
+        });
+        methods.field("count", |this| this.count);
+        methods.field_set("step", |this, step: i64| this.step = step);
+        methods.meta_method("__tostring", |_, this, _| {
+            Ok(vec![format!("Counter({})", this.count).into_lua()?])
+        });
+    }
+}
+
+fn counter(lua: &mut Lua) -> Value {
+    lua.create_userdata(Counter { count: 0, step: 1 })
+}
+
+#[test]
+fn methods_and_fields() {
+    let mut lua = Lua::new();
+    let c = counter(&mut lua);
+    lua.set_global("c", c);
+    let (get, count, s): (i64, i64, String) = lua
+        .load(
+            "c:add() c:add(10) c.step = 5 c:add() return c:get(), c.count, tostring(c)",
+            "c",
+        )
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!((get, count, s.as_str()), (16, 16, "Counter(16)"));
+}
+
+#[test]
+fn wrap_userdata_shares_the_value() {
+    let mut lua = Lua::new();
+    let shared = Rc::new(RefCell::new(Counter { count: 1, step: 1 }));
+    let c = lua.wrap_userdata(shared.clone());
+    lua.set_global("c", c);
+    lua.load("c:add(41)", "add").unwrap().exec().unwrap();
+    assert_eq!(shared.borrow().count, 42);
+
+    shared.borrow_mut().count = 7;
+    let count: i64 = lua.load("return c.count", "count").unwrap().eval().unwrap();
+    assert_eq!(count, 7);
+
+    match lua.get_global("c") {
+        Value::UserData(ud) => {
+            assert!(ud.is::<Counter>());
+            assert_eq!(ud.borrow::<Counter>().unwrap().count, 7);
+            assert!(Rc::ptr_eq(&ud.get::<Counter>().unwrap(), &shared));
+        }
+        v => panic!("expected userdata, got {}", v.type_name()),
+    }
+}
+
+#[test]
+fn errors() {
+    let mut lua = Lua::new();
+    let c = counter(&mut lua);
+    lua.set_global("c", c);
+    let cases = [
+        (
+            "c.get({})",
+            "calling 'get' on bad self (Counter expected, got table)",
+        ),
+        (
+            "c.add()",
+            "calling 'add' on bad self (Counter expected, got no value)",
+        ),
+        (
+            "c:add('x')",
+            "bad argument #1 to 'add' (number expected, got string)",
+        ),
+        ("c.count = 1", "cannot set field 'count' of Counter"),
+        ("c.step = 'x'", "number expected, got string"),
+    ];
+    for (source, expected) in cases {
+        let msg = error_message(lua.load(source, "errors").unwrap().exec());
+        assert!(
+            msg.ends_with(expected),
+            "{} does not end with {}",
+            msg,
+            expected
+        );
+    }
+}
+
+#[test]
+fn borrow_conflicts() {
+    let mut lua = Lua::new();
+    let c = counter(&mut lua);
+    lua.set_global("c", c);
+
+    // A method holding a shared borrow, calling back into a mutating one
+    let msg = error_message(
+        lua.load("c:with(function() c:add() end)", "conflict")
+            .unwrap()
+            .exec(),
+    );
+    assert!(msg.ends_with("userdata is already borrowed"), "{}", msg);
+
+    // Shared borrows nest
+    let n: i64 = lua
+        .load(
+            "return c:with(function(n) return n + c:get() + c.count end)",
+            "shared",
+        )
+        .unwrap()
+        .eval()
+        .unwrap();
+    assert_eq!(n, 0);
+
+    // The host holding a mutable borrow
+    let Value::UserData(ud) = lua.get_global("c") else {
+        panic!("expected userdata");
+    };
+    let held = ud.borrow_mut::<Counter>().unwrap();
+    let msg = error_message(lua.load("return c:get()", "held").unwrap().exec());
+    assert!(
+        msg.ends_with("userdata is already mutably borrowed"),
+        "{}",
+        msg
+    );
+    assert!(ud.borrow::<Counter>().is_err());
+    drop(held);
+    lua.load("c:add()", "released").unwrap().exec().unwrap();
+    assert_eq!(ud.borrow::<Counter>().unwrap().count, 1);
+}
+
+struct Resource {
+    closed: Rc<Cell<u32>>,
+}
+
+impl UserData for Resource {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.meta_method("__gc", |_, this, _| {
+            this.closed.set(this.closed.get() + 1);
+            Ok(vec![])
+        });
+    }
+}
+
+#[test]
+fn gc_metamethod() {
+    let closed = Rc::new(Cell::new(0));
+    let mut lua = Lua::new();
+    let r = lua.create_userdata(Resource {
+        closed: closed.clone(),
+    });
+    lua.set_global("r", r);
+    lua.collect_garbage();
+    assert_eq!(closed.get(), 0);
+
+    lua.load("r = nil", "release").unwrap().exec().unwrap();
+    lua.collect_garbage();
+    assert_eq!(closed.get(), 1);
+
+    // Finalizers still pending run when the state is dropped, once each
+    let r = lua.create_userdata(Resource {
+        closed: closed.clone(),
+    });
+    lua.set_global("r", r);
+    drop(lua);
+    assert_eq!(closed.get(), 2);
+}